@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS idx_processes_epoch_row_id ON processes(epoch, row_id);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_processes_module_id_row_id;
+ALTER TABLE processes DROP COLUMN IF EXISTS module_id;
@@ -0,0 +1,2 @@
+ALTER TABLE processes ADD COLUMN IF NOT EXISTS module_id VARCHAR NULL;
+CREATE INDEX IF NOT EXISTS idx_processes_module_id_row_id ON processes(module_id, row_id);
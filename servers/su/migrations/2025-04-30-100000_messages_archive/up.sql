@@ -0,0 +1,2 @@
+-- same columns as messages but no indexes, archived rows are rarely read
+CREATE TABLE IF NOT EXISTS messages_archive (LIKE messages);
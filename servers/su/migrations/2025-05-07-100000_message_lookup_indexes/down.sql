@@ -0,0 +1,2 @@
+-- the indexes belong to earlier migrations, nothing to undo
+SELECT 1;
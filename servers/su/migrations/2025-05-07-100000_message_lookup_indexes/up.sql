@@ -0,0 +1,3 @@
+-- both were created by earlier migrations, this restores them on databases where they were dropped
+CREATE INDEX IF NOT EXISTS idx_messages_message_id ON messages(message_id);
+CREATE INDEX IF NOT EXISTS idx_assignments_assignment_id ON messages(assignment_id);
@@ -0,0 +1,4 @@
+-- sha256 of the bundle as hex, null for rows written before checksums
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS bundle_checksum TEXT;
+-- archive_old_messages copies rows with SELECT *, keep the columns lined up
+ALTER TABLE messages_archive ADD COLUMN IF NOT EXISTS bundle_checksum TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE messages_archive DROP COLUMN IF EXISTS sender_id;
+DROP INDEX IF EXISTS idx_messages_sender_id_process_id_timestamp;
+ALTER TABLE messages DROP COLUMN IF EXISTS sender_id;
@@ -0,0 +1,7 @@
+-- message_data has no top level sender, the sender is the owner of the
+-- message item. Assignments with no message item get a null sender_id.
+-- Adding a stored generated column rewrites the table.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS sender_id TEXT GENERATED ALWAYS AS (message_data->'message'->'owner'->>'address') STORED;
+CREATE INDEX IF NOT EXISTS idx_messages_sender_id_process_id_timestamp ON messages(sender_id, process_id, timestamp);
+-- archive_old_messages copies rows with SELECT *, keep the columns lined up
+ALTER TABLE messages_archive ADD COLUMN IF NOT EXISTS sender_id TEXT;
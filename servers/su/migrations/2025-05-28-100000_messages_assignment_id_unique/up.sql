@@ -0,0 +1,26 @@
+-- The unique constraint from the column definition is missing on some databases,
+-- which let retries insert the same assignment twice. Refuse to build the index
+-- while duplicates exist so the rows get resolved by an operator rather than
+-- picked at random, ./cli find_duplicate_assignments lists all of them.
+DO $$
+DECLARE
+  duplicates TEXT;
+BEGIN
+  SELECT string_agg(assignment_id || ' (' || row_count || ' rows)', ', ')
+  INTO duplicates
+  FROM (
+    SELECT assignment_id, COUNT(*) AS row_count
+    FROM messages
+    WHERE assignment_id IS NOT NULL
+    GROUP BY assignment_id
+    HAVING COUNT(*) > 1
+    ORDER BY assignment_id
+    LIMIT 20
+  ) d;
+
+  IF duplicates IS NOT NULL THEN
+    RAISE EXCEPTION 'messages has duplicate assignment ids, resolve them before migrating: %', duplicates;
+  END IF;
+END $$;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_assignment_id_unique ON messages(assignment_id) WHERE assignment_id IS NOT NULL;
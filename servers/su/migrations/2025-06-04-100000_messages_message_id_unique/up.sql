@@ -0,0 +1,24 @@
+-- A message id can be assigned many times, but only one live row may carry the
+-- data item itself. Rows that are only an assignment have a null message.
+DO $$
+DECLARE
+  duplicates TEXT;
+BEGIN
+  SELECT string_agg(message_id || ' (' || row_count || ' rows)', ', ')
+  INTO duplicates
+  FROM (
+    SELECT message_id, COUNT(*) AS row_count
+    FROM messages
+    WHERE jsonb_typeof(message_data->'message') = 'object' AND deleted_at IS NULL
+    GROUP BY message_id
+    HAVING COUNT(*) > 1
+    ORDER BY message_id
+    LIMIT 20
+  ) d;
+
+  IF duplicates IS NOT NULL THEN
+    RAISE EXCEPTION 'messages has data items saved more than once, resolve them before migrating: %', duplicates;
+  END IF;
+END $$;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_message_id_data_item_unique ON messages(message_id) WHERE jsonb_typeof(message_data->'message') = 'object' AND deleted_at IS NULL;
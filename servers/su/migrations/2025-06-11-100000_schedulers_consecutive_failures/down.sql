@@ -0,0 +1 @@
+ALTER TABLE schedulers DROP COLUMN IF EXISTS consecutive_failures;
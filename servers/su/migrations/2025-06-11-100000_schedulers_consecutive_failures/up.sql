@@ -0,0 +1,3 @@
+-- How many times in a row the router has failed to reach the scheduler,
+-- schedulers over SCHEDULER_MAX_FAILURES are left out of new spawns.
+ALTER TABLE schedulers ADD COLUMN IF NOT EXISTS consecutive_failures INTEGER NOT NULL DEFAULT 0;
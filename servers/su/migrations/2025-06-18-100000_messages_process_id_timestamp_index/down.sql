@@ -0,0 +1,2 @@
+-- the index belongs to an earlier migration, nothing to undo
+SELECT 1;
@@ -0,0 +1,2 @@
+-- created by an earlier migration, this restores it on databases where it was dropped
+CREATE INDEX IF NOT EXISTS idx_messages_process_id_timestamp ON messages(process_id, timestamp);
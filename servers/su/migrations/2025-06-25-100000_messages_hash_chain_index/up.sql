@@ -0,0 +1,3 @@
+-- text_pattern_ops so a LIKE 'prefix%' on hash_chain can use the index
+-- whatever the database collation is
+CREATE INDEX IF NOT EXISTS idx_messages_process_id_hash_chain ON messages(process_id, hash_chain text_pattern_ops);
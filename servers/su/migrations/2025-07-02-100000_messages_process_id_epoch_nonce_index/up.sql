@@ -0,0 +1,3 @@
+-- matches the ORDER BY of get_latest_message and get_latest_messages_for_processes
+-- so the latest message is read off the index instead of sorting the process
+CREATE INDEX IF NOT EXISTS idx_messages_process_id_epoch_nonce_timestamp ON messages(process_id, epoch DESC, nonce DESC, timestamp DESC);
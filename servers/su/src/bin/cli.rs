@@ -1,7 +1,16 @@
 use std::env;
 use std::io;
+use su::domain::backfill_process_modules;
+use su::domain::checkpoint_bytestore;
+use su::domain::export_router_state;
+use su::domain::find_duplicate_assignments;
+use su::domain::import_router_state;
+use su::domain::migrate_key_format;
 use su::domain::migrate_to_disk;
+#[cfg(feature = "disk-store")]
 use su::domain::migrate_to_local;
+use su::domain::restore_bytestore;
+#[cfg(feature = "disk-store")]
 use su::domain::sync_local_drives;
 
 #[tokio::main]
@@ -10,35 +19,65 @@ async fn main() -> io::Result<()> {
 
     if args.len() < 2 {
         eprintln!("Usage: {} <function_name>", args[0]);
-        eprintln!("Available functions: migrate_to_disk, migrate_to_local, sync_local_drives");
+        eprintln!("Available functions: migrate_to_disk, migrate_to_local, sync_local_drives, backfill_process_modules, checkpoint_bytestore, restore_bytestore, migrate_key_format, find_duplicate_assignments, export_router_state, import_router_state");
         return Ok(());
     }
 
-    let interval = if args.len() >= 3 {
-        match args[2].parse::<u64>() {
-            Ok(val) => val,
-            Err(_) => {
-                eprintln!("Invalid interval: {}. Using default (5 seconds).", args[3]);
-                5
-            }
-        }
-    } else {
-        5
-    };
-
     match args[1].as_str() {
         "migrate_to_disk" => {
             migrate_to_disk().await.unwrap();
         }
+        #[cfg(feature = "disk-store")]
         "migrate_to_local" => {
             migrate_to_local().await.unwrap();
         }
+        #[cfg(feature = "disk-store")]
         "sync_local_drives" => {
+            let interval = if args.len() >= 3 {
+                match args[2].parse::<u64>() {
+                    Ok(val) => val,
+                    Err(_) => {
+                        eprintln!("Invalid interval: {}. Using default (5 seconds).", args[2]);
+                        5
+                    }
+                }
+            } else {
+                5
+            };
             sync_local_drives(interval).await.unwrap();
         }
+        #[cfg(not(feature = "disk-store"))]
+        "migrate_to_local" | "sync_local_drives" => {
+            eprintln!(
+                "{} needs the local store, rebuild the su with the disk-store feature",
+                args[1]
+            );
+            std::process::exit(1);
+        }
+        "backfill_process_modules" => {
+            backfill_process_modules().await.unwrap();
+        }
+        "checkpoint_bytestore" => {
+            checkpoint_bytestore().await.unwrap();
+        }
+        "restore_bytestore" => {
+            restore_bytestore().await.unwrap();
+        }
+        "migrate_key_format" => {
+            migrate_key_format().await.unwrap();
+        }
+        "find_duplicate_assignments" => {
+            find_duplicate_assignments().await.unwrap();
+        }
+        "export_router_state" => {
+            export_router_state().await.unwrap();
+        }
+        "import_router_state" => {
+            import_router_state().await.unwrap();
+        }
         _ => {
             eprintln!("Invalid function name: {}", args[1]);
-            eprintln!("Available functions: migrate_to_disk, migrate_to_local, sync_local_drives");
+            eprintln!("Available functions: migrate_to_disk, migrate_to_local, sync_local_drives, backfill_process_modules, checkpoint_bytestore, restore_bytestore, migrate_key_format, find_duplicate_assignments, export_router_state, import_router_state");
         }
     }
 
@@ -0,0 +1,21 @@
+use std::env;
+use std::io;
+use su::domain::migrate_to_sqlite;
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+
+    let output = match args.iter().position(|a| a == "--output") {
+        Some(i) => args.get(i + 1),
+        None => None,
+    };
+
+    match output {
+        Some(output) => migrate_to_sqlite(output).await,
+        None => {
+            eprintln!("Usage: {} --output <sqlite file>", args[0]);
+            Ok(())
+        }
+    }
+}
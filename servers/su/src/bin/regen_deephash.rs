@@ -0,0 +1,32 @@
+use std::env;
+use std::io;
+use su::domain::regenerate_deep_hashes;
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+
+    let process_id = match args.iter().position(|a| a == "--process") {
+        Some(i) => match args.get(i + 1) {
+            Some(process_id) => Some(process_id.clone()),
+            None => {
+                eprintln!("Usage: {} [--process <id>] [--concurrency N]", args[0]);
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    let concurrency = match args.iter().position(|a| a == "--concurrency") {
+        Some(i) => match args.get(i + 1).map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) if n > 0 => n,
+            _ => {
+                eprintln!("--concurrency needs a number greater than 0");
+                return Ok(());
+            }
+        },
+        None => 1,
+    };
+
+    regenerate_deep_hashes(process_id, concurrency).await
+}
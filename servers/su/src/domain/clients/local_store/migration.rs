@@ -49,6 +49,7 @@ pub async fn migrate_to_local() -> io::Result<()> {
     data_store
         .bytestore
         .try_read_instance_connect()
+        .await
         .expect("Failed to connect to bytestore");
 
     let config = AoConfig::new(None).expect("Failed to read configuration");
@@ -1,11 +1,14 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use dashmap::DashSet;
 use rocksdb::{Options, DB};
 use tokio::time::{sleep, Duration};
 
 use super::super::super::core::dal::{
-    DataStore, Log, Message, PaginatedMessages, Process, StoreErrorType,
+    ByteStoreCheckpoint, ByteStoreCompaction, ByteStoreCounters, ByteStoreStatistics, ByteStoreSyncRun, CacheStats, CircuitBreakerStats, DataStore, HealthStatus, Log, Message, MessageFilter, PaginatedMessages,
+    Process, ProcessIntegrityReport, ProcessTimeline, PurgeReport, StoreErrorType,
 };
 use super::super::super::SuLog;
 
@@ -23,11 +26,15 @@ pub struct LocalStoreClient {
       and Messages, only public for migration purposes
     */
     pub index_db: DB,
+    frozen_processes: DashSet<String>,
 }
 
 impl From<rocksdb::Error> for StoreErrorType {
     fn from(err: rocksdb::Error) -> StoreErrorType {
-        StoreErrorType::DatabaseError(format!("RocksDB error: {:?}", err))
+        StoreErrorType::DatabaseError {
+            message: format!("RocksDB error: {:?}", err),
+            source: Some(Box::new(err)),
+        }
     }
 }
 
@@ -63,6 +70,7 @@ impl LocalStoreClient {
             _logger: logger,
             file_db,
             index_db,
+            frozen_processes: DashSet::new(),
         })
     }
 
@@ -101,6 +109,7 @@ impl LocalStoreClient {
             _logger: logger,
             file_db,
             index_db,
+            frozen_processes: DashSet::new(),
         })
     }
 
@@ -186,6 +195,45 @@ impl LocalStoreClient {
         format!("message_assignment:{}", assignment_id)
     }
 
+    /*
+      The stored bundle for a message, looked up by
+      assignment id first and then by message id.
+    */
+    fn get_message_bytes(&self, tx_id: &str) -> Result<Vec<u8>, StoreErrorType> {
+        let assignment_key = self.msg_assignment_key(tx_id);
+        if let Some(message_bundle) = self.file_db.get(assignment_key.as_bytes())? {
+            return Ok(message_bundle);
+        }
+
+        /*
+          If not found by assignment_id, assume tx_id is a
+          message id. We fetch its assignment id by
+          message id and then fetch the bundle with that
+
+          There is no key that only contains message id. This
+          is because a message can be assignmed many times. So
+          here we use prefix iteration to find the correct message
+        */
+        let cf = self.index_db.cf_handle("message").ok_or_else(|| {
+            StoreErrorType::database("Column family 'message' not found".to_string())
+        })?;
+        let message_key_prefix = format!("message:{}:", tx_id);
+        let mut iter = self
+            .index_db
+            .prefix_iterator_cf(cf, message_key_prefix.as_bytes());
+
+        if let Some(result) = iter.next() {
+            let (_key, assignment_id_bytes) = result?;
+            let assignment_id = String::from_utf8(assignment_id_bytes.to_vec())?;
+            let assignment_key = self.msg_assignment_key(&assignment_id);
+            if let Some(message_bundle) = self.file_db.get(assignment_key.as_bytes())? {
+                return Ok(message_bundle);
+            }
+        }
+
+        Err(StoreErrorType::not_found("message", tx_id))
+    }
+
     fn msg_order_key(&self, message: &Message) -> Result<String, StoreErrorType> {
         let process_id = message.process_id()?;
         let assignment_id = message.assignment_id()?;
@@ -226,7 +274,7 @@ impl LocalStoreClient {
         let process_key_prefix = format!("message_ordering:{}:", process_id);
 
         let cf = self.index_db.cf_handle("message_ordering").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'message_ordering' not found".to_string())
+            StoreErrorType::database("Column family 'message_ordering' not found".to_string())
         })?;
 
         let iter = self
@@ -302,7 +350,7 @@ impl LocalStoreClient {
         let process_key_prefix = format!("message_ordering:{}:", process_id);
 
         let cf = self.index_db.cf_handle("message_ordering").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'message_ordering' not found".to_string())
+            StoreErrorType::database("Column family 'message_ordering' not found".to_string())
         })?;
 
         let iter = self
@@ -377,12 +425,19 @@ impl DataStore for LocalStoreClient {
       it here to remain consistent with how messages
       are saved
     */
-    fn save_process(&self, process: &Process, bundle: &[u8]) -> Result<String, StoreErrorType> {
+    async fn save_process(
+        &self,
+        process: &Process,
+        bundle: &[u8],
+    ) -> Result<String, StoreErrorType> {
+        process
+            .validate()
+            .map_err(|errors| StoreErrorType::invalid_input("process", errors.join("; ")))?;
         let process_id = &process.process.process_id;
         let assignment_id = process.assignment_id()?;
 
         let cf = self.index_db.cf_handle("process").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'process' not found".to_string())
+            StoreErrorType::database("Column family 'process' not found".to_string())
         })?;
 
         let process_key = self.proc_composite_key(process_id, &assignment_id);
@@ -390,7 +445,7 @@ impl DataStore for LocalStoreClient {
             .put_cf(cf, process_key.as_bytes(), assignment_id.as_bytes())?;
 
         let cf = self.index_db.cf_handle("process_ordering").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'process_ordering' not found".to_string())
+            StoreErrorType::database("Column family 'process_ordering' not found".to_string())
         })?;
 
         let process_order_key = self.proc_order_key(process)?;
@@ -415,11 +470,18 @@ impl DataStore for LocalStoreClient {
         bundle_in: &[u8],
         deep_hash: Option<&String>,
     ) -> Result<String, StoreErrorType> {
+        let process_id = message.process_id()?;
+        if self.frozen_processes.contains(&process_id) {
+            return Err(StoreErrorType::ReadOnly(format!(
+                "Process {} is frozen for handover",
+                process_id
+            )));
+        }
         let message_id = message.message_id()?;
         let assignment_id = message.assignment_id()?;
 
         let cf = self.index_db.cf_handle("message").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'message' not found".to_string())
+            StoreErrorType::database("Column family 'message' not found".to_string())
         })?;
 
         let message_composite_key = self.msg_composite_key(&message_id, &assignment_id);
@@ -430,7 +492,7 @@ impl DataStore for LocalStoreClient {
         )?;
 
         let cf = self.index_db.cf_handle("message_ordering").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'message_ordering' not found".to_string())
+            StoreErrorType::database("Column family 'message_ordering' not found".to_string())
         })?;
 
         let msg_order_key = self.msg_order_key(message)?;
@@ -441,7 +503,7 @@ impl DataStore for LocalStoreClient {
         self.file_db.put(assignment_key.as_bytes(), bundle_in)?;
 
         let cf = self.index_db.cf_handle("deep_hash").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'message_ordering' not found".to_string())
+            StoreErrorType::database("Column family 'message_ordering' not found".to_string())
         })?;
 
         match deep_hash {
@@ -459,10 +521,41 @@ impl DataStore for LocalStoreClient {
         Ok("Message saved".to_string())
     }
 
+    async fn atomic_save_process_and_message(
+        &self,
+        _process: &Process,
+        _process_bundle: &[u8],
+        _message: &Message,
+        _message_bundle: &[u8],
+        _deep_hash: Option<&String>,
+    ) -> Result<(), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Atomic process and message saves are not supported by the local store".to_string(),
+        ))
+    }
+
+    /*
+      Writes go straight to RocksDB, nothing is
+      buffered so there is nothing to flush.
+    */
+    async fn shutdown(&self) {}
+
     async fn get_process(&self, tx_id: &str) -> Result<Process, StoreErrorType> {
+        let (process, _) = self.get_process_with_bundle(tx_id).await?;
+        Ok(process)
+    }
+
+    /*
+      Processes are stored as their bundle, so this is
+      the lookup get_process uses.
+    */
+    async fn get_process_with_bundle(
+        &self,
+        tx_id: &str,
+    ) -> Result<(Process, Vec<u8>), StoreErrorType> {
         let assignment_key = self.proc_assignment_key(tx_id);
         if let Some(process_bundle) = self.file_db.get(assignment_key.as_bytes())? {
-            return Ok(Process::from_bytes(process_bundle)?);
+            return Ok((Process::from_bytes(process_bundle.clone())?, process_bundle));
         }
 
         /*
@@ -475,7 +568,7 @@ impl DataStore for LocalStoreClient {
           here we use prefix iteration to find the correct message
         */
         let cf = self.index_db.cf_handle("process").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'process' not found".to_string())
+            StoreErrorType::database("Column family 'process' not found".to_string())
         })?;
         let process_key_prefix = format!("process:{}:", tx_id);
         let mut iter = self
@@ -487,52 +580,160 @@ impl DataStore for LocalStoreClient {
             let assignment_id = String::from_utf8(assignment_id_bytes.to_vec())?;
             let assignment_key = self.proc_assignment_key(&assignment_id);
             if let Some(process_bundle) = self.file_db.get(assignment_key.as_bytes())? {
-                return Ok(Process::from_bytes(process_bundle)?);
+                return Ok((Process::from_bytes(process_bundle.clone())?, process_bundle));
             }
         }
 
-        Err(StoreErrorType::NotFound("Process not found".to_string()))
+        Err(StoreErrorType::not_found("process", tx_id))
     }
 
-    fn get_message(&self, tx_id: &str) -> Result<Message, StoreErrorType> {
-        let assignment_key = self.msg_assignment_key(tx_id);
-        if let Some(message_bundle) = self.file_db.get(assignment_key.as_bytes())? {
-            let message: Message = Message::from_bytes(message_bundle)?;
-            return Ok(message);
-        }
-
-        /*
-          If not found by assignment_id, assume tx_id is a
-          message id. We fetch its assignment id by
-          message id and then fetch the bundle with that
+    /*
+      Nothing is cached here so this is the same
+      as get_process.
+    */
+    async fn get_process_uncached(&self, tx_id: &str) -> Result<Process, StoreErrorType> {
+        self.get_process(tx_id).await
+    }
 
-          There is no key that only contains message id. This
-          is because a message can be assignmed many times. So
-          here we use prefix iteration to find the correct message
-        */
-        let cf = self.index_db.cf_handle("message").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'message' not found".to_string())
+    /*
+      Only looks for the process index key, so the
+      bundle is never read.
+    */
+    async fn check_process_exists(&self, process_id: &str) -> Result<bool, StoreErrorType> {
+        let cf = self.index_db.cf_handle("process").ok_or_else(|| {
+            StoreErrorType::database("Column family 'process' not found".to_string())
         })?;
-        let message_key_prefix = format!("message:{}:", tx_id);
+        let process_key_prefix = format!("process:{}:", process_id);
         let mut iter = self
             .index_db
-            .prefix_iterator_cf(cf, message_key_prefix.as_bytes());
+            .prefix_iterator_cf(cf, process_key_prefix.as_bytes());
 
-        if let Some(result) = iter.next() {
-            let (_key, assignment_id_bytes) = result?;
-            let assignment_id = String::from_utf8(assignment_id_bytes.to_vec())?;
-            let assignment_key = self.msg_assignment_key(&assignment_id);
-            if let Some(message_bundle) = self.file_db.get(assignment_key.as_bytes())? {
-                let message: Message = Message::from_bytes(message_bundle)?;
-                return Ok(message);
+        match iter.next() {
+            Some(result) => {
+                let (key, _) = result?;
+                Ok(key.starts_with(process_key_prefix.as_bytes()))
             }
+            None => Ok(false),
         }
+    }
+
+    /*
+      Processes are not indexed by epoch here.
+    */
+    async fn get_processes_by_epoch(
+        &self,
+        _epoch: i32,
+        _limit: i32,
+        _after_row_id: Option<i32>,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Querying processes by epoch is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_processes_by_module(
+        &self,
+        _module_id: &str,
+        _cursor: Option<i32>,
+        _limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Querying processes by module is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_processes(
+        &self,
+        _after_row_id: Option<i32>,
+        _limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Listing processes is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_processes_by_scheduler(
+        &self,
+        _scheduler_row_id: i32,
+        _after_row_id: Option<i32>,
+        _limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Looking up processes by scheduler is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_process_bundle(&self, _process_id: &str) -> Result<Vec<u8>, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Reading process bundles is not supported by the local store".to_string(),
+        ))
+    }
+
+    /*
+      The local store has no notion of deleted
+      messages, every message it holds is live.
+    */
+    async fn soft_delete_message(&self, _message_id: &str) -> Result<(), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Soft delete is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn tombstone_message_in_bytestore(
+        &self,
+        _message_id: &str,
+    ) -> Result<(), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Bytestore tombstones are not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn archive_old_messages(
+        &self,
+        _process_id: &str,
+        _older_than_days: u32,
+    ) -> Result<u64, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Archiving messages is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_message(&self, tx_id: &str) -> Result<Message, StoreErrorType> {
+        let message_bundle = self.get_message_bytes(tx_id)?;
+        let message: Message = Message::from_bytes(message_bundle)?;
+        Ok(message)
+    }
+
+    async fn get_message_bundle(&self, tx_id: &str) -> Result<Vec<u8>, StoreErrorType> {
+        self.get_message_bytes(tx_id)
+    }
 
-        Err(StoreErrorType::NotFound("Message not found".to_string()))
+    /*
+      There is no index holding the epoch here so
+      the message has to be read in full.
+    */
+    async fn get_message_epoch(&self, message_id: &str) -> Result<i32, StoreErrorType> {
+        Ok(self.get_message(message_id).await?.epoch()?)
     }
 
-    fn check_existing_message(&self, message_id: &String) -> Result<(), StoreErrorType> {
-        if let Ok(_message) = self.get_message(message_id) {
+    /*
+      Everything already lives on disk here so there is
+      nothing to fall back to, the assignment id is the
+      more specific key when we have it.
+    */
+    async fn get_message_with_fallback(
+        &self,
+        message_id: &str,
+        assignment_id: &Option<String>,
+    ) -> Result<Message, StoreErrorType> {
+        match assignment_id {
+            Some(assignment_id) => self.get_message(assignment_id).await,
+            None => self.get_message(message_id).await,
+        }
+    }
+
+    async fn check_existing_message(&self, message_id: &String) -> Result<(), StoreErrorType> {
+        if let Ok(_message) = self.get_message(message_id).await {
             Err(StoreErrorType::MessageExists(
                 "Message already exists".to_string(),
             ))
@@ -541,27 +742,168 @@ impl DataStore for LocalStoreClient {
         }
     }
 
+    fn freeze_process(&self, process_id: &str) {
+        self.frozen_processes.insert(process_id.to_string());
+    }
+
+    fn unfreeze_process(&self, process_id: &str) {
+        self.frozen_processes.remove(process_id);
+    }
+
+    fn is_process_frozen(&self, process_id: &str) -> bool {
+        self.frozen_processes.contains(process_id)
+    }
+
+    fn keeps_deep_hashes(&self) -> bool {
+        true
+    }
+
+    fn bytestore_counters(&self) -> Option<ByteStoreCounters> {
+        None
+    }
+
+    async fn bytestore_statistics(&self) -> Result<ByteStoreStatistics, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Bytestore statistics are not supported by the local store".to_string(),
+        ))
+    }
+
+    fn bytestore_last_sync(&self) -> Option<ByteStoreSyncRun> {
+        None
+    }
+
+    async fn create_bytestore_checkpoint(
+        &self,
+        _path: &str,
+    ) -> Result<ByteStoreCheckpoint, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Bytestore checkpoints are not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn compact_bytestore(
+        &self,
+        _prefix: Option<String>,
+    ) -> Result<ByteStoreCompaction, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Bytestore compaction is not supported by the local store".to_string(),
+        ))
+    }
+
+    fn sync_bytestore_after(&self, _after_row_id: i32) -> Result<ByteStoreSyncRun, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Bytestore syncs are not supported by the local store".to_string(),
+        ))
+    }
+
+    /*
+      The local store doesn't cache processes.
+    */
+    async fn invalidate_process_cache(&self, _process_id: &str) -> Result<(), StoreErrorType> {
+        Ok(())
+    }
+
+    async fn resize_process_cache(&self, _new_size: usize) -> Result<(), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Process cache resizing is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn check_process_integrity(
+        &self,
+        _process_id: &str,
+    ) -> Result<ProcessIntegrityReport, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Integrity checks are not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<String>, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "The local store has no migrations".to_string(),
+        ))
+    }
+
+    async fn cache_stats(&self) -> Vec<CacheStats> {
+        vec![]
+    }
+
+    fn circuit_breakers(&self) -> Vec<CircuitBreakerStats> {
+        vec![]
+    }
+
+    async fn message_throughput(&self) -> Option<f64> {
+        None
+    }
+
+    async fn health_check(&self) -> HealthStatus {
+        HealthStatus {
+            db_write_ok: true,
+            db_read_ok: true,
+            bytestore_ok: true,
+            ..Default::default()
+        }
+    }
+
+    fn ping(&self) -> bool {
+        true
+    }
+
     async fn check_existing_deep_hash(
         &self,
         process_id: &String,
         deep_hash: &String,
     ) -> Result<(), StoreErrorType> {
+        let existing = self
+            .check_existing_deep_hashes(process_id, std::slice::from_ref(deep_hash))
+            .await?;
+        match existing.is_empty() {
+            true => Ok(()),
+            false => Err(StoreErrorType::MessageExists(
+                "Deep hash already exists".to_string(),
+            )),
+        }
+    }
+
+    // a key that can't be read counts as missing
+    async fn check_existing_deep_hashes(
+        &self,
+        process_id: &String,
+        deep_hashes: &[String],
+    ) -> Result<Vec<String>, StoreErrorType> {
         let cf = self.index_db.cf_handle("deep_hash").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'deep_hash' not found".to_string())
+            StoreErrorType::database("Column family 'deep_hash' not found".to_string())
         })?;
 
-        let deep_hash_key = self.deep_hash_key(process_id, deep_hash)?;
-        match self.index_db.get_cf(cf, deep_hash_key) {
-            Ok(dh) => match dh {
-                Some(_) => {
-                    return Err(StoreErrorType::MessageExists(
-                        "Deep hash already exists".to_string(),
-                    ))
-                }
-                None => return Ok(()),
-            },
-            Err(_) => return Ok(()),
+        let mut keys = Vec::with_capacity(deep_hashes.len());
+        for deep_hash in deep_hashes {
+            keys.push((cf, self.deep_hash_key(process_id, deep_hash)?));
         }
+        let values = self.index_db.multi_get_cf(keys);
+
+        Ok(deep_hashes
+            .iter()
+            .zip(values)
+            .filter(|(_, value)| matches!(value, Ok(Some(_))))
+            .map(|(deep_hash, _)| deep_hash.clone())
+            .collect())
+    }
+
+    async fn get_deephashes(&self, process_id: &str) -> Result<Vec<String>, StoreErrorType> {
+        let cf = self.index_db.cf_handle("deep_hash").ok_or_else(|| {
+            StoreErrorType::database("Column family 'deep_hash' not found".to_string())
+        })?;
+
+        let prefix = format!("deep_hash:{}:", process_id);
+        let mut deep_hashes = vec![];
+        for item in self.index_db.prefix_iterator_cf(cf, prefix.as_bytes()) {
+            let (key, _) = item?;
+            match key.strip_prefix(prefix.as_bytes()) {
+                Some(deep_hash) => deep_hashes.push(String::from_utf8(deep_hash.to_vec())?),
+                None => break,
+            }
+        }
+        Ok(deep_hashes)
     }
 
     async fn get_deephash_version(&self, process_id: &String) -> Result<String, StoreErrorType> {
@@ -569,9 +911,7 @@ impl DataStore for LocalStoreClient {
             .index_db
             .cf_handle("deep_hash_version")
             .ok_or_else(|| {
-                StoreErrorType::DatabaseError(
-                    "Column family 'deep_hash_version' not found".to_string(),
-                )
+                StoreErrorType::database("Column family 'deep_hash_version' not found".to_string())
             })?;
 
         let deep_hash_version_key = self.deep_hash_version_key(process_id)?;
@@ -601,9 +941,7 @@ impl DataStore for LocalStoreClient {
             .index_db
             .cf_handle("deep_hash_version")
             .ok_or_else(|| {
-                StoreErrorType::DatabaseError(
-                    "Column family 'deep_hash_version' not found".to_string(),
-                )
+                StoreErrorType::database("Column family 'deep_hash_version' not found".to_string())
             })?;
 
         let deep_hash_version_key = self.deep_hash_version_key(process_id)?;
@@ -618,7 +956,7 @@ impl DataStore for LocalStoreClient {
         deep_hash: &String,
     ) -> Result<(), StoreErrorType> {
         let cf = self.index_db.cf_handle("deep_hash").ok_or_else(|| {
-            StoreErrorType::DatabaseError("Column family 'message_ordering' not found".to_string())
+            StoreErrorType::database("Column family 'message_ordering' not found".to_string())
         })?;
 
         let deep_hash_key = self.deep_hash_key(process_id, deep_hash)?;
@@ -638,12 +976,28 @@ impl DataStore for LocalStoreClient {
     async fn get_messages(
         &self,
         process_in: &Process,
-        from: &Option<String>,
-        to: &Option<String>,
-        limit: &Option<i32>,
-        from_nonce: &Option<String>,
-        to_nonce: &Option<String>,
+        filter: &MessageFilter,
     ) -> Result<PaginatedMessages, StoreErrorType> {
+        /*
+          The index has no epoch ordering, so filtering on
+          it here would break pagination.
+        */
+        if filter.epoch.is_some() {
+            return Err(StoreErrorType::database(
+                "Epoch filter is not supported by the local store".to_string(),
+            ));
+        }
+
+        /*
+          The ranges below only filter exclusively so an
+          inclusive start is shifted back by one.
+        */
+        let from = &filter.exclusive_from()?;
+        let to = &filter.to;
+        let limit = &filter.limit;
+        let from_nonce = &filter.exclusive_from_nonce()?;
+        let to_nonce = &filter.to_nonce;
+
         let process_id = &process_in.process.process_id;
         let limit_val = limit.unwrap_or(100) as usize;
 
@@ -661,14 +1015,8 @@ impl DataStore for LocalStoreClient {
                   Check if the process has an assignment and
                   should be included as the first message
                 */
-                let include_process = process_in.assignment.is_some()
-                    && match from {
-                        Some(ref _from_timestamp) => false,
-                        /*
-                          No 'from' means it's the first page
-                        */
-                        None => true,
-                    };
+                let include_process =
+                    process_in.assignment.is_some() && filter.starts_at_process(process_in)?;
 
                 if include_process {
                     let process_message = Message::from_process(process_in.clone())?;
@@ -722,20 +1070,8 @@ impl DataStore for LocalStoreClient {
                   Check if the process has an assignment and
                   should be included as the first message
                 */
-                let include_process = process_in.assignment.is_some()
-                    && match from_nonce {
-                        Some(ref _from_nonce) => {
-                            if _from_nonce.parse::<i32>()? == -1 {
-                                true
-                            } else {
-                                false
-                            }
-                        }
-                        /*
-                          No 'from' means it's the first page
-                        */
-                        None => true,
-                    };
+                let include_process =
+                    process_in.assignment.is_some() && filter.starts_at_process(process_in)?;
 
                 if include_process {
                     let process_message = Message::from_process(process_in.clone())?;
@@ -853,6 +1189,126 @@ impl DataStore for LocalStoreClient {
         Ok((bundles, has_next_page))
     }
 
+    /*
+      Counts the ordering keys from since_timestamp on,
+      no message has to be read.
+    */
+    async fn count_messages_since(
+        &self,
+        process_id: &str,
+        since_timestamp: i64,
+    ) -> Result<i64, StoreErrorType> {
+        let (keys, _) = self
+            .fetch_message_range(
+                &process_id.to_string(),
+                &Some(since_timestamp.to_string()),
+                &None,
+                &None,
+            )
+            .await?;
+        Ok(keys.len() as i64)
+    }
+
+    async fn get_messages_count_in_timestamp_range(
+        &self,
+        process_id: &str,
+        from: i64,
+        to: i64,
+    ) -> Result<i64, StoreErrorType> {
+        let (keys, _) = self
+            .fetch_message_range(
+                &process_id.to_string(),
+                &Some(from.to_string()),
+                &Some(to.to_string()),
+                &None,
+            )
+            .await?;
+        Ok(keys.len() as i64)
+    }
+
+    /*
+      The timestamps come out of the ordering keys, so
+      no message has to be read.
+    */
+    async fn get_process_timeline(
+        &self,
+        process_id: &str,
+    ) -> Result<ProcessTimeline, StoreErrorType> {
+        let (keys, _) = self
+            .fetch_message_range(&process_id.to_string(), &None, &None, &None)
+            .await?;
+
+        let timestamps: Vec<i64> = keys
+            .iter()
+            .filter_map(|(key, _)| key.split(':').nth(4))
+            .filter_map(|timestamp| timestamp.parse::<i64>().ok())
+            .collect();
+
+        Ok(ProcessTimeline {
+            min_timestamp: timestamps.iter().min().copied(),
+            max_timestamp: timestamps.iter().max().copied(),
+            message_count: keys.len() as i64,
+        })
+    }
+
+    /*
+      Counting the ordering keys is already cheap
+      here, there is nothing to cache.
+    */
+    async fn get_cached_message_count(&self, process_id: &str) -> Result<i64, StoreErrorType> {
+        Ok(self.get_process_timeline(process_id).await?.message_count)
+    }
+
+    async fn get_assignment_count(&self, _process_id: &str) -> Result<(i64, i64), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Counting assignments is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_messages_by_sender(
+        &self,
+        _sender_id: &str,
+        _process_id: &str,
+        _limit: i32,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Querying messages by sender is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_messages_after_rowid(
+        &self,
+        _process_id: &str,
+        _after_row_id: i64,
+        _limit: i32,
+    ) -> Result<(Vec<Message>, Option<i64>), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Reading messages by row id is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_messages_by_hash_chain_prefix(
+        &self,
+        _process_id: &str,
+        _hash_chain_prefix: &str,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Querying messages by hash chain is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_messages_for_epoch_nonce_range(
+        &self,
+        _process_id: &str,
+        _epoch: i32,
+        _from_nonce: i32,
+        _to_nonce: i32,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Querying messages by epoch is not supported by the local store".to_string(),
+        ))
+    }
+
     /*
       Retrieve the latest message for a process.
       Currently this is only run once for a process
@@ -877,8 +1333,73 @@ impl DataStore for LocalStoreClient {
             None => return Ok(None),
         };
 
-        let latest_message = self.get_message(&assignment_id)?;
+        let latest_message = self.get_message(&assignment_id).await?;
 
         Ok(Some(latest_message))
     }
+
+    /*
+      There is no single query to batch here, each
+      process's range is read on its own.
+    */
+    async fn get_latest_messages_for_processes(
+        &self,
+        process_ids: &[&str],
+    ) -> Result<HashMap<String, Message>, StoreErrorType> {
+        let mut latest = HashMap::new();
+        for process_id in process_ids {
+            if let Some(message) = self.get_latest_message(process_id).await? {
+                latest.insert(process_id.to_string(), message);
+            }
+        }
+        Ok(latest)
+    }
+
+    async fn update_message_hash_chain(
+        &self,
+        _process_id: &str,
+        _nonce: i32,
+        _new_hash_chain: &str,
+    ) -> Result<(), StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Updating hash chains is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn update_message_hash_chains_batch(
+        &self,
+        _updates: &[(String, i32, String)],
+    ) -> Result<u64, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Updating hash chains is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn purge_process(
+        &self,
+        _process_id: &str,
+        _force: bool,
+    ) -> Result<PurgeReport, StoreErrorType> {
+        Err(StoreErrorType::database(
+            "Purging processes is not supported by the local store".to_string(),
+        ))
+    }
+
+    async fn get_hash_chain_tail(
+        &self,
+        process_id: &str,
+        depth: u32,
+    ) -> Result<Vec<String>, StoreErrorType> {
+        let (paginated_keys, _) = self
+            .fetch_message_range(&process_id.to_string(), &None, &None, &None)
+            .await?;
+
+        let mut hash_chains = vec![];
+        for (_, assignment_id) in paginated_keys.iter().rev().take(depth as usize) {
+            let message = self.get_message(assignment_id).await?;
+            hash_chains.push(message.hash_chain()?);
+        }
+
+        Ok(hash_chains)
+    }
 }
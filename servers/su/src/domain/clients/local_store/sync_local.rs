@@ -4,7 +4,7 @@ use tokio::time::{sleep, Duration};
 
 use super::super::gateway::ArweaveGateway;
 use crate::domain::config::AoConfig;
-use crate::domain::core::dal::{DataStore, Gateway, Process, StoreErrorType};
+use crate::domain::core::dal::{DataStore, Gateway, MessageFilter, Process, StoreErrorType};
 use crate::domain::flows::msg_deephash;
 
 /*
@@ -89,6 +89,7 @@ pub async fn sync_local_drives(interval: u64) -> io::Result<()> {
 
                     write_sync_store
                         .save_process(&process_to_sync, &process_bundle)
+                        .await
                         .unwrap();
 
                     None
@@ -106,10 +107,17 @@ pub async fn sync_local_drives(interval: u64) -> io::Result<()> {
 
             while has_next_page {
                 let mut messages_fetch = read_only_local_store
-                    .get_messages(&process, &from, &None, &None, &None, &None)
+                    .get_messages(&process, &MessageFilter::default().from(from.clone()))
                     .await
                     .unwrap();
 
+                /*
+                  Taken before the process is dropped below,
+                  so a page holding only the process still
+                  moves from past it.
+                */
+                let next_from = messages_fetch.page_info.next_from.clone();
+
                 if messages_fetch.edges.len() >= 1 {
                     if let Some(m) = &messages_fetch.edges[0].node.message {
                         if let Some(type_tag) = m.tags.iter().find(|t| t.name == "Type") {
@@ -142,8 +150,11 @@ pub async fn sync_local_drives(interval: u64) -> io::Result<()> {
                         .unwrap()
                         .unwrap();
 
-                    match write_sync_store.get_message(&message.node.assignment_id().unwrap()) {
-                        Err(StoreErrorType::NotFound(_)) => {
+                    match write_sync_store
+                        .get_message(&message.node.assignment_id().unwrap())
+                        .await
+                    {
+                        Err(StoreErrorType::NotFound { .. }) => {
                             let deep_hash = msg_deephash(gw, &message.node, &bundle_data_item)
                                 .await
                                 .unwrap()
@@ -159,8 +170,8 @@ pub async fn sync_local_drives(interval: u64) -> io::Result<()> {
                     }
                 }
 
-                if let Some(last) = messages_fetch.edges.last() {
-                    from = Some(last.cursor.clone());
+                if next_from.is_some() {
+                    from = next_from;
                 }
 
                 has_next_page = messages_fetch.page_info.has_next_page;
@@ -178,9 +189,10 @@ pub async fn sync_local_drives(interval: u64) -> io::Result<()> {
 pub async fn get_all_processes(
     store: &Arc<super::store::LocalStoreClient>,
 ) -> Result<Vec<String>, StoreErrorType> {
-    let cf = store.index_db.cf_handle("process").ok_or_else(|| {
-        StoreErrorType::DatabaseError("Column family 'process' not found".to_string())
-    })?;
+    let cf = store
+        .index_db
+        .cf_handle("process")
+        .ok_or_else(|| StoreErrorType::database("Column family 'process' not found".to_string()))?;
 
     let iter = store.index_db.iterator_cf(cf, rocksdb::IteratorMode::Start);
     let mut processes = Vec::new();
@@ -1,10 +1,14 @@
 #[cfg(test)]
 mod tests {
     use super::super::store::LocalStoreClient;
-    use crate::domain::core::dal::{DataStore, Message, Process, StoreErrorType};
+    use crate::domain::core::dal::{DataStore, Message, MessageFilter, Process, StoreErrorType};
+    use crate::domain::core::verify::{
+        verify_message, verify_message_bundle, verify_process_bundle,
+    };
     use base64_url::decode;
     use std::fs;
     use std::path::PathBuf;
+    use std::time::Instant;
 
     struct TestDb {
         file_db_dir: PathBuf,
@@ -49,13 +53,28 @@ mod tests {
         let process_bundle = create_test_process_bundle();
         let test_process = Process::from_bytes(process_bundle.clone())?;
 
-        client.save_process(&test_process, &process_bundle)?;
+        client.save_process(&test_process, &process_bundle).await?;
         let retrieved_process = client.get_process(&test_process.process.process_id).await?;
 
         assert_eq!(
             retrieved_process.process.process_id,
             test_process.process.process_id
         );
+
+        /*
+          get_process reads the stored bundle back through
+          Process::from_bytes, so the whole process should
+          survive the round trip, not just its id.
+        */
+        assert_eq!(
+            serde_json::to_value(&retrieved_process)?,
+            serde_json::to_value(&test_process)?
+        );
+
+        let (_, retrieved_bundle) = client
+            .get_process_with_bundle(&test_process.process.process_id)
+            .await?;
+        assert_eq!(retrieved_bundle, process_bundle);
         Ok(())
     }
 
@@ -70,7 +89,7 @@ mod tests {
         client
             .save_message(&test_message, &message_bundle, None)
             .await?;
-        let retrieved_message = client.get_message(&test_message.assignment.id)?;
+        let retrieved_message = client.get_message(&test_message.assignment.id).await?;
 
         assert_eq!(retrieved_message.assignment.id, test_message.assignment.id);
         Ok(())
@@ -83,7 +102,7 @@ mod tests {
 
         let (process_bundle, message_bundles) = bundle_list();
         let test_process = Process::from_bytes(process_bundle.clone())?;
-        client.save_process(&test_process, &process_bundle)?;
+        client.save_process(&test_process, &process_bundle).await?;
 
         // Save all messages
         for bundle in message_bundles.iter() {
@@ -93,7 +112,7 @@ mod tests {
 
         // Retrieve messages and check nonce order and continuity
         let result = client
-            .get_messages(&test_process, &None, &None, &None, &None, &None)
+            .get_messages(&test_process, &MessageFilter::default())
             .await?;
         let mut previous_nonce: Option<i32> = None;
 
@@ -122,7 +141,7 @@ mod tests {
 
         let (process_bundle, message_bundles) = bundle_list();
         let test_process = Process::from_bytes(process_bundle.clone())?;
-        client.save_process(&test_process, &process_bundle)?;
+        client.save_process(&test_process, &process_bundle).await?;
 
         for bundle in message_bundles.iter() {
             let test_message = Message::from_bytes(bundle.clone())?;
@@ -131,7 +150,7 @@ mod tests {
 
         // Case 1: Default parameters
         let result = client
-            .get_messages(&test_process, &None, &None, &None, &None, &None)
+            .get_messages(&test_process, &MessageFilter::default())
             .await?;
         // result should also include the process
         assert_eq!(result.edges.len(), message_bundles.len() + 1);
@@ -140,7 +159,10 @@ mod tests {
         // Case 2: Limit parameter
         let limit = 11;
         let result = client
-            .get_messages(&test_process, &None, &None, &Some(limit), &None, &None)
+            .get_messages(
+                &test_process,
+                &MessageFilter::default().limit(Some(limit)),
+            )
             .await?;
         assert_eq!(result.edges.len(), limit as usize);
         assert!(result.page_info.has_next_page);
@@ -150,11 +172,7 @@ mod tests {
         let result = client
             .get_messages(
                 &test_process,
-                &Some(from.clone()),
-                &None,
-                &None,
-                &None,
-                &None,
+                &MessageFilter::default().from(Some(from.clone())),
             )
             .await?;
         assert!(result
@@ -165,7 +183,10 @@ mod tests {
         // // Case 4: With 'to' parameter
         let to = "1728412714154".to_string();
         let result = client
-            .get_messages(&test_process, &None, &Some(to.clone()), &None, &None, &None)
+            .get_messages(
+                &test_process,
+                &MessageFilter::default().to(Some(to.clone())),
+            )
             .await?;
         assert!(result
             .edges
@@ -176,11 +197,9 @@ mod tests {
         let result = client
             .get_messages(
                 &test_process,
-                &Some(from.clone()),
-                &Some(to.clone()),
-                &None,
-                &None,
-                &None,
+                &MessageFilter::default()
+                    .from(Some(from.clone()))
+                    .to(Some(to.clone())),
             )
             .await?;
         assert!(result.edges.iter().all(|m| {
@@ -192,11 +211,10 @@ mod tests {
         let result = client
             .get_messages(
                 &test_process,
-                &Some(from.clone()),
-                &Some(to.clone()),
-                &Some(limit),
-                &None,
-                &None,
+                &MessageFilter::default()
+                    .from(Some(from.clone()))
+                    .to(Some(to.clone()))
+                    .limit(Some(limit)),
             )
             .await?;
         assert!(result.edges.iter().all(|m| {
@@ -215,7 +233,7 @@ mod tests {
 
         let (process_bundle, message_bundles) = bundle_list();
         let test_process = Process::from_bytes(process_bundle.clone())?;
-        client.save_process(&test_process, &process_bundle)?;
+        client.save_process(&test_process, &process_bundle).await?;
 
         // Save half of the messages
         for bundle in message_bundles.iter().take(message_bundles.len() / 2) {
@@ -225,7 +243,9 @@ mod tests {
 
         let (process_bundle_2, message_bundles_2) = bundle_list_2();
         let test_process_2 = Process::from_bytes(process_bundle_2.clone())?;
-        client.save_process(&test_process_2, &process_bundle_2)?;
+        client
+            .save_process(&test_process_2, &process_bundle_2)
+            .await?;
 
         // Save half of the messages of next process
         for bundle in message_bundles_2.iter().take(message_bundles_2.len() / 2) {
@@ -247,7 +267,7 @@ mod tests {
 
         // Retrieve messages and check length, nonce order, and continuity
         let result = client
-            .get_messages(&test_process, &None, &None, &None, &None, &None)
+            .get_messages(&test_process, &MessageFilter::default())
             .await?;
         let mut previous_nonce: Option<i32> = None;
 
@@ -287,6 +307,168 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_check_existing_deep_hashes() -> Result<(), StoreErrorType> {
+        let test_db = TestDb::new(8);
+        let client = LocalStoreClient::new(&test_db.file_db_path(), &test_db.index_db_path())?;
+        let pid = "pid".to_string();
+
+        for deep_hash in ["a", "c"] {
+            client.save_deephash(&pid, &deep_hash.to_string()).await?;
+        }
+
+        let asked: Vec<String> = ["c", "b", "a", "d"].iter().map(|s| s.to_string()).collect();
+        let existing = client.check_existing_deep_hashes(&pid, &asked).await?;
+        assert_eq!(existing, vec!["c".to_string(), "a".to_string()]);
+
+        assert!(matches!(
+            client
+                .check_existing_deep_hash(&pid, &"a".to_string())
+                .await,
+            Err(StoreErrorType::MessageExists(_))
+        ));
+        client
+            .check_existing_deep_hash(&"other".to_string(), &"a".to_string())
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inclusive_from() -> Result<(), StoreErrorType> {
+        let test_db = TestDb::new(7);
+        let client = LocalStoreClient::new(&test_db.file_db_path(), &test_db.index_db_path())?;
+
+        let (process_bundle, message_bundles) = bundle_list();
+        let test_process = Process::from_bytes(process_bundle.clone())?;
+        client.save_process(&test_process, &process_bundle).await?;
+
+        for bundle in message_bundles.iter() {
+            let test_message = Message::from_bytes(bundle.clone())?;
+            client.save_message(&test_message, &bundle, None).await?;
+        }
+
+        let all = client
+            .get_messages(&test_process, &MessageFilter::default())
+            .await?;
+        let boundary = &all.edges[3].node;
+
+        // timestamp mode
+        let from = boundary.timestamp()?.to_string();
+        let exclusive = client
+            .get_messages(
+                &test_process,
+                &MessageFilter::default().from(Some(from.clone())),
+            )
+            .await?;
+        assert_eq!(
+            exclusive.edges[0].node.timestamp()?,
+            all.edges[4].node.timestamp()?
+        );
+        let inclusive = client
+            .get_messages(
+                &test_process,
+                &MessageFilter::default().from(Some(from)).inclusive(true),
+            )
+            .await?;
+        assert_eq!(inclusive.edges[0].node.timestamp()?, boundary.timestamp()?);
+
+        // nonce mode
+        let from_nonce = boundary.nonce()?.to_string();
+        let exclusive = client
+            .get_messages(
+                &test_process,
+                &MessageFilter::default().from_nonce(Some(from_nonce.clone())),
+            )
+            .await?;
+        assert_eq!(exclusive.edges[0].node.nonce()?, boundary.nonce()? + 1);
+        let inclusive = client
+            .get_messages(
+                &test_process,
+                &MessageFilter::default()
+                    .from_nonce(Some(from_nonce))
+                    .inclusive(true),
+            )
+            .await?;
+        assert_eq!(inclusive.edges[0].node.nonce()?, boundary.nonce()?);
+
+        // the process is nonce 0, only an inclusive 0 includes it
+        let inclusive = client
+            .get_messages(
+                &test_process,
+                &MessageFilter::default()
+                    .from_nonce(Some("0".to_string()))
+                    .inclusive(true),
+            )
+            .await?;
+        assert_eq!(inclusive.edges.len(), all.edges.len());
+        assert_eq!(inclusive.edges[0].node.nonce()?, 0);
+        let exclusive = client
+            .get_messages(
+                &test_process,
+                &MessageFilter::default().from_nonce(Some("0".to_string())),
+            )
+            .await?;
+        assert_eq!(exclusive.edges.len(), all.edges.len() - 1);
+        assert_eq!(exclusive.edges[0].node.nonce()?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_bundles() -> Result<(), StoreErrorType> {
+        let process_bundle = create_test_process_bundle();
+        let message_bundle = create_test_message_bundle();
+        let test_process = Process::from_bytes(process_bundle.clone())?;
+        let test_message = Message::from_bytes(message_bundle.clone())?;
+        let process_id = test_process.process.process_id.clone();
+        let message_id = test_message.message_id()?;
+
+        verify_process_bundle(&process_bundle, &process_id)?;
+        verify_message(&test_message, &message_bundle)?;
+
+        // each bundle saved under the other's id
+        assert!(matches!(
+            verify_process_bundle(&process_bundle, &message_id),
+            Err(StoreErrorType::VerificationError(_))
+        ));
+        assert!(matches!(
+            verify_message_bundle(&message_bundle, &process_id, None),
+            Err(StoreErrorType::VerificationError(_))
+        ));
+        assert!(matches!(
+            verify_message(&test_message, &process_bundle),
+            Err(StoreErrorType::VerificationError(_))
+        ));
+
+        Ok(())
+    }
+
+    /*
+      What VERIFY_BUNDLES_ON_WRITE adds to each save,
+      run with --release --ignored --nocapture.
+    */
+    #[test]
+    #[ignore]
+    fn test_verify_bundles_timing() -> Result<(), StoreErrorType> {
+        const ROUNDS: u32 = 1000;
+
+        let message_bundle = create_test_message_bundle();
+        let test_message = Message::from_bytes(message_bundle.clone())?;
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            verify_message(&test_message, &message_bundle)?;
+        }
+        println!(
+            "verify_message on a {} byte bundle: {:?} each",
+            message_bundle.len(),
+            start.elapsed() / ROUNDS
+        );
+
+        Ok(())
+    }
+
     /*
       Helper functions to create test data using
       base64_url encoded bundles
@@ -1,6 +1,8 @@
 use super::super::config::AoConfig;
 use super::super::core::dal::CoreMetrics;
-use prometheus::{HistogramOpts, HistogramVec, IntCounter, Registry, TextEncoder};
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntGaugeVec, Opts, Registry, TextEncoder,
+};
 
 /*
   Implementation of metrics
@@ -13,6 +15,8 @@ pub struct PromMetrics {
     enabled: bool,
     core_metrics: HistogramVec,
     message_save_failures: IntCounter,
+    message_rate_limited: IntCounter,
+    process_message_throughput: IntGaugeVec,
     registry: Registry,
 }
 
@@ -45,10 +49,36 @@ impl PromMetrics {
             .register(Box::new(message_save_failures.clone()))
             .unwrap();
 
+        let message_rate_limited: IntCounter = IntCounter::new(
+            "message_rate_limited",
+            "messages turned away by the per process rate limit",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(message_rate_limited.clone()))
+            .unwrap();
+
+        let process_message_throughput = IntGaugeVec::new(
+            Opts::new(
+                "process_message_throughput_1m",
+                "Messages saved per process in the last minute",
+            )
+            .namespace("su"),
+            &["process_id"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(process_message_throughput.clone()))
+            .unwrap();
+
         PromMetrics {
             enabled: config.enable_metrics,
             core_metrics,
             message_save_failures,
+            message_rate_limited,
+            process_message_throughput,
             registry,
         }
     }
@@ -114,4 +144,26 @@ impl CoreMetrics for PromMetrics {
     fn failed_message_save(&self) {
         self.message_save_failures.inc();
     }
+
+    fn rate_limited_message(&self) {
+        self.message_rate_limited.inc();
+    }
+
+    /*
+      Replaces the whole gauge each poll so processes
+      that went quiet drop out instead of reporting
+      their last value forever.
+    */
+    fn process_throughput_observe(&self, counts: Vec<(String, i64)>) {
+        if !self.enabled {
+            return;
+        }
+
+        self.process_message_throughput.reset();
+        for (process_id, count) in counts {
+            self.process_message_throughput
+                .with_label_values(&[&process_id])
+                .set(count);
+        }
+    }
 }
@@ -12,8 +12,12 @@ pub mod uploader;
 pub mod store;
 
 // local database layer
+#[cfg(feature = "disk-store")]
 pub mod local_store;
 
+// copies messages into a sqlite file for offline use
+pub mod sqlite;
+
 // arweave gateway
 pub mod gateway;
 
@@ -10,6 +10,7 @@ table! {
         nonce -> Nullable<Int4>,
         timestamp -> Nullable<BigInt>,
         hash_chain -> Nullable<Text>,
+        module_id -> Nullable<Varchar>,
     }
 }
 
@@ -25,6 +26,9 @@ table! {
         timestamp -> BigInt,
         bundle -> Bytea,
         hash_chain -> Text,
+        deleted_at -> Nullable<BigInt>,
+        bundle_checksum -> Nullable<Text>,
+        sender_id -> Nullable<Text>,
     }
 }
 
@@ -36,6 +40,8 @@ table! {
         no_route -> Nullable<Bool>,
         wallets_to_route -> Nullable<Text>,
         wallets_only -> Nullable<Bool>,
+        version -> Int4,
+        consecutive_failures -> Int4,
     }
 }
 
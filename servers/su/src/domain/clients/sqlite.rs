@@ -0,0 +1,141 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+
+use dotenv::dotenv;
+use rusqlite::{params, Connection};
+
+use super::store::{require_disk_store, StoreClient};
+use crate::domain::config::AoConfig;
+use crate::domain::core::dal::Message;
+
+/*
+  This is a migration which copies the messages out
+  of postgres + the bytestore into a single SQLite
+  file, for offline analysis and testing without a
+  live postgres. The table mirrors the postgres
+  messages table.
+
+  Rerunning it against the same output file picks up
+  after the last message that was copied.
+*/
+
+const CREATE_MESSAGES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        row_id INTEGER PRIMARY KEY AUTOINCREMENT,
+        process_id TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        assignment_id TEXT,
+        message_data TEXT NOT NULL,
+        epoch INTEGER NOT NULL,
+        nonce INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        bundle BLOB NOT NULL,
+        hash_chain TEXT NOT NULL,
+        deleted_at INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS idx_messages_process_id_nonce ON messages(process_id, nonce);
+    CREATE INDEX IF NOT EXISTS idx_messages_process_id_timestamp ON messages(process_id, timestamp);
+";
+
+fn sqlite_err(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("sqlite error: {:?}", e))
+}
+
+pub async fn migrate_to_sqlite(output: &str) -> io::Result<()> {
+    require_disk_store()?;
+    let start = Instant::now();
+    dotenv().ok();
+
+    let data_store =
+        Arc::new(StoreClient::new_single_connection().expect("Failed to create StoreClient"));
+    data_store
+        .bytestore
+        .try_read_instance_connect()
+        .await
+        .expect("Failed to connect to bytestore");
+
+    let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+    let batch_size = config.migration_batch_size;
+
+    let mut sqlite = Connection::open(output).map_err(sqlite_err)?;
+    sqlite
+        .execute_batch(CREATE_MESSAGES_TABLE)
+        .map_err(sqlite_err)?;
+
+    let already_copied: i64 = sqlite
+        .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+        .map_err(sqlite_err)?;
+
+    let total_count = data_store
+        .get_message_count()
+        .expect("Failed to get message count");
+
+    data_store.logger.log(format!(
+        "Total messages to copy: {}, already copied: {}",
+        total_count, already_copied
+    ));
+
+    let mut processed_count = already_copied;
+    for batch_start in (already_copied..total_count).step_by(batch_size as usize) {
+        let messages = data_store
+            .get_all_messages_using_bytestore(batch_start, Some(batch_start + batch_size))
+            .await
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Error fetching messages: {:?}", e),
+                )
+            })?;
+
+        let tx = sqlite.transaction().map_err(sqlite_err)?;
+        {
+            let mut insert = tx
+                .prepare(
+                    "INSERT INTO messages (process_id, message_id, assignment_id, message_data, epoch, nonce, timestamp, bundle, hash_chain)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                )
+                .map_err(sqlite_err)?;
+
+            for message in messages {
+                let (msg_id, assignment_id, process_id, timestamp, epoch, nonce, hash_chain, bundle) =
+                    message;
+
+                let parsed = Message::from_bytes(bundle.clone()).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to parse message {}: {:?}", msg_id, e),
+                    )
+                })?;
+                let message_data = serde_json::to_string(&parsed)?;
+
+                insert
+                    .execute(params![
+                        process_id,
+                        msg_id,
+                        assignment_id,
+                        message_data,
+                        epoch,
+                        nonce,
+                        timestamp,
+                        bundle,
+                        hash_chain
+                    ])
+                    .map_err(sqlite_err)?;
+                processed_count += 1;
+            }
+        }
+        tx.commit().map_err(sqlite_err)?;
+
+        data_store
+            .logger
+            .log(format!("Messages copied update: {}", processed_count));
+    }
+
+    let duration = start.elapsed();
+    data_store
+        .logger
+        .log(format!("Time elapsed in sqlite migration is: {:?}", duration));
+
+    Ok(())
+}
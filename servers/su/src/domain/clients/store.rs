@@ -1,28 +1,36 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env::VarError;
+use std::future::Future;
 use std::num::NonZeroUsize;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, io};
 
 use async_trait::async_trait;
+use dashmap::{DashMap, DashSet};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, PooledConnection};
 use diesel::r2d2::Pool;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use dotenv::dotenv;
 use futures::future::join_all;
 use lru::LruCache;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify, Semaphore};
 use tokio::task::JoinHandle;
 use tokio::time::interval;
 
 use super::super::SuLog;
 
 use super::super::core::dal::{
-    DataStore, JsonErrorType, Log, Message, PaginatedMessages, Process, ProcessScheduler,
-    RouterDataStore, Scheduler, StoreErrorType,
+    ByteStoreCheckpoint, ByteStoreCompaction, ByteStoreCounters, ByteStoreStatistics, ByteStoreSyncRun, CacheStats, CircuitBreakerStats, DataStore, HealthStatus, JsonErrorType, Log, Message, MessageFilter, PaginatedMessages, Process,
+    ProcessIntegrityReport, ProcessScheduler, ProcessTimeline,
+    ProcessCountCorrection, PurgeReport, ReadTiming, RouterDataStore, RouterImportReport, RouterState,
+    RouterStateAssignment, RouterStateScheduler, Scheduler, StoreErrorType,
 };
+use super::super::core::router::{resolve_assignments, select_scheduler};
+use super::super::core::verify::{verify_message, verify_message_bundle, verify_process_bundle};
 
 use crate::domain::config::AoConfig;
 
@@ -30,2049 +38,10623 @@ pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
 
 use diesel::result::Error as DieselError; // Import Diesel's Error
 
+/*
+  Run the first lookup and only fall back to the
+  second when it finds nothing. Both get the same
+  connection passed through.
+*/
+fn first_match<C, T, E>(
+    conn: &mut C,
+    first: impl FnOnce(&mut C) -> Result<Option<T>, E>,
+    fallback: impl FnOnce(&mut C) -> Result<Option<T>, E>,
+) -> Result<Option<T>, E> {
+    match first(conn)? {
+        Some(found) => Ok(Some(found)),
+        None => fallback(conn),
+    }
+}
+
+/*
+  A bytestore key, (message_id, assignment_id,
+  process_id, timestamp), and the part of it used
+  to find the row in postgres when the bytestore
+  doesn't have it.
+*/
+type BinaryKey = (String, Option<String>, String, String);
+type FallbackKey = (String, Option<String>);
+
+/*
+  Keep the oldest row for each wanted key, the row a
+  lookup by message id and assignment id, or message
+  id alone when there is no assignment id, ordered by
+  timestamp would return. rows must be in ascending
+  timestamp order.
+*/
+fn oldest_per_key(
+    rows: Vec<DbMessage>,
+    wanted: &HashSet<FallbackKey>,
+) -> HashMap<FallbackKey, DbMessage> {
+    let mut found: HashMap<FallbackKey, DbMessage> = HashMap::new();
+    for row in rows {
+        let mut keys: Vec<FallbackKey> = vec![
+            (row.message_id.clone(), row.assignment_id.clone()),
+            (row.message_id.clone(), None),
+        ];
+        keys.dedup();
+        keys.retain(|k| wanted.contains(k) && !found.contains_key(k));
+
+        if let Some(last) = keys.pop() {
+            for key in keys {
+                found.insert(key, row.clone());
+            }
+            found.insert(last, row);
+        }
+    }
+    found
+}
+
+/*
+  Build a page from the bytestore binaries, the rows
+  that missed the bytestore are handed to load_missing
+  all at once so they come out of postgres in a single
+  query instead of one per row. The page keeps the
+  order of rows.
+*/
+fn fill_page<R, T>(
+    rows: &[R],
+    binaries: &DashMap<BinaryKey, Vec<u8>>,
+    key: impl Fn(&R) -> BinaryKey,
+    load_missing: impl FnOnce(
+        &HashSet<FallbackKey>,
+    ) -> Result<HashMap<FallbackKey, DbMessage>, StoreErrorType>,
+    from_binary: impl Fn(&R, &Vec<u8>) -> Result<T, StoreErrorType>,
+    from_db: impl Fn(&R, &DbMessage) -> Result<T, StoreErrorType>,
+) -> Result<Vec<T>, StoreErrorType> {
+    let keys: Vec<BinaryKey> = rows.iter().map(&key).collect();
+    let missing: HashSet<FallbackKey> = keys
+        .iter()
+        .filter(|k| !binaries.contains_key(*k))
+        .map(|(msg_id, assign_id, _, _)| (msg_id.clone(), assign_id.clone()))
+        .collect();
+
+    let loaded = if missing.is_empty() {
+        HashMap::new()
+    } else {
+        load_missing(&missing)?
+    };
+
+    let mut page = Vec::with_capacity(rows.len());
+    for (row, (msg_id, assign_id, proc_id, ts)) in rows.iter().zip(keys) {
+        let binary_key = (msg_id, assign_id, proc_id, ts);
+        if let Some(binary) = binaries.get(&binary_key) {
+            page.push(from_binary(row, binary.value())?);
+            continue;
+        }
+        let fallback_key = (binary_key.0, binary_key.1);
+        match loaded.get(&fallback_key) {
+            Some(db_message) => page.push(from_db(row, db_message)?),
+            None => return Err(StoreErrorType::not_found("message", &fallback_key.0)),
+        }
+    }
+
+    Ok(page)
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/*
+  Turn a page of (row_id, process_data) rows into
+  processes and the row id to continue from, None once
+  the page is short so the caller knows there is
+  nothing left.
+*/
+fn process_page(
+    rows: Vec<(i32, serde_json::Value)>,
+    limit: i32,
+) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+    let cursor = if rows.len() as i32 == limit {
+        rows.last().map(|(row_id, _)| *row_id)
+    } else {
+        None
+    };
+
+    let mut result = Vec::with_capacity(rows.len());
+    for (_, process_data) in rows {
+        result.push(Process::from_val(&process_data)?);
+    }
+
+    Ok((result, cursor))
+}
+
+/*
+  LIKE patterns matching any string containing value
+  or starting with it, with the LIKE wildcards in value
+  escaped.
+*/
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn like_contains(value: &str) -> String {
+    format!("%{}%", escape_like(value))
+}
+
+fn like_prefix(value: &str) -> String {
+    format!("{}%", escape_like(value))
+}
+
+// most messages get_messages_by_hash_chain_prefix returns
+const MAX_HASH_CHAIN_PREFIX_MATCHES: i64 = 10;
+
+/*
+  Unique constraints that cover messages.assignment_id,
+  the column constraint from when it was added and the
+  index that replaced it where that had gone missing.
+*/
+const ASSIGNMENT_ID_CONSTRAINTS: [&str; 2] = [
+    "messages_assignment_id_key",
+    "idx_messages_assignment_id_unique",
+];
+
+/*
+  Unique index on the message id of rows that carry
+  a data item, assignment only rows can share it.
+*/
+const DATA_ITEM_CONSTRAINT: &str = "idx_messages_message_id_data_item_unique";
+
+/*
+  Map an error inserting a message, a duplicate data
+  item or assignment id comes back as MessageExists
+  instead of a generic DatabaseError. A duplicate data
+  item gets the same error check_existing_message
+  gives, so losing the race to another insert looks
+  the same as being caught by the check.
+*/
+fn insert_message_error(error: DieselError, assignment_id: &str) -> StoreErrorType {
+    use diesel::result::DatabaseErrorKind;
+
+    let constraint = match &error {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+            Some(info.constraint_name().map(|c| c.to_string()))
+        }
+        _ => None,
+    };
+
+    match constraint {
+        Some(Some(c)) if c == DATA_ITEM_CONSTRAINT => {
+            StoreErrorType::MessageExists("Message already exists".to_string())
+        }
+        Some(Some(c)) if !ASSIGNMENT_ID_CONSTRAINTS.contains(&c.as_str()) => {
+            StoreErrorType::from(error)
+        }
+        Some(_) => StoreErrorType::MessageExists(format!(
+            "Message with assignment id {} already exists",
+            assignment_id
+        )),
+        None => StoreErrorType::from(error),
+    }
+}
+
+/*
+  Whether a row with this assignment id is in postgres.
+  Errors count as saved, leaking a bytestore entry is
+  better than deleting one a saved row reads from.
+*/
+fn assignment_saved(conn: &mut PgConnection, assignment_id_in: &str) -> bool {
+    use super::schema::messages::dsl::*;
+
+    messages
+        .filter(assignment_id.eq(assignment_id_in))
+        .select(row_id)
+        .first::<i32>(conn)
+        .optional()
+        .map_or(true, |r| r.is_some())
+}
+
+/*
+  Postgres aborts a transaction that hit a serialization
+  failure or a deadlock, running it again is safe. The
+  deadlock has no DatabaseErrorKind of its own so it is
+  picked out by its message.
+*/
+fn is_retryable(error: &StoreErrorType) -> bool {
+    use diesel::result::DatabaseErrorKind;
+
+    let source = match error {
+        StoreErrorType::DatabaseError {
+            source: Some(source),
+            ..
+        } => source,
+        _ => return false,
+    };
+
+    match source.downcast_ref::<DieselError>() {
+        Some(DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _)) => true,
+        Some(DieselError::DatabaseError(_, info)) => info.message().contains("deadlock detected"),
+        _ => false,
+    }
+}
+
+fn retry_backoff(attempt: u32) -> Duration {
+    use rand::Rng;
+
+    let base_ms = 20 * 2u64.pow(attempt.clamp(1, 6) - 1);
+    Duration::from_millis(base_ms + rand::thread_rng().gen_range(0..=base_ms))
+}
+
+/*
+  Runs a write again, up to retries more times, when
+  postgres aborted it with an error is_retryable lets
+  through. Each attempt takes its own connection, any
+  other error is returned straight away.
+*/
+async fn retry_write<T, F, Fut>(
+    logger: &Arc<dyn Log>,
+    retries: u32,
+    operation: &str,
+    mut write: F,
+) -> Result<T, StoreErrorType>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, StoreErrorType>>,
+{
+    let mut attempt = 1;
+    loop {
+        match write().await {
+            Err(e) if attempt <= retries && is_retryable(&e) => {
+                let backoff = retry_backoff(attempt);
+                logger.log(format!(
+                    "{} attempt {} failed, retrying in {:?}: {}",
+                    operation, attempt, backoff, e
+                ));
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn read_only_error() -> StoreErrorType {
+    StoreErrorType::ReadOnly("This su is a read only replica".to_string())
+}
+
+/*
+  Polls the pool until none of its connections are
+  checked out, or gives up after the timeout.
+*/
+async fn drain_pool<M: diesel::r2d2::ManageConnection>(
+    pool: &Pool<M>,
+    timeout: Duration,
+) -> Result<(), StoreErrorType> {
+    let drained = tokio::time::timeout(timeout, async {
+        while pool.state().connections > pool.state().idle_connections {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+
+    drained.map_err(|_| {
+        let state = pool.state();
+        StoreErrorType::Unavailable(format!(
+            "{} write connections still checked out after {:?}",
+            state.connections - state.idle_connections,
+            timeout
+        ))
+    })
+}
+
+fn has_idle_connection<M: diesel::r2d2::ManageConnection>(pool: &Pool<M>) -> bool {
+    pool.state().idle_connections > 0
+}
+
+fn current_unix_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as i64
+}
+
+/*
+  A query that expected a row and found none is a
+  NotFound like any other, every other diesel error
+  is a DatabaseError.
+*/
 impl From<DieselError> for StoreErrorType {
     fn from(diesel_error: DieselError) -> Self {
-        StoreErrorType::DatabaseError(format!("{:?}", diesel_error))
+        if let DieselError::NotFound = diesel_error {
+            return StoreErrorType::not_found("record", "");
+        }
+        StoreErrorType::DatabaseError {
+            message: format!("{:?}", diesel_error),
+            source: Some(Box::new(diesel_error)),
+        }
     }
 }
 
 impl From<JsonErrorType> for StoreErrorType {
     fn from(error: JsonErrorType) -> Self {
-        StoreErrorType::JsonError(format!("data store json error: {:?}", error))
+        StoreErrorType::JsonError {
+            message: format!("data store json error: {:?}", error),
+            source: None,
+        }
     }
 }
 
 impl From<VarError> for StoreErrorType {
     fn from(error: VarError) -> Self {
-        StoreErrorType::EnvVarError(format!("data store env var error: {}", error))
+        StoreErrorType::EnvVarError(error)
     }
 }
 
 impl From<diesel::prelude::ConnectionError> for StoreErrorType {
     fn from(error: diesel::prelude::ConnectionError) -> Self {
-        StoreErrorType::DatabaseError(format!("data store connection error: {}", error))
+        StoreErrorType::DatabaseError {
+            message: format!("data store connection error: {}", error),
+            source: Some(Box::new(error)),
+        }
     }
 }
 
 impl From<std::num::ParseIntError> for StoreErrorType {
     fn from(error: std::num::ParseIntError) -> Self {
-        StoreErrorType::IntError(format!("data store int error: {}", error))
+        StoreErrorType::IntError(error)
     }
 }
 
-struct InMemoryCache {
-    process_cache: Mutex<LruCache<String, Process>>,
+/*
+  An lru cache that counts its hits, misses, insertions
+  and evictions so we can tell if it is sized well.
+  With a ttl, entries older than it are dropped when
+  they are next read and count as a miss.
+*/
+struct CountedCache<V> {
+    name: &'static str,
+    cache: Mutex<LruCache<String, (Instant, V)>>,
+    ttl: Option<Duration>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    insertions: AtomicU64,
+    evictions: AtomicU64,
 }
 
-impl InMemoryCache {
-    pub fn new(size: usize) -> Self {
-        InMemoryCache {
-            process_cache: Mutex::new(LruCache::new(
+impl<V: Clone> CountedCache<V> {
+    pub fn new(name: &'static str, size: usize) -> Self {
+        CountedCache {
+            name,
+            cache: Mutex::new(LruCache::new(
                 NonZeroUsize::new(size).expect("failed to init cache"),
             )),
+            ttl: None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            insertions: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
         }
     }
 
-    pub async fn get_process(&self, process_id: String) -> Option<Process> {
-        let mut cache = self.process_cache.lock().await;
-        cache.get(&process_id).cloned()
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
     }
 
-    pub async fn insert_process(&self, process_id: String, process: Process) {
-        let mut cache = self.process_cache.lock().await;
-        cache.put(process_id, process);
+    pub async fn get(&self, key: &String) -> Option<V> {
+        let mut cache = self.cache.lock().await;
+        if let (Some(ttl), Some((inserted, _))) = (self.ttl, cache.peek(key)) {
+            if inserted.elapsed() >= ttl {
+                cache.pop(key);
+            }
+        }
+        let value = cache.get(key).map(|(_, value)| value.clone());
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
     }
-}
 
-pub struct StoreClient {
-    pool: Pool<ConnectionManager<PgConnection>>,
-    read_pool: Pool<ConnectionManager<PgConnection>>,
+    pub async fn insert(&self, key: String, value: V) {
+        let mut cache = self.cache.lock().await;
+        /*
+          push hands back the entry it displaced, which is
+          either the old value for this key or the least
+          recently used entry if the cache was full
+        */
+        if let Some((displaced, _)) = cache.push(key.clone(), (Instant::now(), value)) {
+            if displaced != key {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.insertions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn remove(&self, key: &String) -> bool {
+        let mut cache = self.cache.lock().await;
+        cache.pop(key).is_some()
+    }
 
     /*
-      These are only public for the purposes of
-      the migration program.
+      Change a cached value in place, nothing happens
+      if it isn't cached. The insertion time is kept so
+      the ttl still runs from when it was first read.
     */
-    pub logger: Arc<dyn Log>,
-    pub bytestore: Arc<bytestore::ByteStore>,
-    in_memory_cache: InMemoryCache,
-    enable_process_assignment: bool,
+    pub async fn update(&self, key: &String, f: impl FnOnce(&mut V)) -> bool {
+        let mut cache = self.cache.lock().await;
+        match cache.peek_mut(key) {
+            Some((_, value)) => {
+                f(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn clear(&self) {
+        self.cache.lock().await.clear();
+    }
+
+    // shrinking drops the least recently used entries, which count as evictions
+    pub async fn resize(&self, size: NonZeroUsize) {
+        let mut cache = self.cache.lock().await;
+        let before = cache.len();
+        cache.resize(size);
+        self.evictions
+            .fetch_add((before - cache.len()) as u64, Ordering::Relaxed);
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        let cache = self.cache.lock().await;
+        CacheStats {
+            name: self.name.to_string(),
+            capacity: cache.cap().get(),
+            len: cache.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            insertions: self.insertions.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
 }
 
 /*
-  This is the data storage layer of the su.
-  It currently uses postgresql, and if the environmnent
-  variable USE_DISK is set to true, it will initialize
-  (if not already initialized) a rocksdb instance in
-  SU_DATA_DIR to store the message binary data for
-  performance.
-
-  USE_DISK should be set after the migration function
-  migrate_to_disk is run, which is built into its own
-  binary in the build process. Things will not speed
-  up unless the data is already migrated.
+  Each cache is sized from its own config value,
+  a new cache should get its own *_CACHE_SIZE
+  variable rather than sharing one.
 */
-impl StoreClient {
-    pub fn new() -> Result<Self, StoreErrorType> {
-        let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
-        let c_clone = config.clone();
-        let database_url = config.database_url;
-        let database_read_url = config.database_read_url;
-        let manager = ConnectionManager::<PgConnection>::new(database_url);
-        let read_manager = ConnectionManager::<PgConnection>::new(database_read_url);
-        let logger = SuLog::init();
+struct InMemoryCache {
+    process_cache: CountedCache<Process>,
+    timeline_cache: CountedCache<ProcessTimeline>,
+    timeline_cache_enabled: bool,
+    message_count_cache: CountedCache<i64>,
+}
 
-        let pool = Pool::builder()
-            .max_size(config.db_write_connections)
-            .test_on_check_out(true)
-            .build(manager)
-            .map_err(|_| {
-                StoreErrorType::DatabaseError("Failed to initialize connection pool.".to_string())
-            })?;
+impl InMemoryCache {
+    pub fn new(config: &AoConfig) -> Self {
+        InMemoryCache {
+            process_cache: CountedCache::new("processes", config.process_cache_size),
+            timeline_cache: CountedCache::new("timelines", config.timeline_cache_size)
+                .with_ttl(Duration::from_secs(config.timeline_cache_ttl_secs)),
+            timeline_cache_enabled: config.timeline_cache_ttl_secs > 0,
+            message_count_cache: CountedCache::new(
+                "message_counts",
+                config.message_count_cache_size,
+            )
+            .with_ttl(Duration::from_secs(config.message_count_cache_ttl_secs)),
+        }
+    }
 
-        let read_pool = Pool::builder()
-            .max_size(config.db_read_connections)
-            .test_on_check_out(true)
-            .build(read_manager)
-            .map_err(|_| {
-                StoreErrorType::DatabaseError(
-                    "Failed to initialize read connection pool.".to_string(),
-                )
-            })?;
+    pub async fn get_process(&self, process_id: String) -> Option<Process> {
+        self.process_cache.get(&process_id).await
+    }
 
-        Ok(StoreClient {
-            pool,
-            read_pool,
-            logger,
-            bytestore: Arc::new(bytestore::ByteStore::new(c_clone)),
-            in_memory_cache: InMemoryCache::new(config.process_cache_size),
-            enable_process_assignment: config.enable_process_assignment,
-        })
+    pub async fn insert_process(&self, process_id: String, process: Process) {
+        self.process_cache.insert(process_id, process).await;
     }
 
-    pub fn new_single_connection() -> Result<Self, StoreErrorType> {
-        let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
-        let c_clone = config.clone();
-        let database_url = config.database_url;
-        let database_read_url = config.database_read_url;
-        let manager = ConnectionManager::<PgConnection>::new(database_url);
-        let read_manager = ConnectionManager::<PgConnection>::new(database_read_url);
-        let logger = SuLog::init();
+    pub async fn remove_process(&self, process_id: String) -> bool {
+        self.process_cache.remove(&process_id).await
+    }
 
-        let pool = Pool::builder()
-            .max_size(1)
-            .test_on_check_out(true)
-            .build(manager)
-            .map_err(|_| {
-                StoreErrorType::DatabaseError("Failed to initialize connection pool.".to_string())
-            })?;
+    pub async fn clear_processes(&self) {
+        self.process_cache.clear().await;
+    }
 
-        let read_pool = Pool::builder()
-            .max_size(1)
-            .test_on_check_out(true)
-            .build(read_manager)
-            .map_err(|_| {
-                StoreErrorType::DatabaseError(
-                    "Failed to initialize read connection pool.".to_string(),
-                )
-            })?;
+    pub async fn resize_processes(&self, size: NonZeroUsize) {
+        self.process_cache.resize(size).await;
+    }
 
-        Ok(StoreClient {
-            pool,
-            read_pool,
-            logger,
-            bytestore: Arc::new(bytestore::ByteStore::new(c_clone)),
-            in_memory_cache: InMemoryCache::new(config.process_cache_size),
-            enable_process_assignment: config.enable_process_assignment,
-        })
+    pub async fn get_timeline(&self, process_id: String) -> Option<ProcessTimeline> {
+        if !self.timeline_cache_enabled {
+            return None;
+        }
+        self.timeline_cache.get(&process_id).await
     }
 
-    /*
-      Get a connection to the writer database using
-      the connection pool initialized in r2d2. This
-      should be used in functions that write data
-      or critically require the most up to date data.
-    */
-    pub fn get_conn(
-        &self,
-    ) -> Result<diesel::r2d2::PooledConnection<ConnectionManager<PgConnection>>, StoreErrorType>
-    {
-        self.pool.get().map_err(|_| {
-            StoreErrorType::DatabaseError("Failed to get connection from pool.".to_string())
-        })
+    pub async fn insert_timeline(&self, process_id: String, timeline: ProcessTimeline) {
+        if self.timeline_cache_enabled {
+            self.timeline_cache.insert(process_id, timeline).await;
+        }
     }
 
-    /*
-      Get a connection to the reader instance. If
-      no DATABASE_READ_URL is set, this will default
-      to the DATABASE_URL. This should be used in
-      functions that only read data.
-    */
-    pub fn get_read_conn(
-        &self,
-    ) -> Result<diesel::r2d2::PooledConnection<ConnectionManager<PgConnection>>, StoreErrorType>
-    {
-        self.read_pool.get().map_err(|_| {
-            StoreErrorType::DatabaseError("Failed to get connection from pool.".to_string())
-        })
+    pub async fn get_message_count(&self, process_id: String) -> Option<i64> {
+        self.message_count_cache.get(&process_id).await
     }
 
-    /*
-        Run at server startup to modify the database as needed.
-        Migrations are embedded directly into the binary that
-        get built.
-    */
-    pub fn run_migrations(&self) -> Result<String, StoreErrorType> {
-        let conn = &mut self.get_conn()?;
-        match conn.run_pending_migrations(MIGRATIONS) {
-            Ok(m) => Ok(format!("Migrations applied... {:?}", m)),
-            Err(e) => Err(StoreErrorType::DatabaseError(format!(
-                "Error applying migrations: {}",
-                e.to_string()
-            ))),
-        }
+    pub async fn insert_message_count(&self, process_id: String, count: i64) {
+        self.message_count_cache.insert(process_id, count).await;
     }
 
-    /*
-      Method to get the total number of messages
-      in the database, this is important for the migration
-      and sync functions.
-    */
-    pub fn get_message_count(&self) -> Result<i64, StoreErrorType> {
-        use super::schema::messages::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+    pub async fn increment_message_count(&self, process_id: String) {
+        self.message_count_cache
+            .update(&process_id, |count| *count += 1)
+            .await;
+    }
 
-        let count_result: Result<i64, DieselError> = messages.count().get_result(conn);
+    pub async fn remove_message_count(&self, process_id: String) -> bool {
+        self.message_count_cache.remove(&process_id).await
+    }
 
-        match count_result {
-            Ok(count) => Ok(count),
-            Err(e) => Err(StoreErrorType::from(e)),
+    pub async fn stats(&self) -> Vec<CacheStats> {
+        vec![
+            self.process_cache.stats().await,
+            self.timeline_cache.stats().await,
+            self.message_count_cache.stats().await,
+        ]
+    }
+}
+
+/*
+  Registry of async locks keyed by process id. Writes for
+  a single process are serialized through here so two saves
+  can't interleave between checking the nonce is ahead of
+  the latest saved one and inserting. The nonce itself is
+  assigned upstream under the scheduler lock, a caller that
+  built its message from a stale latest message is rejected
+  here with a Conflict and has to read the latest again, it
+  is never saved with a duplicate nonce. Entries are removed once nobody
+  holds or waits on them so the map doesn't grow with every
+  process ever seen.
+*/
+struct ProcessLocks {
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+impl ProcessLocks {
+    pub fn new() -> Self {
+        ProcessLocks {
+            locks: DashMap::new(),
         }
     }
 
-    /*
-      Method to get the total number of processes
-      in the database, this is used by the mig_local migration.
-    */
-    pub fn get_process_count(&self) -> Result<i64, StoreErrorType> {
-        use super::schema::processes::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+    pub async fn with_lock<F, T>(&self, process_id: &str, fut: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let lock = self
+            .locks
+            .entry(process_id.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .value()
+            .clone();
+
+        let result = {
+            let _guard = lock.lock().await;
+            fut.await
+        };
 
-        let count_result: Result<i64, DieselError> = processes.count().get_result(conn);
+        /*
+          The map holds one reference, if that is the only
+          one left the entry is idle and can be cleaned up
+        */
+        drop(lock);
+        self.locks
+            .remove_if(process_id, |_, l| Arc::strong_count(l) == 1);
 
-        match count_result {
-            Ok(count) => Ok(count),
-            Err(e) => Err(StoreErrorType::from(e)),
-        }
+        result
     }
+}
 
-    /*
-      Get all processes in the database, within a
-      certain range. This is used for migrations.
-    */
-    pub fn get_all_processes(
-        &self,
-        from: i64,
-        to: Option<i64>,
-    ) -> Result<Vec<Vec<u8>>, StoreErrorType> {
-        use super::schema::processes::dsl::*;
-        let conn = &mut self.get_read_conn()?;
-        let mut query = processes.into_boxed();
+/*
+  Sets statement_timeout on every connection a pool
+  opens, before it is handed out, so one slow query
+  can't hold a connection for minutes. 0 turns the
+  timeout off.
+*/
+#[derive(Debug)]
+struct StatementTimeoutCustomizer {
+    timeout_ms: u64,
+}
 
-        // Apply the offset
-        query = query.offset(from);
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for StatementTimeoutCustomizer {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        set_statement_timeout(conn, self.timeout_ms).map_err(diesel::r2d2::Error::QueryError)
+    }
+}
 
-        // Apply the limit if `to` is provided
-        if let Some(to) = to {
-            let limit = to - from;
-            query = query.limit(limit);
-        }
+// SET can't take a bind parameter, the timeout is a number so it is formatted in
+fn set_statement_timeout(conn: &mut PgConnection, timeout_ms: u64) -> QueryResult<()> {
+    diesel::sql_query(format!("SET statement_timeout = {}", timeout_ms)).execute(conn)?;
+    Ok(())
+}
 
-        let db_processes_result: Result<Vec<DbProcess>, DieselError> = query.load(conn);
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BreakerState {
+    Closed,
+    Open(Instant),
+    HalfOpen,
+}
 
-        match db_processes_result {
-            Ok(db_processes) => {
-                let mut processes_mapped: Vec<Vec<u8>> = vec![];
-                for db_process in db_processes.iter() {
-                    let bytes: Vec<u8> = db_process.bundle.clone();
-                    processes_mapped.push(bytes);
-                }
+/*
+  Circuit breaker in front of a connection pool. During
+  an outage every checkout would otherwise wait out the
+  pool timeout, so once threshold checkouts fail in a row
+  the breaker opens and callers get Unavailable straight
+  away. After the cool down a single checkout is let
+  through as a probe, success closes the breaker and
+  failure opens it for another cool down.
+*/
+struct CircuitBreaker {
+    name: &'static str,
+    threshold: u32,
+    cooldown: Duration,
+    state: std::sync::Mutex<(BreakerState, u32)>,
+    opened: AtomicU64,
+    rejected: AtomicU64,
+    logger: Arc<dyn Log>,
+}
 
-                Ok(processes_mapped)
-            }
-            Err(e) => Err(StoreErrorType::from(e)),
+impl CircuitBreaker {
+    pub fn new(
+        name: &'static str,
+        threshold: u32,
+        cooldown: Duration,
+        logger: Arc<dyn Log>,
+    ) -> Self {
+        CircuitBreaker {
+            name,
+            threshold: threshold.max(1),
+            cooldown,
+            state: std::sync::Mutex::new((BreakerState::Closed, 0)),
+            opened: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            logger,
         }
     }
 
     /*
-      Get all messages in the database, within a
-      certain range. This is used for the migration.
+      Called before a checkout, errors if the breaker is
+      open or a probe is already in flight.
     */
-    pub fn get_all_messages(
-        &self,
-        from: i64,
-        to: Option<i64>,
-    ) -> Result<
-        Vec<(
-            String,
-            Option<String>,
-            Vec<u8>,
-            String,
-            serde_json::Value,
-            String,
-        )>,
-        StoreErrorType,
-    > {
-        use super::schema::messages::dsl::*;
-        let conn = &mut self.get_read_conn()?;
-        let mut query = messages.into_boxed();
-
-        // Apply the offset
-        query = query.offset(from);
-
-        // Apply the limit if `to` is provided
-        if let Some(to) = to {
-            let limit = to - from;
-            query = query.limit(limit);
+    pub fn check(&self) -> Result<(), StoreErrorType> {
+        let mut state = self.state.lock().unwrap();
+        match state.0 {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open(since) if since.elapsed() >= self.cooldown => {
+                state.0 = BreakerState::HalfOpen;
+                self.logger.log(format!(
+                    "db circuit breaker {} half open, probing the database",
+                    self.name
+                ));
+                Ok(())
+            }
+            _ => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(StoreErrorType::Unavailable(format!(
+                    "Database unavailable, circuit breaker {} is open",
+                    self.name
+                )))
+            }
         }
+    }
 
-        let db_messages_result: Result<Vec<DbMessage>, DieselError> =
-            query.order(timestamp.asc()).load(conn);
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.0 != BreakerState::Closed {
+            self.logger
+                .log(format!("db circuit breaker {} closed", self.name));
+        }
+        *state = (BreakerState::Closed, 0);
+    }
 
-        match db_messages_result {
-            Ok(db_messages) => {
-                let mut messages_mapped: Vec<(
-                    String,
-                    Option<String>,
-                    Vec<u8>,
-                    String,
-                    serde_json::Value,
-                    String,
-                )> = vec![];
-                for db_message in db_messages.iter() {
-                    let bytes: Vec<u8> = db_message.bundle.clone();
-                    messages_mapped.push((
-                        db_message.message_id.clone(),
-                        db_message.assignment_id.clone(),
-                        bytes,
-                        db_message.process_id.clone(),
-                        db_message.message_data.clone(),
-                        db_message.timestamp.to_string().clone(),
-                    ));
-                }
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.1 = state.1.saturating_add(1);
+        let trip = match state.0 {
+            BreakerState::Closed => state.1 >= self.threshold,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open(_) => false,
+        };
+        if trip {
+            state.0 = BreakerState::Open(Instant::now());
+            self.opened.fetch_add(1, Ordering::Relaxed);
+            self.logger.error(format!(
+                "db circuit breaker {} opened after {} consecutive failures",
+                self.name, state.1
+            ));
+        }
+    }
 
-                Ok(messages_mapped)
+    /*
+      Run a pool checkout through the breaker
+    */
+    pub fn call<T, E>(&self, checkout: impl FnOnce() -> Result<T, E>) -> Result<T, StoreErrorType> {
+        self.check()?;
+        match checkout() {
+            Ok(conn) => {
+                self.record_success();
+                Ok(conn)
+            }
+            Err(_) => {
+                self.record_failure();
+                Err(StoreErrorType::database(
+                    "Failed to get connection from pool.".to_string(),
+                ))
             }
-            Err(e) => Err(StoreErrorType::from(e)),
         }
     }
 
-    // used by the mig_local migration
-    pub async fn get_all_messages_using_bytestore(
-        &self,
-        from: i64,
-        to: Option<i64>,
-    ) -> Result<
-        Vec<(
-            String,         // message_id
-            Option<String>, // assignment_id
-            String,         // process_id
-            i64,            // timestamp
-            i32,            // epoch
-            i32,            // nonce
-            String,         // hash_chain
-            Vec<u8>,        // bundle
-        )>,
-        StoreErrorType,
-    > {
-        use super::schema::messages::dsl::*;
-        let conn = &mut self.get_read_conn()?;
-        let mut query = messages.into_boxed();
+    pub fn stats(&self) -> CircuitBreakerStats {
+        let state = self.state.lock().unwrap();
+        CircuitBreakerStats {
+            name: self.name.to_string(),
+            state: match state.0 {
+                BreakerState::Closed => "closed",
+                BreakerState::Open(_) => "open",
+                BreakerState::HalfOpen => "half_open",
+            }
+            .to_string(),
+            consecutive_failures: state.1,
+            opened: self.opened.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
 
-        // Apply the offset
-        query = query.offset(from);
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    throttled: u64,
+}
 
-        // Apply the limit if `to` is provided
-        if let Some(to) = to {
-            let limit = to - from;
-            query = query.limit(limit);
-        }
+/*
+  Per process token bucket applied to message saves,
+  so one process being spammed can't take the whole
+  write pool from the others. Buckets that have been
+  idle long enough to refill are the same as a new
+  one, so they are dropped every CLEANUP_INTERVAL.
+*/
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    exempt: Vec<String>,
+    buckets: DashMap<String, TokenBucket>,
+    last_cleanup: std::sync::Mutex<Instant>,
+    logger: Arc<dyn Log>,
+}
 
-        // Select only the fields that you are using
-        let selected_fields = query.select((
-            message_id,
-            assignment_id,
-            process_id,
-            timestamp,
-            epoch,
-            nonce,
-            hash_chain,
-        ));
+impl RateLimiter {
+    const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
 
-        // Load only the selected fields
-        let db_messages_result: Result<
-            Vec<(
-                String,         // message_id
-                Option<String>, // assignment_id
-                String,         // process_id
-                i64,            // timestamp
-                i32,            // epoch
-                i32,            // nonce
-                String,         // hash_chain
-            )>,
-            DieselError,
-        > = selected_fields.order(timestamp.asc()).load(conn);
+    /*
+      None when no rate is configured, the burst
+      defaults to one second worth of messages.
+    */
+    pub fn new(
+        rate: Option<f64>,
+        burst: Option<u32>,
+        exempt: Vec<String>,
+        logger: Arc<dyn Log>,
+    ) -> Option<Self> {
+        let rate = rate.filter(|r| *r > 0.0)?;
+        let burst = burst.map(|b| b as f64).unwrap_or(rate.ceil()).max(1.0);
+        Some(RateLimiter {
+            rate,
+            burst,
+            exempt,
+            buckets: DashMap::new(),
+            last_cleanup: std::sync::Mutex::new(Instant::now()),
+            logger,
+        })
+    }
 
-        match db_messages_result {
-            Ok(db_messages) => {
-                // Map the result into the desired tuple with timestamp as String
-                let messages_mapped = db_messages
-                    .into_iter()
-                    .map(|db_message| {
-                        (
-                            db_message.0, // message_id
-                            db_message.1, // assignment_id
-                            db_message.2, // process_id
-                            db_message.3, // timestamp
-                            db_message.4, // epoch
-                            db_message.5, // nonce
-                            db_message.6, // hash_chain
-                        )
-                    })
-                    .collect::<Vec<_>>();
+    pub fn acquire(&self, process_id: &str) -> Result<(), StoreErrorType> {
+        if self.exempt.iter().any(|p| p == process_id) {
+            return Ok(());
+        }
+        self.cleanup();
+
+        let now = Instant::now();
+        let mut bucket = self
+            .buckets
+            .entry(process_id.to_string())
+            .or_insert_with(|| TokenBucket {
+                tokens: self.burst,
+                last_refill: now,
+                throttled: 0,
+            });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.throttled = 0;
+            return Ok(());
+        }
 
-                let message_ids: Vec<(String, Option<String>, String, String)> = messages_mapped
-                    .iter()
-                    .map(|msg| {
-                        (
-                            msg.0.clone(),
-                            msg.1.clone(),
-                            msg.2.clone(),
-                            msg.3.to_string().clone(),
-                        )
-                    })
-                    .collect();
+        /*
+          Log the first write turned away and then every
+          100th so a spamming client doesn't flood the logs
+        */
+        bucket.throttled += 1;
+        if bucket.throttled == 1 || bucket.throttled % 100 == 0 {
+            self.logger.log(format!(
+                "rate limited process {}, {} writes turned away",
+                process_id, bucket.throttled
+            ));
+        }
 
-                let binaries = self.bytestore.clone().read_binaries(message_ids).await?;
-                let mut messages_with_bundles = vec![];
-
-                for db_message in messages_mapped.iter() {
-                    match binaries.get(&(
-                        db_message.0.clone(),             // message id
-                        db_message.1.clone(),             // assignment id
-                        db_message.2.clone(),             // process id
-                        db_message.3.to_string().clone(), // timestamp
-                    )) {
-                        Some(bytes_result) => {
-                            messages_with_bundles.push((
-                                db_message.0.clone(), // message_id
-                                db_message.1.clone(), // assignment_id
-                                db_message.2.clone(), // process_id
-                                db_message.3.clone(), // timestamp
-                                db_message.4.clone(), // epoch
-                                db_message.5.clone(), // nonce
-                                db_message.6.clone(), // hash_chain
-                                bytes_result.clone(), // bundle
-                            ));
-                        }
-                        None => {
-                            // Fall back to the database if the binary isn't available
-                            let db_message_with_bundle: DbMessage = match db_message.1.clone() {
-                                Some(assignment_id_d) => messages
-                                    .filter(
-                                        message_id
-                                            .eq(db_message.0.clone())
-                                            .and(assignment_id.eq(assignment_id_d)),
-                                    )
-                                    .order(timestamp.asc())
-                                    .first(conn)?,
-                                None => messages
-                                    .filter(message_id.eq(db_message.0.clone()))
-                                    .order(timestamp.asc())
-                                    .first(conn)?,
-                            };
-                            messages_with_bundles.push((
-                                db_message.0.clone(),                  // message_id
-                                db_message.1.clone(),                  // assignment_id
-                                db_message.2.clone(),                  // process_id
-                                db_message.3.clone(),                  // timestamp
-                                db_message.4.clone(),                  // epoch
-                                db_message.5.clone(),                  // nonce
-                                db_message.6.clone(),                  // hash_chain
-                                db_message_with_bundle.bundle.clone(), // bundle
-                            ));
-                        }
-                    }
-                }
+        let retry_after = ((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64;
+        Err(StoreErrorType::RateLimited {
+            message: format!(
+                "Process {} is over its write rate limit, retry after {}s",
+                process_id, retry_after
+            ),
+            retry_after,
+        })
+    }
 
-                Ok(messages_with_bundles)
-            }
-            Err(e) => Err(StoreErrorType::from(e)),
+    fn cleanup(&self) {
+        let mut last_cleanup = match self.last_cleanup.try_lock() {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        if last_cleanup.elapsed() < Self::CLEANUP_INTERVAL {
+            return;
         }
+        *last_cleanup = Instant::now();
+
+        let refill_secs = self.burst / self.rate;
+        self.buckets
+            .retain(|_, b| b.last_refill.elapsed().as_secs_f64() < refill_secs);
     }
+}
 
-    /*
-      Used as a fallback when USE_DISK is true. If the
-      Message cannot be found in the bytestore it will
-      fall back to this.
+/*
+  State of the background bytestore sync started by
+  spawn_bytestore_sync. running keeps two passes from
+  overlapping, stopping and wake end the task on
+  shutdown, and last_run is what the admin stats show.
+  degraded_since is the latest row id when the
+  bytestore was last degraded, until the rows after
+  it have been synced.
+*/
+#[derive(Default)]
+struct BytestoreSync {
+    running: AtomicBool,
+    stopping: AtomicBool,
+    wake: Notify,
+    last_run: StdMutex<Option<ByteStoreSyncRun>>,
+    handle: StdMutex<Option<JoinHandle<()>>>,
+    degraded_since: StdMutex<Option<i32>>,
+}
+
+/*
+  Held while a sync pass runs, clears running when
+  the pass ends even if it panicked.
+*/
+struct SyncPassGuard<'a>(&'a AtomicBool);
+
+impl Drop for SyncPassGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl BytestoreSync {
+    fn begin_pass(&self) -> Option<SyncPassGuard<'_>> {
+        self.running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| SyncPassGuard(&self.running))
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /*
+      Sleep for duration or until stop is called,
+      returns false if the task should stop.
     */
-    fn get_message_internal(
-        &self,
-        message_id_in: &String,
-        assignment_id_in: &Option<String>,
-        conn: &mut PooledConnection<ConnectionManager<PgConnection>>
-    ) -> Result<Message, StoreErrorType> {
-        use super::schema::messages::dsl::*;
+    async fn sleep(&self, duration: Duration) -> bool {
+        if self.is_stopping() {
+            return false;
+        }
+        let _ = tokio::time::timeout(duration, self.wake.notified()).await;
+        !self.is_stopping()
+    }
+
+    async fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+
+        let handle = match self.handle.lock() {
+            Ok(mut h) => h.take(),
+            Err(_) => None,
+        };
+        if let Some(handle) = handle {
+            let _ = handle.await;
+        }
+    }
+
+    fn last_run(&self) -> Option<ByteStoreSyncRun> {
+        match self.last_run.lock() {
+            Ok(r) => r.clone(),
+            Err(_) => None,
+        }
+    }
+}
+
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(60);
+
+/*
+  When each message saved in the last minute was
+  saved, for the messages per second in /health.
+*/
+#[derive(Default)]
+struct MessageThroughput {
+    saves: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl MessageThroughput {
+    async fn record(&self) {
+        let now = Instant::now();
+        let mut saves = self.saves.lock().await;
+        trim_window(&mut saves, now);
+        saves.push_back(now);
+    }
+
+    async fn per_second(&self) -> f64 {
+        let mut saves = self.saves.lock().await;
+        trim_window(&mut saves, Instant::now());
+        saves.len() as f64 / THROUGHPUT_WINDOW.as_secs_f64()
+    }
+}
+
+fn trim_window(saves: &mut VecDeque<Instant>, now: Instant) {
+    while let Some(oldest) = saves.front() {
+        if now.duration_since(*oldest) < THROUGHPUT_WINDOW {
+            break;
+        }
+        saves.pop_front();
+    }
+}
+
+pub struct StoreClient {
+    /*
+      None on a read only replica, which has no writer
+      and never runs migrations.
+    */
+    pool: Option<Pool<ConnectionManager<PgConnection>>>,
+    read_pool: Pool<ConnectionManager<PgConnection>>,
+    write_breaker: CircuitBreaker,
+    read_breaker: CircuitBreaker,
+    rate_limiter: Option<RateLimiter>,
+
+    /*
+      These are only public for the purposes of
+      the migration program.
+    */
+    pub logger: Arc<dyn Log>,
+    pub bytestore: Arc<bytestore::ByteStore>,
+    in_memory_cache: Arc<InMemoryCache>,
+    process_locks: ProcessLocks,
+    batch_writer: Option<batch_writer::BatchWriter>,
+    enable_process_assignment: bool,
+    cache_invalidation: bool,
+    bytestore_sync: BytestoreSync,
+    shutdown_drain_timeout: Duration,
+    message_throughput: MessageThroughput,
+    write_retries: u32,
+    max_read_replica_lag_ms: Option<i64>,
+    scheduler_max_failures: i32,
+    scheduler_capacity: Option<i32>,
+    frozen_processes: DashSet<String>,
+    statement_timeout_ms: u64,
+    verify_bundles: bool,
+}
+
+/*
+  This is the data storage layer of the su.
+  It currently uses postgresql, and if the environmnent
+  variable USE_DISK is set to true, it will initialize
+  (if not already initialized) a rocksdb instance in
+  SU_DATA_DIR to store the message binary data for
+  performance.
+
+  USE_DISK should be set after the migration function
+  migrate_to_disk is run, which is built into its own
+  binary in the build process. Things will not speed
+  up unless the data is already migrated.
+*/
+impl StoreClient {
+    pub fn new() -> Result<Self, StoreErrorType> {
+        let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+        let c_clone = config.clone();
+        let in_memory_cache = Arc::new(InMemoryCache::new(&config));
+        let listener_url = config.database_url.clone();
+        let database_url = config.database_url;
+        let database_read_url = config.database_read_url;
+        let manager = ConnectionManager::<PgConnection>::new(database_url);
+        let read_manager = ConnectionManager::<PgConnection>::new(database_read_url);
+        let logger = SuLog::init();
+
+        let pool = Pool::builder()
+            .max_size(config.db_write_connections)
+            .test_on_check_out(true)
+            .idle_timeout(config.db_idle_timeout_secs.map(Duration::from_secs))
+            .connection_customizer(Box::new(StatementTimeoutCustomizer {
+                timeout_ms: config.db_statement_timeout_ms,
+            }))
+            .build(manager)
+            .map_err(|_| {
+                StoreErrorType::database("Failed to initialize connection pool.".to_string())
+            })?;
+
+        let read_pool = Pool::builder()
+            .max_size(config.db_read_connections)
+            .test_on_check_out(true)
+            .idle_timeout(config.db_idle_timeout_secs.map(Duration::from_secs))
+            .connection_customizer(Box::new(StatementTimeoutCustomizer {
+                timeout_ms: config.db_statement_timeout_ms,
+            }))
+            .build(read_manager)
+            .map_err(|_| {
+                StoreErrorType::database("Failed to initialize read connection pool.".to_string())
+            })?;
+
+        if config.use_disk {
+            bytestore::validate_data_dir(&c_clone, true).map_err(StoreErrorType::ConfigError)?;
+        }
+        let bytestore = Arc::new(bytestore::ByteStore::new(c_clone, logger.clone()));
+
+        let batch_writer = if config.write_batching {
+            Some(batch_writer::BatchWriter::new(
+                pool.clone(),
+                bytestore.clone(),
+                logger.clone(),
+                config.write_batch_max,
+                config.write_batch_interval_ms,
+            ))
+        } else {
+            None
+        };
+
+        let write_breaker = CircuitBreaker::new(
+            "writer",
+            config.db_breaker_threshold,
+            Duration::from_secs(config.db_breaker_cooldown_secs),
+            logger.clone(),
+        );
+        let read_breaker = CircuitBreaker::new(
+            "reader",
+            config.db_breaker_threshold,
+            Duration::from_secs(config.db_breaker_cooldown_secs),
+            logger.clone(),
+        );
+
+        let rate_limiter = RateLimiter::new(
+            config.max_messages_per_second_per_process,
+            config.message_rate_limit_burst,
+            config.rate_limit_exempt_processes.clone(),
+            logger.clone(),
+        );
+
+        if config.cache_invalidation {
+            cache_invalidation::spawn_listener(
+                listener_url,
+                in_memory_cache.clone(),
+                logger.clone(),
+            );
+        }
+
+        Ok(StoreClient {
+            pool: Some(pool),
+            read_pool,
+            write_breaker,
+            read_breaker,
+            rate_limiter,
+            logger,
+            bytestore,
+            in_memory_cache,
+            process_locks: ProcessLocks::new(),
+            batch_writer,
+            enable_process_assignment: config.enable_process_assignment,
+            cache_invalidation: config.cache_invalidation,
+            bytestore_sync: BytestoreSync::default(),
+            shutdown_drain_timeout: Duration::from_secs(config.shutdown_drain_timeout_secs),
+            message_throughput: MessageThroughput::default(),
+            write_retries: config.db_write_retries,
+            max_read_replica_lag_ms: config.max_read_replica_lag_ms,
+            scheduler_max_failures: config.scheduler_max_failures,
+            scheduler_capacity: config.scheduler_capacity,
+            frozen_processes: DashSet::new(),
+            statement_timeout_ms: config.db_statement_timeout_ms,
+            verify_bundles: config.verify_bundles_on_write,
+        })
+    }
+
+    pub fn new_single_connection() -> Result<Self, StoreErrorType> {
+        let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+        let c_clone = config.clone();
+        let in_memory_cache = Arc::new(InMemoryCache::new(&config));
+        let database_url = config.database_url;
+        let database_read_url = config.database_read_url;
+        let manager = ConnectionManager::<PgConnection>::new(database_url);
+        let read_manager = ConnectionManager::<PgConnection>::new(database_read_url);
+        let logger = SuLog::init();
+
+        let pool = Pool::builder()
+            .max_size(1)
+            .test_on_check_out(true)
+            .idle_timeout(config.db_idle_timeout_secs.map(Duration::from_secs))
+            .connection_customizer(Box::new(StatementTimeoutCustomizer {
+                timeout_ms: config.db_statement_timeout_ms,
+            }))
+            .build(manager)
+            .map_err(|_| {
+                StoreErrorType::database("Failed to initialize connection pool.".to_string())
+            })?;
+
+        let read_pool = Pool::builder()
+            .max_size(1)
+            .test_on_check_out(true)
+            .idle_timeout(config.db_idle_timeout_secs.map(Duration::from_secs))
+            .connection_customizer(Box::new(StatementTimeoutCustomizer {
+                timeout_ms: config.db_statement_timeout_ms,
+            }))
+            .build(read_manager)
+            .map_err(|_| {
+                StoreErrorType::database("Failed to initialize read connection pool.".to_string())
+            })?;
+
+        if config.use_disk {
+            bytestore::validate_data_dir(&c_clone, true).map_err(StoreErrorType::ConfigError)?;
+        }
+        let bytestore = Arc::new(bytestore::ByteStore::new(c_clone, logger.clone()));
+
+        let write_breaker = CircuitBreaker::new(
+            "writer",
+            config.db_breaker_threshold,
+            Duration::from_secs(config.db_breaker_cooldown_secs),
+            logger.clone(),
+        );
+        let read_breaker = CircuitBreaker::new(
+            "reader",
+            config.db_breaker_threshold,
+            Duration::from_secs(config.db_breaker_cooldown_secs),
+            logger.clone(),
+        );
+
+        let rate_limiter = RateLimiter::new(
+            config.max_messages_per_second_per_process,
+            config.message_rate_limit_burst,
+            config.rate_limit_exempt_processes.clone(),
+            logger.clone(),
+        );
+
+        Ok(StoreClient {
+            pool: Some(pool),
+            read_pool,
+            write_breaker,
+            read_breaker,
+            rate_limiter,
+            logger,
+            bytestore,
+            in_memory_cache,
+            process_locks: ProcessLocks::new(),
+            batch_writer: None,
+            enable_process_assignment: config.enable_process_assignment,
+            cache_invalidation: config.cache_invalidation,
+            bytestore_sync: BytestoreSync::default(),
+            shutdown_drain_timeout: Duration::from_secs(config.shutdown_drain_timeout_secs),
+            message_throughput: MessageThroughput::default(),
+            write_retries: config.db_write_retries,
+            max_read_replica_lag_ms: config.max_read_replica_lag_ms,
+            scheduler_max_failures: config.scheduler_max_failures,
+            scheduler_capacity: config.scheduler_capacity,
+            frozen_processes: DashSet::new(),
+            statement_timeout_ms: config.db_statement_timeout_ms,
+            verify_bundles: config.verify_bundles_on_write,
+        })
+    }
+
+    /*
+      For the instances that only serve reads. Only the
+      read pool is built, migrations are left to the
+      writer, and every mutating method returns ReadOnly
+      so a write routed here by mistake can't touch the
+      schedule. The bytestore should be opened with
+      try_read_instance_connect.
+    */
+    pub fn new_read_only() -> Result<Self, StoreErrorType> {
+        let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+        let c_clone = config.clone();
+        let in_memory_cache = Arc::new(InMemoryCache::new(&config));
+        let read_manager = ConnectionManager::<PgConnection>::new(config.database_read_url);
+        let logger = SuLog::init();
+
+        let read_pool = Pool::builder()
+            .max_size(config.db_read_connections)
+            .test_on_check_out(true)
+            .idle_timeout(config.db_idle_timeout_secs.map(Duration::from_secs))
+            .connection_customizer(Box::new(StatementTimeoutCustomizer {
+                timeout_ms: config.db_statement_timeout_ms,
+            }))
+            .build(read_manager)
+            .map_err(|_| {
+                StoreErrorType::database("Failed to initialize read connection pool.".to_string())
+            })?;
+
+        if config.use_disk {
+            bytestore::validate_data_dir(&c_clone, false).map_err(StoreErrorType::ConfigError)?;
+        }
+        let bytestore = Arc::new(bytestore::ByteStore::new(c_clone, logger.clone()));
+
+        let write_breaker = CircuitBreaker::new(
+            "writer",
+            config.db_breaker_threshold,
+            Duration::from_secs(config.db_breaker_cooldown_secs),
+            logger.clone(),
+        );
+        let read_breaker = CircuitBreaker::new(
+            "reader",
+            config.db_breaker_threshold,
+            Duration::from_secs(config.db_breaker_cooldown_secs),
+            logger.clone(),
+        );
 
         /*
-            get the oldest match. in the case of a message that has
-            later assignments, it should be the original message itself.
+          LISTEN isn't allowed on a hot standby, so even a
+          read only instance listens on the primary.
         */
-        let db_message_result: Result<Option<DbMessage>, DieselError> = match assignment_id_in {
-            Some(assignment_id_d) => messages
-                .filter(
-                    message_id
-                        .eq(message_id_in)
-                        .and(assignment_id.eq(assignment_id_d)),
-                )
-                .order(timestamp.asc())
-                .first(conn)
-                .optional(),
-            None => messages
-                .filter(message_id.eq(message_id_in))
-                .order(timestamp.asc())
-                .first(conn)
-                .optional(),
+        if config.cache_invalidation {
+            cache_invalidation::spawn_listener(
+                config.database_url.clone(),
+                in_memory_cache.clone(),
+                logger.clone(),
+            );
+        }
+
+        Ok(StoreClient {
+            pool: None,
+            read_pool,
+            write_breaker,
+            read_breaker,
+            rate_limiter: None,
+            logger,
+            bytestore,
+            in_memory_cache,
+            process_locks: ProcessLocks::new(),
+            batch_writer: None,
+            enable_process_assignment: config.enable_process_assignment,
+            cache_invalidation: config.cache_invalidation,
+            bytestore_sync: BytestoreSync::default(),
+            shutdown_drain_timeout: Duration::from_secs(config.shutdown_drain_timeout_secs),
+            message_throughput: MessageThroughput::default(),
+            write_retries: config.db_write_retries,
+            max_read_replica_lag_ms: config.max_read_replica_lag_ms,
+            scheduler_max_failures: config.scheduler_max_failures,
+            scheduler_capacity: config.scheduler_capacity,
+            frozen_processes: DashSet::new(),
+            statement_timeout_ms: config.db_statement_timeout_ms,
+            verify_bundles: config.verify_bundles_on_write,
+        })
+    }
+
+    fn ensure_writable(&self) -> Result<(), StoreErrorType> {
+        match self.pool {
+            Some(_) => Ok(()),
+            None => Err(read_only_error()),
+        }
+    }
+
+    fn ensure_not_frozen(&self, process_id_in: &str) -> Result<(), StoreErrorType> {
+        match self.frozen_processes.contains(process_id_in) {
+            true => Err(StoreErrorType::ReadOnly(format!(
+                "Process {} is frozen for handover",
+                process_id_in
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /*
+      Get a connection to the writer database using
+      the connection pool initialized in r2d2. This
+      should be used in functions that write data
+      or critically require the most up to date data.
+    */
+    pub fn get_conn(
+        &self,
+    ) -> Result<diesel::r2d2::PooledConnection<ConnectionManager<PgConnection>>, StoreErrorType>
+    {
+        match &self.pool {
+            Some(pool) => self.write_breaker.call(|| pool.get()),
+            None => Err(read_only_error()),
+        }
+    }
+
+    /*
+      For reads that want the most up to date data. A
+      read only replica has no writer so these settle
+      for the reader.
+    */
+    fn get_latest_conn(
+        &self,
+    ) -> Result<diesel::r2d2::PooledConnection<ConnectionManager<PgConnection>>, StoreErrorType>
+    {
+        match self.pool {
+            Some(_) => self.get_conn(),
+            None => self.get_read_conn(),
+        }
+    }
+
+    /*
+      How far behind the primary the read pool's server
+      is. A primary has no replay timestamp, so this is
+      None when reads aren't going to a replica.
+    */
+    pub async fn get_read_replica_lag_ms(&self) -> Result<Option<i64>, StoreErrorType> {
+        let conn = &mut self.get_read_conn()?;
+        let lag = diesel::sql_query(
+            "SELECT (EXTRACT(EPOCH FROM (now() - pg_last_xact_replay_timestamp())) * 1000)::BIGINT AS lag_ms",
+        )
+        .get_result::<ReplicaLag>(conn)?;
+        Ok(lag.lag_ms)
+    }
+
+    /*
+      get_latest_conn only settles for the reader on a
+      read only replica, which has no writer to fall
+      back to, so a lagging reader is only logged.
+    */
+    async fn warn_if_replica_lagging(&self) {
+        let max_lag = match (self.pool.as_ref(), self.max_read_replica_lag_ms) {
+            (None, Some(max_lag)) => max_lag,
+            _ => return,
         };
+        match self.get_read_replica_lag_ms().await {
+            Ok(Some(lag)) if lag > max_lag => self.logger.error(format!(
+                "Read replica is {}ms behind, over MAX_READ_REPLICA_LAG_MS {}, latest messages may be stale",
+                lag, max_lag
+            )),
+            Ok(_) => (),
+            Err(e) => self
+                .logger
+                .error(format!("Failed to read the read replica lag: {:?}", e)),
+        }
+    }
 
-        match db_message_result {
-            Ok(Some(db_message)) => {
-                let message_val: serde_json::Value =
-                    serde_json::from_value(db_message.message_data.clone())?;
-                let message: Message = Message::from_val(&message_val, db_message.bundle.clone())?;
-                Ok(message)
-            }
-            Ok(None) => Err(StoreErrorType::NotFound("Message not found".to_string())), // Adjust this error type as needed
-            Err(e) => Err(StoreErrorType::from(e)),
+    /*
+      Get a connection to the reader instance. If
+      no DATABASE_READ_URL is set, this will default
+      to the DATABASE_URL. This should be used in
+      functions that only read data.
+    */
+    pub fn get_read_conn(
+        &self,
+    ) -> Result<diesel::r2d2::PooledConnection<ConnectionManager<PgConnection>>, StoreErrorType>
+    {
+        self.read_breaker.call(|| self.read_pool.get())
+    }
+
+    /*
+      Wait for every checked out write connection to be
+      returned to the pool, so writes that were in flight
+      when the server stopped get to finish. A read only
+      replica has no write pool and returns straight away.
+    */
+    async fn with_write_retries<T, F, Fut>(
+        &self,
+        operation: &str,
+        write: F,
+    ) -> Result<T, StoreErrorType>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, StoreErrorType>>,
+    {
+        retry_write(&self.logger, self.write_retries, operation, write).await
+    }
+
+    pub async fn message_throughput_per_second(&self) -> f64 {
+        self.message_throughput.per_second().await
+    }
+
+    pub async fn drain_write_pool(&self, timeout: Duration) -> Result<(), StoreErrorType> {
+        match &self.pool {
+            Some(pool) => drain_pool(pool, timeout).await,
+            None => Ok(()),
         }
     }
 
     /*
-      Used in the sync_bytestore function to iterate
-      over the message table starting at the end.
+      Read a process from postgres on the given
+      connection and put it in the cache, replacing
+      anything older that was cached for it.
+    */
+    async fn load_process(
+        &self,
+        conn: &mut PgConnection,
+        process_id_in: &str,
+    ) -> Result<Process, StoreErrorType> {
+        use super::schema::processes::dsl::*;
+
+        let db_process_result: Result<Option<DbProcess>, DieselError> = processes
+            .filter(process_id.eq(process_id_in))
+            .first(conn)
+            .optional();
+
+        match db_process_result {
+            Ok(Some(db_process)) => {
+                let process: Process = Process::from_val(&db_process.process_data)?;
+                self.in_memory_cache
+                    .insert_process(process_id_in.to_string(), process.clone())
+                    .await;
+                Ok(process)
+            }
+            Ok(None) => Err(StoreErrorType::not_found("process", process_id_in)),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    /*
+      Run a future while holding the write lock for a
+      process. Anything that checks the latest state of a
+      process and then writes based on it should go
+      through here so concurrent writes are serialized.
+    */
+    pub async fn with_process_lock<F, T>(&self, process_id: &str, fut: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        self.process_locks.with_lock(process_id, fut).await
+    }
+
+    /*
+        Run at server startup to modify the database as needed.
+        Migrations are embedded directly into the binary that
+        get built.
+    */
+    pub fn run_migrations(&self) -> Result<String, StoreErrorType> {
+        let conn = &mut self.get_conn()?;
+        // building an index on a big table can take longer than the statement timeout
+        set_statement_timeout(conn, 0)?;
+        let result = match conn.run_pending_migrations(MIGRATIONS) {
+            Ok(m) => Ok(format!("Migrations applied... {:?}", m)),
+            Err(e) => Err(StoreErrorType::database(format!(
+                "Error applying migrations: {}",
+                e.to_string()
+            ))),
+        };
+        set_statement_timeout(conn, self.statement_timeout_ms)?;
+        result
+    }
+
+    /*
+      Building the pools only sets up the connection
+      managers, so check out a connection from each one
+      at startup and make sure it can run a query rather
+      than finding out on the first request.
+    */
+    pub fn validate_pool_connections(&self) -> Result<(), StoreErrorType> {
+        if self.pool.is_some() {
+            self.ping_write_pool()?;
+        }
+        self.ping_read_pool()?;
+        Ok(())
+    }
+
+    fn ping_write_pool(&self) -> Result<(), StoreErrorType> {
+        let conn = &mut self.get_conn()?;
+        diesel::sql_query("SELECT 1").execute(conn)?;
+        Ok(())
+    }
+
+    fn ping_read_pool(&self) -> Result<(), StoreErrorType> {
+        let read_conn = &mut self.get_read_conn()?;
+        diesel::sql_query("SELECT 1").execute(read_conn)?;
+        Ok(())
+    }
+
+    /*
+      For a liveness probe, true when each pool has an
+      idle connection. Nothing is checked out or queried
+      so it can't wait on an exhausted pool, the pools
+      keep their idle connections tested themselves.
+    */
+    pub fn ping(&self) -> bool {
+        let write_ok = match &self.pool {
+            Some(pool) => has_idle_connection(pool),
+            None => true,
+        };
+        write_ok && has_idle_connection(&self.read_pool)
+    }
+
+    /*
+      Idle connections in the write and read pools, the
+      write pool counts as 0 on a read only replica.
+    */
+    fn pool_stats(&self) -> (u32, u32) {
+        let write_idle = match &self.pool {
+            Some(pool) => pool.state().idle_connections,
+            None => 0,
+        };
+        (write_idle, self.read_pool.state().idle_connections)
+    }
+
+    /*
+      Assignment ids with more than one row in messages,
+      these have to be resolved before the unique index
+      on assignment_id can be built.
+    */
+    pub fn find_duplicate_assignments(&self) -> Result<Vec<DuplicateAssignment>, StoreErrorType> {
+        let conn = &mut self.get_read_conn()?;
+        Ok(diesel::sql_query(
+            "SELECT assignment_id, COUNT(*) AS row_count, \
+             array_agg(row_id ORDER BY row_id) AS row_ids, \
+             array_agg(message_id ORDER BY row_id) AS message_ids \
+             FROM messages WHERE assignment_id IS NOT NULL \
+             GROUP BY assignment_id HAVING COUNT(*) > 1 ORDER BY assignment_id",
+        )
+        .load::<DuplicateAssignment>(conn)?)
+    }
+
+    /*
+      Move bytestore entries written under the legacy key,
+      without an assignment id, to the key the current code
+      reads. The assignment id comes from the postgres row
+      for the message. Messages that have no assignment id
+      in postgres keep their legacy key, which is still the
+      right key for them. Safe to rerun.
+    */
+    pub async fn migrate_key_format(&self) -> Result<u64, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let _bulk_write = self.bytestore.begin_bulk_write();
+
+        let batch_size = AoConfig::new(Some("su".to_string()))
+            .expect("Failed to read configuration")
+            .migration_batch_size as usize;
+        let mut start = b"message___".to_vec();
+        let mut migrated = 0;
+
+        loop {
+            let (legacy_keys, next) = self
+                .bytestore
+                .scan_legacy_message_keys(&start, batch_size)
+                .await?;
+
+            let conn = &mut self.get_read_conn()?;
+            let mut moves = Vec::new();
+            for legacy in legacy_keys {
+                let legacy_timestamp: i64 = legacy.timestamp.parse()?;
+                let found: Option<Option<String>> = messages
+                    .filter(message_id.eq(&legacy.message_id))
+                    .filter(process_id.eq(&legacy.process_id))
+                    .filter(timestamp.eq(legacy_timestamp))
+                    .select(assignment_id)
+                    .first(conn)
+                    .optional()?;
+                if let Some(Some(found_assignment_id)) = found {
+                    moves.push((legacy, found_assignment_id));
+                }
+            }
+
+            migrated += self.bytestore.rekey_messages(moves).await?;
+            self.logger
+                .log(format!("Bytestore keys migrated: {}", migrated));
+
+            match next {
+                Some(next_start) => start = next_start,
+                None => break,
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    /*
+      Method to get the total number of messages
+      in the database, this is important for the migration
+      and sync functions.
+    */
+    pub fn get_message_count(&self) -> Result<i64, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let count_result: Result<i64, DieselError> = messages.count().get_result(conn);
+
+        match count_result {
+            Ok(count) => Ok(count),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    /*
+      Method to get the total number of processes
+      in the database, this is used by the mig_local migration.
+    */
+    pub fn get_process_count(&self) -> Result<i64, StoreErrorType> {
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let count_result: Result<i64, DieselError> = processes.count().get_result(conn);
+
+        match count_result {
+            Ok(count) => Ok(count),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    /*
+      Fill in module_id for a page of processes saved
+      before the column existed, starting after the
+      given row id. Returns the last row id looked at,
+      None when there are no rows left, and how many
+      rows were updated. Processes without a Module
+      tag are left NULL.
+    */
+    pub fn backfill_module_ids(
+        &self,
+        after_row_id: i32,
+        limit: i64,
+    ) -> Result<(Option<i32>, usize), StoreErrorType> {
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_conn()?;
+
+        let rows: Vec<(i32, serde_json::Value)> = processes
+            .filter(module_id.is_null())
+            .filter(row_id.gt(after_row_id))
+            .order(row_id.asc())
+            .limit(limit)
+            .select((row_id, process_data))
+            .load(conn)?;
+
+        let last_row_id = rows.last().map(|r| r.0);
+        let mut updated = 0;
+        for (row, data) in rows {
+            let process = Process::from_val(&data)?;
+            if let Some(module) = process.module_id() {
+                diesel::update(processes.filter(row_id.eq(row)))
+                    .set(module_id.eq(module))
+                    .execute(conn)?;
+                updated += 1;
+            }
+        }
+
+        Ok((last_row_id, updated))
+    }
+
+    /*
+      Get all processes in the database, within a
+      certain range. This is used for migrations.
+    */
+    pub fn get_all_processes(
+        &self,
+        from: i64,
+        to: Option<i64>,
+    ) -> Result<Vec<Vec<u8>>, StoreErrorType> {
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+        let mut query = processes.into_boxed();
+
+        // Apply the offset
+        query = query.offset(from);
+
+        // Apply the limit if `to` is provided
+        if let Some(to) = to {
+            let limit = to - from;
+            query = query.limit(limit);
+        }
+
+        let db_processes_result: Result<Vec<DbProcess>, DieselError> = query.load(conn);
+
+        match db_processes_result {
+            Ok(db_processes) => {
+                let mut processes_mapped: Vec<Vec<u8>> = vec![];
+                for db_process in db_processes.iter() {
+                    let bytes: Vec<u8> = db_process.bundle.clone();
+                    processes_mapped.push(bytes);
+                }
+
+                Ok(processes_mapped)
+            }
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    /*
+      Get all messages in the database, within a
+      certain range. This is used for the migration.
+    */
+    pub fn get_all_messages(
+        &self,
+        from: i64,
+        to: Option<i64>,
+    ) -> Result<
+        Vec<(
+            String,
+            Option<String>,
+            Vec<u8>,
+            String,
+            serde_json::Value,
+            String,
+        )>,
+        StoreErrorType,
+    > {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+        let mut query = messages.into_boxed();
+
+        // Apply the offset
+        query = query.offset(from);
+
+        // Apply the limit if `to` is provided
+        if let Some(to) = to {
+            let limit = to - from;
+            query = query.limit(limit);
+        }
+
+        let db_messages_result: Result<Vec<DbMessage>, DieselError> =
+            query.order((timestamp.asc(), row_id.asc())).load(conn);
+
+        match db_messages_result {
+            Ok(db_messages) => {
+                let mut messages_mapped: Vec<(
+                    String,
+                    Option<String>,
+                    Vec<u8>,
+                    String,
+                    serde_json::Value,
+                    String,
+                )> = vec![];
+                for db_message in db_messages.iter() {
+                    let bytes: Vec<u8> = db_message.bundle.clone();
+                    messages_mapped.push((
+                        db_message.message_id.clone(),
+                        db_message.assignment_id.clone(),
+                        bytes,
+                        db_message.process_id.clone(),
+                        db_message.message_data.clone(),
+                        db_message.timestamp.to_string().clone(),
+                    ));
+                }
+
+                Ok(messages_mapped)
+            }
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    // used by the mig_local migration
+    pub async fn get_all_messages_using_bytestore(
+        &self,
+        from: i64,
+        to: Option<i64>,
+    ) -> Result<
+        Vec<(
+            String,         // message_id
+            Option<String>, // assignment_id
+            String,         // process_id
+            i64,            // timestamp
+            i32,            // epoch
+            i32,            // nonce
+            String,         // hash_chain
+            Vec<u8>,        // bundle
+        )>,
+        StoreErrorType,
+    > {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+        let mut query = messages.into_boxed();
+
+        // Apply the offset
+        query = query.offset(from);
+
+        // Apply the limit if `to` is provided
+        if let Some(to) = to {
+            let limit = to - from;
+            query = query.limit(limit);
+        }
+
+        // Select only the fields that you are using
+        let selected_fields = query.select((
+            message_id,
+            assignment_id,
+            process_id,
+            timestamp,
+            epoch,
+            nonce,
+            hash_chain,
+        ));
+
+        // Load only the selected fields
+        let db_messages_result: Result<
+            Vec<(
+                String,         // message_id
+                Option<String>, // assignment_id
+                String,         // process_id
+                i64,            // timestamp
+                i32,            // epoch
+                i32,            // nonce
+                String,         // hash_chain
+            )>,
+            DieselError,
+        > = selected_fields
+            .order((timestamp.asc(), row_id.asc()))
+            .load(conn);
+
+        match db_messages_result {
+            Ok(db_messages) => {
+                // Map the result into the desired tuple with timestamp as String
+                let messages_mapped = db_messages
+                    .into_iter()
+                    .map(|db_message| {
+                        (
+                            db_message.0, // message_id
+                            db_message.1, // assignment_id
+                            db_message.2, // process_id
+                            db_message.3, // timestamp
+                            db_message.4, // epoch
+                            db_message.5, // nonce
+                            db_message.6, // hash_chain
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                let message_ids: Vec<(String, Option<String>, String, String)> = messages_mapped
+                    .iter()
+                    .map(|msg| {
+                        (
+                            msg.0.clone(),
+                            msg.1.clone(),
+                            msg.2.clone(),
+                            msg.3.to_string().clone(),
+                        )
+                    })
+                    .collect();
+
+                let binaries = self.bytestore.clone().read_binaries(message_ids).await?;
+                let messages_with_bundles = fill_page(
+                    &messages_mapped,
+                    &binaries,
+                    |msg| {
+                        (
+                            msg.0.clone(),
+                            msg.1.clone(),
+                            msg.2.clone(),
+                            msg.3.to_string(),
+                        )
+                    },
+                    |missing| self.load_fallback_messages(conn, missing),
+                    |msg, bytes| {
+                        Ok((
+                            msg.0.clone(), // message_id
+                            msg.1.clone(), // assignment_id
+                            msg.2.clone(), // process_id
+                            msg.3,         // timestamp
+                            msg.4,         // epoch
+                            msg.5,         // nonce
+                            msg.6.clone(), // hash_chain
+                            bytes.clone(), // bundle
+                        ))
+                    },
+                    |msg, db_message_with_bundle| {
+                        self.verify_bundle(
+                            &msg.0,
+                            &db_message_with_bundle.bundle,
+                            &db_message_with_bundle.bundle_checksum,
+                        )?;
+                        Ok((
+                            msg.0.clone(),
+                            msg.1.clone(),
+                            msg.2.clone(),
+                            msg.3,
+                            msg.4,
+                            msg.5,
+                            msg.6.clone(),
+                            db_message_with_bundle.bundle.clone(),
+                        ))
+                    },
+                )?;
+
+                Ok(messages_with_bundles)
+            }
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    /*
+      Used as a fallback when USE_DISK is true. If the
+      Message cannot be found in the bytestore it will
+      fall back to this.
+    */
+    /*
+      Check a bundle read from postgres against the
+      checksum saved with it. A mismatch is logged and
+      counted and returned as a database error, so it
+      isn't mistaken for a bad bundle from a client.
+    */
+    fn verify_bundle(
+        &self,
+        tx_id: &str,
+        bundle_in: &[u8],
+        checksum: &Option<String>,
+    ) -> Result<(), StoreErrorType> {
+        if bytestore::checksum_matches(bundle_in, checksum) {
+            return Ok(());
+        }
+        self.bytestore.record_corrupt_read();
+        self.logger
+            .error(format!("Bundle checksum mismatch for message {}", tx_id));
+        Err(StoreErrorType::database(format!(
+            "Bundle checksum mismatch for message {}",
+            tx_id
+        )))
+    }
+
+    fn get_message_internal(
+        &self,
+        message_id_in: &String,
+        assignment_id_in: &Option<String>,
+        conn: &mut PooledConnection<ConnectionManager<PgConnection>>
+    ) -> Result<Message, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+
+        /*
+            get the oldest match. in the case of a message that has
+            later assignments, it should be the original message itself.
+        */
+        let db_message_result: Result<Option<DbMessage>, DieselError> = match assignment_id_in {
+            Some(assignment_id_d) => messages
+                .filter(
+                    message_id
+                        .eq(message_id_in)
+                        .and(assignment_id.eq(assignment_id_d)),
+                )
+                .order(timestamp.asc())
+                .first(conn)
+                .optional(),
+            None => messages
+                .filter(message_id.eq(message_id_in))
+                .order(timestamp.asc())
+                .first(conn)
+                .optional(),
+        };
+
+        match db_message_result {
+            Ok(Some(db_message)) => self.db_message_to_message(&db_message),
+            Ok(None) => Err(StoreErrorType::not_found("message", message_id_in)),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    fn db_message_to_message(&self, db_message: &DbMessage) -> Result<Message, StoreErrorType> {
+        self.verify_bundle(
+            &db_message.message_id,
+            &db_message.bundle,
+            &db_message.bundle_checksum,
+        )?;
+        let message_val: serde_json::Value =
+            serde_json::from_value(db_message.message_data.clone())?;
+        let message: Message = Message::from_val(&message_val, db_message.bundle.clone())?;
+        Ok(message)
+    }
+
+    /*
+      Load the rows for a page's bytestore misses with
+      one eq_any query on message id, the oldest match
+      for each key is picked out in oldest_per_key.
+    */
+    fn load_fallback_messages(
+        &self,
+        conn: &mut PgConnection,
+        keys: &HashSet<FallbackKey>,
+    ) -> Result<HashMap<FallbackKey, DbMessage>, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+
+        let ids: HashSet<String> = keys.iter().map(|(msg_id, _)| msg_id.clone()).collect();
+        let rows: Vec<DbMessage> = messages
+            .filter(message_id.eq_any(ids.into_iter().collect::<Vec<String>>()))
+            .order(timestamp.asc())
+            .load(conn)?;
+
+        Ok(oldest_per_key(rows, keys))
+    }
+
+    /*
+      Used in the sync_bytestore function to iterate
+      over the message table starting at the end.
+    */
+    pub fn get_message_by_offset_from_end(
+        &self,
+        offset: i64,
+    ) -> Result<
+        Option<(
+            String,
+            Option<String>,
+            Vec<u8>,
+            String,
+            serde_json::Value,
+            String,
+        )>,
+        StoreErrorType,
+    > {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        // row_id keeps the order total so no offset is skipped or repeated
+        let db_message_result: Result<Option<DbMessage>, DieselError> = messages
+            .order((timestamp.desc(), nonce.desc(), row_id.desc()))
+            .offset(offset)
+            .first(conn)
+            .optional();
+
+        match db_message_result {
+            Ok(Some(db_message)) => {
+                let bytes: Vec<u8> = db_message.bundle.clone();
+                Ok(Some((
+                    db_message.message_id.clone(),
+                    db_message.assignment_id.clone(),
+                    bytes,
+                    db_message.process_id.clone(),
+                    db_message.message_data.clone(),
+                    db_message.timestamp.to_string().clone(),
+                )))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    /*
+      The read only replica's version of sync_bytestore,
+      it opens the bytestore read only once it can and
+      leaves syncing to the writer.
+    */
+    pub fn connect_bytestore_read_only(&self) {
+        let runtime = tokio::runtime::Handle::current();
+        while let Err(e) = runtime.block_on(self.bytestore.try_read_instance_connect()) {
+            self.logger
+                .debug(format!("Bytestore not ready, waiting... {}", e));
+            std::thread::sleep(std::time::Duration::from_secs(5));
+        }
+        self.logger.log("Bytestore opened read only".to_string());
+    }
+
+    /*
+      Read the latest messages_per_process messages of the
+      process_count most active processes through the
+      bytestore and throw them away, so the first page
+      requests after a restart find RocksDB's block cache
+      and the OS page cache warm. Most active means the
+      most messages among the last WARMUP_RECENT_ROWS rows.
+      Reads go in batches of WARMUP_BATCH_SIZE, a batch
+      over MAX_READ_MEMORY is logged and skipped. This is
+      blocking, run it on a blocking thread once the
+      bytestore is connected.
+    */
+    pub fn warm_bytestore(
+        &self,
+        messages_per_process: i64,
+        process_count: i64,
+    ) -> Result<(), StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        use diesel::sql_types::BigInt;
+
+        const WARMUP_RECENT_ROWS: i64 = 100_000;
+        const WARMUP_BATCH_SIZE: usize = 100;
+
+        if !self.bytestore.is_ready() {
+            return Err(StoreErrorType::database(
+                "Bytestore is not connected".to_string(),
+            ));
+        }
+
+        let runtime = tokio::runtime::Handle::current();
+        let start = Instant::now();
+        let conn = &mut self.get_read_conn()?;
+
+        let active: Vec<ActiveProcess> = diesel::sql_query(
+            "SELECT process_id FROM \
+             (SELECT process_id FROM messages ORDER BY row_id DESC LIMIT $1) recent \
+             GROUP BY process_id ORDER BY COUNT(*) DESC LIMIT $2",
+        )
+        .bind::<BigInt, _>(WARMUP_RECENT_ROWS)
+        .bind::<BigInt, _>(process_count)
+        .load(conn)?;
+
+        self.logger.log(format!(
+            "Warming the bytestore with up to {} messages each for {} processes",
+            messages_per_process,
+            active.len()
+        ));
+
+        let (mut messages_read, mut bytes_read) = (0, 0);
+        for (i, active_process) in active.iter().enumerate() {
+            let keys: Vec<(String, Option<String>, String, String)> = messages
+                .filter(process_id.eq(&active_process.process_id))
+                .filter(deleted_at.is_null())
+                .order(timestamp.desc())
+                .limit(messages_per_process)
+                .select((message_id, assignment_id, process_id, timestamp))
+                .load::<(String, Option<String>, String, i64)>(conn)?
+                .into_iter()
+                .map(|(msg_id, assign_id, proc_id, ts)| {
+                    (msg_id, assign_id, proc_id, ts.to_string())
+                })
+                .collect();
+
+            for batch in keys.chunks(WARMUP_BATCH_SIZE) {
+                match runtime.block_on(self.bytestore.read_binaries(batch.to_vec())) {
+                    Ok(binaries) => {
+                        messages_read += binaries.len();
+                        bytes_read += binaries.iter().map(|b| b.value().len()).sum::<usize>();
+                    }
+                    Err(e) => self.logger.error(format!(
+                        "Skipping a bytestore warmup batch for {}: {}",
+                        active_process.process_id, e
+                    )),
+                }
+            }
+
+            if (i + 1) % 10 == 0 {
+                self.logger.log(format!(
+                    "Bytestore warmup: {}/{} processes, {} messages, {} bytes",
+                    i + 1,
+                    active.len(),
+                    messages_read,
+                    bytes_read
+                ));
+            }
+        }
+
+        self.logger.log(format!(
+            "Bytestore warmup done in {:?}: {} processes, {} messages, {} bytes",
+            start.elapsed(),
+            active.len(),
+            messages_read,
+            bytes_read
+        ));
+        Ok(())
+    }
+
+    /*
+      Start the background bytestore sync, run at server
+      startup if USE_DISK is true. It waits for the
+      bytestore to connect, syncs the tail of the
+      messages table, runs after_tail, then every
+      interval syncs the rows written since the last
+      pass so a bytestore write that failed is healed
+      without a restart. interval None only syncs the
+      tail. The task ends when shutdown is called.
+
+      try_connect is called here because the server can
+      operate normally without the bytestore until it can
+      be initialized, in case another program is still
+      using the same embedded db. Until it is, is_ready
+      returns false and the rest of the StoreClient won't
+      read or write the bytestore.
+    */
+    pub fn spawn_bytestore_sync<F>(self: &Arc<Self>, interval: Option<Duration>, after_tail: F)
+    where
+        F: FnOnce(&StoreClient) + Send + 'static,
+    {
+        let mut handle = match self.bytestore_sync.handle.lock() {
+            Ok(h) => h,
+            Err(_) => return,
+        };
+        if handle.is_some() {
+            self.logger
+                .error("The bytestore sync is already running".to_string());
+            return;
+        }
+
+        let store = self.clone();
+        *handle = Some(tokio::spawn(async move {
+            while let Err(e) = store.bytestore.try_connect().await {
+                store
+                    .logger
+                    .debug(format!("Bytestore not ready, waiting... {}", e));
+                if !store.bytestore_sync.sleep(Duration::from_secs(5)).await {
+                    return;
+                }
+            }
+
+            /*
+              The db and bytestore calls block, so each
+              pass runs on a blocking thread.
+            */
+            let tail_store = store.clone();
+            let watermark = match tokio::task::spawn_blocking(move || {
+                let watermark = tail_store.latest_message_row_id();
+                tail_store.run_sync_pass("tail", |run| tail_store.sync_tail(run));
+                after_tail(&tail_store);
+                watermark
+            })
+            .await
+            {
+                Ok(w) => w,
+                Err(e) => {
+                    store
+                        .logger
+                        .error(format!("The bytestore tail sync failed: {:?}", e));
+                    return;
+                }
+            };
+
+            let (interval, mut watermark) = match (interval, watermark) {
+                (Some(i), Ok(w)) => (i, w),
+                (Some(_), Err(e)) => {
+                    store.logger.error(format!(
+                        "Not re-syncing the bytestore, failed to read the latest row: {:?}",
+                        e
+                    ));
+                    return;
+                }
+                (None, _) => return,
+            };
+
+            while store.bytestore_sync.sleep(interval).await {
+                let pass_store = store.clone();
+                match tokio::task::spawn_blocking(move || {
+                    let mut next = watermark;
+                    pass_store.run_sync_pass("incremental", |run| {
+                        next = pass_store.sync_since(watermark, run)?;
+                        Ok(())
+                    });
+                    next
+                })
+                .await
+                {
+                    Ok(next) => watermark = next,
+                    Err(e) => store
+                        .logger
+                        .error(format!("A bytestore re-sync failed: {:?}", e)),
+                }
+            }
+        }));
+    }
+
+    /*
+      Check the disk usage of the bytestore every
+      interval, run at server startup if USE_DISK is
+      true. While the disk is over
+      BYTESTORE_DISK_HARD_LIMIT_PERCENT the bytestore is
+      degraded and messages are only saved to postgres,
+      once it is back under the rows saved meanwhile are
+      synced to the bytestore. The task ends when
+      shutdown is called.
+    */
+    pub fn spawn_bytestore_disk_watchdog(self: &Arc<Self>, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            loop {
+                store.check_bytestore_disk().await;
+                if !store.bytestore_sync.sleep(interval).await {
+                    return;
+                }
+            }
+        });
+    }
+
+    async fn check_bytestore_disk(self: &Arc<Self>) {
+        match self.bytestore.check_disk() {
+            Ok(Some(true)) => match self.latest_message_row_id() {
+                Ok(latest) => {
+                    if let Ok(mut since) = self.bytestore_sync.degraded_since.lock() {
+                        since.get_or_insert(latest);
+                    }
+                }
+                Err(e) => self.logger.error(format!(
+                    "Failed to read the latest row, the bytestore will need a full sync: {:?}",
+                    e
+                )),
+            },
+            Ok(_) => (),
+            Err(e) => self
+                .logger
+                .error(format!("Failed to check the bytestore disk: {}", e)),
+        }
+
+        /*
+          Retried every check until a recovery pass runs,
+          in case the bytestore isn't connected yet or
+          another pass is running.
+        */
+        let after = match self.bytestore_sync.degraded_since.lock() {
+            Ok(since) => *since,
+            Err(_) => None,
+        };
+        let after = match after {
+            Some(a) if self.bytestore.is_ready() => a,
+            _ => return,
+        };
+
+        let store = self.clone();
+        match tokio::task::spawn_blocking(move || {
+            store.run_sync_pass("recovery", |run| store.sync_gap(after, run))
+        })
+        .await
+        {
+            Ok(Some(_)) if !self.bytestore.is_degraded() => {
+                if let Ok(mut since) = self.bytestore_sync.degraded_since.lock() {
+                    *since = None;
+                }
+            }
+            Ok(_) => (),
+            Err(e) => self
+                .logger
+                .error(format!("The bytestore recovery sync failed: {:?}", e)),
+        }
+    }
+
+    /*
+      Run one sync pass unless another one is still
+      running, and keep its result for the admin stats.
+    */
+    fn run_sync_pass<F>(&self, pass: &str, sync: F) -> Option<ByteStoreSyncRun>
+    where
+        F: FnOnce(&mut ByteStoreSyncRun) -> Result<(), StoreErrorType>,
+    {
+        if self.bytestore.is_degraded() {
+            self.logger.log(format!(
+                "Skipping the {} bytestore sync, the bytestore is degraded",
+                pass
+            ));
+            return None;
+        }
+        let _running = match self.bytestore_sync.begin_pass() {
+            Some(guard) => guard,
+            None => {
+                self.logger.log(format!(
+                    "Skipping the {} bytestore sync, another pass is running",
+                    pass
+                ));
+                return None;
+            }
+        };
+        let _bulk_write = self.bytestore.begin_bulk_write();
+
+        let start = Instant::now();
+        let mut run = ByteStoreSyncRun {
+            pass: pass.to_string(),
+            ..Default::default()
+        };
+        if let Err(e) = sync(&mut run) {
+            run.record_error(format!("{:?}", e));
+        }
+        run.duration_ms = start.elapsed().as_millis() as u64;
+        run.finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        self.logger.log(format!(
+            "Bytestore {} sync done in {:?}: {} checked, {} synced, {} errors",
+            pass,
+            start.elapsed(),
+            run.checked,
+            run.synced,
+            run.errors
+        ));
+        if let Some(e) = &run.last_error {
+            self.logger
+                .error(format!("Last bytestore {} sync error: {}", pass, e));
+        }
+
+        if let Ok(mut last_run) = self.bytestore_sync.last_run.lock() {
+            *last_run = Some(run.clone());
+        }
+        Some(run)
+    }
+
+    fn latest_message_row_id(&self) -> Result<i32, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+        let latest: Option<i32> = messages.select(diesel::dsl::max(row_id)).first(conn)?;
+        Ok(latest.unwrap_or(0))
+    }
+
+    /*
+      Start at the end of the messages table, scan
+      backwards and insert messages into the bytestore
+      until one is already there. Blocking, and the
+      bytestore must be connected.
+    */
+    fn sync_tail(&self, run: &mut ByteStoreSyncRun) -> Result<(), StoreErrorType> {
+        let runtime = tokio::runtime::Handle::current();
+        let total_count = self.get_message_count()?;
+
+        for offset in 0..total_count {
+            if self.bytestore_sync.is_stopping() {
+                break;
+            }
+
+            let (msg_id, assignment_id, bundle, process_id, _, timestamp) =
+                match self.get_message_by_offset_from_end(offset)? {
+                    Some(message) => message,
+                    None => break,
+                };
+            run.checked += 1;
+
+            if runtime.block_on(self.bytestore.exists(
+                &msg_id,
+                &assignment_id,
+                &process_id,
+                &timestamp,
+            )) {
+                break;
+            }
+
+            match runtime.block_on(self.bytestore.save_binary(
+                msg_id.clone(),
+                assignment_id,
+                process_id,
+                timestamp,
+                bundle,
+            )) {
+                Ok(_) => run.synced += 1,
+                Err(e) => run.record_error(format!("{}: {}", msg_id, e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+      Check the rows after row id after, less
+      BYTESTORE_SYNC_OVERLAP in case some committed out
+      of order, and write the ones the bytestore is
+      missing. At most BYTESTORE_SYNC_MAX_ROWS are
+      checked, the last row id checked is returned so
+      the next pass carries on from there. Blocking,
+      and the bytestore must be connected.
+    */
+    fn sync_since(&self, after: i32, run: &mut ByteStoreSyncRun) -> Result<i32, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+
+        const BYTESTORE_SYNC_OVERLAP: i32 = 1_000;
+        const BYTESTORE_SYNC_MAX_ROWS: i64 = 10_000;
+        const BYTESTORE_SYNC_BATCH_SIZE: usize = 100;
+
+        let runtime = tokio::runtime::Handle::current();
+        let conn = &mut self.get_read_conn()?;
+
+        let rows: Vec<(i32, String, Option<String>, String, i64)> = messages
+            .filter(row_id.gt(after.saturating_sub(BYTESTORE_SYNC_OVERLAP)))
+            .filter(deleted_at.is_null())
+            .order(row_id.asc())
+            .limit(BYTESTORE_SYNC_MAX_ROWS)
+            .select((row_id, message_id, assignment_id, process_id, timestamp))
+            .load(conn)?;
+
+        let mut last_checked = after;
+        let mut missing = vec![];
+        for (r_id, msg_id, assign_id, proc_id, ts) in rows {
+            if self.bytestore_sync.is_stopping() {
+                break;
+            }
+            run.checked += 1;
+            last_checked = last_checked.max(r_id);
+            if !runtime.block_on(self.bytestore.exists(
+                &msg_id,
+                &assign_id,
+                &proc_id,
+                &ts.to_string(),
+            )) {
+                missing.push(r_id);
+            }
+        }
+
+        for batch in missing.chunks(BYTESTORE_SYNC_BATCH_SIZE) {
+            let binaries: Vec<(String, Option<String>, String, i64, Vec<u8>)> = messages
+                .filter(row_id.eq_any(batch))
+                .select((message_id, assignment_id, process_id, timestamp, bundle))
+                .load(conn)?;
+
+            for (msg_id, assign_id, proc_id, ts, binary) in binaries {
+                match runtime.block_on(self.bytestore.save_binary(
+                    msg_id.clone(),
+                    assign_id,
+                    proc_id,
+                    ts.to_string(),
+                    binary,
+                )) {
+                    Ok(_) => run.synced += 1,
+                    Err(e) => run.record_error(format!("{}: {}", msg_id, e)),
+                }
+            }
+        }
+
+        Ok(last_checked)
+    }
+
+    /*
+      Sync every row after row id after, to fill the
+      gap left while the bytestore was degraded.
+      Blocking, and the bytestore must be connected.
+    */
+    fn sync_gap(&self, after: i32, run: &mut ByteStoreSyncRun) -> Result<(), StoreErrorType> {
+        let mut after = after;
+        loop {
+            let next = self.sync_since(after, run)?;
+            if next == after || self.bytestore_sync.is_stopping() || self.bytestore.is_degraded() {
+                return Ok(());
+            }
+            after = next;
+        }
+    }
+
+    /*
+      Sync the tail of the messages table once, for the
+      cli binaries that run while the su is stopped.
+      Blocking, and the bytestore must be connected.
+    */
+    pub fn sync_bytestore(&self) -> Result<ByteStoreSyncRun, StoreErrorType> {
+        self.run_sync_pass("tail", |run| self.sync_tail(run))
+            .ok_or_else(|| {
+                StoreErrorType::database("A bytestore sync is already running".to_string())
+            })
+    }
+
+    /*
+      This runs under the process lock, so the latest
+      nonce read here can't change before the insert
+      that follows it. A nonce that isn't ahead of it
+      means two writes were scheduled off the same
+      predecessor.
+    */
+    fn check_nonce_ahead(
+        &self,
+        message: &Message,
+        conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    ) -> Result<(), StoreErrorType> {
+        use super::schema::messages::dsl::*;
+
+        let process_id_in = message.process_id()?;
+        let nonce_in = message.nonce()?;
+        let saved_nonce: Option<i32> = messages
+            .filter(process_id.eq(&process_id_in))
+            .select(diesel::dsl::max(nonce))
+            .first(conn)?;
+
+        // a message queued in the batch writer isn't in postgres yet
+        let reserved_nonce = self
+            .batch_writer
+            .as_ref()
+            .and_then(|writer| writer.reserved_nonce(&process_id_in));
+        let latest_nonce = saved_nonce.max(reserved_nonce);
+
+        if let Some(latest) = latest_nonce {
+            if nonce_in <= latest {
+                return Err(StoreErrorType::Conflict(format!(
+                    "Nonce {} is not ahead of the latest nonce {} for process {}",
+                    nonce_in, latest, process_id_in
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+      Insert the process row, a process that is already
+      saved is left as it is.
+    */
+    fn insert_process(
+        &self,
+        conn: &mut PgConnection,
+        process: &Process,
+        bundle_in: &[u8],
+    ) -> Result<usize, StoreErrorType> {
+        use super::schema::processes::dsl::*;
+
+        process
+            .validate()
+            .map_err(|errors| StoreErrorType::invalid_input("process", errors.join("; ")))?;
+
+        let (process_epoch, process_hash_chain, process_timestamp, process_nonce) =
+            match self.enable_process_assignment {
+                true => (
+                    process.epoch().ok(),
+                    process.hash_chain().ok(),
+                    process.timestamp().ok(),
+                    process.nonce().ok(),
+                ),
+                false => (None, None, None, None),
+            };
+
+        let new_process = NewProcess {
+            process_id: &process.process.process_id,
+            process_data: serde_json::to_value(process).expect("Failed to serialize Process"),
+            bundle: bundle_in,
+            epoch: process_epoch,
+            hash_chain: process_hash_chain.as_deref(),
+            nonce: process_nonce,
+            timestamp: process_timestamp,
+            module_id: process.module_id(),
+        };
+
+        let inserted = diesel::insert_into(processes)
+            .values(&new_process)
+            .on_conflict(process_id)
+            .do_nothing()
+            .execute(conn)?;
+
+        if inserted > 0 && self.cache_invalidation {
+            cache_invalidation::notify(conn, &process.process.process_id)?;
+        }
+
+        Ok(inserted)
+    }
+
+    fn insert_message(
+        &self,
+        conn: &mut PgConnection,
+        message: &Message,
+        bundle_in: &[u8],
+    ) -> Result<(), StoreErrorType> {
+        use super::schema::messages::dsl::*;
+
+        let new_message = NewMessage {
+            process_id: &message.process_id()?,
+            message_id: &message.message_id()?,
+            assignment_id: &message.assignment_id()?,
+            message_data: serde_json::to_value(message).expect("Failed to serialize Message"),
+            epoch: &message.epoch()?,
+            nonce: &message.nonce()?,
+            timestamp: &message.timestamp()?,
+            bundle: bundle_in,
+            hash_chain: &message.hash_chain()?,
+            bundle_checksum: Some(bytestore::bundle_checksum(bundle_in)),
+        };
+
+        let row_count = diesel::insert_into(messages)
+            .values(&new_message)
+            .execute(conn)
+            .map_err(|e| insert_message_error(e, new_message.assignment_id))?;
+        if row_count == 0 {
+            return Err(StoreErrorType::database("Error saving message".to_string()));
+        }
+        Ok(())
+    }
+
+    /*
+      This is moved above the sql logic now, so that
+      if it fails, the message doesnt get scheduled
+      if the server has USE_DISK enabled. It can run without
+      the RocksDB records but if the RocksDB saves fail
+      for a long period of time processes will slow
+      down so it is better to fail loud here. and not
+      schedule the message.
+    */
+    async fn save_message_binary(
+        &self,
+        message: &Message,
+        bundle_in: &[u8],
+        deep_hash: Option<&String>,
+    ) -> Result<(), StoreErrorType> {
+        let bytestore = self.bytestore.clone();
+        if bytestore.is_ready() {
+            bytestore
+                .save_binary(
+                    message.message_id()?,
+                    Some(message.assignment_id()?),
+                    message.process_id()?,
+                    message.timestamp()?.to_string(),
+                    bundle_in.to_vec(),
+                )
+                .await?;
+            if let Some(dh) = deep_hash {
+                bytestore.save_deep_hash(&message.process_id()?, dh).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /*
+      Clean the message out of the bytestore if the
+      sql insert failed. It would be ok if messages
+      leak into RocksDB that are not in sql because sql
+      controls the schedule, but will avoid it if possible.
+
+      A duplicate shares its deep hash with the row that
+      is already saved, and a duplicate assignment shares
+      its binary key too, those stay where they are.
+    */
+    async fn clean_up_failed_insert(
+        &self,
+        conn: &mut PgConnection,
+        message: &Message,
+        deep_hash: Option<&String>,
+        error: &StoreErrorType,
+    ) -> Result<(), StoreErrorType> {
+        let bytestore = self.bytestore.clone();
+        if !bytestore.is_ready() {
+            return Ok(());
+        }
+
+        let (delete_binary, deep_hash) = match error {
+            StoreErrorType::MessageExists(_) => {
+                (!assignment_saved(conn, &message.assignment_id()?), None)
+            }
+            _ => (true, deep_hash),
+        };
+
+        if delete_binary {
+            bytestore
+                .delete_binary(
+                    message.message_id()?,
+                    Some(message.assignment_id()?),
+                    message.process_id()?,
+                    message.timestamp()?.to_string(),
+                )
+                .await?;
+        }
+        if let Some(dh) = deep_hash {
+            bytestore
+                .delete_deep_hash(&message.process_id()?, dh)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /*
+      The body of save_message, it must only be called
+      while holding the process lock for the message.
+    */
+    async fn save_message_locked(
+        &self,
+        message: &Message,
+        bundle_in: &[u8],
+        deep_hash: Option<&String>,
+    ) -> Result<String, StoreErrorType> {
+        let conn = &mut self.get_conn()?;
+
+        self.check_nonce_ahead(message, conn)?;
+        self.save_message_binary(message, bundle_in, deep_hash)
+            .await?;
+
+        match self.insert_message(conn, message, bundle_in) {
+            Ok(()) => Ok("saved".to_string()),
+            Err(e) => {
+                self.clean_up_failed_insert(conn, message, deep_hash, &e)
+                    .await?;
+                Err(e)
+            }
+        }
+    }
+
+    /*
+      The body of atomic_save_process_and_message, the
+      bytestore writes go first like in save_message and
+      are removed again if the transaction doesn't commit,
+      so a retried attempt writes them again.
+    */
+    async fn save_process_and_message_locked(
+        &self,
+        process: &Process,
+        process_bundle: &[u8],
+        message: &Message,
+        message_bundle: &[u8],
+        deep_hash: Option<&String>,
+    ) -> Result<(), StoreErrorType> {
+        let conn = &mut self.get_conn()?;
+
+        self.check_nonce_ahead(message, conn)?;
+        self.save_message_binary(message, message_bundle, deep_hash)
+            .await?;
+
+        let res = conn.transaction::<_, StoreErrorType, _>(|conn| {
+            self.insert_process(conn, process, process_bundle)?;
+            self.insert_message(conn, message, message_bundle)
+        });
+
+        if let Err(e) = res {
+            self.clean_up_failed_insert(conn, message, deep_hash, &e)
+                .await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/*
+  The DataStore trait is what the business logic uses
+  to interact with the data storage layer. The implementations
+  can change here but the function definitions cannot unless
+  the business logic needs them to.
+*/
+#[async_trait]
+impl DataStore for StoreClient {
+    async fn save_process(
+        &self,
+        process: &Process,
+        bundle_in: &[u8],
+    ) -> Result<String, StoreErrorType> {
+        self.ensure_writable()?;
+        if self.verify_bundles {
+            verify_process_bundle(bundle_in, &process.process.process_id)?;
+        }
+        self.with_write_retries("save_process", move || async move {
+            let conn = &mut self.get_conn()?;
+            self.insert_process(conn, process, bundle_in)?;
+            Ok("saved".to_string())
+        })
+        .await
+    }
+
+    async fn get_process(&self, process_id_in: &str) -> Result<Process, StoreErrorType> {
+        if let Some(cached_process) = self
+            .in_memory_cache
+            .get_process(process_id_in.to_string())
+            .await
+        {
+            return Ok(cached_process);
+        }
+
+        let conn = &mut self.get_read_conn()?;
+        self.load_process(conn, process_id_in).await
+    }
+
+    async fn get_process_uncached(&self, process_id_in: &str) -> Result<Process, StoreErrorType> {
+        let conn = &mut self.get_latest_conn()?;
+        self.load_process(conn, process_id_in).await
+    }
+
+    async fn check_process_exists(&self, process_id_in: &str) -> Result<bool, StoreErrorType> {
+        if self
+            .in_memory_cache
+            .get_process(process_id_in.to_string())
+            .await
+            .is_some()
+        {
+            return Ok(true);
+        }
+
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let found = processes
+            .filter(process_id.eq(process_id_in))
+            .select(row_id)
+            .first::<i32>(conn)
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+
+    /*
+      Page through the processes created in an epoch,
+      pass the returned row id back in as after_row_id
+      to get the next page. The cursor is None on the
+      last page.
+    */
+    async fn get_processes_by_epoch(
+        &self,
+        epoch_in: i32,
+        limit: i32,
+        after_row_id: Option<i32>,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let rows = processes
+            .filter(epoch.eq(epoch_in))
+            .filter(row_id.gt(after_row_id.unwrap_or(0)))
+            .order(row_id.asc())
+            .limit(limit.into())
+            .select((row_id, process_data))
+            .load(conn)?;
+
+        process_page(rows, limit)
+    }
+
+    async fn get_processes_by_module(
+        &self,
+        module_id_in: &str,
+        cursor: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let rows = processes
+            .filter(module_id.eq(module_id_in))
+            .filter(row_id.gt(cursor.unwrap_or(0)))
+            .order(row_id.asc())
+            .limit(limit.into())
+            .select((row_id, process_data))
+            .load(conn)?;
+
+        process_page(rows, limit)
+    }
+
+    async fn get_processes(
+        &self,
+        after_row_id: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let rows = processes
+            .filter(row_id.gt(after_row_id.unwrap_or(0)))
+            .order(row_id.asc())
+            .limit(limit.into())
+            .select((row_id, process_data))
+            .load(conn)?;
+
+        process_page(rows, limit)
+    }
+
+    async fn get_processes_by_scheduler(
+        &self,
+        scheduler_row_id_in: i32,
+        after_row_id: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        use super::schema::{process_schedulers, processes};
+        let conn = &mut self.get_read_conn()?;
+
+        let rows = process_schedulers::table
+            .inner_join(
+                processes::table.on(processes::process_id.eq(process_schedulers::process_id)),
+            )
+            .filter(process_schedulers::scheduler_row_id.eq(scheduler_row_id_in))
+            .filter(process_schedulers::row_id.gt(after_row_id.unwrap_or(0)))
+            .order(process_schedulers::row_id.asc())
+            .limit(limit.into())
+            .select((process_schedulers::row_id, processes::process_data))
+            .load(conn)?;
+
+        process_page(rows, limit)
+    }
+
+    async fn get_process_bundle(&self, process_id_in: &str) -> Result<Vec<u8>, StoreErrorType> {
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        processes
+            .filter(process_id.eq(process_id_in))
+            .select(bundle)
+            .first(conn)
+            .optional()?
+            .ok_or_else(|| StoreErrorType::not_found("process", process_id_in))
+    }
+
+    /*
+      Only the two columns that are needed, and the
+      cache is neither read nor filled since it would
+      only have the process.
+    */
+    async fn get_process_with_bundle(
+        &self,
+        process_id_in: &str,
+    ) -> Result<(Process, Vec<u8>), StoreErrorType> {
+        use super::schema::processes::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let (data, process_bundle): (serde_json::Value, Vec<u8>) = processes
+            .filter(process_id.eq(process_id_in))
+            .select((process_data, bundle))
+            .first(conn)
+            .optional()?
+            .ok_or_else(|| StoreErrorType::not_found("process", process_id_in))?;
+
+        Ok((Process::from_val(&data)?, process_bundle))
+    }
+
+    /*
+        If we are trying to write an actual data item
+        not just an assignment we need to check that it
+        doesnt already exist.
+    */
+    async fn check_existing_message(&self, message_id: &String) -> Result<(), StoreErrorType> {
+        match self.get_message(&message_id).await {
+            Ok(parsed) => {
+                /*
+                    If the message already exists and it contains
+                    an actual message (it is not just an assignment)
+                    then throw an error to avoid duplicate data items
+                    being written
+                */
+                match parsed.message {
+                    Some(_) => Err(StoreErrorType::MessageExists(
+                        "Message already exists".to_string(),
+                    )),
+                    /*
+                      this is an assignment so its ok, although currently
+                      this method is not used to check the assingment ids
+                      this is still here in case someone calls it with
+                      the assignment id in the future
+                    */
+                    None => Ok(()),
+                }
+            }
+            // The message wasnt found at all so it can be written
+            Err(StoreErrorType::NotFound { .. }) => Ok(()),
+            // Some other error happened
+            Err(_) => Err(StoreErrorType::database(
+                "Error checking message".to_string(),
+            )),
+        }
+    }
+
+    async fn check_existing_deep_hash(
+        &self,
+        process_id: &String,
+        deep_hash: &String,
+    ) -> Result<(), StoreErrorType> {
+        let existing = self
+            .check_existing_deep_hashes(process_id, std::slice::from_ref(deep_hash))
+            .await?;
+        match existing.is_empty() {
+            true => Ok(()),
+            false => Err(StoreErrorType::MessageExists(
+                "Deep hash already exists".to_string(),
+            )),
+        }
+    }
+
+    /*
+      Deep hashes are only kept in the bytestore, there
+      is no postgres table to fall back to, so without
+      it nothing counts as existing.
+    */
+    async fn check_existing_deep_hashes(
+        &self,
+        process_id: &String,
+        deep_hashes: &[String],
+    ) -> Result<Vec<String>, StoreErrorType> {
+        if !self.bytestore.is_ready() {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .bytestore
+            .existing_deep_hashes(process_id, deep_hashes)
+            .await)
+    }
+
+    async fn get_deephashes(&self, process_id: &str) -> Result<Vec<String>, StoreErrorType> {
+        if !self.bytestore.is_ready() {
+            return Err(StoreErrorType::Unavailable(
+                "Deep hashes are kept in the bytestore, which isn't ready".to_string(),
+            ));
+        }
+        Ok(self.bytestore.get_deep_hashes(process_id).await?)
+    }
+
+    async fn get_deephash_version(&self, process_id: &String) -> Result<String, StoreErrorType> {
+        if self.bytestore.is_ready() {
+            if let Ok(dhv) = self.bytestore.get_deep_hash_version(process_id).await {
+                return Ok(dhv);
+            }
+        }
+        Err(StoreErrorType::database(
+            "Deep hash version does not exist for this process".to_string(),
+        ))
+    }
+
+    async fn save_deephash_version(
+        &self,
+        process_id: &String,
+        version: &String,
+    ) -> Result<(), StoreErrorType> {
+        self.ensure_writable()?;
+        if self.bytestore.is_ready() {
+            self.bytestore.save_deep_hash_version(process_id, version).await?;
+        }
+        Ok(())
+    }
+
+    async fn save_deephash(
+        &self,
+        process_id: &String,
+        deep_hash: &String,
+    ) -> Result<(), StoreErrorType> {
+        self.ensure_writable()?;
+        if self.bytestore.is_ready() {
+            self.bytestore.save_deep_hash(process_id, deep_hash).await?;
+        }
+        Ok(())
+    }
+
+    async fn save_message(
+        &self,
+        message: &Message,
+        bundle_in: &[u8],
+        deep_hash: Option<&String>,
+    ) -> Result<String, StoreErrorType> {
+        self.ensure_writable()?;
+        let process_id_in = message.process_id()?;
+        self.ensure_not_frozen(&process_id_in)?;
+        if self.verify_bundles {
+            verify_message(message, bundle_in)?;
+        }
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.acquire(&process_id_in)?;
+        }
+        let process_id = process_id_in.as_str();
+        let result = match &self.batch_writer {
+            /*
+              The process lock is only held while the nonce
+              is checked and the message queued, the queued
+              nonce is reserved until it is written. So
+              saves for one process can share a batch.
+            */
+            Some(writer) => {
+                self.with_write_retries("save_message", move || async move {
+                    let queued = self
+                        .with_process_lock(process_id, async {
+                            self.check_nonce_ahead(message, &mut self.get_conn()?)?;
+                            let pending =
+                                batch_writer::PendingMessage::new(message, bundle_in, deep_hash)?;
+                            writer.enqueue(pending).await
+                        })
+                        .await?;
+                    queued.result().await
+                })
+                .await
+            }
+            None => {
+                self.with_process_lock(
+                    process_id,
+                    self.with_write_retries("save_message", move || async move {
+                        self.save_message_locked(message, bundle_in, deep_hash).await
+                    }),
+                )
+                .await
+            }
+        };
+
+        if result.is_ok() {
+            self.message_throughput.record().await;
+            self.in_memory_cache
+                .increment_message_count(process_id_in)
+                .await;
+        }
+        result
+    }
+
+    /*
+      Bypasses the batch writer, the process lock is
+      held for the whole write either way.
+    */
+    async fn atomic_save_process_and_message(
+        &self,
+        process: &Process,
+        process_bundle: &[u8],
+        message: &Message,
+        message_bundle: &[u8],
+        deep_hash: Option<&String>,
+    ) -> Result<(), StoreErrorType> {
+        self.ensure_writable()?;
+        let process_id_in = message.process_id()?;
+        self.ensure_not_frozen(&process_id_in)?;
+        if self.verify_bundles {
+            verify_process_bundle(process_bundle, &process.process.process_id)?;
+            verify_message(message, message_bundle)?;
+        }
+        self.with_process_lock(
+            &process_id_in,
+            self.with_write_retries("atomic_save_process_and_message", move || async move {
+                self.save_process_and_message_locked(
+                    process,
+                    process_bundle,
+                    message,
+                    message_bundle,
+                    deep_hash,
+                )
+                .await
+            }),
+        )
+        .await?;
+
+        self.in_memory_cache
+            .increment_message_count(process_id_in)
+            .await;
+        Ok(())
+    }
+
+    async fn shutdown(&self) {
+        self.bytestore_sync.stop().await;
+        if let Some(writer) = &self.batch_writer {
+            writer.shutdown().await;
+        }
+
+        if let Err(e) = self.drain_write_pool(self.shutdown_drain_timeout).await {
+            self.logger
+                .error(format!("Shutting down with writes in flight: {}", e));
+        }
+
+        /*
+          With BYTESTORE_WAL_SYNC_MODE=disable the memtables
+          hold the only copy of recent writes.
+        */
+        if self.pool.is_some() && self.bytestore.is_ready() {
+            if let Err(e) = self.bytestore.flush().await {
+                self.logger
+                    .error(format!("Failed to flush the bytestore: {}", e));
+            }
+        }
+    }
+
+    fn freeze_process(&self, process_id: &str) {
+        self.frozen_processes.insert(process_id.to_string());
+    }
+
+    fn unfreeze_process(&self, process_id: &str) {
+        self.frozen_processes.remove(process_id);
+    }
+
+    fn is_process_frozen(&self, process_id: &str) -> bool {
+        self.frozen_processes.contains(process_id)
+    }
+
+    fn keeps_deep_hashes(&self) -> bool {
+        self.bytestore.is_ready()
+    }
+
+    fn bytestore_counters(&self) -> Option<ByteStoreCounters> {
+        Some(self.bytestore.counters())
+    }
+
+    async fn bytestore_statistics(&self) -> Result<ByteStoreStatistics, StoreErrorType> {
+        Ok(self.bytestore.get_statistics()?)
+    }
+
+    fn bytestore_last_sync(&self) -> Option<ByteStoreSyncRun> {
+        self.bytestore_sync.last_run()
+    }
+
+    async fn create_bytestore_checkpoint(
+        &self,
+        path: &str,
+    ) -> Result<ByteStoreCheckpoint, StoreErrorType> {
+        Ok(self.bytestore.create_checkpoint(path).await?)
+    }
+
+    /*
+      A compaction can run for a long time and blocks
+      while it does, so it is moved off the runtime.
+    */
+    async fn compact_bytestore(
+        &self,
+        prefix: Option<String>,
+    ) -> Result<ByteStoreCompaction, StoreErrorType> {
+        self.ensure_writable()?;
+        if !self.bytestore.is_ready() {
+            return Err(StoreErrorType::database(
+                "Bytestore is not ready".to_string(),
+            ));
+        }
+        if self.bytestore.is_bulk_writing() {
+            return Err(StoreErrorType::Conflict(
+                "A bytestore sync or migration is running, try again once it is done".to_string(),
+            ));
+        }
+
+        let bytestore = self.bytestore.clone();
+        let compaction =
+            tokio::task::spawn_blocking(move || bytestore.compact_range(prefix.as_deref()))
+                .await
+                .map_err(|e| {
+                    StoreErrorType::database(format!("Bytestore compaction failed: {:?}", e))
+                })??;
+        Ok(compaction)
+    }
+
+    fn sync_bytestore_after(&self, after_row_id: i32) -> Result<ByteStoreSyncRun, StoreErrorType> {
+        self.ensure_writable()?;
+        if !self.bytestore.is_ready() {
+            return Err(StoreErrorType::database(
+                "Bytestore is not ready".to_string(),
+            ));
+        }
+        self.run_sync_pass("manual", |run| self.sync_gap(after_row_id, run))
+            .ok_or_else(|| {
+                StoreErrorType::Conflict("A bytestore sync is already running".to_string())
+            })
+    }
+
+    async fn invalidate_process_cache(&self, process_id_in: &str) -> Result<(), StoreErrorType> {
+        self.in_memory_cache
+            .remove_process(process_id_in.to_string())
+            .await;
+        self.in_memory_cache
+            .remove_message_count(process_id_in.to_string())
+            .await;
+
+        if self.cache_invalidation {
+            let conn = &mut self.get_conn()?;
+            cache_invalidation::notify(conn, process_id_in)?;
+        }
+        Ok(())
+    }
+
+    async fn resize_process_cache(&self, new_size: usize) -> Result<(), StoreErrorType> {
+        let size = NonZeroUsize::new(new_size)
+            .ok_or_else(|| StoreErrorType::invalid_input("size", "must be more than 0"))?;
+        self.in_memory_cache.resize_processes(size).await;
+        Ok(())
+    }
+
+    /*
+      Reads the process's messages a page at a time by
+      row id, the bytestore copies are read back in the
+      same pages so it stays under MAX_READ_MEMORY.
+    */
+    async fn check_process_integrity(
+        &self,
+        process_id_in: &str,
+    ) -> Result<ProcessIntegrityReport, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+
+        const INTEGRITY_PAGE_SIZE: i64 = 100;
+
+        let mut report = ProcessIntegrityReport {
+            process_id: process_id_in.to_string(),
+            ..Default::default()
+        };
+        let check_bytestore = self.bytestore.is_ready();
+        let conn = &mut self.get_read_conn()?;
+
+        let mut after = 0;
+        loop {
+            let rows: Vec<(i32, String, Option<String>, i64, Vec<u8>, Option<String>)> = messages
+                .filter(process_id.eq(process_id_in))
+                .filter(deleted_at.is_null())
+                .filter(row_id.gt(after))
+                .order(row_id.asc())
+                .limit(INTEGRITY_PAGE_SIZE)
+                .select((
+                    row_id,
+                    message_id,
+                    assignment_id,
+                    timestamp,
+                    bundle,
+                    bundle_checksum,
+                ))
+                .load(conn)?;
+            let last = match rows.last() {
+                Some(row) => row.0,
+                None => break,
+            };
+
+            let mut keys = Vec::with_capacity(rows.len());
+            let mut checksums = Vec::with_capacity(rows.len());
+            for (_, msg_id, assign_id, ts, binary, checksum) in rows.iter() {
+                report.checked += 1;
+                if !bytestore::checksum_matches(binary, checksum) {
+                    report.corrupt_bundles.push(msg_id.clone());
+                } else if verify_message_bundle(binary, msg_id, assign_id.as_deref()).is_err() {
+                    report.mismatched_bundles.push(msg_id.clone());
+                }
+                keys.push((
+                    msg_id.clone(),
+                    assign_id.clone(),
+                    process_id_in.to_string(),
+                    ts.to_string(),
+                ));
+                checksums.push(bytestore::bundle_checksum(binary));
+            }
+
+            if check_bytestore {
+                let binaries = self
+                    .bytestore
+                    .read_binaries(keys.clone())
+                    .await
+                    .map_err(StoreErrorType::database)?;
+                for (key, checksum) in keys.iter().zip(checksums) {
+                    match binaries.get(key) {
+                        Some(value) if bytestore::bundle_checksum(value.value()) == checksum => (),
+                        Some(_) => report.bytestore_mismatches.push(key.0.clone()),
+                        None => report.missing_from_bytestore.push(key.0.clone()),
+                    }
+                }
+            }
+
+            after = last;
+        }
+
+        Ok(report)
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<String>, StoreErrorType> {
+        let conn = &mut self.get_read_conn()?;
+        match conn.applied_migrations() {
+            Ok(versions) => Ok(versions.iter().map(|v| v.to_string()).collect()),
+            Err(e) => Err(StoreErrorType::database(format!(
+                "Error reading the applied migrations: {}",
+                e
+            ))),
+        }
+    }
+
+    async fn cache_stats(&self) -> Vec<CacheStats> {
+        self.in_memory_cache.stats().await
+    }
+
+    async fn message_throughput(&self) -> Option<f64> {
+        Some(self.message_throughput_per_second().await)
+    }
+
+    fn circuit_breakers(&self) -> Vec<CircuitBreakerStats> {
+        match self.pool {
+            Some(_) => vec![self.write_breaker.stats(), self.read_breaker.stats()],
+            None => vec![self.read_breaker.stats()],
+        }
+    }
+
+    async fn health_check(&self) -> HealthStatus {
+        let mut error_messages = vec![];
+
+        let db_write_ok = match self.pool {
+            Some(_) => match self.ping_write_pool() {
+                Ok(_) => true,
+                Err(e) => {
+                    error_messages.push(format!("write pool: {:?}", e));
+                    false
+                }
+            },
+            None => true,
+        };
+
+        let db_read_ok = match self.ping_read_pool() {
+            Ok(_) => true,
+            Err(e) => {
+                error_messages.push(format!("read pool: {:?}", e));
+                false
+            }
+        };
+
+        let bytestore_degraded = self.bytestore.is_degraded();
+        let bytestore_ok =
+            !self.bytestore.is_enabled() || self.bytestore.is_ready() || bytestore_degraded;
+        if !bytestore_ok {
+            error_messages.push("bytestore: not connected".to_string());
+        }
+
+        let disk_usage_percent = match self.bytestore.disk_usage() {
+            Some((usage, level)) => {
+                match level {
+                    bytestore::DiskLevel::Hard => error_messages
+                        .push(format!("bytestore: degraded, disk is {:.1}% full", usage)),
+                    bytestore::DiskLevel::Soft => {
+                        error_messages.push(format!("bytestore: disk is {:.1}% full", usage))
+                    }
+                    bytestore::DiskLevel::Ok => (),
+                }
+                Some(usage)
+            }
+            None => None,
+        };
+
+        let (hits, misses) = self
+            .cache_stats()
+            .await
+            .iter()
+            .fold((0, 0), |(h, m), c| (h + c.hits, m + c.misses));
+        let cache_hit_rate = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+
+        let (write_pool_idle, read_pool_idle) = self.pool_stats();
+
+        HealthStatus {
+            db_write_ok,
+            db_read_ok,
+            bytestore_ok,
+            cache_hit_rate,
+            write_pool_idle,
+            read_pool_idle,
+            bytestore_degraded,
+            disk_usage_percent,
+            error_messages,
+        }
+    }
+
+    fn ping(&self) -> bool {
+        StoreClient::ping(self)
+    }
+
+    async fn get_messages(
+        &self,
+        process_in: &Process,
+        filter: &MessageFilter,
+    ) -> Result<PaginatedMessages, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let from = &filter.from;
+        let to = &filter.to;
+        let limit = &filter.limit;
+        let from_nonce = &filter.from_nonce;
+        let to_nonce = &filter.to_nonce;
+        let started = Instant::now();
+
+        let conn = &mut self.get_read_conn()?;
+        let mut query = messages
+            .filter(process_id.eq(process_in.process.process_id.clone()))
+            .into_boxed();
+
+        /*
+          Soft deleted messages are hidden unless an
+          audit query explicitly asks for them.
+        */
+        if !filter.include_deleted {
+            query = query.filter(deleted_at.is_null());
+        }
+
+        if let Some(epoch_in) = filter.epoch {
+            query = query.filter(epoch.eq(epoch_in));
+        }
+
+        let mut sequence_mode = "timestamp";
+
+        match (from_nonce, to_nonce) {
+            (None, None) => {
+                if let Some(from_timestamp_str) = from {
+                    let from_timestamp = from_timestamp_str
+                        .parse::<i64>()
+                        .map_err(StoreErrorType::from)?;
+                    query = if filter.inclusive {
+                        query.filter(timestamp.ge(from_timestamp))
+                    } else {
+                        query.filter(timestamp.gt(from_timestamp))
+                    };
+                }
+
+                if let Some(to_timestamp_str) = to {
+                    let to_timestamp = to_timestamp_str
+                        .parse::<i64>()
+                        .map_err(StoreErrorType::from)?;
+                    query = query.filter(timestamp.le(to_timestamp));
+                }
+            }
+            (_, _) => {
+                sequence_mode = "nonce";
+
+                if let Some(from_nonce_s) = from_nonce {
+                    let f = from_nonce_s.parse::<i32>().map_err(StoreErrorType::from)?;
+                    query = if filter.inclusive {
+                        query.filter(nonce.ge(f))
+                    } else {
+                        query.filter(nonce.gt(f))
+                    };
+                }
+
+                if let Some(to_nonce_s) = to_nonce {
+                    let t = to_nonce_s.parse::<i32>().map_err(StoreErrorType::from)?;
+                    query = query.filter(nonce.le(t));
+                }
+            }
+        }
+
+        // Apply limit, converting Option<i32> to i64 and adding 1 to check for the next page
+        let limit_val = limit.unwrap_or(100) as i64; // Default limit if none is provided
+
+        let include_process =
+            process_in.assignment.is_some() && filter.starts_at_process(process_in)?;
+
+        // If including the process, reduce the limit for the database query by 1
+        let adjusted_limit_val = if include_process {
+            limit_val - 1
+        } else {
+            limit_val
+        };
+
+        /*
+          nonce orders messages saved in the same
+          millisecond. A from timestamp can still skip the
+          rest of a millisecond split across two pages,
+          get_messages_after_rowid doesn't have that problem.
+        */
+
+        if self.bytestore.clone().is_ready() {
+            let db_messages_result: Result<Vec<DbMessageWithoutData>, DieselError> = query
+                .select((
+                    row_id,
+                    process_id,
+                    message_id,
+                    assignment_id,
+                    epoch,
+                    nonce,
+                    timestamp,
+                    hash_chain,
+                ))
+                .order((timestamp.asc(), nonce.asc()))
+                .limit(adjusted_limit_val + 1) // Fetch one extra record to determine if a next page exists
+                .load(conn);
+            let query_ms = elapsed_ms(started);
+
+            match db_messages_result {
+                Ok(db_messages) => {
+                    let has_next_page = db_messages.len() as i64 > adjusted_limit_val;
+
+                    // Take only up to the limit if there's an extra indicating a next page
+                    let messages_o = if has_next_page {
+                        &db_messages[..(adjusted_limit_val as usize)]
+                    } else {
+                        &db_messages[..]
+                    };
+
+                    let mut messages_mapped: Vec<Message> = vec![];
+
+                    // Include the process as the first message if determined to be on the first page and has assignment
+                    if include_process {
+                        let process_message = Message::from_process(process_in.clone())?;
+                        messages_mapped.push(process_message);
+                    }
+
+                    // Map database messages to the Message struct
+                    let message_ids: Vec<(String, Option<String>, String, String)> = messages_o
+                        .iter()
+                        .map(|msg| {
+                            (
+                                msg.message_id.clone(),
+                                msg.assignment_id.clone(),
+                                msg.process_id.clone(),
+                                msg.timestamp.to_string().clone(),
+                            )
+                        })
+                        .collect();
+
+                    let read_started = Instant::now();
+                    let binaries = self.bytestore.clone().read_binaries(message_ids).await?;
+                    let read_binaries_ms = elapsed_ms(read_started);
+                    let bytestore_hits = binaries.len();
+
+                    let mut fallback_ms = None;
+                    let fill_started = Instant::now();
+                    let page = fill_page(
+                        messages_o,
+                        &binaries,
+                        |msg| {
+                            (
+                                msg.message_id.clone(),
+                                msg.assignment_id.clone(),
+                                msg.process_id.clone(),
+                                msg.timestamp.to_string(),
+                            )
+                        },
+                        |missing| {
+                            let fallback_started = Instant::now();
+                            let loaded = self.load_fallback_messages(conn, missing);
+                            fallback_ms = Some(elapsed_ms(fallback_started));
+                            loaded
+                        },
+                        |_, bytes| Ok(Message::from_bytes(bytes.clone())?),
+                        |_, db_message| self.db_message_to_message(db_message),
+                    )?;
+                    let fill_ms = elapsed_ms(fill_started);
+                    messages_mapped.extend(page);
+
+                    // Create paginated result
+                    let mut paginated = PaginatedMessages::from_messages(
+                        messages_mapped,
+                        has_next_page,
+                        sequence_mode,
+                    )?;
+                    paginated.timing = filter.timing.then(|| ReadTiming {
+                        query_ms,
+                        read_binaries_ms,
+                        fallback_queries: usize::from(fallback_ms.is_some()),
+                        fallback_ms: fallback_ms.unwrap_or(0.0),
+                        deserialize_ms: fill_ms - fallback_ms.unwrap_or(0.0),
+                        total_ms: elapsed_ms(started),
+                        bytestore_hits,
+                        bytestore_misses: messages_o.len() - bytestore_hits,
+                    });
+                    Ok(paginated)
+                }
+                Err(e) => Err(StoreErrorType::from(e)),
+            }
+        } else {
+            let db_messages_result: Result<Vec<DbMessage>, DieselError> = query
+                .order((timestamp.asc(), nonce.asc()))
+                .limit(adjusted_limit_val + 1) // Fetch one extra record to determine if a next page exists
+                .load(conn);
+            let query_ms = elapsed_ms(started);
+
+            match db_messages_result {
+                Ok(db_messages) => {
+                    let has_next_page = db_messages.len() as i64 > adjusted_limit_val;
+
+                    // Take only up to the limit if there's an extra indicating a next page
+                    let messages_o = if has_next_page {
+                        &db_messages[..(adjusted_limit_val as usize)]
+                    } else {
+                        &db_messages[..]
+                    };
+
+                    let mut messages_mapped: Vec<Message> = vec![];
+
+                    // Include the process as the first message if determined to be on the first page and has assignment
+                    if include_process {
+                        let process_message = Message::from_process(process_in.clone())?;
+                        messages_mapped.push(process_message);
+                    }
+
+                    let deserialize_started = Instant::now();
+                    for db_message in messages_o.iter() {
+                        let json = serde_json::from_value(db_message.message_data.clone())?;
+                        let bytes: Vec<u8> = db_message.bundle.clone();
+                        let mapped = Message::from_val(&json, bytes)?;
+                        messages_mapped.push(mapped);
+                    }
+                    let deserialize_ms = elapsed_ms(deserialize_started);
+
+                    let mut paginated = PaginatedMessages::from_messages(
+                        messages_mapped,
+                        has_next_page,
+                        sequence_mode,
+                    )?;
+                    paginated.timing = filter.timing.then(|| ReadTiming {
+                        query_ms,
+                        deserialize_ms,
+                        total_ms: elapsed_ms(started),
+                        ..Default::default()
+                    });
+                    Ok(paginated)
+                }
+                Err(e) => Err(StoreErrorType::from(e)),
+            }
+        }
+    }
+
+    /*
+      This is a stripped down version of get_messages
+      used to fetch message bunldes for regenerating hash chains
+    */
+    async fn get_message_bundles(
+        &self,
+        process_in: &Process,
+        from: &Option<String>,
+        limit: &Option<i32>,
+    ) -> Result<(Vec<(String, Vec<u8>)>, bool), StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+        let mut query = messages
+            .filter(process_id.eq(process_in.process.process_id.clone()))
+            .into_boxed();
+
+        if let Some(from_timestamp_str) = from {
+            let from_timestamp = from_timestamp_str
+                .parse::<i64>()
+                .map_err(StoreErrorType::from)?;
+            query = query.filter(timestamp.gt(from_timestamp));
+        }
+
+        let limit_val = limit.unwrap_or(100) as i64;
+
+        if self.bytestore.clone().is_ready() {
+            let db_messages_result: Result<Vec<DbMessageWithoutData>, DieselError> = query
+                .select((
+                    row_id,
+                    process_id,
+                    message_id,
+                    assignment_id,
+                    epoch,
+                    nonce,
+                    timestamp,
+                    hash_chain,
+                ))
+                .order((timestamp.asc(), nonce.asc()))
+                .limit(limit_val + 1)
+                .load(conn);
+
+            match db_messages_result {
+                Ok(db_messages) => {
+                    let has_next_page = db_messages.len() as i64 > limit_val;
+
+                    let messages_o = if has_next_page {
+                        &db_messages[..(limit_val as usize)]
+                    } else {
+                        &db_messages[..]
+                    };
+
+                    let message_ids: Vec<(String, Option<String>, String, String)> = messages_o
+                        .iter()
+                        .map(|msg| {
+                            (
+                                msg.message_id.clone(),
+                                msg.assignment_id.clone(),
+                                msg.process_id.clone(),
+                                msg.timestamp.to_string().clone(),
+                            )
+                        })
+                        .collect();
+
+                    let binaries = self.bytestore.clone().read_binaries(message_ids).await?;
+
+                    /*
+                      Anything old enough that it doesnt have
+                      an assignemnt can be ignored
+                    */
+                    let message_bundles: Vec<(String, Vec<u8>)> = fill_page(
+                        messages_o,
+                        &binaries,
+                        |msg| {
+                            (
+                                msg.message_id.clone(),
+                                msg.assignment_id.clone(),
+                                msg.process_id.clone(),
+                                msg.timestamp.to_string(),
+                            )
+                        },
+                        |missing| self.load_fallback_messages(conn, missing),
+                        |msg, bytes| Ok(Some((msg.message_id.clone(), bytes.clone()))),
+                        |_, full_message| {
+                            Ok(full_message
+                                .assignment_id
+                                .clone()
+                                .map(|a| (a, full_message.bundle.clone())))
+                        },
+                    )?
+                    .into_iter()
+                    .flatten()
+                    .collect();
+
+                    return Ok((message_bundles, has_next_page));
+                }
+                Err(e) => return Err(StoreErrorType::from(e)),
+            }
+        }
+
+        Err(StoreErrorType::database(
+            "Bytestore not ready, cannot regenerate deep hashes".to_string(),
+        ))
+    }
+
+    async fn get_message(&self, tx_id: &str) -> Result<Message, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        /*
+            get the oldest match. in the case of a message that has
+            later assignments, it should be the original message itself.
+
+            message_id and assignment_id are looked up one at a time
+            so each query can use its own index, an OR across both
+            columns was planned as a sequential scan.
+        */
+        let db_message_result: Result<Option<DbMessage>, DieselError> = first_match(
+            conn,
+            |conn| {
+                messages
+                    .filter(message_id.eq(tx_id))
+                    .filter(deleted_at.is_null())
+                    .order(timestamp.asc())
+                    .first(conn)
+                    .optional()
+            },
+            |conn| {
+                messages
+                    .filter(assignment_id.eq(tx_id))
+                    .filter(deleted_at.is_null())
+                    .first(conn)
+                    .optional()
+            },
+        );
+
+        match db_message_result {
+            Ok(Some(db_message)) => {
+                self.verify_bundle(tx_id, &db_message.bundle, &db_message.bundle_checksum)?;
+                let message_val: serde_json::Value =
+                    serde_json::from_value(db_message.message_data.clone())?;
+                let message: Message = Message::from_val(&message_val, db_message.bundle.clone())?;
+                Ok(message)
+            }
+            Ok(None) => Err(StoreErrorType::not_found("message", tx_id)),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    /*
+      Only the epoch column is read, for callers that
+      don't need the bundle or message_data. The id can
+      be a message id or an assignment id.
+    */
+    async fn get_message_epoch(&self, tx_id: &str) -> Result<i32, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        messages
+            .filter(message_id.eq(tx_id).or(assignment_id.eq(tx_id)))
+            .filter(deleted_at.is_null())
+            .order(timestamp.asc())
+            .select(epoch)
+            .first::<i32>(conn)
+            .optional()?
+            .ok_or_else(|| StoreErrorType::not_found("message", tx_id))
+    }
+
+    /*
+      The exact stored bundle for a message. The row
+      is fetched without the bundle column first so the
+      bytestore key can be built, the bundle column is
+      only read if the bytestore doesn't have it.
+    */
+    async fn get_message_bundle(&self, tx_id: &str) -> Result<Vec<u8>, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let row: Option<(i32, String, Option<String>, String, i64)> = first_match(
+            conn,
+            |conn| {
+                messages
+                    .filter(message_id.eq(tx_id))
+                    .filter(deleted_at.is_null())
+                    .order(timestamp.asc())
+                    .select((row_id, message_id, assignment_id, process_id, timestamp))
+                    .first(conn)
+                    .optional()
+            },
+            |conn| {
+                messages
+                    .filter(assignment_id.eq(tx_id))
+                    .filter(deleted_at.is_null())
+                    .select((row_id, message_id, assignment_id, process_id, timestamp))
+                    .first(conn)
+                    .optional()
+            },
+        )?;
+
+        let (row, msg_id, assign_id, proc_id, ts) = match row {
+            Some(r) => r,
+            None => return Err(StoreErrorType::not_found("message", tx_id)),
+        };
+
+        if self.bytestore.is_ready() {
+            let key = (msg_id, assign_id, proc_id, ts.to_string());
+            if let Ok(binaries) = self.bytestore.read_binaries(vec![key.clone()]).await {
+                if let Some((_, binary)) = binaries.remove(&key) {
+                    return Ok(binary);
+                }
+            }
+        }
+
+        let (binary, checksum) = messages
+            .filter(row_id.eq(row))
+            .select((bundle, bundle_checksum))
+            .first::<(Vec<u8>, Option<String>)>(conn)?;
+        self.verify_bundle(tx_id, &binary, &checksum)?;
+
+        Ok(binary)
+    }
+
+    /*
+      Single message lookup that reads the bundle from
+      the bytestore when it has it, the same way
+      get_messages does, and falls back to the full
+      postgres row when it doesn't.
+    */
+    async fn get_message_with_fallback(
+        &self,
+        message_id_in: &str,
+        assignment_id_in: &Option<String>,
+    ) -> Result<Message, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let mut query = messages.filter(message_id.eq(message_id_in)).into_boxed();
+        if let Some(assignment_id_d) = assignment_id_in {
+            query = query.filter(assignment_id.eq(assignment_id_d));
+        }
+
+        let row: Option<(String, Option<String>, String, i64)> = query
+            .order(timestamp.asc())
+            .select((message_id, assignment_id, process_id, timestamp))
+            .first(conn)
+            .optional()?;
+
+        let (msg_id, assign_id, proc_id, ts) = match row {
+            Some(r) => r,
+            None => return Err(StoreErrorType::not_found("message", message_id_in)),
+        };
+
+        if self.bytestore.is_ready() {
+            let key = (msg_id.clone(), assign_id.clone(), proc_id, ts.to_string());
+            if let Ok(binaries) = self.bytestore.read_binaries(vec![key.clone()]).await {
+                if let Some((_, binary)) = binaries.remove(&key) {
+                    return Ok(Message::from_bytes(binary)?);
+                }
+            }
+        }
+
+        self.get_message_internal(&msg_id, &assign_id, conn)
+    }
+
+    /*
+      Mark a message as deleted without removing the row.
+      The bytestore entry is tombstoned rather than
+      deleted, reads of it fall back to the row which
+      still has the bundle, so the hash chain can still
+      be verified across it and get_latest_message still
+      sees it.
+    */
+    async fn soft_delete_message(&self, tx_id: &str) -> Result<(), StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::messages::dsl::*;
+
+        let row_count = {
+            let conn = &mut self.get_conn()?;
+            diesel::update(
+                messages
+                    .filter(message_id.eq(tx_id).or(assignment_id.eq(tx_id)))
+                    .filter(deleted_at.is_null()),
+            )
+            .set(deleted_at.eq(current_unix_ms()))
+            .execute(conn)?
+        };
+
+        if row_count == 0 {
+            return Err(StoreErrorType::not_found("message", tx_id));
+        }
+
+        /*
+          The row is already marked, a bytestore entry
+          that couldn't be tombstoned is only served
+          the way it was before.
+        */
+        if let Err(e) = self.tombstone_message_in_bytestore(tx_id).await {
+            self.logger.error(format!(
+                "Failed to tombstone bytestore entry for {}: {:?}",
+                tx_id, e
+            ));
+        }
+
+        Ok(())
+    }
+
+    /*
+      Overwrite the bytestore entries for every row of a
+      message with a tombstone, so reads treat them as
+      missing without the data being removed from disk.
+    */
+    async fn tombstone_message_in_bytestore(&self, tx_id: &str) -> Result<(), StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::messages::dsl::*;
+
+        let keys: Vec<(String, Option<String>, String, i64)> = {
+            let conn = &mut self.get_conn()?;
+            messages
+                .filter(message_id.eq(tx_id).or(assignment_id.eq(tx_id)))
+                .select((message_id, assignment_id, process_id, timestamp))
+                .load(conn)?
+        };
+
+        if keys.is_empty() {
+            return Err(StoreErrorType::not_found("message", tx_id));
+        }
+
+        if self.bytestore.is_ready() {
+            for (msg_id, assign_id, proc_id, ts) in keys {
+                self.bytestore
+                    .tombstone_binary(msg_id, assign_id, proc_id, ts.to_string())
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+      Move a process's messages older than the cutoff
+      into messages_archive. The bytestore copies are
+      left alone, they are cheap to keep around.
+    */
+    async fn archive_old_messages(
+        &self,
+        process_id_in: &str,
+        older_than_days: u32,
+    ) -> Result<u64, StoreErrorType> {
+        self.ensure_writable()?;
+        use diesel::sql_types::{BigInt, Text};
+        let conn = &mut self.get_conn()?;
+
+        let cutoff = current_unix_ms() - older_than_days as i64 * 86_400_000;
+
+        let archived = conn.transaction::<_, StoreErrorType, _>(|conn| {
+            diesel::sql_query(
+                "INSERT INTO messages_archive SELECT * FROM messages WHERE process_id = $1 AND timestamp < $2",
+            )
+            .bind::<Text, _>(process_id_in)
+            .bind::<BigInt, _>(cutoff)
+            .execute(conn)?;
+
+            let deleted = diesel::sql_query(
+                "DELETE FROM messages WHERE process_id = $1 AND timestamp < $2",
+            )
+            .bind::<Text, _>(process_id_in)
+            .bind::<BigInt, _>(cutoff)
+            .execute(conn)?;
+
+            Ok(deleted)
+        })?;
+
+        self.in_memory_cache
+            .remove_message_count(process_id_in.to_string())
+            .await;
+        Ok(archived as u64)
+    }
+
+    /*
+      A single pass over idx_messages_process_id_timestamp,
+      cached for TIMELINE_CACHE_TTL_SECS because the same
+      process is asked for over and over by dashboards.
+    */
+    async fn get_process_timeline(
+        &self,
+        process_id_in: &str,
+    ) -> Result<ProcessTimeline, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        use diesel::dsl::{count_star, max, min};
+
+        if let Some(timeline) = self
+            .in_memory_cache
+            .get_timeline(process_id_in.to_string())
+            .await
+        {
+            return Ok(timeline);
+        }
+
+        let conn = &mut self.get_read_conn()?;
+        let (min_timestamp, max_timestamp, message_count) = messages
+            .filter(process_id.eq(process_id_in))
+            .select((min(timestamp), max(timestamp), count_star()))
+            .first::<(Option<i64>, Option<i64>, i64)>(conn)?;
+
+        let timeline = ProcessTimeline {
+            min_timestamp,
+            max_timestamp,
+            message_count,
+        };
+        self.in_memory_cache
+            .insert_timeline(process_id_in.to_string(), timeline.clone())
+            .await;
+        Ok(timeline)
+    }
+
+    /*
+      Counted once from the read pool and then kept up
+      by every save on this su. Saves from another su,
+      replica lag and deletes other than a purge or an
+      archive make it drift, so it is counted again
+      after MESSAGE_COUNT_CACHE_TTL_SECS.
+    */
+    async fn get_cached_message_count(&self, process_id_in: &str) -> Result<i64, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+
+        if let Some(count) = self
+            .in_memory_cache
+            .get_message_count(process_id_in.to_string())
+            .await
+        {
+            return Ok(count);
+        }
+
+        let count = {
+            let conn = &mut self.get_read_conn()?;
+            messages
+                .filter(process_id.eq(process_id_in))
+                .count()
+                .get_result::<i64>(conn)?
+        };
+        self.in_memory_cache
+            .insert_message_count(process_id_in.to_string(), count)
+            .await;
+        Ok(count)
+    }
+
+    /*
+      COUNT(assignment_id) skips the NULLs, so both
+      counts come out of one pass over the process.
+    */
+    async fn get_assignment_count(
+        &self,
+        process_id_in: &str,
+    ) -> Result<(i64, i64), StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        use diesel::dsl::{count, count_star};
+        let conn = &mut self.get_read_conn()?;
+
+        let (total, assignments) = messages
+            .filter(process_id.eq(process_id_in))
+            .select((count_star(), count(assignment_id)))
+            .first::<(i64, i64)>(conn)?;
+
+        Ok((assignments, total - assignments))
+    }
+
+    /*
+      Backed by idx_messages_process_id_timestamp
+      from the messages_indexing migration.
+    */
+    async fn count_messages_since(
+        &self,
+        process_id_in: &str,
+        since_timestamp: i64,
+    ) -> Result<i64, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let count = messages
+            .filter(process_id.eq(process_id_in))
+            .filter(timestamp.gt(since_timestamp))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        Ok(count)
+    }
+
+    async fn get_messages_count_in_timestamp_range(
+        &self,
+        process_id_in: &str,
+        from: i64,
+        to: i64,
+    ) -> Result<i64, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let count = messages
+            .filter(process_id.eq(process_id_in))
+            .filter(timestamp.gt(from))
+            .filter(timestamp.le(to))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        Ok(count)
+    }
+
+    async fn get_latest_message(
+        &self,
+        process_id_in: &str,
+    ) -> Result<Option<Message>, StoreErrorType> {
+        self.logger.debug(format!(
+            "retreiving latest message for process - {}",
+            &process_id_in
+        ));
+        use super::schema::messages::dsl::*;
+        /*
+            This must use get_latest_conn because it needs
+            an up to date record from the writer instance
+            it cannot be behind at all as it is used
+            in the scheduling process. Read only replicas
+            don't schedule so they can use the reader.
+        */
+        self.warn_if_replica_lagging().await;
+        let conn = &mut self.get_latest_conn()?;
+
+        self.logger
+            .debug(format!("connection established - {}", &process_id_in));
+
+        /*
+          Two messages can be scheduled in the same
+          millisecond, so the timestamp alone doesn't say
+          which is the predecessor of the next one. The
+          timestamp only decides between legacy rows that
+          share an epoch and nonce.
+        */
+        let latest_db_message = messages
+            .filter(process_id.eq(process_id_in))
+            .order((epoch.desc(), nonce.desc(), timestamp.desc()))
+            .first::<DbMessage>(conn)
+            .optional()?;
+
+        self.logger.debug(format!(
+            "latest message query complete - {}",
+            &process_id_in
+        ));
+
+        match latest_db_message {
+            Some(db_message) => {
+                // Deserialize the message_data into Message
+                let message_val: serde_json::Value =
+                    serde_json::from_value(db_message.message_data)
+                        .map_err(|e| StoreErrorType::from(e))?;
+
+                let message: Message = Message::from_val(&message_val, db_message.bundle.clone())?;
+
+                Ok(Some(message))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /*
+      DISTINCT ON keeps the first row of each process in
+      the ORDER BY, which is its latest. Uses the latest
+      conn for the same reason get_latest_message does.
+    */
+    async fn get_latest_messages_for_processes(
+        &self,
+        process_ids: &[&str],
+    ) -> Result<HashMap<String, Message>, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+
+        if process_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let conn = &mut self.get_latest_conn()?;
+
+        let db_messages = messages
+            .filter(process_id.eq_any(process_ids.to_vec()))
+            .distinct_on(process_id)
+            .order((process_id, epoch.desc(), nonce.desc(), timestamp.desc()))
+            .load::<DbMessage>(conn)?;
+
+        let mut latest = HashMap::with_capacity(db_messages.len());
+        for db_message in db_messages {
+            let message = Message::from_val(&db_message.message_data, db_message.bundle)?;
+            latest.insert(db_message.process_id, message);
+        }
+        Ok(latest)
+    }
+
+    /*
+      The newest messages a wallet sent to a process,
+      backed by idx_messages_sender_id_process_id_timestamp.
+      sender_id is generated by postgres from the owner
+      of the message item so it is never written here.
+    */
+    async fn get_messages_by_sender(
+        &self,
+        sender_id_in: &str,
+        process_id_in: &str,
+        limit: i32,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let db_messages = messages
+            .filter(sender_id.eq(sender_id_in))
+            .filter(process_id.eq(process_id_in))
+            .filter(deleted_at.is_null())
+            .order(timestamp.desc())
+            .limit(limit.max(0) as i64)
+            .load::<DbMessage>(conn)?;
+
+        let mut sender_messages = Vec::with_capacity(db_messages.len());
+        for db_message in db_messages {
+            self.verify_bundle(
+                &db_message.message_id,
+                &db_message.bundle,
+                &db_message.bundle_checksum,
+            )?;
+            let message_val: serde_json::Value = serde_json::from_value(db_message.message_data)?;
+            sender_messages.push(Message::from_val(&message_val, db_message.bundle)?);
+        }
+
+        Ok(sender_messages)
+    }
+
+    /*
+      row_id is a serial so it only goes up in insert
+      order. Soft deleted messages are skipped, the
+      cursor still moves past them.
+    */
+    async fn get_messages_after_rowid(
+        &self,
+        process_id_in: &str,
+        after_row_id: i64,
+        limit: i32,
+    ) -> Result<(Vec<Message>, Option<i64>), StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let after = after_row_id.min(i32::MAX as i64) as i32;
+        let query = messages
+            .filter(process_id.eq(process_id_in))
+            .filter(row_id.gt(after))
+            .filter(deleted_at.is_null())
+            .order(row_id.asc())
+            .limit(limit.max(0) as i64)
+            .into_boxed();
+
+        if self.bytestore.is_ready() {
+            let rows: Vec<DbMessageWithoutData> = query
+                .select((
+                    row_id,
+                    process_id,
+                    message_id,
+                    assignment_id,
+                    epoch,
+                    nonce,
+                    timestamp,
+                    hash_chain,
+                ))
+                .load(conn)?;
+            let cursor = rows.last().map(|msg| msg.row_id as i64);
+
+            let message_ids: Vec<(String, Option<String>, String, String)> = rows
+                .iter()
+                .map(|msg| {
+                    (
+                        msg.message_id.clone(),
+                        msg.assignment_id.clone(),
+                        msg.process_id.clone(),
+                        msg.timestamp.to_string(),
+                    )
+                })
+                .collect();
+            let binaries = self.bytestore.clone().read_binaries(message_ids).await?;
+
+            let page = fill_page(
+                &rows,
+                &binaries,
+                |msg| {
+                    (
+                        msg.message_id.clone(),
+                        msg.assignment_id.clone(),
+                        msg.process_id.clone(),
+                        msg.timestamp.to_string(),
+                    )
+                },
+                |missing| self.load_fallback_messages(conn, missing),
+                |_, bytes| Ok(Message::from_bytes(bytes.clone())?),
+                |_, db_message| self.db_message_to_message(db_message),
+            )?;
+            Ok((page, cursor))
+        } else {
+            let rows: Vec<DbMessage> = query.load(conn)?;
+            let cursor = rows.last().map(|msg| msg.row_id as i64);
+
+            let mut page = Vec::with_capacity(rows.len());
+            for db_message in rows.iter() {
+                page.push(self.db_message_to_message(db_message)?);
+            }
+            Ok((page, cursor))
+        }
+    }
+
+    /*
+      The most recent hash chains for a process, newest
+      first. Like get_latest_message this reads from the
+      writer so it is never behind.
+    */
+    async fn get_hash_chain_tail(
+        &self,
+        process_id_in: &str,
+        depth: u32,
+    ) -> Result<Vec<String>, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_latest_conn()?;
+
+        let hash_chains = messages
+            .filter(process_id.eq(process_id_in))
+            .order(nonce.desc())
+            .limit(depth as i64)
+            .select(hash_chain)
+            .load::<String>(conn)?;
+
+        Ok(hash_chains)
+    }
+
+    /*
+      Backed by idx_messages_process_id_hash_chain, which
+      uses text_pattern_ops so LIKE with a fixed prefix
+      is an index range scan. Meant for audit tooling,
+      don't call it while handling requests. An empty
+      prefix would match every message so it is refused.
+    */
+    async fn get_messages_by_hash_chain_prefix(
+        &self,
+        process_id_in: &str,
+        hash_chain_prefix: &str,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        if hash_chain_prefix.is_empty() {
+            return Err(StoreErrorType::invalid_input(
+                "hash_chain_prefix",
+                "must not be empty",
+            ));
+        }
+        let conn = &mut self.get_read_conn()?;
+
+        let db_messages = messages
+            .filter(process_id.eq(process_id_in))
+            .filter(hash_chain.like(like_prefix(hash_chain_prefix)))
+            .filter(deleted_at.is_null())
+            .order(nonce.asc())
+            .limit(MAX_HASH_CHAIN_PREFIX_MATCHES)
+            .load::<DbMessage>(conn)?;
+
+        let mut matches = Vec::with_capacity(db_messages.len());
+        for db_message in db_messages.iter() {
+            matches.push(self.db_message_to_message(db_message)?);
+        }
+
+        Ok(matches)
+    }
+
+    async fn get_messages_for_epoch_nonce_range(
+        &self,
+        process_id_in: &str,
+        epoch_in: i32,
+        from_nonce: i32,
+        to_nonce: i32,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        use super::schema::messages::dsl::*;
+        if from_nonce > to_nonce {
+            return Err(StoreErrorType::invalid_input(
+                "from_nonce",
+                format!("{} is after to_nonce {}", from_nonce, to_nonce),
+            ));
+        }
+        let conn = &mut self.get_read_conn()?;
+
+        let db_messages = messages
+            .filter(process_id.eq(process_id_in))
+            .filter(epoch.eq(epoch_in))
+            .filter(nonce.between(from_nonce, to_nonce))
+            .filter(deleted_at.is_null())
+            .order(nonce.asc())
+            .load::<DbMessage>(conn)?;
+
+        let mut result = Vec::with_capacity(db_messages.len());
+        for db_message in db_messages.iter() {
+            result.push(self.db_message_to_message(db_message)?);
+        }
+
+        Ok(result)
+    }
+
+    /*
+      Write back a recomputed hash chain. Only the
+      column is changed, message_data and the bundle
+      keep the hash chain they were assigned with.
+    */
+    async fn update_message_hash_chain(
+        &self,
+        process_id_in: &str,
+        nonce_in: i32,
+        new_hash_chain: &str,
+    ) -> Result<(), StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_conn()?;
+
+        let row_count = diesel::update(
+            messages
+                .filter(process_id.eq(process_id_in))
+                .filter(nonce.eq(nonce_in)),
+        )
+        .set(hash_chain.eq(new_hash_chain))
+        .execute(conn)?;
+
+        if row_count == 0 {
+            return Err(StoreErrorType::not_found(
+                "message",
+                format!("{} nonce {}", process_id_in, nonce_in),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /*
+      Same as update_message_hash_chain for many
+      (process_id, nonce, hash_chain) at once, in one
+      transaction so a recomputation is applied fully
+      or not at all. Returns the rows updated.
+    */
+    async fn update_message_hash_chains_batch(
+        &self,
+        updates: &[(String, i32, String)],
+    ) -> Result<u64, StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::messages::dsl::*;
+        let conn = &mut self.get_conn()?;
+
+        let updated = conn.transaction::<_, StoreErrorType, _>(|conn| {
+            let mut updated = 0;
+            for (process_id_in, nonce_in, new_hash_chain) in updates {
+                updated += diesel::update(
+                    messages
+                        .filter(process_id.eq(process_id_in))
+                        .filter(nonce.eq(nonce_in)),
+                )
+                .set(hash_chain.eq(new_hash_chain))
+                .execute(conn)?;
+            }
+            Ok(updated)
+        })?;
+
+        Ok(updated as u64)
+    }
+
+    /*
+      Remove everything stored for a process. Each store
+      is cleaned on its own and a failure is recorded in
+      the report instead of stopping the purge, so a
+      rerun picks up whatever was left behind. Without
+      force we refuse to start unless the process exists
+      and the bytestore is reachable, otherwise a purge
+      could report success while its binaries are still
+      on disk.
+    */
+    async fn purge_process(
+        &self,
+        process_id_in: &str,
+        force: bool,
+    ) -> Result<PurgeReport, StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::{messages, process_schedulers, processes};
+        use diesel::sql_types::Text;
+
+        if !force {
+            self.get_process_uncached(process_id_in).await?;
+            if self.bytestore.is_enabled() && !self.bytestore.is_ready() {
+                return Err(StoreErrorType::database(
+                    "Bytestore is not ready, use force to purge anyway".to_string(),
+                ));
+            }
+        }
+
+        let mut report = PurgeReport {
+            process_id: process_id_in.to_string(),
+            ..Default::default()
+        };
+        let conn = &mut self.get_conn()?;
+
+        match diesel::delete(messages::table.filter(messages::process_id.eq(process_id_in)))
+            .execute(conn)
+        {
+            Ok(deleted) => report.messages_deleted = deleted as u64,
+            Err(e) => report.failures.push(format!("messages: {:?}", e)),
+        }
+
+        match diesel::sql_query("DELETE FROM messages_archive WHERE process_id = $1")
+            .bind::<Text, _>(process_id_in)
+            .execute(conn)
+        {
+            Ok(deleted) => report.archived_messages_deleted = deleted as u64,
+            Err(e) => report.failures.push(format!("messages_archive: {:?}", e)),
+        }
+
+        if self.bytestore.is_ready() {
+            match self.bytestore.prune_by_process(process_id_in).await {
+                Ok(deleted) => report.bytestore_keys_deleted = deleted,
+                Err(e) => report.failures.push(format!("bytestore: {}", e)),
+            }
+        } else if self.bytestore.is_enabled() {
+            report.failures.push("bytestore: not ready".to_string());
+        }
+
+        match diesel::delete(processes::table.filter(processes::process_id.eq(process_id_in)))
+            .execute(conn)
+        {
+            Ok(deleted) => report.process_deleted = deleted > 0,
+            Err(e) => report.failures.push(format!("processes: {:?}", e)),
+        }
+
+        /*
+          Give the process back to its scheduler's count,
+          the same way a new assignment takes one.
+        */
+        match diesel::delete(
+            process_schedulers::table.filter(process_schedulers::process_id.eq(process_id_in)),
+        )
+        .returning(process_schedulers::scheduler_row_id)
+        .get_results::<i32>(conn)
+        {
+            Ok(row_ids) => {
+                report.process_scheduler_deleted = !row_ids.is_empty();
+                for row_id in row_ids {
+                    if let Err(e) = self.increment_process_count(row_id, -1).await {
+                        report
+                            .failures
+                            .push(format!("schedulers process_count: {:?}", e));
+                    }
+                }
+            }
+            Err(e) => report.failures.push(format!("process_schedulers: {:?}", e)),
+        }
+
+        report.cache_evicted = self
+            .in_memory_cache
+            .remove_process(process_id_in.to_string())
+            .await;
+        self.in_memory_cache
+            .remove_message_count(process_id_in.to_string())
+            .await;
+
+        if self.cache_invalidation {
+            if let Err(e) = cache_invalidation::notify(conn, process_id_in) {
+                report.failures.push(format!("cache invalidation: {:?}", e));
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl RouterDataStore for StoreClient {
+    async fn save_process_scheduler(
+        &self,
+        process_scheduler: &ProcessScheduler,
+    ) -> Result<String, StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::process_schedulers::dsl::*;
+        let conn = &mut self.get_conn()?;
+
+        let new_process_scheduler = NewProcessScheduler {
+            process_id: &process_scheduler.process_id,
+            scheduler_row_id: &process_scheduler.scheduler_row_id,
+        };
+
+        match diesel::insert_into(process_schedulers)
+            .values(&new_process_scheduler)
+            .on_conflict(process_id)
+            .do_nothing()
+            .execute(conn)
+        {
+            Ok(_) => Ok("saved".to_string()),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    async fn get_process_scheduler(
+        &self,
+        process_id_in: &str,
+    ) -> Result<ProcessScheduler, StoreErrorType> {
+        use super::schema::process_schedulers::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let db_process_result: Result<Option<DbProcessScheduler>, DieselError> = process_schedulers
+            .filter(process_id.eq(process_id_in))
+            .first(conn)
+            .optional();
+
+        match db_process_result {
+            Ok(Some(db_process_scheduler)) => {
+                let process_scheduler: ProcessScheduler = ProcessScheduler {
+                    row_id: Some(db_process_scheduler.row_id),
+                    process_id: db_process_scheduler.process_id,
+                    scheduler_row_id: db_process_scheduler.scheduler_row_id,
+                };
+                Ok(process_scheduler)
+            }
+            Ok(None) => Err(StoreErrorType::not_found(
+                "process scheduler",
+                process_id_in,
+            )),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    async fn save_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::schedulers::dsl::*;
+        scheduler.wallets_to_route_list()?;
+        let conn = &mut self.get_conn()?;
+
+        let new_scheduler = NewScheduler {
+            url: &scheduler.url,
+            process_count: &scheduler.process_count,
+            no_route: scheduler.no_route.as_ref(),
+            wallets_to_route: scheduler.wallets_to_route.as_deref(),
+            wallets_only: scheduler.wallets_only.as_ref(),
+        };
+
+        match diesel::insert_into(schedulers)
+            .values(&new_scheduler)
+            .on_conflict(url)
+            .do_nothing()
+            .execute(conn)
+        {
+            Ok(_) => Ok("saved".to_string()),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    async fn update_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::schedulers::dsl::*;
+        let row_id_in = scheduler_row_id(scheduler)?;
+        scheduler.wallets_to_route_list()?;
+        self.with_write_retries("update_scheduler", move || async move {
+            let conn = &mut self.get_conn()?;
+
+            /*
+              process_count is left out on purpose, it is only
+              changed through increment_process_count and
+              recount_process_counts so a stale Scheduler
+              can't overwrite increments made in between.
+            */
+            let values = (
+                url.eq(&scheduler.url),
+                no_route.eq(&scheduler.no_route),
+                wallets_to_route.eq(&scheduler.wallets_to_route),
+                wallets_only.eq(&scheduler.wallets_only),
+                version.eq(version + 1),
+            );
+
+            /*
+              Compare and swap on the version the caller read,
+              zero rows updated then means someone else got
+              there first and the caller should re-read.
+            */
+            let update_result = match scheduler.version {
+                Some(version_in) => diesel::update(
+                    schedulers
+                        .filter(row_id.eq(row_id_in))
+                        .filter(version.eq(version_in)),
+                )
+                .set(values)
+                .execute(conn),
+                None => diesel::update(schedulers.filter(row_id.eq(row_id_in)))
+                    .set(values)
+                    .execute(conn),
+            };
+
+            match update_result {
+                Ok(row_count) => scheduler_update_result(row_id_in, row_count, scheduler.version),
+                Err(e) => Err(StoreErrorType::from(e)),
+            }
+        })
+        .await
+    }
+
+    async fn get_scheduler(&self, row_id_in: &i32) -> Result<Scheduler, StoreErrorType> {
+        use super::schema::schedulers::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let db_scheduler_result: Result<Option<DbScheduler>, DieselError> = schedulers
+            .filter(row_id.eq(row_id_in))
+            .first(conn)
+            .optional();
+
+        match db_scheduler_result {
+            Ok(Some(db_scheduler)) => {
+                let scheduler: Scheduler = Scheduler {
+                    row_id: Some(db_scheduler.row_id),
+                    url: db_scheduler.url,
+                    process_count: db_scheduler.process_count,
+                    no_route: db_scheduler.no_route,
+                    wallets_to_route: db_scheduler.wallets_to_route,
+                    wallets_only: db_scheduler.wallets_only,
+                    version: Some(db_scheduler.version),
+                    consecutive_failures: db_scheduler.consecutive_failures,
+                };
+                Ok(scheduler)
+            }
+            Ok(None) => Err(StoreErrorType::not_found("scheduler", row_id_in)),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    async fn get_scheduler_by_url(&self, url_in: &String) -> Result<Scheduler, StoreErrorType> {
+        use super::schema::schedulers::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let db_scheduler_result: Result<Option<DbScheduler>, DieselError> =
+            schedulers.filter(url.eq(url_in)).first(conn).optional();
+
+        match db_scheduler_result {
+            Ok(Some(db_scheduler)) => {
+                let scheduler: Scheduler = Scheduler {
+                    row_id: Some(db_scheduler.row_id),
+                    url: db_scheduler.url,
+                    process_count: db_scheduler.process_count,
+                    no_route: db_scheduler.no_route,
+                    wallets_to_route: db_scheduler.wallets_to_route,
+                    wallets_only: db_scheduler.wallets_only,
+                    version: Some(db_scheduler.version),
+                    consecutive_failures: db_scheduler.consecutive_failures,
+                };
+                Ok(scheduler)
+            }
+            Ok(None) => Err(StoreErrorType::not_found("scheduler", url_in)),
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    async fn get_all_schedulers(&self) -> Result<Vec<Scheduler>, StoreErrorType> {
+        use super::schema::schedulers::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        match schedulers.order(row_id.asc()).load::<DbScheduler>(conn) {
+            Ok(db_schedulers) => {
+                let schedulers_out: Vec<Scheduler> = db_schedulers
+                    .into_iter()
+                    .map(|db_scheduler| Scheduler {
+                        row_id: Some(db_scheduler.row_id),
+                        url: db_scheduler.url,
+                        process_count: db_scheduler.process_count,
+                        no_route: db_scheduler.no_route,
+                        wallets_to_route: db_scheduler.wallets_to_route,
+                        wallets_only: db_scheduler.wallets_only,
+                        version: Some(db_scheduler.version),
+                        consecutive_failures: db_scheduler.consecutive_failures,
+                    })
+                    .collect();
+                Ok(schedulers_out)
+            }
+            Err(e) => Err(StoreErrorType::from(e)),
+        }
+    }
+
+    /*
+      The least loaded wallets_only scheduler that routes
+      this wallet, None if there isn't one so the caller
+      can fall back to general routing.
+
+      wallets_to_route is a comma separated string so this
+      has to be a LIKE on it. The wallet is bound as a
+      parameter, not spliced into the sql, but LIKE treats
+      _ and % in it as wildcards and will match a wallet
+      that is part of a longer entry, so the wildcards are
+      escaped and the matches are checked against the
+      parsed list. Storing the wallets as an array would
+      let this be a plain = ANY lookup instead.
+    */
+    async fn get_scheduler_for_wallet(
+        &self,
+        wallet_id: &str,
+    ) -> Result<Option<Scheduler>, StoreErrorType> {
+        use super::schema::schedulers::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let db_schedulers: Vec<DbScheduler> = schedulers
+            .filter(wallets_only.eq(true))
+            .filter(wallets_to_route.like(like_contains(wallet_id)))
+            .order(process_count.asc())
+            .load(conn)?;
+
+        for db_scheduler in db_schedulers {
+            let scheduler = Scheduler {
+                row_id: Some(db_scheduler.row_id),
+                url: db_scheduler.url,
+                process_count: db_scheduler.process_count,
+                no_route: db_scheduler.no_route,
+                wallets_to_route: db_scheduler.wallets_to_route,
+                wallets_only: db_scheduler.wallets_only,
+                version: Some(db_scheduler.version),
+                consecutive_failures: db_scheduler.consecutive_failures,
+            };
+            if scheduler
+                .wallets_to_route_list()?
+                .iter()
+                .any(|w| w == wallet_id)
+            {
+                return Ok(Some(scheduler));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /*
+      Adjust the count in a single UPDATE so concurrent
+      spawns can't lose each other's increments the way
+      a read, bump, update_scheduler sequence does.
+    */
+    async fn increment_process_count(
+        &self,
+        row_id_in: i32,
+        delta: i32,
+    ) -> Result<i32, StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::schedulers::dsl::*;
+        self.with_write_retries("increment_process_count", move || async move {
+            let conn = &mut self.get_conn()?;
+
+            match diesel::update(schedulers.filter(row_id.eq(row_id_in)))
+                .set(process_count.eq(process_count + delta))
+                .returning(process_count)
+                .get_result::<i32>(conn)
+                .optional()
+            {
+                Ok(Some(count)) => Ok(count),
+                Ok(None) => Err(StoreErrorType::not_found("scheduler", row_id_in)),
+                Err(e) => Err(StoreErrorType::from(e)),
+            }
+        })
+        .await
+    }
+
+    /*
+      Recompute every scheduler's process_count from the
+      process_schedulers table and fix any that drifted,
+      a scheduler with no processes is set to 0. The
+      scheduler rows are locked for the duration so
+      increments wait until the recount is written.
+
+      It runs as repeatable read so the schedulers and
+      the counts come from one snapshot, a scheduler or
+      assignment added while it runs is left for the
+      next recount instead of half counted. A conflict
+      with a concurrent update is retried.
+    */
+    async fn recount_process_counts(&self) -> Result<Vec<ProcessCountCorrection>, StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::process_schedulers::dsl as ps;
+        use super::schema::schedulers::dsl::*;
+        self.with_write_retries("recount_process_counts", move || async move {
+            let conn = &mut self.get_conn()?;
+
+            conn.build_transaction()
+                .repeatable_read()
+                .run::<_, StoreErrorType, _>(|conn| {
+                    let db_schedulers = schedulers
+                        .order(row_id.asc())
+                        .for_update()
+                        .load::<DbScheduler>(conn)?;
+
+                    let actual_counts: Vec<(i32, i64)> = ps::process_schedulers
+                        .group_by(ps::scheduler_row_id)
+                        .select((ps::scheduler_row_id, diesel::dsl::count_star()))
+                        .load(conn)?;
+
+                    let mut corrections = vec![];
+                    for db_scheduler in db_schedulers {
+                        let actual = actual_counts
+                            .iter()
+                            .find(|(s_row_id, _)| *s_row_id == db_scheduler.row_id)
+                            .map(|(_, count)| *count as i32)
+                            .unwrap_or(0);
+
+                        if actual != db_scheduler.process_count {
+                            diesel::update(schedulers.filter(row_id.eq(db_scheduler.row_id)))
+                                .set(process_count.eq(actual))
+                                .execute(conn)?;
+
+                            corrections.push(ProcessCountCorrection {
+                                scheduler_row_id: db_scheduler.row_id,
+                                url: db_scheduler.url,
+                                previous_count: db_scheduler.process_count,
+                                corrected_count: actual,
+                            });
+                        }
+                    }
+
+                    Ok(corrections)
+                })
+        })
+        .await
+    }
+
+    async fn select_scheduler_for_spawn(
+        &self,
+        owner_wallet: &str,
+    ) -> Result<Scheduler, StoreErrorType> {
+        let schedulers = self.get_all_schedulers().await?;
+        select_scheduler(
+            schedulers,
+            owner_wallet,
+            self.scheduler_max_failures,
+            self.scheduler_capacity,
+        )
+    }
+
+    /*
+      Read both tables in one repeatable read transaction
+      so the assignments match the schedulers exported.
+    */
+    async fn export_router_state(&self) -> Result<RouterState, StoreErrorType> {
+        use super::schema::process_schedulers::dsl as ps;
+        use super::schema::schedulers::dsl::*;
+        let conn = &mut self.get_read_conn()?;
+
+        let (db_schedulers, db_assignments) = conn
+            .build_transaction()
+            .read_only()
+            .repeatable_read()
+            .run::<_, StoreErrorType, _>(|conn| {
+                let db_schedulers = schedulers.order(row_id.asc()).load::<DbScheduler>(conn)?;
+                let db_assignments = ps::process_schedulers
+                    .order(ps::row_id.asc())
+                    .load::<DbProcessScheduler>(conn)?;
+                Ok((db_schedulers, db_assignments))
+            })?;
+
+        let urls: HashMap<i32, &str> = db_schedulers
+            .iter()
+            .map(|s| (s.row_id, s.url.as_str()))
+            .collect();
+
+        let mut assignments = Vec::with_capacity(db_assignments.len());
+        for db_assignment in &db_assignments {
+            match urls.get(&db_assignment.scheduler_row_id) {
+                Some(scheduler_url) => assignments.push(RouterStateAssignment {
+                    process_id: db_assignment.process_id.clone(),
+                    scheduler_url: scheduler_url.to_string(),
+                }),
+                None => self.logger.error(format!(
+                    "Skipping process {} assigned to missing scheduler {}",
+                    db_assignment.process_id, db_assignment.scheduler_row_id
+                )),
+            }
+        }
+
+        let schedulers_out = db_schedulers
+            .into_iter()
+            .map(|s| RouterStateScheduler {
+                url: s.url,
+                no_route: s.no_route,
+                wallets_to_route: s.wallets_to_route,
+                wallets_only: s.wallets_only,
+            })
+            .collect();
+
+        Ok(RouterState {
+            schedulers: schedulers_out,
+            assignments,
+        })
+    }
+
+    async fn import_router_state(
+        &self,
+        state: &RouterState,
+        merge: bool,
+        skip_violations: bool,
+    ) -> Result<RouterImportReport, StoreErrorType> {
+        self.ensure_writable()?;
+        use super::schema::process_schedulers::dsl as ps;
+        use super::schema::schedulers::dsl::*;
+        use diesel::upsert::excluded;
+
+        for scheduler in &state.schedulers {
+            Scheduler::from(scheduler).wallets_to_route_list()?;
+        }
+
+        let conn = &mut self.get_conn()?;
+
+        conn.transaction::<_, StoreErrorType, _>(|conn| {
+            if !merge {
+                diesel::delete(ps::process_schedulers).execute(conn)?;
+                diesel::delete(schedulers).execute(conn)?;
+            }
+
+            for scheduler in &state.schedulers {
+                let new_scheduler = NewScheduler {
+                    url: &scheduler.url,
+                    process_count: &0,
+                    no_route: scheduler.no_route.as_ref(),
+                    wallets_to_route: scheduler.wallets_to_route.as_deref(),
+                    wallets_only: scheduler.wallets_only.as_ref(),
+                };
+
+                diesel::insert_into(schedulers)
+                    .values(&new_scheduler)
+                    .on_conflict(url)
+                    .do_update()
+                    .set((
+                        no_route.eq(&scheduler.no_route),
+                        wallets_to_route.eq(&scheduler.wallets_to_route),
+                        wallets_only.eq(&scheduler.wallets_only),
+                        version.eq(version + 1),
+                    ))
+                    .execute(conn)?;
+            }
+
+            let scheduler_ids: HashMap<String, i32> = schedulers
+                .select((url, row_id))
+                .load::<(String, i32)>(conn)?
+                .into_iter()
+                .collect();
+
+            let (resolved, skipped) = resolve_assignments(&state.assignments, &scheduler_ids);
+            for violation in &skipped {
+                self.logger.error(format!(
+                    "Process {} is assigned to unknown scheduler {}",
+                    violation.process_id, violation.scheduler_url
+                ));
+            }
+            if !skipped.is_empty() && !skip_violations {
+                return Err(StoreErrorType::invalid_input(
+                    "assignments",
+                    format!(
+                        "{} assignments point at schedulers that don't exist",
+                        skipped.len()
+                    ),
+                ));
+            }
+
+            for chunk in resolved.chunks(1000) {
+                let rows: Vec<NewProcessScheduler> = chunk
+                    .iter()
+                    .map(|(p_id, s_row_id)| NewProcessScheduler {
+                        process_id: p_id,
+                        scheduler_row_id: s_row_id,
+                    })
+                    .collect();
+
+                diesel::insert_into(ps::process_schedulers)
+                    .values(&rows)
+                    .on_conflict(ps::process_id)
+                    .do_update()
+                    .set(ps::scheduler_row_id.eq(excluded(ps::scheduler_row_id)))
+                    .execute(conn)?;
+            }
+
+            /*
+              The counts carried by the source router don't
+              apply here, recount from what was imported.
+            */
+            let actual_counts: Vec<(i32, i64)> = ps::process_schedulers
+                .group_by(ps::scheduler_row_id)
+                .select((ps::scheduler_row_id, diesel::dsl::count_star()))
+                .load(conn)?;
+            diesel::update(schedulers)
+                .set(process_count.eq(0))
+                .execute(conn)?;
+            for (s_row_id, count) in actual_counts {
+                diesel::update(schedulers.filter(row_id.eq(s_row_id)))
+                    .set(process_count.eq(count as i32))
+                    .execute(conn)?;
+            }
+
+            Ok(RouterImportReport {
+                schedulers: state.schedulers.len(),
+                assignments: resolved.len(),
+                skipped,
+            })
+        })
+    }
+}
+
+fn scheduler_row_id(scheduler: &Scheduler) -> Result<i32, StoreErrorType> {
+    scheduler.row_id.ok_or_else(|| {
+        StoreErrorType::invalid_input("row_id", "Cannot update a scheduler without a row_id")
+    })
+}
+
+fn scheduler_update_result(
+    row_id_in: i32,
+    row_count: usize,
+    expected_version: Option<i32>,
+) -> Result<String, StoreErrorType> {
+    match (row_count, expected_version) {
+        (0, Some(v)) => Err(StoreErrorType::Conflict(format!(
+            "Scheduler was modified since version {} was read",
+            v
+        ))),
+        (0, None) => Err(StoreErrorType::not_found("scheduler", row_id_in)),
+        _ => Ok("updated".to_string()),
+    }
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::processes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DbProcess {
+    pub row_id: i32,
+    pub process_id: String,
+    pub process_data: serde_json::Value,
+    pub bundle: Vec<u8>,
+    pub epoch: Option<i32>,
+    pub nonce: Option<i32>,
+    pub timestamp: Option<i64>,
+    pub hash_chain: Option<String>,
+    pub module_id: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = super::schema::messages)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DbMessage {
+    pub row_id: i32,
+    pub process_id: String,
+    pub message_id: String,
+    pub assignment_id: Option<String>,
+    pub message_data: serde_json::Value,
+    pub epoch: i32,
+    pub nonce: i32,
+    pub timestamp: i64,
+    pub bundle: Vec<u8>,
+    pub hash_chain: String,
+    pub deleted_at: Option<i64>,
+    pub bundle_checksum: Option<String>,
+    pub sender_id: Option<String>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::messages)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DbMessageWithoutData {
+    pub row_id: i32,
+    pub process_id: String,
+    pub message_id: String,
+    pub assignment_id: Option<String>,
+    pub epoch: i32,
+    pub nonce: i32,
+    pub timestamp: i64,
+    pub hash_chain: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::messages)]
+pub struct NewMessage<'a> {
+    pub process_id: &'a str,
+    pub message_id: &'a str,
+    pub assignment_id: &'a str,
+    pub message_data: serde_json::Value,
+    pub bundle: &'a [u8],
+    pub epoch: &'a i32,
+    pub nonce: &'a i32,
+    pub timestamp: &'a i64,
+    pub hash_chain: &'a str,
+    pub bundle_checksum: Option<String>,
+}
+
+#[derive(QueryableByName, Debug)]
+pub struct DuplicateAssignment {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub assignment_id: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub row_count: i64,
+    #[diesel(sql_type = diesel::sql_types::Array<diesel::sql_types::Integer>)]
+    pub row_ids: Vec<i32>,
+    #[diesel(sql_type = diesel::sql_types::Array<diesel::sql_types::Text>)]
+    pub message_ids: Vec<String>,
+}
+
+#[derive(QueryableByName, Debug)]
+struct ReplicaLag {
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+    lag_ms: Option<i64>,
+}
+
+#[derive(QueryableByName, Debug)]
+struct ActiveProcess {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    process_id: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::processes)]
+pub struct NewProcess<'a> {
+    pub process_id: &'a str,
+    pub process_data: serde_json::Value,
+    pub bundle: &'a [u8],
+    pub epoch: Option<i32>,          // New nullable field
+    pub nonce: Option<i32>,          // New nullable field
+    pub hash_chain: Option<&'a str>, // New nullable field
+    pub timestamp: Option<i64>,      // New nullable field
+    pub module_id: Option<String>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::schedulers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DbScheduler {
+    pub row_id: i32,
+    pub url: String,
+    pub process_count: i32,
+    pub no_route: Option<bool>,
+    pub wallets_to_route: Option<String>,
+    pub wallets_only: Option<bool>,
+    pub version: i32,
+    pub consecutive_failures: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::schedulers)]
+pub struct NewScheduler<'a> {
+    pub url: &'a str,
+    pub process_count: &'a i32,
+    pub no_route: Option<&'a bool>,
+    pub wallets_to_route: Option<&'a str>,
+    pub wallets_only: Option<&'a bool>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::process_schedulers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DbProcessScheduler {
+    pub row_id: i32,
+    pub process_id: String,
+    pub scheduler_row_id: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::process_schedulers)]
+pub struct NewProcessScheduler<'a> {
+    pub process_id: &'a str,
+    pub scheduler_row_id: &'a i32,
+}
+
+/*
+  bytestore is a performance enhancement implemented within
+  the data store. This is implemented using RocksDB in BlobDB mode.
+  It is used for fast retrieval of messages.
+
+  See https://rocksdb.org/blog/2021/05/26/integrated-blob-db.html
+*/
+#[cfg(feature = "disk-store")]
+mod bytestore {
+    use super::super::super::config::AoConfig;
+    use super::super::super::core::dal::{
+        ByteStoreCheckpoint, ByteStoreCompaction, ByteStoreCounters, ByteStoreStatistics, Log,
+    };
+    use dashmap::DashMap;
+    use data_encoding::HEXLOWER;
+    use rocksdb::checkpoint::Checkpoint;
+    use rocksdb::{
+        BottommostLevelCompaction, ColumnFamily, ColumnFamilyDescriptor, CompactOptions,
+        DBCompressionType, Direction, IteratorMode, Options, WriteBatch, WriteOptions, DB,
+    };
+    use sha2::{Digest, Sha256};
+    use std::ffi::CString;
+    use std::fs;
+    use std::ops::Deref;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex as StdMutex};
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+    use tokio::sync::RwLock;
+
+    pub struct ByteStore {
+        db: RwLock<Option<DB>>,
+        config: AoConfig,
+        logger: Arc<dyn Log>,
+
+        /*
+          Counted since startup so we can see how often
+          reads miss and silently fall back to postgres.
+        */
+        reads_attempted: AtomicU64,
+        reads_hit: AtomicU64,
+        reads_missed: AtomicU64,
+        writes: AtomicU64,
+        write_failures: AtomicU64,
+        deletes: AtomicU64,
+        stored_bytes: AtomicU64,
+        logical_bytes: AtomicU64,
+        corrupt_reads: AtomicU64,
+
+        /*
+          Bytes held by read_binaries results that haven't
+          been dropped yet, across every request, checked
+          against MAX_TOTAL_READ_MEMORY.
+        */
+        in_flight_read_memory: Arc<AtomicUsize>,
+
+        /*
+          Raised by the sync passes and migrations for as
+          long as they write to the bytestore in bulk, a
+          manual compaction isn't started until it is 0.
+        */
+        bulk_writers: AtomicUsize,
+
+        /*
+          Set by check_disk while SU_DATA_DIR is over
+          BYTESTORE_DISK_HARD_LIMIT_PERCENT, is_ready is
+          false until it is cleared so messages are only
+          written to postgres.
+        */
+        degraded: AtomicBool,
+        degraded_transitions: AtomicU64,
+        disk_usage: StdMutex<Option<(f64, DiskLevel)>>,
+    }
+
+    /*
+      Held while writing to the bytestore in bulk,
+      lowers bulk_writers again when dropped.
+    */
+    pub struct BulkWriteGuard<'a>(&'a AtomicUsize);
+
+    impl Drop for BulkWriteGuard<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /*
+      Holds a share of the in flight read memory and gives
+      it back when dropped.
+    */
+    pub struct MemoryGuard {
+        in_flight: Arc<AtomicUsize>,
+        bytes: usize,
+    }
+
+    impl MemoryGuard {
+        pub fn new(in_flight: Arc<AtomicUsize>) -> Self {
+            MemoryGuard {
+                in_flight,
+                bytes: 0,
+            }
+        }
+
+        pub fn is_full(&self, limit: usize) -> bool {
+            self.in_flight.load(Ordering::Relaxed) >= limit
+        }
+
+        /*
+          Take bytes more, failing without taking anything
+          if that would put the total over limit.
+        */
+        pub fn reserve(&mut self, bytes: usize, limit: usize) -> Result<(), String> {
+            self.in_flight
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+                    current.checked_add(bytes).filter(|total| *total <= limit)
+                })
+                .map_err(|_| {
+                    format!("In flight read memory exceeded the limit: {} bytes", limit)
+                })?;
+            self.bytes += bytes;
+            Ok(())
+        }
+    }
+
+    impl Drop for MemoryGuard {
+        fn drop(&mut self) {
+            self.in_flight.fetch_sub(self.bytes, Ordering::AcqRel);
+        }
+    }
+
+    /*
+      What read_binaries hands back, the binaries count
+      against the in flight read memory until this is
+      dropped.
+    */
+    pub struct ReadBinaries {
+        binaries: DashMap<(String, Option<String>, String, String), Vec<u8>>,
+        _guard: MemoryGuard,
+    }
+
+    impl Deref for ReadBinaries {
+        type Target = DashMap<(String, Option<String>, String, String), Vec<u8>>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.binaries
+        }
+    }
+
+    /*
+      Values written with compression on start with this
+      header, the magic followed by a codec byte. Values
+      written before compression existed are raw bundles,
+      which start with a 32 byte little endian item count,
+      so they won't start with the magic in practice.
+    */
+    const COMPRESSION_MAGIC: &[u8; 4] = b"AOZ\0";
+    const CODEC_ZSTD: u8 = 1;
+    const HEADER_LEN: usize = COMPRESSION_MAGIC.len() + 1;
+
+    /*
+      Every value is now written with a sha256 of the
+      bundle in front of it, so a truncated value read
+      back from disk shows up as a checksum failure
+      instead of a bundle that won't parse. This wraps
+      the compression header, values written before it
+      existed have no digest and are not verified.
+    */
+    const CHECKSUM_MAGIC: &[u8; 4] = b"AOH\0";
+    const CHECKSUM_HEADER_LEN: usize = CHECKSUM_MAGIC.len() + 32;
+
+    /*
+      Written over a message's value to mark it logically
+      deleted. Raw bundles start with a 32 byte item
+      count and encoded values with a magic, so no real
+      value is ever exactly this.
+    */
+    const TOMBSTONE: &[u8] = b"tombstone";
+
+    pub fn is_tombstone(value: &[u8]) -> bool {
+        value == TOMBSTONE
+    }
+
+    /*
+      Message bundles and deep hashes are kept in their
+      own column families so each can be tuned on its
+      own. Keys written before they existed are still in
+      the default column family and are read from there
+      when they aren't found in their own.
+    */
+    pub const MESSAGES_CF: &str = "messages";
+    pub const DEEPHASHES_CF: &str = "deephashes";
+    const DEEPHASHES_BLOCK_CACHE_MB: u64 = 64;
+
+    /*
+      Bundles are large and written once so they go to
+      blob files, compressed unless the values already
+      are. Deep hashes are small and only ever looked up
+      by key.
+    */
+    fn column_family_handles(db: &DB) -> Vec<&ColumnFamily> {
+        [MESSAGES_CF, DEEPHASHES_CF]
+            .iter()
+            .filter_map(|name| db.cf_handle(name))
+            .collect()
+    }
+
+    /*
+      The properties are per column family, so they
+      are summed over all of them.
+    */
+    fn sum_property(db: &DB, name: &str) -> Result<Option<u64>, String> {
+        let error = |e| format!("Failed to read RocksDB property {}: {:?}", name, e);
+        let mut total = db.property_int_value(name).map_err(error)?;
+        for cf in column_family_handles(db) {
+            if let Some(value) = db.property_int_value_cf(cf, name).map_err(error)? {
+                total = Some(total.unwrap_or(0) + value);
+            }
+        }
+        Ok(total)
+    }
+
+    fn live_file_size(db: &DB) -> Result<u64, String> {
+        let sst = sum_property(db, "rocksdb.total-sst-files-size")?.unwrap_or(0);
+        let blob = sum_property(db, "rocksdb.total-blob-file-size")?.unwrap_or(0);
+        Ok(sst + blob)
+    }
+
+    /*
+      The first key after every key starting with
+      prefix, None if there is no such key.
+    */
+    pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+        let mut end = prefix.to_vec();
+        while let Some(last) = end.pop() {
+            if last < u8::MAX {
+                end.push(last + 1);
+                return Some(end);
+            }
+        }
+        None
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum DiskLevel {
+        Ok,
+        Soft,
+        Hard,
+    }
+
+    pub fn disk_level(usage_percent: f64, soft_limit: f64, hard_limit: f64) -> DiskLevel {
+        if usage_percent >= hard_limit {
+            DiskLevel::Hard
+        } else if usage_percent >= soft_limit {
+            DiskLevel::Soft
+        } else {
+            DiskLevel::Ok
+        }
+    }
+
+    /*
+      Percent of the filesystem holding path that is
+      in use, counted the way df does so the space
+      reserved for root counts as used.
+    */
+    pub fn disk_usage_percent(path: &Path) -> Result<f64, String> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| format!("Invalid path {:?}: {:?}", path, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(format!(
+                "Failed to stat {:?}: {:?}",
+                path,
+                std::io::Error::last_os_error()
+            ));
+        }
+        let used = (stat.f_blocks - stat.f_bfree) as f64;
+        let available = stat.f_bavail as f64;
+        if used + available == 0.0 {
+            return Ok(0.0);
+        }
+        Ok(used / (used + available) * 100.0)
+    }
+
+    pub fn column_families(
+        compressed_values: bool,
+        blob_file_size: u64,
+        min_blob_size: u64,
+    ) -> Vec<ColumnFamilyDescriptor> {
+        let mut messages = Options::default();
+        messages.set_enable_blob_files(true);
+        messages.set_blob_file_size(blob_file_size);
+        messages.set_min_blob_size(min_blob_size);
+        messages.set_enable_blob_gc(true);
+        if !compressed_values {
+            messages.set_blob_compression_type(DBCompressionType::Lz4);
+        }
+
+        let mut deephashes = Options::default();
+        deephashes.optimize_for_point_lookup(DEEPHASHES_BLOCK_CACHE_MB);
+
+        vec![
+            ColumnFamilyDescriptor::new(MESSAGES_CF, messages),
+            ColumnFamilyDescriptor::new(DEEPHASHES_CF, deephashes),
+        ]
+    }
+
+    /*
+      How writes reach the WAL, from BYTESTORE_WAL_SYNC_MODE.
+      Sync fsyncs the WAL on every write. Async leaves it
+      in the OS buffers and syncs it in the background, so
+      a crash can lose the last writes. Disable skips the
+      WAL, so a crash loses everything not yet flushed
+      from the memtables.
+    */
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum WalSyncMode {
+        Sync,
+        Async,
+        Disable,
+    }
+
+    const WAL_BYTES_PER_SYNC: u64 = 1024 * 1024;
+
+    impl WalSyncMode {
+        pub fn from_config(mode: &str) -> Self {
+            match mode {
+                "async" => WalSyncMode::Async,
+                "disable" => WalSyncMode::Disable,
+                _ => WalSyncMode::Sync,
+            }
+        }
+
+        pub fn write_options(&self) -> WriteOptions {
+            let mut write_options = WriteOptions::default();
+            match self {
+                WalSyncMode::Sync => write_options.set_sync(true),
+                WalSyncMode::Async => (),
+                WalSyncMode::Disable => write_options.disable_wal(true),
+            }
+            write_options
+        }
+    }
+
+    fn column_family<'a>(db: &'a DB, name: &str) -> Result<&'a ColumnFamily, String> {
+        db.cf_handle(name)
+            .ok_or_else(|| format!("RocksDB column family {} is missing", name))
+    }
+
+    /*
+      Read key from its own column family, falling back
+      to the default one. A db opened without the column
+      families, like the ones in the tests, only has the
+      default.
+    */
+    pub fn get_with_fallback(
+        db: &DB,
+        cf_name: &str,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, String> {
+        if let Some(cf) = db.cf_handle(cf_name) {
+            match db.get_cf(cf, key) {
+                Ok(Some(value)) => return Ok(Some(value)),
+                Ok(None) => (),
+                Err(e) => return Err(format!("Failed to read from RocksDB: {:?}", e)),
+            }
+        }
+        db.get(key)
+            .map_err(|e| format!("Failed to read from RocksDB: {:?}", e))
+    }
+
+    /*
+      get_with_fallback for many keys at once, one
+      multi_get on the column family and another on the
+      default one for the keys it didn't have. The
+      values are in the order of keys.
+    */
+    pub fn multi_get_with_fallback(
+        db: &DB,
+        cf_name: &str,
+        keys: &[Vec<u8>],
+    ) -> Vec<Result<Option<Vec<u8>>, String>> {
+        let read_error = |e: rocksdb::Error| format!("Failed to read from RocksDB: {:?}", e);
+        let mut values: Vec<Result<Option<Vec<u8>>, String>> = match db.cf_handle(cf_name) {
+            Some(cf) => db
+                .multi_get_cf(keys.iter().map(|key| (cf, key)))
+                .into_iter()
+                .map(|value| value.map_err(read_error))
+                .collect(),
+            None => vec![Ok(None); keys.len()],
+        };
+
+        let missing: Vec<usize> = (0..keys.len())
+            .filter(|i| matches!(values[*i], Ok(None)))
+            .collect();
+        let fallbacks = db.multi_get(missing.iter().map(|i| &keys[*i]));
+        for (i, value) in missing.into_iter().zip(fallbacks) {
+            values[i] = value.map_err(read_error);
+        }
+        values
+    }
+
+    /*
+      Delete key from its own column family and the
+      default one in the same batch.
+    */
+    fn delete_with_fallback(batch: &mut WriteBatch, db: &DB, cf_name: &str, key: &[u8]) {
+        if let Some(cf) = db.cf_handle(cf_name) {
+            batch.delete_cf(cf, key);
+        }
+        batch.delete(key);
+    }
+
+    /*
+      Checkpoints are named with a zero padded unix ms
+      so sorting the names sorts them oldest first.
+    */
+    const CHECKPOINT_PREFIX: &str = "checkpoint-";
+
+    /*
+      The same digest is stored in the bundle_checksum
+      column in postgres, hex encoded.
+    */
+    pub fn bundle_checksum(bundle: &[u8]) -> String {
+        HEXLOWER.encode(&Sha256::digest(bundle))
+    }
+
+    /*
+      Rows saved before checksums existed have none
+      and always pass.
+    */
+    pub fn checksum_matches(bundle: &[u8], checksum: &Option<String>) -> bool {
+        match checksum {
+            Some(expected) => bundle_checksum(bundle) == *expected,
+            None => true,
+        }
+    }
+
+    pub fn encode_value(binary: Vec<u8>, zstd_level: Option<i32>) -> Vec<u8> {
+        let digest = Sha256::digest(&binary);
+        let compressed = compress_value(binary, zstd_level);
+        let mut value = Vec::with_capacity(CHECKSUM_HEADER_LEN + compressed.len());
+        value.extend_from_slice(CHECKSUM_MAGIC);
+        value.extend_from_slice(&digest);
+        value.extend_from_slice(&compressed);
+        value
+    }
+
+    /*
+      Undo encode_value, failing if the bundle doesn't
+      match the digest it was written with.
+    */
+    pub fn decode_value(mut value: Vec<u8>) -> Result<Vec<u8>, String> {
+        if value.len() < CHECKSUM_HEADER_LEN || &value[..CHECKSUM_MAGIC.len()] != CHECKSUM_MAGIC {
+            return decompress_value(value);
+        }
+        let compressed = value.split_off(CHECKSUM_HEADER_LEN);
+        let bundle = decompress_value(compressed)?;
+        if Sha256::digest(&bundle)[..] != value[CHECKSUM_MAGIC.len()..] {
+            return Err("Bytestore value does not match its checksum".to_string());
+        }
+        Ok(bundle)
+    }
+
+    /*
+      Compress a value for storage if a zstd level is
+      given, falling back to the raw value if zstd fails.
+    */
+    fn compress_value(binary: Vec<u8>, zstd_level: Option<i32>) -> Vec<u8> {
+        let level = match zstd_level {
+            Some(l) => l,
+            None => return binary,
+        };
+        match zstd::bulk::compress(&binary, level) {
+            Ok(compressed) => {
+                let mut value = Vec::with_capacity(HEADER_LEN + compressed.len());
+                value.extend_from_slice(COMPRESSION_MAGIC);
+                value.push(CODEC_ZSTD);
+                value.extend_from_slice(&compressed);
+                value
+            }
+            Err(_) => binary,
+        }
+    }
+
+    /*
+      Undo compress_value, values without the header
+      are returned as they are.
+    */
+    fn decompress_value(value: Vec<u8>) -> Result<Vec<u8>, String> {
+        if value.len() < HEADER_LEN || &value[..COMPRESSION_MAGIC.len()] != COMPRESSION_MAGIC {
+            return Ok(value);
+        }
+        match value[COMPRESSION_MAGIC.len()] {
+            CODEC_ZSTD => zstd::stream::decode_all(&value[HEADER_LEN..])
+                .map_err(|e| format!("Failed to decompress bytestore value: {:?}", e)),
+            codec => Err(format!("Unknown bytestore codec: {}", codec)),
+        }
+    }
+
+    const MESSAGE_KEY_PREFIX: &str = "message___";
+    const ID_LEN: usize = 43;
+
+    /*
+      A message key written before assignments were
+      added to the key, message___{pid}___{ts}___{mid}.
+    */
+    #[derive(Debug, PartialEq)]
+    pub struct LegacyMessageKey {
+        pub key: Vec<u8>,
+        pub process_id: String,
+        pub timestamp: String,
+        pub message_id: String,
+    }
+
+    /*
+      Ids are base64url and can contain underscores
+      themselves, so the key is split by the fixed id
+      length rather than on the separator. Returns None
+      for keys that already end in an assignment id.
+    */
+    pub fn parse_legacy_message_key(key: &[u8]) -> Option<LegacyMessageKey> {
+        let key_str = std::str::from_utf8(key).ok()?;
+        let rest = key_str.strip_prefix(MESSAGE_KEY_PREFIX)?;
+        let process_id = rest.get(..ID_LEN)?;
+        let rest = rest.get(ID_LEN..)?.strip_prefix("___")?;
+        let (timestamp, message_id) = rest.split_once("___")?;
+        if timestamp.is_empty()
+            || !timestamp.bytes().all(|b| b.is_ascii_digit())
+            || message_id.len() != ID_LEN
+        {
+            return None;
+        }
+
+        Some(LegacyMessageKey {
+            key: key.to_vec(),
+            process_id: process_id.to_string(),
+            timestamp: timestamp.to_string(),
+            message_id: message_id.to_string(),
+        })
+    }
+
+    /*
+      Every key stored for a process, its message binaries,
+      deep hashes and deep hash version, with the column
+      family each was found in, None for the default one.
+      Without a prefix extractor the prefix iterator runs
+      past the prefix, so it stops at the first key that
+      doesn't match.
+    */
+    fn process_keys<'a>(
+        db: &'a DB,
+        process_id: &str,
+    ) -> Result<Vec<(Option<&'a ColumnFamily>, Box<[u8]>)>, String> {
+        let mut keys = Vec::new();
+        for (cf_name, prefix) in [
+            (MESSAGES_CF, format!("message___{}___", process_id)),
+            (DEEPHASHES_CF, format!("deephash___{}___", process_id)),
+        ] {
+            for cf in [db.cf_handle(cf_name), None] {
+                let iter = match cf {
+                    Some(cf) => db.prefix_iterator_cf(cf, prefix.as_bytes()),
+                    None => db.prefix_iterator(prefix.as_bytes()),
+                };
+                for item in iter {
+                    let (key, _) =
+                        item.map_err(|e| format!("Failed to iterate RocksDB: {:?}", e))?;
+                    if !key.starts_with(prefix.as_bytes()) {
+                        break;
+                    }
+                    keys.push((cf, key));
+                }
+            }
+        }
+
+        let version_key = format!("deephashversion___{}", process_id).into_bytes();
+        for cf in [db.cf_handle(DEEPHASHES_CF), None] {
+            let found = match cf {
+                Some(cf) => db.get_cf(cf, &version_key),
+                None => db.get(&version_key),
+            };
+            match found {
+                Ok(Some(_)) => keys.push((cf, version_key.clone().into_boxed_slice())),
+                Ok(None) => (),
+                Err(e) => return Err(format!("Failed to read from RocksDB: {:?}", e)),
+            }
+        }
+        Ok(keys)
+    }
+
+    pub fn prune_process_keys(
+        db: &DB,
+        process_id: &str,
+        write_options: &WriteOptions,
+    ) -> Result<u64, String> {
+        let keys = process_keys(db, process_id)?;
+        let mut batch = WriteBatch::default();
+        for (cf, key) in &keys {
+            match cf {
+                Some(cf) => batch.delete_cf(*cf, key),
+                None => batch.delete(key),
+            }
+        }
+        db.write_opt(batch, write_options)
+            .map_err(|e| format!("Failed to delete from RocksDB: {:?}", e))?;
+        Ok(keys.len() as u64)
+    }
+
+    /*
+      The deep hashes saved for a process, out of both
+      the deep hash column family and the default one.
+      Only deep hash keys have this prefix, message keys
+      and the version key don't.
+    */
+    pub fn process_deep_hashes(db: &DB, process_id: &str) -> Result<Vec<String>, String> {
+        let prefix = format!("deephash___{}___", process_id);
+        let mut deep_hashes = Vec::new();
+        for (_, key) in process_keys(db, process_id)? {
+            if let Some(deep_hash) = key.strip_prefix(prefix.as_bytes()) {
+                deep_hashes.push(String::from_utf8_lossy(deep_hash).to_string());
+            }
+        }
+        deep_hashes.sort();
+        deep_hashes.dedup();
+        Ok(deep_hashes)
+    }
+
+    /*
+      Canonicalize a path that may not exist yet by
+      resolving its closest existing ancestor, so a new
+      target dir can still be compared to the data dir.
+    */
+    fn resolve_path(path: &Path) -> Result<PathBuf, String> {
+        let mut existing = path;
+        let mut missing = Vec::new();
+        while !existing.exists() {
+            match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    missing.push(name);
+                    existing = if parent.as_os_str().is_empty() {
+                        Path::new(".")
+                    } else {
+                        parent
+                    };
+                }
+                _ => return Err(format!("Invalid checkpoint path {:?}", path)),
+            }
+        }
+
+        let mut resolved = existing
+            .canonicalize()
+            .map_err(|e| format!("Failed to resolve {:?}: {:?}", existing, e))?;
+        for name in missing.iter().rev() {
+            resolved.push(name);
+        }
+        Ok(resolved)
+    }
+
+    /*
+      A checkpoint written inside the live data dir would
+      end up inside the next checkpoint and confuse RocksDB,
+      so the target has to be somewhere else.
+    */
+    fn checkpoint_target(data_dir: &str, target_dir: &str) -> Result<PathBuf, String> {
+        let data_dir = resolve_path(Path::new(data_dir))?;
+        let target_dir = resolve_path(Path::new(target_dir))?;
+        if target_dir.starts_with(&data_dir) {
+            return Err(format!(
+                "Checkpoint path {:?} is inside the bytestore data dir {:?}",
+                target_dir, data_dir
+            ));
+        }
+        Ok(target_dir)
+    }
+
+    /*
+      Run when a StoreClient is built with USE_DISK on, so
+      a bad SU_DATA_DIR stops startup instead of showing up
+      later as a RocksDB error in the sync logs while the su
+      quietly runs without the bytestore. A read only su
+      only needs the dir to be there.
+    */
+    pub fn validate_data_dir(config: &AoConfig, writable: bool) -> Result<(), String> {
+        let mut others = vec![
+            ("SU_FILE_SYNC_DB_DIR", config.su_file_sync_db_dir.as_str()),
+            ("SU_INDEX_SYNC_DB_DIR", config.su_index_sync_db_dir.as_str()),
+        ];
+        if let Some(checkpoint_dir) = &config.bytestore_checkpoint_dir {
+            others.push(("BYTESTORE_CHECKPOINT_DIR", checkpoint_dir));
+        }
+        let data_dir = Path::new(&config.su_data_dir);
+        check_data_dir(data_dir, config.su_data_dir_create, writable, &others)?;
+
+        match writable && !LOCK_CHECKED.swap(true, Ordering::SeqCst) {
+            true => check_lock_file(data_dir),
+            false => Ok(()),
+        }
+    }
+
+    pub fn check_data_dir(
+        path: &Path,
+        create: bool,
+        writable: bool,
+        others: &[(&str, &str)],
+    ) -> Result<(), String> {
+        if !path.exists() {
+            if !writable || !create {
+                return Err(format!(
+                    "SU_DATA_DIR {:?} does not exist, create it or set SU_DATA_DIR_CREATE=true",
+                    path
+                ));
+            }
+            fs::create_dir_all(path)
+                .map_err(|e| format!("Failed to create SU_DATA_DIR {:?}: {}", path, e))?;
+        }
+        if !path.is_dir() {
+            return Err(format!("SU_DATA_DIR {:?} is not a directory", path));
+        }
+
+        let data_dir = resolve_path(path)?;
+        for (name, other) in others {
+            let other = resolve_path(Path::new(other))?;
+            if other.starts_with(&data_dir) || data_dir.starts_with(&other) {
+                return Err(format!(
+                    "SU_DATA_DIR {:?} overlaps {} {:?}, they need separate directories",
+                    data_dir, name, other
+                ));
+            }
+        }
+
+        if !writable {
+            return Ok(());
+        }
+        let probe = data_dir.join(".write_probe");
+        fs::write(&probe, b"probe")
+            .and_then(|_| fs::remove_file(&probe))
+            .map_err(|e| format!("SU_DATA_DIR {:?} is not writable: {}", data_dir, e))?;
+        Ok(())
+    }
+
+    /*
+      RocksDB holds an fcntl lock on LOCK while the db is
+      open. F_GETLK only asks who holds it, but closing the
+      file drops every lock this process has on it, so it
+      is only ever checked once, before the bytestore of
+      the first StoreClient is opened.
+    */
+    static LOCK_CHECKED: AtomicBool = AtomicBool::new(false);
+
+    pub fn check_lock_file(data_dir: &Path) -> Result<(), String> {
+        let lock_path = data_dir.join("LOCK");
+        let file = match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&lock_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("Failed to open {:?}: {}", lock_path, e)),
+        };
+
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } != 0 {
+            return Err(format!(
+                "Failed to check the lock on {:?}: {}",
+                lock_path,
+                std::io::Error::last_os_error()
+            ));
+        }
+        if lock.l_type != libc::F_UNLCK as libc::c_short {
+            return Err(format!(
+                "{:?} is locked by process {}, another su or migration is using SU_DATA_DIR. \
+                 Stop it before starting this one. If that process is gone the lock is \
+                 stale from an unclean shutdown, which can happen on network filesystems, \
+                 delete {:?} and start again",
+                lock_path, lock.l_pid, lock_path
+            ));
+        }
+        Ok(())
+    }
+
+    fn dir_size(path: &Path) -> Result<u64, String> {
+        let mut size = 0;
+        for entry in
+            fs::read_dir(path).map_err(|e| format!("Failed to read {:?}: {:?}", path, e))?
+        {
+            let metadata = entry
+                .and_then(|entry| entry.metadata())
+                .map_err(|e| format!("Failed to read {:?}: {:?}", path, e))?;
+            if metadata.is_file() {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+
+    /*
+      Remove all but the newest keep checkpoints in
+      target_dir, returning the paths removed. The one
+      just written is always kept.
+    */
+    fn rotate_checkpoints(target_dir: &Path, keep: usize) -> Result<Vec<String>, String> {
+        let mut checkpoints = Vec::new();
+        for entry in fs::read_dir(target_dir)
+            .map_err(|e| format!("Failed to read {:?}: {:?}", target_dir, e))?
+        {
+            let entry = entry.map_err(|e| format!("Failed to read {:?}: {:?}", target_dir, e))?;
+            let is_checkpoint = entry
+                .file_name()
+                .to_str()
+                .map_or(false, |name| name.starts_with(CHECKPOINT_PREFIX));
+            if is_checkpoint && entry.path().is_dir() {
+                checkpoints.push(entry.path());
+            }
+        }
+        checkpoints.sort();
+
+        let excess = checkpoints.len().saturating_sub(keep.max(1));
+        let mut removed = Vec::new();
+        for path in checkpoints.into_iter().take(excess) {
+            fs::remove_dir_all(&path)
+                .map_err(|e| format!("Failed to remove checkpoint {:?}: {:?}", path, e))?;
+            removed.push(path.display().to_string());
+        }
+        Ok(removed)
+    }
+
+    /*
+      Write a checkpoint of db into a new directory under
+      target_dir. RocksDB flushes the memtables and hard
+      links the live files, so writes can carry on while
+      it runs and the copy is still consistent.
+    */
+    pub fn checkpoint_db(
+        db: &DB,
+        data_dir: &str,
+        target_dir: &str,
+        keep: Option<usize>,
+    ) -> Result<ByteStoreCheckpoint, String> {
+        let target_dir = checkpoint_target(data_dir, target_dir)?;
+        fs::create_dir_all(&target_dir)
+            .map_err(|e| format!("Failed to create {:?}: {:?}", target_dir, e))?;
+
+        let millis = unix_millis()?;
+        let path = target_dir.join(format!("{}{:015}", CHECKPOINT_PREFIX, millis));
+
+        Checkpoint::new(db)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(&path))
+            .map_err(|e| format!("Failed to create RocksDB checkpoint: {:?}", e))?;
+
+        let size_bytes = dir_size(&path)?;
+        let removed = match keep {
+            Some(keep) => rotate_checkpoints(&target_dir, keep)?,
+            None => vec![],
+        };
+
+        Ok(ByteStoreCheckpoint {
+            path: path.display().to_string(),
+            size_bytes,
+            removed,
+        })
+    }
+
+    fn unix_millis() -> Result<u128, String> {
+        Ok(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| format!("{:?}", e))?
+            .as_millis())
+    }
+
+    /*
+      A path next to data_dir, so renaming between the
+      two stays on one filesystem.
+    */
+    fn sibling_path(data_dir: &Path, suffix: &str) -> Result<PathBuf, String> {
+        match data_dir.file_name() {
+            Some(name) => {
+                Ok(data_dir.with_file_name(format!("{}.{}", name.to_string_lossy(), suffix)))
+            }
+            None => Err(format!("Invalid data dir {:?}", data_dir)),
+        }
+    }
+
+    /*
+      Checkpoints are a flat directory of files, anything
+      else in there means it isn't one.
+    */
+    fn copy_checkpoint(from: &Path, to: &Path, logger: &Arc<dyn Log>) -> Result<(), String> {
+        let mut files = Vec::new();
+        let mut total_bytes = 0;
+        for entry in
+            fs::read_dir(from).map_err(|e| format!("Failed to read {:?}: {:?}", from, e))?
+        {
+            let entry = entry.map_err(|e| format!("Failed to read {:?}: {:?}", from, e))?;
+            let metadata = entry
+                .metadata()
+                .map_err(|e| format!("Failed to read {:?}: {:?}", entry.path(), e))?;
+            if !metadata.is_file() {
+                return Err(format!(
+                    "Unexpected entry {:?} in checkpoint, it is not a file",
+                    entry.path()
+                ));
+            }
+            total_bytes += metadata.len();
+            files.push(entry.path());
+        }
+
+        logger.log(format!(
+            "bytestore restore - copying {} files, {} bytes to {:?}",
+            files.len(),
+            total_bytes,
+            to
+        ));
+
+        fs::create_dir_all(to).map_err(|e| format!("Failed to create {:?}: {:?}", to, e))?;
+        let mut copied_bytes = 0;
+        for (i, file) in files.iter().enumerate() {
+            let name = file
+                .file_name()
+                .ok_or_else(|| format!("Invalid checkpoint file {:?}", file))?;
+            copied_bytes += fs::copy(file, to.join(name))
+                .map_err(|e| format!("Failed to copy {:?}: {:?}", file, e))?;
+            if (i + 1) % 100 == 0 {
+                logger.log(format!(
+                    "bytestore restore - copied {} of {} files, {} of {} bytes",
+                    i + 1,
+                    files.len(),
+                    copied_bytes,
+                    total_bytes
+                ));
+            }
+        }
+
+        logger.log(format!(
+            "bytestore restore - copied all {} files, {} bytes",
+            files.len(),
+            copied_bytes
+        ));
+        Ok(())
+    }
+
+    /*
+      Replace data_dir with a copy of a checkpoint. The
+      checkpoint is opened read only first so a broken one
+      is caught before anything is touched. It is copied
+      next to data_dir and renamed into place so a copy
+      that fails halfway leaves data_dir as it was. A non
+      empty data_dir is only replaced with force, and even
+      then it is moved aside rather than deleted.
+    */
+    pub fn restore_checkpoint(
+        checkpoint: &str,
+        data_dir: &str,
+        force: bool,
+        logger: &Arc<dyn Log>,
+    ) -> Result<(), String> {
+        logger.log(format!(
+            "bytestore restore - restoring {} into {}, force: {}",
+            checkpoint, data_dir, force
+        ));
+
+        let result = restore_checkpoint_steps(checkpoint, data_dir, force, logger);
+        match &result {
+            Ok(_) => logger.log("bytestore restore - complete".to_string()),
+            Err(e) => logger.error(format!("bytestore restore - failed: {}", e)),
+        }
+        result
+    }
+
+    fn restore_checkpoint_steps(
+        checkpoint: &str,
+        data_dir: &str,
+        force: bool,
+        logger: &Arc<dyn Log>,
+    ) -> Result<(), String> {
+        let checkpoint = resolve_path(Path::new(checkpoint))?;
+        let data_dir = resolve_path(Path::new(data_dir))?;
+
+        if !checkpoint.is_dir() {
+            return Err(format!("Checkpoint {:?} is not a directory", checkpoint));
+        }
+        if checkpoint.starts_with(&data_dir) || data_dir.starts_with(&checkpoint) {
+            return Err(format!(
+                "Checkpoint {:?} overlaps the data dir {:?}",
+                checkpoint, data_dir
+            ));
+        }
+
+        {
+            let mut opts = Options::default();
+            opts.set_enable_blob_files(true);
+            let db = DB::open_for_read_only(&opts, &checkpoint, false).map_err(|e| {
+                format!("Checkpoint {:?} does not open cleanly: {:?}", checkpoint, e)
+            })?;
+            let num_keys = db
+                .property_int_value("rocksdb.estimate-num-keys")
+                .ok()
+                .flatten();
+            logger.log(format!(
+                "bytestore restore - checkpoint opened read only, estimated keys: {:?}",
+                num_keys
+            ));
+        }
+
+        let data_dir_in_use = match data_dir.exists() {
+            true => fs::read_dir(&data_dir)
+                .map_err(|e| format!("Failed to read {:?}: {:?}", data_dir, e))?
+                .next()
+                .is_some(),
+            false => false,
+        };
+        if data_dir_in_use && !force {
+            return Err(format!(
+                "Data dir {:?} is not empty, restore with force to replace it",
+                data_dir
+            ));
+        }
+
+        let millis = unix_millis()?;
+        let staging = sibling_path(&data_dir, &format!("restoring-{}", millis))?;
+        if let Err(e) = copy_checkpoint(&checkpoint, &staging, logger) {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+
+        if data_dir_in_use {
+            let replaced = sibling_path(&data_dir, &format!("replaced-{}", millis))?;
+            fs::rename(&data_dir, &replaced)
+                .map_err(|e| format!("Failed to move {:?} aside: {:?}", data_dir, e))?;
+            logger.log(format!(
+                "bytestore restore - moved the old data dir to {:?}, delete it once the restore is checked",
+                replaced
+            ));
+        } else if data_dir.exists() {
+            fs::remove_dir(&data_dir)
+                .map_err(|e| format!("Failed to remove empty {:?}: {:?}", data_dir, e))?;
+        }
+
+        fs::rename(&staging, &data_dir).map_err(|e| {
+            format!(
+                "Failed to move {:?} into place at {:?}: {:?}",
+                staging, data_dir, e
+            )
+        })?;
+        logger.log(format!(
+            "bytestore restore - checkpoint is in place at {:?}",
+            data_dir
+        ));
+
+        Ok(())
+    }
+
+    impl ByteStore {
+        pub fn new(config: AoConfig, logger: Arc<dyn Log>) -> Self {
+            ByteStore {
+                db: RwLock::new(None),
+                config,
+                logger,
+                reads_attempted: AtomicU64::new(0),
+                reads_hit: AtomicU64::new(0),
+                reads_missed: AtomicU64::new(0),
+                writes: AtomicU64::new(0),
+                write_failures: AtomicU64::new(0),
+                deletes: AtomicU64::new(0),
+                stored_bytes: AtomicU64::new(0),
+                logical_bytes: AtomicU64::new(0),
+                corrupt_reads: AtomicU64::new(0),
+                in_flight_read_memory: Arc::new(AtomicUsize::new(0)),
+                bulk_writers: AtomicUsize::new(0),
+                degraded: AtomicBool::new(false),
+                degraded_transitions: AtomicU64::new(0),
+                disk_usage: StdMutex::new(None),
+            }
+        }
+
+        pub fn begin_bulk_write(&self) -> BulkWriteGuard<'_> {
+            self.bulk_writers.fetch_add(1, Ordering::SeqCst);
+            BulkWriteGuard(&self.bulk_writers)
+        }
+
+        pub fn is_bulk_writing(&self) -> bool {
+            self.bulk_writers.load(Ordering::SeqCst) > 0
+        }
+
+        pub fn is_degraded(&self) -> bool {
+            self.degraded.load(Ordering::SeqCst)
+        }
+
+        /*
+          The disk usage of SU_DATA_DIR as of the last
+          check_disk, None before the first one.
+        */
+        pub fn disk_usage(&self) -> Option<(f64, DiskLevel)> {
+            self.disk_usage.lock().ok().and_then(|u| *u)
+        }
+
+        /*
+          Check the disk usage of SU_DATA_DIR against the
+          soft and hard limits, going in or out of degraded
+          mode as it crosses the hard one. Returns the new
+          degraded state if it changed.
+        */
+        pub fn check_disk(&self) -> Result<Option<bool>, String> {
+            let usage = disk_usage_percent(Path::new(&self.config.su_data_dir))?;
+            let level = disk_level(
+                usage,
+                self.config.bytestore_disk_soft_limit_percent,
+                self.config.bytestore_disk_hard_limit_percent,
+            );
+            if let Ok(mut u) = self.disk_usage.lock() {
+                *u = Some((usage, level));
+            }
+            if level != DiskLevel::Ok {
+                self.logger.error(format!(
+                    "Bytestore disk {} is {:.1}% full",
+                    self.config.su_data_dir, usage
+                ));
+            }
+
+            let degrade = level == DiskLevel::Hard;
+            if self.degraded.swap(degrade, Ordering::SeqCst) == degrade {
+                return Ok(None);
+            }
+            self.degraded_transitions.fetch_add(1, Ordering::Relaxed);
+            if degrade {
+                self.logger.error(format!(
+                    "Bytestore degraded, disk is over {}%, writing messages to postgres only",
+                    self.config.bytestore_disk_hard_limit_percent
+                ));
+            } else {
+                self.logger.log(format!(
+                    "Bytestore recovered, disk is back under {}%",
+                    self.config.bytestore_disk_hard_limit_percent
+                ));
+            }
+            Ok(Some(degrade))
+        }
+
+        pub fn counters(&self) -> ByteStoreCounters {
+            ByteStoreCounters {
+                reads_attempted: self.reads_attempted.load(Ordering::Relaxed),
+                reads_hit: self.reads_hit.load(Ordering::Relaxed),
+                reads_missed: self.reads_missed.load(Ordering::Relaxed),
+                writes: self.writes.load(Ordering::Relaxed),
+                write_failures: self.write_failures.load(Ordering::Relaxed),
+                deletes: self.deletes.load(Ordering::Relaxed),
+                stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+                logical_bytes: self.logical_bytes.load(Ordering::Relaxed),
+                corrupt_reads: self.corrupt_reads.load(Ordering::Relaxed),
+                degraded_transitions: self.degraded_transitions.load(Ordering::Relaxed),
+            }
+        }
+
+        /*
+          Postgres bundles that fail their checksum are
+          counted here too so there is one number to watch.
+        */
+        pub fn record_corrupt_read(&self) {
+            self.corrupt_reads.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn zstd_level(&self) -> Option<i32> {
+            match self.config.bytestore_compression.as_str() {
+                "zstd" => Some(self.config.bytestore_compression_level),
+                _ => None,
+            }
+        }
+
+        fn blob_file_size(&self) -> u64 {
+            self.config.bytestore_blob_file_size_gb * 1024 * 1024 * 1024
+        }
+
+        fn column_families(&self) -> Vec<ColumnFamilyDescriptor> {
+            column_families(
+                self.zstd_level().is_some(),
+                self.blob_file_size(),
+                self.config.bytestore_min_blob_size,
+            )
+        }
+
+        fn wal_sync_mode(&self) -> WalSyncMode {
+            WalSyncMode::from_config(&self.config.bytestore_wal_sync_mode)
+        }
+
+        fn write_options(&self) -> WriteOptions {
+            self.wal_sync_mode().write_options()
+        }
+
+        fn record_bytes(&self, logical: usize, stored: usize) {
+            self.logical_bytes
+                .fetch_add(logical as u64, Ordering::Relaxed);
+            self.stored_bytes.fetch_add(stored as u64, Ordering::Relaxed);
+        }
+
+        fn record_read(&self, hit: bool) {
+            self.reads_attempted.fetch_add(1, Ordering::Relaxed);
+            if hit {
+                self.reads_hit.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.reads_missed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        fn record_writes<T, E>(&self, result: &Result<T, E>, count: u64) {
+            match result {
+                Ok(_) => self.writes.fetch_add(count, Ordering::Relaxed),
+                Err(_) => self.write_failures.fetch_add(count, Ordering::Relaxed),
+            };
+        }
+
+        /*
+          These options are also the default column
+          family's, which keeps them for the keys written
+          before the column families existed.
+        */
+        pub async fn try_connect(&self) -> Result<(), String> {
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            opts.create_missing_column_families(true);
+            opts.set_enable_blob_files(true); // Enable blob files
+            opts.set_blob_file_size(self.blob_file_size());
+            opts.set_min_blob_size(self.config.bytestore_min_blob_size);
+            opts.set_enable_blob_gc(true);
+
+            let wal_sync_mode = self.wal_sync_mode();
+            opts.set_use_fsync(wal_sync_mode == WalSyncMode::Sync);
+            if wal_sync_mode == WalSyncMode::Async {
+                opts.set_wal_bytes_per_sync(WAL_BYTES_PER_SYNC);
+            }
+
+            let new_db =
+                DB::open_cf_descriptors(&opts, &self.config.su_data_dir, self.column_families())
+                    .map_err(|e| format!("Failed to open RocksDB: {:?}", e))?;
+
+            let mut db_write = self.db.write().await;
+            *db_write = Some(new_db);
+
+            Ok(())
+        }
+
+        pub async fn try_read_instance_connect(&self) -> Result<(), String> {
+            let mut opts = Options::default();
+            opts.set_enable_blob_files(true); // Enable blob files
+
+            /*
+              A read only open can't create the column
+              families, so only the ones the writer has
+              already created are opened.
+            */
+            let existing = DB::list_cf(&opts, &self.config.su_data_dir)
+                .map_err(|e| format!("Failed to list RocksDB column families: {:?}", e))?;
+            let column_families = self
+                .column_families()
+                .into_iter()
+                .filter(|cf| existing.iter().any(|name| name == cf.name()));
+
+            // Open the database in read-only mode
+            let new_db = DB::open_cf_descriptors_read_only(
+                &opts,
+                &self.config.su_data_dir,
+                column_families,
+                false,
+            )
+            .map_err(|e| format!("Failed to open RocksDB in read-only mode: {:?}", e))?;
+
+            let mut db_write = self.db.write().await;
+            *db_write = Some(new_db);
+
+            Ok(())
+        }
+
+        pub fn is_ready(&self) -> bool {
+            if self.is_degraded() {
+                return false;
+            }
+            match self.db.try_read() {
+                Ok(r) => r.is_some(),
+                Err(_) => false,
+            }
+        }
+
+        /*
+          Flush the memtables of every column family to disk,
+          without a WAL this is the only thing that makes
+          the writes so far durable.
+        */
+        pub async fn flush(&self) -> Result<(), String> {
+            let db = self.db.read().await;
+            let db = match *db {
+                Some(ref db) => db,
+                None => return Err("Database is not initialized".to_string()),
+            };
+
+            db.flush()
+                .map_err(|e| format!("Failed to flush RocksDB: {:?}", e))?;
+            for name in [MESSAGES_CF, DEEPHASHES_CF] {
+                if let Some(cf) = db.cf_handle(name) {
+                    db.flush_cf(cf)
+                        .map_err(|e| format!("Failed to flush RocksDB: {:?}", e))?;
+                }
+            }
+            Ok(())
+        }
+
+        /*
+          Only level 0 files are counted for sst_count,
+          those are the ones that pile up when compaction
+          falls behind.
+        */
+        pub fn get_statistics(&self) -> Result<ByteStoreStatistics, String> {
+            let db = self
+                .db
+                .try_read()
+                .map_err(|_| "Bytestore is busy".to_string())?;
+            let db = match *db {
+                Some(ref db) => db,
+                None => return Err("Database is not initialized".to_string()),
+            };
+
+            let property = |name: &str| sum_property(db, name);
+
+            Ok(ByteStoreStatistics {
+                sst_count: property("rocksdb.num-files-at-level0")?.unwrap_or(0),
+                blob_file_count: property("rocksdb.num-blob-files")?.unwrap_or(0),
+                memtable_bytes: property("rocksdb.cur-size-all-mem-tables")?.unwrap_or(0),
+                num_keys: property("rocksdb.estimate-num-keys")?,
+            })
+        }
+
+        /*
+          RocksDB's estimate of the number of entries, it
+          counts overwritten and deleted keys that haven't
+          been compacted away yet so it can be well off the
+          true count. 0 if RocksDB doesn't report it.
+          admin_stats already shows the same estimate as
+          statistics.num_keys, this is for code that only
+          wants the one number.
+        */
+        #[allow(dead_code)]
+        pub fn get_key_count(&self) -> Result<u64, String> {
+            let db = self
+                .db
+                .try_read()
+                .map_err(|_| "Bytestore is busy".to_string())?;
+            let db = match *db {
+                Some(ref db) => db,
+                None => return Err("Database is not initialized".to_string()),
+            };
+            Ok(sum_property(db, "rocksdb.estimate-num-keys")?.unwrap_or(0))
+        }
+
+        /*
+          The exact number of entries, for integrity checks
+          where the estimate isn't good enough. This reads
+          every key and value in the bytestore so it can
+          take minutes on a large one, and it holds the db
+          lock the whole time. Blocking. No route runs it
+          for that reason, it is for one off checks from
+          a maintenance build.
+        */
+        #[allow(dead_code)]
+        pub fn exact_key_count(&self) -> Result<u64, String> {
+            let db = self.db.blocking_read();
+            let db = match *db {
+                Some(ref db) => db,
+                None => return Err("Database is not initialized".to_string()),
+            };
+
+            let mut iterators = vec![db.iterator(IteratorMode::Start)];
+            for cf in column_family_handles(db) {
+                iterators.push(db.iterator_cf(cf, IteratorMode::Start));
+            }
+
+            let mut count = 0;
+            for iterator in iterators {
+                for item in iterator {
+                    item.map_err(|e| format!("Failed to read from RocksDB: {:?}", e))?;
+                    count += 1;
+                }
+            }
+            Ok(count)
+        }
+
+        /*
+          Compact every column family over the keys that
+          start with prefix, or over all of them. Blob
+          files are only rewritten by a compaction, so
+          this is what gives back the space held by
+          deleted messages. Blocking.
+        */
+        pub fn compact_range(&self, prefix: Option<&str>) -> Result<ByteStoreCompaction, String> {
+            if self.is_bulk_writing() {
+                return Err("The bytestore is being written in bulk".to_string());
+            }
+            let db = self.db.blocking_read();
+            let db = match *db {
+                Some(ref db) => db,
+                None => return Err("Database is not initialized".to_string()),
+            };
+
+            let start = Instant::now();
+            let size_before = live_file_size(db)?;
+
+            let (from, to) = match prefix {
+                Some(prefix) => (
+                    Some(prefix.as_bytes().to_vec()),
+                    prefix_end(prefix.as_bytes()),
+                ),
+                None => (None, None),
+            };
+            let mut opts = CompactOptions::default();
+            opts.set_bottommost_level_compaction(BottommostLevelCompaction::Force);
+            db.compact_range_opt(from.as_deref(), to.as_deref(), &opts);
+            for cf in column_family_handles(db) {
+                db.compact_range_cf_opt(cf, from.as_deref(), to.as_deref(), &opts);
+            }
+
+            Ok(ByteStoreCompaction {
+                prefix: prefix.map(str::to_string),
+                size_before,
+                size_after: live_file_size(db)?,
+                duration_ms: start.elapsed().as_millis() as u64,
+            })
+        }
+
+        pub async fn create_checkpoint(&self, path: &str) -> Result<ByteStoreCheckpoint, String> {
+            let db = self.db.read().await;
+            match *db {
+                Some(ref db) => checkpoint_db(
+                    db,
+                    &self.config.su_data_dir,
+                    path,
+                    self.config.bytestore_checkpoint_keep,
+                ),
+                None => Err("Database is not initialized".to_string()),
+            }
+        }
+
+        pub async fn read_binaries(
+            &self,
+            ids: Vec<(String, Option<String>, String, String)>,
+        ) -> Result<ReadBinaries, String> {
+            let max_memory_usage = self.config.max_read_memory;
+            let max_total_memory = self.config.max_total_read_memory;
+            let binaries = Arc::new(DashMap::new());
+            let mut guard = MemoryGuard::new(self.in_flight_read_memory.clone());
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                let mut total_memory_usage: usize = 0;
+
+                for id in ids {
+                    /*
+                      Other requests may already hold all of
+                      it, don't read anything more until they
+                      let go.
+                    */
+                    if guard.is_full(max_total_memory) {
+                        return Err(format!(
+                            "In flight read memory exceeded the limit: {} bytes",
+                            max_total_memory
+                        ));
+                    }
+
+                    let binaries = binaries.clone();
+                    let key = ByteStore::create_key(&id.0, &id.1, &id.2, &id.3);
+                    let result = get_with_fallback(db, MESSAGES_CF, &key);
+                    /*
+                      a value that fails its checksum or
+                      fails to decompress is treated as a
+                      miss so the caller falls back to postgres,
+                      so is a tombstone
+                    */
+                    let value = match result {
+                        Ok(Some(raw)) if is_tombstone(&raw) => None,
+                        Ok(Some(raw)) => match decode_value(raw) {
+                            Ok(value) => Some(value),
+                            Err(e) => {
+                                self.record_corrupt_read();
+                                self.logger.error(format!(
+                                    "Unreadable bytestore value for {:?}, falling back: {}",
+                                    id, e
+                                ));
+                                None
+                            }
+                        },
+                        _ => None,
+                    };
+                    self.record_read(value.is_some());
+                    if let Some(value) = value {
+                        /*
+                          This is added here because really large message lists
+                          with large messages are filling up the machines memory
+                          and freezing it. Counted after decompression since
+                          that is what is held in memory.
+                        */
+                        total_memory_usage += value.len();
+                        if total_memory_usage > max_memory_usage {
+                            return Err(format!(
+                                "Memory usage exceeded the limit: {} bytes",
+                                max_memory_usage
+                            ));
+                        }
+                        guard.reserve(value.len(), max_total_memory)?;
+                        binaries.insert(id.clone(), value);
+                    }
+                }
+                Ok(ReadBinaries {
+                    binaries: Arc::try_unwrap(binaries).map_err(|_| "Failed to unwrap Arc")?,
+                    _guard: guard,
+                })
+            } else {
+                Err("Database is not initialized".into())
+            }
+        }
+
+        pub async fn save_binary(
+            &self,
+            message_id: String,
+            assignment_id: Option<String>,
+            process_id: String,
+            timestamp: String,
+            binary: Vec<u8>,
+        ) -> Result<(), String> {
+            let key = ByteStore::create_key(&message_id, &assignment_id, &process_id, &timestamp);
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                let logical = binary.len();
+                let value = encode_value(binary, self.zstd_level());
+                let stored = value.len();
+                let result = column_family(db, MESSAGES_CF).and_then(|cf| {
+                    db.put_cf_opt(cf, key, value, &self.write_options())
+                        .map_err(|e| format!("Failed to write to RocksDB: {:?}", e))
+                });
+                self.record_writes(&result, 1);
+                result?;
+                self.record_bytes(logical, stored);
+                Ok(())
+            } else {
+                Err("Database is not initialized".into())
+            }
+        }
+
+        /*
+          Write a set of message binaries and deep hashes
+          in a single atomic RocksDB write, used by the
+          batch writer instead of one put per message.
+        */
+        pub async fn save_batch(
+            &self,
+            binaries: Vec<(String, Option<String>, String, String, Vec<u8>)>,
+            deep_hashes: Vec<(String, String)>,
+        ) -> Result<(), String> {
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                let messages_cf = column_family(db, MESSAGES_CF)?;
+                let deephashes_cf = column_family(db, DEEPHASHES_CF)?;
+                let mut batch = WriteBatch::default();
+                let count = binaries.len() as u64;
+                let zstd_level = self.zstd_level();
+                let (mut logical, mut stored) = (0, 0);
+                for (message_id, assignment_id, process_id, timestamp, binary) in binaries {
+                    let key =
+                        ByteStore::create_key(&message_id, &assignment_id, &process_id, &timestamp);
+                    logical += binary.len();
+                    let value = encode_value(binary, zstd_level);
+                    stored += value.len();
+                    batch.put_cf(messages_cf, key, value);
+                }
+                for (process_id, deep_hash) in deep_hashes {
+                    let key = format!("deephash___{}___{}", process_id, deep_hash).into_bytes();
+                    batch.put_cf(deephashes_cf, key, process_id.into_bytes());
+                }
+                let result = db.write_opt(batch, &self.write_options());
+                self.record_writes(&result, count);
+                result.map_err(|e| format!("Failed to write batch to RocksDB: {:?}", e))?;
+                self.record_bytes(logical, stored);
+                Ok(())
+            } else {
+                Err("Database is not initialized".into())
+            }
+        }
+
+        pub async fn delete_binary(
+            &self,
+            message_id: String,
+            assignment_id: Option<String>,
+            process_id: String,
+            timestamp: String,
+        ) -> Result<(), String> {
+            let key = ByteStore::create_key(&message_id, &assignment_id, &process_id, &timestamp);
+            let db = self.db.read().await;
+        
+            if let Some(ref db) = *db {
+                let mut batch = WriteBatch::default();
+                delete_with_fallback(&mut batch, db, MESSAGES_CF, &key);
+                db.write_opt(batch, &self.write_options())
+                    .map_err(|e| format!("Failed to delete from RocksDB: {:?}", e))?;
+                self.deletes.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            } else {
+                Err("Database is not initialized".into())
+            }
+        }
+      
+
+        /*
+          Mark an entry deleted without removing it, read_binaries
+          treats it as a miss. exists still sees the key so the
+          tail sync doesn't write the bundle back over it.
+        */
+        pub async fn tombstone_binary(
+            &self,
+            message_id: String,
+            assignment_id: Option<String>,
+            process_id: String,
+            timestamp: String,
+        ) -> Result<(), String> {
+            let key = ByteStore::create_key(&message_id, &assignment_id, &process_id, &timestamp);
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                /*
+                  Written to the messages column family even
+                  if the value is in the default one, it is
+                  read first so the tombstone hides the value.
+                */
+                db.put_cf_opt(
+                    column_family(db, MESSAGES_CF)?,
+                    key,
+                    TOMBSTONE,
+                    &self.write_options(),
+                )
+                .map_err(|e| format!("Failed to write tombstone to RocksDB: {:?}", e))?;
+                self.deletes.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            } else {
+                Err("Database is not initialized".into())
+            }
+        }
+
+        fn create_key(
+            message_id: &str,
+            assignment_id: &Option<String>,
+            process_id: &str,
+            timestamp: &str,
+        ) -> Vec<u8> {
+            match assignment_id {
+                Some(assignment_id) => format!(
+                    "message___{}___{}___{}___{}",
+                    process_id, timestamp, message_id, assignment_id
+                )
+                .into_bytes(),
+                None => format!("message___{}___{}___{}", process_id, timestamp, message_id)
+                    .into_bytes(),
+            }
+        }
+
+        pub async fn exists(
+            &self,
+            message_id: &str,
+            assignment_id: &Option<String>,
+            process_id: &str,
+            timestamp: &str,
+        ) -> bool {
+            let key = ByteStore::create_key(message_id, assignment_id, process_id, timestamp);
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                let hit = matches!(get_with_fallback(db, MESSAGES_CF, &key), Ok(Some(_)));
+                self.record_read(hit);
+                hit
+            } else {
+                false
+            }
+        }
+
+        pub async fn save_deep_hash(
+            &self,
+            process_id: &String,
+            deep_hash: &String,
+        ) -> Result<(), String> {
+            let key = format!("deephash___{}___{}", process_id, deep_hash).into_bytes();
+
+            let value = format!("{}", process_id).into_bytes();
+
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                db.put_cf_opt(
+                    column_family(db, DEEPHASHES_CF)?,
+                    key,
+                    value,
+                    &self.write_options(),
+                )
+                .map_err(|e| format!("Failed to write to RocksDB: {:?}", e))?;
+                Ok(())
+            } else {
+                Err("Database is not initialized".into())
+            }
+        }
+
+        pub async fn delete_deep_hash(
+            &self,
+            process_id: &String,
+            deep_hash: &String,
+        ) -> Result<(), String> {
+            let key = format!("deephash___{}___{}", process_id, deep_hash).into_bytes();
+        
+            let db = self.db.read().await;
+        
+            if let Some(ref db) = *db {
+                let mut batch = WriteBatch::default();
+                delete_with_fallback(&mut batch, db, DEEPHASHES_CF, &key);
+                db.write_opt(batch, &self.write_options())
+                    .map_err(|e| format!("Failed to delete from RocksDB: {:?}", e))?;
+                Ok(())
+            } else {
+                Err("Database is not initialized".into())
+            }
+        }
+      
+
+        pub async fn save_deep_hash_version(
+            &self,
+            process_id: &String,
+            version: &String,
+        ) -> Result<(), String> {
+            let key = format!("deephashversion___{}", process_id).into_bytes();
+
+            let value = format!("{}", version).into_bytes();
+
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                db.put_cf_opt(
+                    column_family(db, DEEPHASHES_CF)?,
+                    key,
+                    value,
+                    &self.write_options(),
+                )
+                .map_err(|e| format!("Failed to write to RocksDB: {:?}", e))?;
+                Ok(())
+            } else {
+                Err("Database is not initialized".into())
+            }
+        }
+
+        pub async fn get_deep_hash_version(&self, process_id: &String) -> Result<String, String> {
+            let key = format!("deephashversion___{}", process_id).into_bytes();
+
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                match get_with_fallback(db, DEEPHASHES_CF, &key) {
+                    Ok(Some(v)) => match String::from_utf8(v) {
+                        Ok(vs) => Ok(vs),
+                        Err(_) => Err("Error parsing deep hash version".to_string()),
+                    },
+                    _ => return Err("No deephash version found".to_string()),
+                }
+            } else {
+                return Err("Cannot acquire read lock, deep hash version, match".to_string());
+            }
+        }
+
+        pub async fn get_deep_hashes(&self, process_id: &str) -> Result<Vec<String>, String> {
+            let db = self.db.read().await;
+            match *db {
+                Some(ref db) => process_deep_hashes(db, process_id),
+                None => Err("Database is not initialized".to_string()),
+            }
+        }
+
+        /*
+          Whether this instance is configured to keep a
+          bytestore at all, as opposed to is_ready which
+          says if it is connected right now.
+        */
+        pub fn is_enabled(&self) -> bool {
+            self.config.use_disk
+        }
+
+        /*
+          Delete every bytestore entry for a process in one
+          WriteBatch. The batch is atomic so this only needs
+          the read lock, holding the write lock would make
+          is_ready false for every other process until the
+          purge finished. The caller holds the process's
+          scheduler lock so nothing is written for it while
+          its keys are being removed. Returns the number of
+          keys deleted.
+        */
+        pub async fn prune_by_process(&self, process_id: &str) -> Result<u64, String> {
+            let db = self.db.read().await;
+            let db = match *db {
+                Some(ref db) => db,
+                None => return Err("Database is not initialized".to_string()),
+            };
+
+            let deleted = prune_process_keys(db, process_id, &self.write_options())?;
+            self.deletes.fetch_add(deleted, Ordering::Relaxed);
+            Ok(deleted)
+        }
+
+        /*
+          Scan up to limit message keys starting at start and
+          return the legacy ones among them, along with the
+          key to start the next scan at, None once the scan
+          has passed the last message key. Legacy keys were
+          all written before the column families existed, so
+          only the default one is scanned.
+        */
+        pub async fn scan_legacy_message_keys(
+            &self,
+            start: &[u8],
+            limit: usize,
+        ) -> Result<(Vec<LegacyMessageKey>, Option<Vec<u8>>), String> {
+            let db = self.db.read().await;
+            let db = match *db {
+                Some(ref db) => db,
+                None => return Err("Database is not initialized".to_string()),
+            };
+
+            let mut legacy = Vec::new();
+            let mut scanned = 0;
+            for item in db.iterator(IteratorMode::From(start, Direction::Forward)) {
+                let (key, _) = item.map_err(|e| format!("Failed to iterate RocksDB: {:?}", e))?;
+                if !key.starts_with(MESSAGE_KEY_PREFIX.as_bytes()) {
+                    return Ok((legacy, None));
+                }
+                if scanned == limit {
+                    return Ok((legacy, Some(key.to_vec())));
+                }
+                scanned += 1;
+                if let Some(legacy_key) = parse_legacy_message_key(&key) {
+                    legacy.push(legacy_key);
+                }
+            }
+            Ok((legacy, None))
+        }
+
+        /*
+          Move each legacy key to the key with its assignment
+          id in the messages column family in one WriteBatch,
+          so an entry is never under both keys or neither.
+          The stored value is moved as is. Returns the number
+          of keys moved.
+        */
+        pub async fn rekey_messages(
+            &self,
+            moves: Vec<(LegacyMessageKey, String)>,
+        ) -> Result<u64, String> {
+            let db = self.db.read().await;
+            let db = match *db {
+                Some(ref db) => db,
+                None => return Err("Database is not initialized".to_string()),
+            };
+
+            let messages_cf = column_family(db, MESSAGES_CF)?;
+            let mut batch = WriteBatch::default();
+            let mut moved = 0;
+            for (legacy, assignment_id) in moves {
+                let value = match db.get(&legacy.key) {
+                    Ok(Some(value)) => value,
+                    Ok(None) => continue,
+                    Err(e) => return Err(format!("Failed to read from RocksDB: {:?}", e)),
+                };
+                let new_key = ByteStore::create_key(
+                    &legacy.message_id,
+                    &Some(assignment_id),
+                    &legacy.process_id,
+                    &legacy.timestamp,
+                );
+                batch.put_cf(messages_cf, new_key, value);
+                batch.delete(&legacy.key);
+                moved += 1;
+            }
+
+            db.write_opt(batch, &self.write_options())
+                .map_err(|e| format!("Failed to write to RocksDB: {:?}", e))?;
+            Ok(moved)
+        }
+
+        /*
+          The deep hashes that are saved for the process,
+          read with one multi_get. A key that can't be read
+          counts as missing.
+        */
+        pub async fn existing_deep_hashes(
+            &self,
+            process_id: &String,
+            deep_hashes: &[String],
+        ) -> Vec<String> {
+            let keys: Vec<Vec<u8>> = deep_hashes
+                .iter()
+                .map(|deep_hash| format!("deephash___{}___{}", process_id, deep_hash).into_bytes())
+                .collect();
+
+            let db = self.db.read().await;
+
+            if let Some(ref db) = *db {
+                deep_hashes
+                    .iter()
+                    .zip(multi_get_with_fallback(db, DEEPHASHES_CF, &keys))
+                    .filter(|(_, value)| matches!(value, Ok(Some(_))))
+                    .map(|(deep_hash, _)| deep_hash.clone())
+                    .collect()
+            } else {
+                vec![]
+            }
+        }
+    }
+}
+
+/*
+  Stands in for the bytestore when the su is built
+  without the disk-store feature. It is never ready,
+  so every caller takes its postgres path, and the
+  operations that only exist for the bytestore fail
+  saying the feature is missing.
+*/
+#[cfg(not(feature = "disk-store"))]
+mod bytestore {
+    use super::super::super::config::AoConfig;
+    use super::super::super::core::dal::{
+        ByteStoreCheckpoint, ByteStoreCompaction, ByteStoreCounters, ByteStoreStatistics, Log,
+    };
+    use dashmap::DashMap;
+    use data_encoding::HEXLOWER;
+    use sha2::{Digest, Sha256};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const NO_DISK_STORE: &str = "The su was built without the disk-store feature";
+
+    pub struct ByteStore {
+        bulk_writers: AtomicUsize,
+        corrupt_reads: AtomicU64,
+    }
+
+    pub struct BulkWriteGuard<'a>(&'a AtomicUsize);
+
+    impl Drop for BulkWriteGuard<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    pub type ReadBinaries = DashMap<(String, Option<String>, String, String), Vec<u8>>;
+
+    /*
+      These two are only named in signatures and
+      matched on, the stub never makes one.
+    */
+    #[allow(dead_code)]
+    pub struct LegacyMessageKey {
+        pub key: Vec<u8>,
+        pub process_id: String,
+        pub timestamp: String,
+        pub message_id: String,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum DiskLevel {
+        Ok,
+        Soft,
+        Hard,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum WalSyncMode {
+        Sync,
+        Async,
+        Disable,
+    }
+
+    impl WalSyncMode {
+        pub fn from_config(mode: &str) -> Self {
+            match mode {
+                "async" => WalSyncMode::Async,
+                "disable" => WalSyncMode::Disable,
+                _ => WalSyncMode::Sync,
+            }
+        }
+    }
+
+    /*
+      Still needed without a bytestore, postgres keeps
+      the checksum of every bundle.
+    */
+    pub fn bundle_checksum(bundle: &[u8]) -> String {
+        HEXLOWER.encode(&Sha256::digest(bundle))
+    }
+
+    pub fn checksum_matches(bundle: &[u8], checksum: &Option<String>) -> bool {
+        match checksum {
+            Some(expected) => bundle_checksum(bundle) == *expected,
+            None => true,
+        }
+    }
+
+    pub fn restore_checkpoint(
+        _checkpoint: &str,
+        _data_dir: &str,
+        _force: bool,
+        _logger: &Arc<dyn Log>,
+    ) -> Result<(), String> {
+        Err(NO_DISK_STORE.to_string())
+    }
+
+    // nothing is ever opened in SU_DATA_DIR without the feature
+    pub fn validate_data_dir(_config: &AoConfig, _writable: bool) -> Result<(), String> {
+        Ok(())
+    }
+
+    impl ByteStore {
+        pub fn new(_config: AoConfig, _logger: Arc<dyn Log>) -> Self {
+            ByteStore {
+                bulk_writers: AtomicUsize::new(0),
+                corrupt_reads: AtomicU64::new(0),
+            }
+        }
+
+        pub fn begin_bulk_write(&self) -> BulkWriteGuard<'_> {
+            self.bulk_writers.fetch_add(1, Ordering::SeqCst);
+            BulkWriteGuard(&self.bulk_writers)
+        }
+
+        pub fn is_bulk_writing(&self) -> bool {
+            self.bulk_writers.load(Ordering::SeqCst) > 0
+        }
+
+        pub fn is_degraded(&self) -> bool {
+            false
+        }
+
+        pub fn disk_usage(&self) -> Option<(f64, DiskLevel)> {
+            None
+        }
+
+        pub fn check_disk(&self) -> Result<Option<bool>, String> {
+            Ok(None)
+        }
+
+        pub fn counters(&self) -> ByteStoreCounters {
+            ByteStoreCounters {
+                corrupt_reads: self.corrupt_reads.load(Ordering::Relaxed),
+                ..Default::default()
+            }
+        }
+
+        pub fn record_corrupt_read(&self) {
+            self.corrupt_reads.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub async fn try_connect(&self) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn try_read_instance_connect(&self) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub fn is_ready(&self) -> bool {
+            false
+        }
+
+        pub fn is_enabled(&self) -> bool {
+            false
+        }
+
+        pub async fn flush(&self) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub fn get_statistics(&self) -> Result<ByteStoreStatistics, String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub fn compact_range(&self, _prefix: Option<&str>) -> Result<ByteStoreCompaction, String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn create_checkpoint(&self, _path: &str) -> Result<ByteStoreCheckpoint, String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn read_binaries(
+            &self,
+            _ids: Vec<(String, Option<String>, String, String)>,
+        ) -> Result<ReadBinaries, String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn save_binary(
+            &self,
+            _message_id: String,
+            _assignment_id: Option<String>,
+            _process_id: String,
+            _timestamp: String,
+            _binary: Vec<u8>,
+        ) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn save_batch(
+            &self,
+            _binaries: Vec<(String, Option<String>, String, String, Vec<u8>)>,
+            _deep_hashes: Vec<(String, String)>,
+        ) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn delete_binary(
+            &self,
+            _message_id: String,
+            _assignment_id: Option<String>,
+            _process_id: String,
+            _timestamp: String,
+        ) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn tombstone_binary(
+            &self,
+            _message_id: String,
+            _assignment_id: Option<String>,
+            _process_id: String,
+            _timestamp: String,
+        ) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn exists(
+            &self,
+            _message_id: &str,
+            _assignment_id: &Option<String>,
+            _process_id: &str,
+            _timestamp: &str,
+        ) -> bool {
+            false
+        }
+
+        pub async fn save_deep_hash(
+            &self,
+            _process_id: &String,
+            _deep_hash: &String,
+        ) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn delete_deep_hash(
+            &self,
+            _process_id: &String,
+            _deep_hash: &String,
+        ) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn save_deep_hash_version(
+            &self,
+            _process_id: &String,
+            _version: &String,
+        ) -> Result<(), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn get_deep_hash_version(&self, _process_id: &String) -> Result<String, String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn get_deep_hashes(&self, _process_id: &str) -> Result<Vec<String>, String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn prune_by_process(&self, _process_id: &str) -> Result<u64, String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn scan_legacy_message_keys(
+            &self,
+            _start: &[u8],
+            _limit: usize,
+        ) -> Result<(Vec<LegacyMessageKey>, Option<Vec<u8>>), String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn rekey_messages(
+            &self,
+            _moves: Vec<(LegacyMessageKey, String)>,
+        ) -> Result<u64, String> {
+            Err(NO_DISK_STORE.to_string())
+        }
+
+        pub async fn existing_deep_hashes(
+            &self,
+            _process_id: &String,
+            _deep_hashes: &[String],
+        ) -> Vec<String> {
+            vec![]
+        }
+    }
+}
+
+/*
+  Keeps the process caches of several su instances
+  on the same database in step. A process write sends
+  a notification naming the process on the connection
+  that made it, and each instance holds one extra
+  connection that LISTENs on the channel and evicts
+  whatever process it names from its cache.
+*/
+mod cache_invalidation {
+    use super::super::super::core::dal::{Log, StoreErrorType};
+    use super::InMemoryCache;
+    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
+    use diesel::sql_types::Text;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::Handle;
+
+    pub const CHANNEL: &str = "su_cache_invalidation";
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+    const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /*
+      Inside a transaction postgres holds the notification
+      until commit, so a rolled back write evicts nothing.
     */
-    pub fn get_message_by_offset_from_end(
-        &self,
-        offset: i64,
-    ) -> Result<
-        Option<(
-            String,
-            Option<String>,
-            Vec<u8>,
-            String,
-            serde_json::Value,
-            String,
-        )>,
-        StoreErrorType,
-    > {
-        use super::schema::messages::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+    pub fn notify(conn: &mut PgConnection, process_id: &str) -> Result<(), StoreErrorType> {
+        diesel::sql_query("SELECT pg_notify($1, $2)")
+            .bind::<Text, _>(CHANNEL)
+            .bind::<Text, _>(process_id)
+            .execute(conn)?;
+        Ok(())
+    }
 
-        let db_message_result: Result<Option<DbMessage>, DieselError> = messages
-            .order(timestamp.desc())
-            .offset(offset)
-            .first(conn)
-            .optional();
+    /*
+      Runs for the life of the su on a blocking thread
+      since diesel connections are synchronous. Each time
+      the connection drops it is re-established with an
+      exponential backoff.
+    */
+    pub fn spawn_listener(database_url: String, cache: Arc<InMemoryCache>, logger: Arc<dyn Log>) {
+        let runtime = match Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => {
+                logger.error("Cache invalidation needs a tokio runtime, not listening".to_string());
+                return;
+            }
+        };
 
-        match db_message_result {
-            Ok(Some(db_message)) => {
-                let bytes: Vec<u8> = db_message.bundle.clone();
-                Ok(Some((
-                    db_message.message_id.clone(),
-                    db_message.assignment_id.clone(),
-                    bytes,
-                    db_message.process_id.clone(),
-                    db_message.message_data.clone(),
-                    db_message.timestamp.to_string().clone(),
-                )))
+        tokio::task::spawn_blocking(move || {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                if let Err(e) = listen(&database_url, &cache, &logger, &runtime, &mut backoff) {
+                    logger.error(format!(
+                        "Cache invalidation listener failed, retrying in {:?}: {}",
+                        backoff, e
+                    ));
+                }
+                std::thread::sleep(backoff);
+                backoff = next_backoff(backoff);
             }
-            Ok(None) => Ok(None),
-            Err(e) => Err(StoreErrorType::from(e)),
-        }
+        });
+    }
+
+    pub fn next_backoff(backoff: Duration) -> Duration {
+        (backoff * 2).min(MAX_BACKOFF)
     }
 
     /*
-      Start at the end of the messages table, scan
-      backwards and insert messages into the bytestore
-      if they dont exist. Run at server startup to
-      sync the bytestore if USE_DISK is true.
+      Only returns once the connection is lost. Anything
+      cached while nobody was listening may have missed
+      its eviction, so the whole cache is dropped each
+      time the LISTEN goes through.
     */
-    pub fn sync_bytestore(&self) -> Result<(), ()> {
+    fn listen(
+        database_url: &str,
+        cache: &InMemoryCache,
+        logger: &Arc<dyn Log>,
+        runtime: &Handle,
+        backoff: &mut Duration,
+    ) -> Result<(), String> {
+        let mut conn = PgConnection::establish(database_url)
+            .map_err(|e| format!("Failed to connect: {:?}", e))?;
+        diesel::sql_query(format!("LISTEN {}", CHANNEL))
+            .execute(&mut conn)
+            .map_err(|e| format!("Failed to LISTEN: {:?}", e))?;
+
+        *backoff = MIN_BACKOFF;
+        runtime.block_on(cache.clear_processes());
+        logger.log(format!("Listening for cache invalidations on {}", CHANNEL));
+
         /*
-          if self.bytestore.clone().try_connect() is never
-          called, the is_ready method on the byte store will
-          never return true, and the rest of the StoreClient
-          will not read or write bytestore.
-
-          We call it here because this runs the in background.
-          So the server can operate normally without bytestore
-          until bytestore can be initialized. This is in case
-          another program is still using the same embedded db.
+          Reading notifications only fails once the server
+          has closed the socket, the probe catches a
+          connection that went away without saying so.
         */
+        let mut last_probe = Instant::now();
         loop {
-            match self.bytestore.clone().try_connect() {
-                Ok(_) => {
-                    break;
-                }
-                Err(_) => {
-                    self.logger
-                        .log("Bytestore not ready, waiting...".to_string());
-                    std::thread::sleep(std::time::Duration::from_secs(5));
-                }
+            let mut process_ids = vec![];
+            for notification in conn.notifications_iter() {
+                let notification =
+                    notification.map_err(|e| format!("Lost the connection: {:?}", e))?;
+                process_ids.push(notification.payload);
             }
-        }
-        self.logger
-            .log("Syncing the tail of the messages table".to_string());
-        use std::time::Instant;
-        let start = Instant::now();
-
-        let total_count = self
-            .get_message_count()
-            .expect("Failed to get message count");
-        let mut synced_count = 0;
-
-        for offset in 0..total_count {
-            let result = self.get_message_by_offset_from_end(offset);
-
-            match result {
-                Ok(Some(message)) => {
-                    let msg_id = message.0;
-                    let assignment_id = message.1;
-                    let bundle = message.2;
-                    let process_id = message.3;
-                    let timestamp = message.5;
-
-                    /*
-                      we would want to panic here if trying to
-                      call this without initializing the bytestore
-                    */
-                    if self.bytestore.clone().exists(
-                        &msg_id,
-                        &assignment_id,
-                        &process_id,
-                        &timestamp,
-                    ) {
-                        // Stop the migration if message is already in byte store
-                        let duration = start.elapsed();
-                        self.logger
-                            .log(format!("Time elapsed in sync is: {:?}", duration));
-                        self.logger
-                            .log(format!("Number of messages synced: {}", synced_count));
-                        return Ok(());
-                    }
-
-                    self.bytestore
-                        .clone()
-                        .save_binary(
-                            msg_id.clone(),
-                            assignment_id.clone(),
-                            process_id.clone(),
-                            timestamp.clone(),
-                            bundle,
-                        )
-                        .expect("Failed to save message binary");
-
-                    synced_count += 1;
-                }
-                Ok(None) => {
-                    self.logger.log(format!("No more messages to process."));
-                    break;
-                }
-                Err(e) => {
-                    self.logger
-                        .error(format!("Error fetching messages: {:?}", e));
-                }
+            for process_id in process_ids {
+                runtime.block_on(cache.remove_process(process_id));
             }
-        }
 
-        let duration = start.elapsed();
-        self.logger
-            .log(format!("Time elapsed in sync is: {:?}", duration));
-        self.logger
-            .log(format!("Number of messages synced: {}", synced_count));
+            if last_probe.elapsed() >= PROBE_INTERVAL {
+                diesel::sql_query("SELECT 1")
+                    .execute(&mut conn)
+                    .map_err(|e| format!("Lost the connection: {:?}", e))?;
+                last_probe = Instant::now();
+            }
 
-        Ok(())
+            std::thread::sleep(POLL_INTERVAL);
+        }
     }
 }
 
 /*
-  The DataStore trait is what the business logic uses
-  to interact with the data storage layer. The implementations
-  can change here but the function definitions cannot unless
-  the business logic needs them to.
+  Opt in write path for save_message, enabled with
+  WRITE_BATCHING. Callers push prepared messages onto
+  a bounded channel and await their own result, a
+  background task drains the channel every
+  WRITE_BATCH_INTERVAL_MS or WRITE_BATCH_MAX items and
+  writes the whole batch with one RocksDB WriteBatch and
+  one multi row insert.
 */
-#[async_trait]
-impl DataStore for StoreClient {
-    fn save_process(&self, process: &Process, bundle_in: &[u8]) -> Result<String, StoreErrorType> {
-        use super::schema::processes::dsl::*;
-        let conn = &mut self.get_conn()?;
+mod batch_writer {
+    use std::future::Future;
+    use std::sync::{Arc, Mutex as StdMutex};
+    use std::time::Duration;
 
-        let (process_epoch, process_hash_chain, process_timestamp, process_nonce) =
-            match self.enable_process_assignment {
-                true => (
-                    process.epoch().ok(),
-                    process.hash_chain().ok(),
-                    process.timestamp().ok(),
-                    process.nonce().ok(),
-                ),
-                false => (None, None, None, None),
-            };
+    use dashmap::DashMap;
+    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::task::JoinHandle;
+    use tokio::time::{timeout_at, Instant};
+
+    use super::super::super::core::dal::{Log, Message, StoreErrorType};
+    use super::bytestore::{bundle_checksum, ByteStore};
+    use super::{assignment_saved, insert_message_error, NewMessage};
+
+    pub struct PendingMessage {
+        process_id: String,
+        message_id: String,
+        assignment_id: String,
+        message_data: serde_json::Value,
+        epoch: i32,
+        nonce: i32,
+        timestamp: i64,
+        bundle: Vec<u8>,
+        hash_chain: String,
+        deep_hash: Option<String>,
+    }
 
-        let new_process = NewProcess {
-            process_id: &process.process.process_id,
-            process_data: serde_json::to_value(process).expect("Failed to serialize Process"),
-            bundle: bundle_in,
-            epoch: process_epoch,
-            hash_chain: process_hash_chain.as_deref(),
-            nonce: process_nonce,
-            timestamp: process_timestamp,
-        };
+    impl PendingMessage {
+        pub fn new(
+            message: &Message,
+            bundle_in: &[u8],
+            deep_hash: Option<&String>,
+        ) -> Result<Self, StoreErrorType> {
+            Ok(PendingMessage {
+                process_id: message.process_id()?,
+                message_id: message.message_id()?,
+                assignment_id: message.assignment_id()?,
+                message_data: serde_json::to_value(message)?,
+                epoch: message.epoch()?,
+                nonce: message.nonce()?,
+                timestamp: message.timestamp()?,
+                bundle: bundle_in.to_vec(),
+                hash_chain: message.hash_chain()?,
+                deep_hash: deep_hash.cloned(),
+            })
+        }
 
-        match diesel::insert_into(processes)
-            .values(&new_process)
-            .on_conflict(process_id)
-            .do_nothing()
-            .execute(conn)
-        {
-            Ok(_) => Ok("saved".to_string()),
-            Err(e) => Err(StoreErrorType::from(e)),
+        fn new_message(&self) -> NewMessage<'_> {
+            NewMessage {
+                process_id: &self.process_id,
+                message_id: &self.message_id,
+                assignment_id: &self.assignment_id,
+                message_data: self.message_data.clone(),
+                epoch: &self.epoch,
+                nonce: &self.nonce,
+                timestamp: &self.timestamp,
+                bundle: &self.bundle,
+                hash_chain: &self.hash_chain,
+                bundle_checksum: Some(bundle_checksum(&self.bundle)),
+            }
         }
     }
 
-    async fn get_process(&self, process_id_in: &str) -> Result<Process, StoreErrorType> {
-        if let Some(cached_process) = self
-            .in_memory_cache
-            .get_process(process_id_in.to_string())
-            .await
-        {
-            return Ok(cached_process);
+    struct QueuedMessage {
+        message: PendingMessage,
+        respond: oneshot::Sender<Result<String, StoreErrorType>>,
+    }
+
+    /*
+      A message that is in the queue, its result arrives
+      once the batch it is in has been written.
+    */
+    pub struct Queued(oneshot::Receiver<Result<String, StoreErrorType>>);
+
+    impl Queued {
+        pub async fn result(self) -> Result<String, StoreErrorType> {
+            match self.0.await {
+                Ok(r) => r,
+                Err(_) => Err(StoreErrorType::database(
+                    "Batch writer dropped the message".to_string(),
+                )),
+            }
         }
+    }
 
-        use super::schema::processes::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+    pub struct BatchWriter {
+        sender: StdMutex<Option<mpsc::Sender<QueuedMessage>>>,
+        handle: StdMutex<Option<JoinHandle<()>>>,
+        reserved: Arc<DashMap<String, i32>>,
+    }
 
-        let db_process_result: Result<Option<DbProcess>, DieselError> = processes
-            .filter(process_id.eq(process_id_in))
-            .first(conn)
-            .optional();
+    impl BatchWriter {
+        /*
+          Must be called from within the tokio runtime
+          because it spawns the flushing task.
+        */
+        pub fn new(
+            pool: Pool<ConnectionManager<PgConnection>>,
+            bytestore: Arc<ByteStore>,
+            logger: Arc<dyn Log>,
+            batch_max: usize,
+            interval_ms: u64,
+        ) -> Self {
+            BatchWriter::with_writer(batch_max, interval_ms, move |pending| {
+                write_batch(pending, pool.clone(), bytestore.clone(), logger.clone())
+            })
+        }
 
-        match db_process_result {
-            Ok(Some(db_process)) => {
-                let process: Process = Process::from_val(&db_process.process_data)?;
-                self.in_memory_cache
-                    .insert_process(process_id_in.to_string(), process.clone())
-                    .await;
-                Ok(process)
+        /*
+          The queue and flushing task with write doing the
+          writing of each batch, new passes the postgres and
+          bytestore one.
+        */
+        pub fn with_writer<W, Fut>(batch_max: usize, interval_ms: u64, write: W) -> Self
+        where
+            W: Fn(Vec<PendingMessage>) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = Vec<Result<String, StoreErrorType>>> + Send + 'static,
+        {
+            let batch_max = batch_max.max(1);
+            /*
+              Bounded so a stalled database pushes back on
+              callers instead of queueing without limit.
+            */
+            let (sender, receiver) = mpsc::channel(batch_max * 4);
+            let reserved = Arc::new(DashMap::new());
+            let handle = tokio::spawn(run(
+                receiver,
+                reserved.clone(),
+                batch_max,
+                Duration::from_millis(interval_ms),
+                write,
+            ));
+
+            BatchWriter {
+                sender: StdMutex::new(Some(sender)),
+                handle: StdMutex::new(Some(handle)),
+                reserved,
             }
-            Ok(None) => Err(StoreErrorType::NotFound("Process not found".to_string())),
-            Err(e) => Err(StoreErrorType::from(e)),
         }
-    }
 
-    /*
-        If we are trying to write an actual data item
-        not just an assignment we need to check that it
-        doesnt already exist.
-    */
-    fn check_existing_message(&self, message_id: &String) -> Result<(), StoreErrorType> {
-        match self.get_message(&message_id) {
-            Ok(parsed) => {
-                /*
-                    If the message already exists and it contains
-                    an actual message (it is not just an assignment)
-                    then throw an error to avoid duplicate data items
-                    being written
-                */
-                match parsed.message {
-                    Some(_) => Err(StoreErrorType::MessageExists(
-                        "Message already exists".to_string(),
-                    )),
-                    /*
-                      this is an assignment so its ok, although currently
-                      this method is not used to check the assingment ids
-                      this is still here in case someone calls it with
-                      the assignment id in the future
-                    */
-                    None => Ok(()),
-                }
+        /*
+          Queue a message without waiting for it to be
+          written. Its nonce stays reserved until the batch
+          it is in has been written, so the next save for
+          the process is checked against it and the caller
+          only needs the process lock until this returns.
+        */
+        pub async fn enqueue(&self, message: PendingMessage) -> Result<Queued, StoreErrorType> {
+            let sender = match self.sender.lock() {
+                Ok(s) => (*s).clone(),
+                Err(_) => None,
+            };
+            let sender = sender
+                .ok_or_else(|| StoreErrorType::database("Batch writer is shut down".to_string()))?;
+
+            let (process_id, nonce) = (message.process_id.clone(), message.nonce);
+            self.reserved.insert(process_id.clone(), nonce);
+
+            let (respond, result) = oneshot::channel();
+            if sender.send(QueuedMessage { message, respond }).await.is_err() {
+                self.reserved
+                    .remove_if(&process_id, |_, reserved| *reserved == nonce);
+                return Err(StoreErrorType::database(
+                    "Batch writer is not running".to_string(),
+                ));
             }
-            // The message wasnt found at all so it can be written
-            Err(StoreErrorType::NotFound(_)) => Ok(()),
-            // Some other error happened
-            Err(_) => Err(StoreErrorType::DatabaseError(
-                "Error checking message".to_string(),
-            )),
+            Ok(Queued(result))
         }
-    }
 
-    async fn check_existing_deep_hash(
-        &self,
-        process_id: &String,
-        deep_hash: &String,
-    ) -> Result<(), StoreErrorType> {
-        if self.bytestore.is_ready() {
-            match self.bytestore.deep_hash_exists(process_id, deep_hash) {
-                true => {
-                    return Err(StoreErrorType::MessageExists(
-                        "Deep hash already exists".to_string(),
-                    ))
-                }
-                false => return Ok(()),
+        // the highest nonce queued for a process that isn't written yet
+        pub fn reserved_nonce(&self, process_id: &str) -> Option<i32> {
+            self.reserved.get(process_id).map(|nonce| *nonce)
+        }
+
+        /*
+          Close the queue and wait for the flushing task
+          to write everything that was already queued.
+        */
+        pub async fn shutdown(&self) {
+            let sender = match self.sender.lock() {
+                Ok(mut s) => s.take(),
+                Err(_) => None,
+            };
+            drop(sender);
+
+            let handle = match self.handle.lock() {
+                Ok(mut h) => h.take(),
+                Err(_) => None,
+            };
+            if let Some(handle) = handle {
+                let _ = handle.await;
             }
         }
-        Ok(())
     }
 
-    async fn get_deephash_version(&self, process_id: &String) -> Result<String, StoreErrorType> {
-        if self.bytestore.is_ready() {
-            if let Ok(dhv) = self.bytestore.get_deep_hash_version(process_id) {
-                return Ok(dhv);
+    async fn run<W, Fut>(
+        mut receiver: mpsc::Receiver<QueuedMessage>,
+        reserved: Arc<DashMap<String, i32>>,
+        batch_max: usize,
+        interval: Duration,
+        write: W,
+    ) where
+        W: Fn(Vec<PendingMessage>) -> Fut,
+        Fut: Future<Output = Vec<Result<String, StoreErrorType>>>,
+    {
+        /*
+          recv keeps returning buffered items after every
+          sender is dropped, so on shutdown this loop
+          drains the queue before it exits.
+        */
+        while let Some(first) = receiver.recv().await {
+            let mut batch = vec![first];
+            let deadline = Instant::now() + interval;
+
+            while batch.len() < batch_max {
+                match timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(queued)) => batch.push(queued),
+                    Ok(None) | Err(_) => break,
+                }
             }
+
+            flush(batch, &reserved, &write).await;
         }
-        Err(StoreErrorType::DatabaseError(
-            "Deep hash version does not exist for this process".to_string(),
-        ))
     }
 
-    async fn save_deephash_version(
-        &self,
-        process_id: &String,
-        version: &String,
-    ) -> Result<(), StoreErrorType> {
-        if self.bytestore.is_ready() {
-            self.bytestore.save_deep_hash_version(process_id, version)?;
+    async fn flush<W, Fut>(batch: Vec<QueuedMessage>, reserved: &DashMap<String, i32>, write: &W)
+    where
+        W: Fn(Vec<PendingMessage>) -> Fut,
+        Fut: Future<Output = Vec<Result<String, StoreErrorType>>>,
+    {
+        let (pending, responders): (Vec<PendingMessage>, Vec<_>) = batch
+            .into_iter()
+            .map(|q| (q.message, q.respond))
+            .unzip();
+        let nonces: Vec<(String, i32)> = pending
+            .iter()
+            .map(|m| (m.process_id.clone(), m.nonce))
+            .collect();
+
+        let results = write(pending).await;
+
+        /*
+          Saved nonces are in postgres now and failed ones
+          are free again, either way only release a
+          reservation a later message hasn't taken over.
+        */
+        for (process_id, nonce) in nonces {
+            reserved.remove_if(&process_id, |_, reserved| *reserved == nonce);
         }
-        Ok(())
-    }
 
-    async fn save_deephash(
-        &self,
-        process_id: &String,
-        deep_hash: &String,
-    ) -> Result<(), StoreErrorType> {
-        if self.bytestore.is_ready() {
-            self.bytestore.save_deep_hash(process_id, deep_hash)?;
+        for (respond, result) in responders.into_iter().zip(results) {
+            let _ = respond.send(result);
         }
-        Ok(())
     }
 
-    async fn save_message(
-        &self,
-        message: &Message,
-        bundle_in: &[u8],
-        deep_hash: Option<&String>,
-    ) -> Result<String, StoreErrorType> {
-        use super::schema::messages::dsl::*;
-        let conn = &mut self.get_conn()?;
-
-        let new_message = NewMessage {
-            process_id: &message.process_id()?,
-            message_id: &message.message_id()?,
-            assignment_id: &message.assignment_id()?,
-            message_data: serde_json::to_value(
-                message
-            ).expect("Failed to serialize Message"),
-            epoch: &message.epoch()?,
-            nonce: &message.nonce()?,
-            timestamp: &message.timestamp()?,
-            bundle: bundle_in,
-            hash_chain: &message.hash_chain()?,
-        };
+    async fn write_batch(
+        pending: Vec<PendingMessage>,
+        pool: Pool<ConnectionManager<PgConnection>>,
+        bytestore: Arc<ByteStore>,
+        logger: Arc<dyn Log>,
+    ) -> Vec<Result<String, StoreErrorType>> {
+        let batch_len = pending.len();
 
         /*
-          This is moved above the sql logic now, so that
-          if it fails, the message doesnt get scheduled
-          if the server has USE_DISK enabled. It can run without
-          the RocksDB records but if the RocksDB saves fail
-          for a long period of time processes will slow
-          down so it is better to fail loud here. and not
-          schedule the message. 
+          Same ordering as the unbatched save_message, the
+          bytestore is written first so a failure there
+          fails loud and nothing gets scheduled.
         */
-        let bytestore = self.bytestore.clone();
-        if bytestore.is_ready() {
-            bytestore.save_binary(
-                message.message_id()?,
-                Some(message.assignment_id()?),
-                message.process_id()?,
-                message.timestamp()?.to_string(),
-                bundle_in.to_vec(),
-            )?;
-            match deep_hash {
-                Some(dh) => {
-                    bytestore.save_deep_hash(
-                        &message.process_id()?, 
-                        dh
-                    )?;
-                }
-                None => (),
-            };
+        let use_bytestore = bytestore.is_ready();
+        if use_bytestore {
+            let binaries = pending
+                .iter()
+                .map(|m| {
+                    (
+                        m.message_id.clone(),
+                        Some(m.assignment_id.clone()),
+                        m.process_id.clone(),
+                        m.timestamp.to_string(),
+                        m.bundle.clone(),
+                    )
+                })
+                .collect();
+            let deep_hashes = pending
+                .iter()
+                .filter_map(|m| m.deep_hash.clone().map(|dh| (m.process_id.clone(), dh)))
+                .collect();
+
+            if let Err(e) = bytestore.save_batch(binaries, deep_hashes).await {
+                return pending
+                    .iter()
+                    .map(|_| Err(StoreErrorType::database(e.clone())))
+                    .collect();
+            }
         }
 
-        let res = match diesel::insert_into(messages)
-            .values(&new_message)
-            .execute(conn)
-        {
-            Ok(row_count) => {
-                if row_count == 0 {
-                    Err(StoreErrorType::DatabaseError(
-                        "Error saving message".to_string(),
-                    )) 
-                } else {
-                    Ok("saved".to_string())
+        /*
+          diesel is blocking so the insert runs on the
+          blocking pool rather than an async worker.
+        */
+        let insert_result = tokio::task::spawn_blocking(move || {
+            let (results, saved) = match pool.get() {
+                Ok(mut conn) => {
+                    let results = insert_messages(&pending, &mut conn);
+                    let saved = pending
+                        .iter()
+                        .zip(results.iter())
+                        .map(|(m, result)| match result {
+                            Err(StoreErrorType::MessageExists(_)) => {
+                                assignment_saved(&mut conn, &m.assignment_id)
+                            }
+                            _ => false,
+                        })
+                        .collect();
+                    (results, saved)
                 }
+                Err(e) => (
+                    pending
+                        .iter()
+                        .map(|_| {
+                            Err(StoreErrorType::database(format!(
+                                "Failed to get connection from pool: {}",
+                                e
+                            )))
+                        })
+                        .collect(),
+                    vec![false; pending.len()],
+                ),
+            };
+            (pending, results, saved)
+        })
+        .await;
+
+        let (pending, results, saved) = match insert_result {
+            Ok(r) => r,
+            Err(e) => {
+                logger.error(format!("Batch writer insert panicked: {:?}", e));
+                return (0..batch_len)
+                    .map(|_| {
+                        Err(StoreErrorType::database(
+                            "Batch writer flush failed".to_string(),
+                        ))
+                    })
+                    .collect();
             }
-            Err(e) => Err(StoreErrorType::from(e)),
         };
 
         /*
-          Clean the message out of the bytestore if the
-          sql insert failed. It would be ok if messages
-          leak into RocksDB that are not in sql because sql
-          controls the schedule, but will avoid it if possible.
+          Clean the failed messages out of the bytestore,
+          same as the unbatched path, a duplicate keeps its
+          deep hash and, if its assignment is saved, its
+          binary.
         */
-        match res {
-          Ok(r) => Ok(r),
-          Err(e) => {
-            if bytestore.is_ready() {
-                bytestore.delete_binary(
-                    message.message_id()?,
-                    Some(message.assignment_id()?),
-                    message.process_id()?,
-                    message.timestamp()?.to_string()
-                )?;
-                match deep_hash {
-                    Some(dh) => {
-                        bytestore.delete_deep_hash(
-                            &message.process_id()?, 
-                            dh
-                        )?;
+        if use_bytestore {
+            for ((m, result), keep_binary) in pending.iter().zip(results.iter()).zip(saved) {
+                let duplicate = matches!(result, Err(StoreErrorType::MessageExists(_)));
+                if result.is_err() && !keep_binary {
+                    let _ = bytestore
+                        .delete_binary(
+                            m.message_id.clone(),
+                            Some(m.assignment_id.clone()),
+                            m.process_id.clone(),
+                            m.timestamp.to_string(),
+                        )
+                        .await;
+                }
+                if result.is_err() && !duplicate {
+                    if let Some(dh) = &m.deep_hash {
+                        let _ = bytestore.delete_deep_hash(&m.process_id, dh).await;
                     }
-                    None => (),
-                };
+                }
             }
-            Err(e)
-          }
         }
-    }
-
-    async fn get_messages(
-        &self,
-        process_in: &Process,
-        from: &Option<String>,
-        to: &Option<String>,
-        limit: &Option<i32>,
-        from_nonce: &Option<String>,
-        to_nonce: &Option<String>,
-    ) -> Result<PaginatedMessages, StoreErrorType> {
-        use super::schema::messages::dsl::*;
-        let conn = &mut self.get_read_conn()?;
-        let mut query = messages
-            .filter(process_id.eq(process_in.process.process_id.clone()))
-            .into_boxed();
-
-        let mut sequence_mode = "timestamp";
 
-        match (from_nonce, to_nonce) {
-            (None, None) => {
-                if let Some(from_timestamp_str) = from {
-                    let from_timestamp = from_timestamp_str
-                        .parse::<i64>()
-                        .map_err(StoreErrorType::from)?;
-                    query = query.filter(timestamp.gt(from_timestamp));
-                }
+        results
+    }
 
-                if let Some(to_timestamp_str) = to {
-                    let to_timestamp = to_timestamp_str
-                        .parse::<i64>()
-                        .map_err(StoreErrorType::from)?;
-                    query = query.filter(timestamp.le(to_timestamp));
-                }
-            }
-            (_, _) => {
-                sequence_mode = "nonce";
+    fn insert_messages(
+        pending: &[PendingMessage],
+        conn: &mut PgConnection,
+    ) -> Vec<Result<String, StoreErrorType>> {
+        use super::super::schema::messages::dsl::messages;
 
-                if let Some(from_nonce_s) = from_nonce {
-                    let f = from_nonce_s.parse::<i32>().map_err(StoreErrorType::from)?;
-                    query = query.filter(nonce.gt(f));
-                }
+        let new_messages: Vec<NewMessage> = pending.iter().map(|m| m.new_message()).collect();
 
-                if let Some(to_nonce_s) = to_nonce {
-                    let t = to_nonce_s.parse::<i32>().map_err(StoreErrorType::from)?;
-                    query = query.filter(nonce.le(t));
-                }
-            }
+        /*
+          A multi row insert is all or nothing, if it fails
+          we can't tell which row caused it so retry the
+          rows one at a time and give each caller its own
+          result.
+        */
+        match diesel::insert_into(messages)
+            .values(&new_messages)
+            .execute(conn)
+        {
+            Ok(_) => new_messages.iter().map(|_| Ok("saved".to_string())).collect(),
+            Err(_) => new_messages
+                .iter()
+                .map(|new_message| {
+                    match diesel::insert_into(messages)
+                        .values(new_message)
+                        .execute(conn)
+                    {
+                        Ok(0) => Err(StoreErrorType::database("Error saving message".to_string())),
+                        Ok(_) => Ok("saved".to_string()),
+                        Err(e) => Err(insert_message_error(e, new_message.assignment_id)),
+                    }
+                })
+                .collect(),
         }
+    }
+}
 
-        // Apply limit, converting Option<i32> to i64 and adding 1 to check for the next page
-        let limit_val = limit.unwrap_or(100) as i64; // Default limit if none is provided
+type MigrationKey = (String, Option<String>, String, String);
 
-        let include_process = match (from_nonce, to_nonce) {
-            // we are dealing with timestamps
-            (None, None) => {
-                process_in.assignment.is_some()
-                    && match from {
-                        Some(_) => false,
-                        None => true,
+/*
+  Read back what a migration batch wrote and return the
+  keys whose value is missing or doesn't match the
+  checksum of its source bundle. Reads go through
+  read_binaries in chunks so they stay under
+  MAX_READ_MEMORY, a chunk that can't be read counts
+  as mismatched.
+*/
+async fn verify_migrated(
+    data_store: &StoreClient,
+    written: &[(MigrationKey, String)],
+) -> Vec<MigrationKey> {
+    const VERIFY_CHUNK_SIZE: usize = 100;
+
+    let mut mismatched = Vec::new();
+    for chunk in written.chunks(VERIFY_CHUNK_SIZE) {
+        let keys: Vec<MigrationKey> = chunk.iter().map(|(key, _)| key.clone()).collect();
+        match data_store.bytestore.read_binaries(keys.clone()).await {
+            Ok(binaries) => {
+                for (key, checksum) in chunk {
+                    let matches = binaries.get(key).map_or(false, |value| {
+                        bytestore::bundle_checksum(value.value()) == *checksum
+                    });
+                    if !matches {
+                        mismatched.push(key.clone());
                     }
+                }
             }
-            // if we are dealing with nonce sequencing
-            (_, _) => {
-                process_in.assignment.is_some()
-                    && match from_nonce {
-                        Some(ref _from_nonce) => {
-                            if _from_nonce.parse::<i32>()? == -1 {
-                                true
-                            } else {
-                                false
-                            }
-                        }
-                        /*
-                          No 'from' means it's the first page
-                        */
-                        None => true,
-                    }
+            Err(e) => {
+                data_store
+                    .logger
+                    .error(format!("Failed to read back a migrated chunk: {}", e));
+                mismatched.extend(keys);
             }
-        };
-
-        // If including the process, reduce the limit for the database query by 1
-        let adjusted_limit_val = if include_process {
-            limit_val - 1
-        } else {
-            limit_val
-        };
-
-        if self.bytestore.clone().is_ready() {
-            let db_messages_result: Result<Vec<DbMessageWithoutData>, DieselError> = query
-                .select((
-                    row_id,
-                    process_id,
-                    message_id,
-                    assignment_id,
-                    epoch,
-                    nonce,
-                    timestamp,
-                    hash_chain,
-                ))
-                .order(timestamp.asc())
-                .limit(adjusted_limit_val + 1) // Fetch one extra record to determine if a next page exists
-                .load(conn);
-
-            match db_messages_result {
-                Ok(db_messages) => {
-                    let has_next_page = db_messages.len() as i64 > adjusted_limit_val;
+        }
+    }
+    mismatched
+}
 
-                    // Take only up to the limit if there's an extra indicating a next page
-                    let messages_o = if has_next_page {
-                        &db_messages[..(adjusted_limit_val as usize)]
-                    } else {
-                        &db_messages[..]
-                    };
+/*
+  Write the mismatched messages of a batch once more from
+  postgres and verify them again, returning the ones
+  that still don't match.
+*/
+async fn retry_mismatched(
+    data_store: &StoreClient,
+    batch: (i64, i64),
+    mismatched: Vec<MigrationKey>,
+) -> Vec<MigrationKey> {
+    let retry: HashSet<MigrationKey> = mismatched.iter().cloned().collect();
+    let rows = match data_store.get_all_messages(batch.0, Some(batch.1)) {
+        Ok(rows) => rows,
+        Err(e) => {
+            data_store.logger.error(format!(
+                "Failed to refetch batch {}-{} for a retry: {:?}",
+                batch.0, batch.1, e
+            ));
+            return mismatched;
+        }
+    };
 
-                    let mut messages_mapped: Vec<Message> = vec![];
+    let mut rewritten = Vec::new();
+    for (msg_id, assignment_id, bundle, process_id, _, timestamp) in rows {
+        let key = (msg_id, assignment_id, process_id, timestamp);
+        if !retry.contains(&key) {
+            continue;
+        }
+        let checksum = bytestore::bundle_checksum(&bundle);
+        let saved = data_store
+            .bytestore
+            .save_binary(
+                key.0.clone(),
+                key.1.clone(),
+                key.2.clone(),
+                key.3.clone(),
+                bundle,
+            )
+            .await;
+        if saved.is_ok() {
+            rewritten.push((key, checksum));
+        }
+    }
 
-                    // Include the process as the first message if determined to be on the first page and has assignment
-                    if include_process {
-                        let process_message = Message::from_process(process_in.clone())?;
-                        messages_mapped.push(process_message);
-                    }
+    let mut still_mismatched = verify_migrated(data_store, &rewritten).await;
+    let rewritten: HashSet<&MigrationKey> = rewritten.iter().map(|(key, _)| key).collect();
+    still_mismatched.extend(
+        mismatched
+            .into_iter()
+            .filter(|key| !rewritten.contains(key)),
+    );
+    still_mismatched
+}
 
-                    // Map database messages to the Message struct
-                    let message_ids: Vec<(String, Option<String>, String, String)> = messages_o
-                        .iter()
-                        .map(|msg| {
-                            (
-                                msg.message_id.clone(),
-                                msg.assignment_id.clone(),
-                                msg.process_id.clone(),
-                                msg.timestamp.to_string().clone(),
-                            )
-                        })
-                        .collect();
+/*
+  The migrations that only work on the bytestore call
+  this first, so a su built without the disk-store
+  feature fails before touching anything.
+*/
+pub fn require_disk_store() -> io::Result<()> {
+    match cfg!(feature = "disk-store") {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This needs the bytestore, rebuild the su with the disk-store feature",
+        )),
+    }
+}
 
-                    let binaries = self.bytestore.clone().read_binaries(message_ids).await?;
+/*
+  Where migrate_to_disk records the offset to resume
+  from, as {"offset": n}. It is written to a temp file
+  and renamed into place so an interrupted write leaves
+  the previous checkpoint rather than a truncated one.
+*/
+const MIGRATION_CHECKPOINT: &str = "migration_checkpoint";
 
-                    for db_message in messages_o.iter() {
-                        match binaries.get(&(
-                            db_message.message_id.clone(),
-                            db_message.assignment_id.clone(),
-                            db_message.process_id.clone(),
-                            db_message.timestamp.to_string().clone(),
-                        )) {
-                            Some(bytes_result) => {
-                                let mapped = Message::from_bytes(bytes_result.clone())?;
-                                messages_mapped.push(mapped);
-                            }
-                            None => {
-                                // Fall back to the database if the binary isn't available
-                                let full_message = self.get_message_internal(
-                                    &db_message.message_id,
-                                    &db_message.assignment_id,
-                                    conn
-                                )?;
-                                messages_mapped.push(full_message);
-                            }
-                        }
-                    }
+fn read_migration_checkpoint(path: &std::path::Path) -> io::Result<Option<i64>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let checkpoint: serde_json::Value = serde_json::from_str(&contents)?;
+    match checkpoint["offset"].as_i64() {
+        Some(offset) => Ok(Some(offset)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No offset in migration checkpoint {:?}", path),
+        )),
+    }
+}
 
-                    // Create paginated result
-                    let paginated = PaginatedMessages::from_messages(
-                        messages_mapped,
-                        has_next_page,
-                        sequence_mode,
-                    )?;
-                    Ok(paginated)
-                }
-                Err(e) => Err(StoreErrorType::from(e)),
-            }
-        } else {
-            let db_messages_result: Result<Vec<DbMessage>, DieselError> = query
-                .order(timestamp.asc())
-                .limit(adjusted_limit_val + 1) // Fetch one extra record to determine if a next page exists
-                .load(conn);
+fn write_migration_checkpoint(path: &std::path::Path, offset: i64) -> io::Result<()> {
+    use std::io::Write;
+    let temp = path.with_extension("tmp");
+    let checkpoint = serde_json::json!({ "offset": offset }).to_string();
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(checkpoint.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)
+}
 
-            match db_messages_result {
-                Ok(db_messages) => {
-                    let has_next_page = db_messages.len() as i64 > adjusted_limit_val;
+/*
+  This function is the migation program will
+  copy all the message data from the database to rocksdb.
+  It is not meant to be run anywhere within the su
+  server itself but is built into its own binary.
+  With --verify each batch is read back after it is
+  written, see verify_migrated.
+
+  After each batch that is fully written the offset
+  after it is saved to SU_DATA_DIR/migration_checkpoint,
+  a rerun starts from there instead of the start of the
+  range unless --ignore-checkpoint is passed. The file
+  is removed once the migration finishes without
+  failures.
+*/
+pub async fn migrate_to_disk() -> io::Result<()> {
+    use std::time::{Duration, Instant};
+    require_disk_store()?;
+    let start = Instant::now();
+    dotenv().ok();
 
-                    // Take only up to the limit if there's an extra indicating a next page
-                    let messages_o = if has_next_page {
-                        &db_messages[..(adjusted_limit_val as usize)]
-                    } else {
-                        &db_messages[..]
-                    };
+    /*
+      --wal-sync-mode overrides BYTESTORE_WAL_SYNC_MODE for
+      this run, it has to be set before the config is read.
+    */
+    let args: Vec<String> = env::args().collect();
+    if let Some(mode) = args
+        .iter()
+        .skip(3)
+        .find_map(|arg| arg.strip_prefix("--wal-sync-mode="))
+    {
+        env::set_var("BYTESTORE_WAL_SYNC_MODE", mode);
+    }
 
-                    let mut messages_mapped: Vec<Message> = vec![];
+    let data_store = Arc::new(StoreClient::new().expect("Failed to create StoreClient"));
+    data_store
+        .bytestore
+        .try_connect()
+        .await
+        .expect("Failed to connect to bytestore");
+    let _bulk_write = data_store.bytestore.begin_bulk_write();
 
-                    // Include the process as the first message if determined to be on the first page and has assignment
-                    if include_process {
-                        let process_message = Message::from_process(process_in.clone())?;
-                        messages_mapped.push(process_message);
-                    }
+    let range: &String = args.get(2).expect("Range argument not provided");
+    let parts: Vec<&str> = range.split('-').collect();
+    let mut from = parts[0].parse().expect("Invalid starting offset");
+    let to = if parts.len() > 1 {
+        Some(parts[1].parse().expect("Invalid records to pull"))
+    } else {
+        None
+    };
+    let verify = args.iter().skip(3).any(|arg| arg == "--verify");
+    let ignore_checkpoint = args.iter().skip(3).any(|arg| arg == "--ignore-checkpoint");
 
-                    for db_message in messages_o.iter() {
-                        let json = serde_json::from_value(db_message.message_data.clone())?;
-                        let bytes: Vec<u8> = db_message.bundle.clone();
-                        let mapped = Message::from_val(&json, bytes)?;
-                        messages_mapped.push(mapped);
-                    }
+    let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+    let checkpoint_path = std::path::Path::new(&config.su_data_dir).join(MIGRATION_CHECKPOINT);
+    /*
+      Without the WAL a batch is only safe once it is
+      flushed, so it is flushed before it is checkpointed.
+    */
+    let flush_before_checkpoint =
+        bytestore::WalSyncMode::from_config(&config.bytestore_wal_sync_mode)
+            == bytestore::WalSyncMode::Disable;
+    if !ignore_checkpoint {
+        if let Some(offset) = read_migration_checkpoint(&checkpoint_path)? {
+            if offset > from {
+                data_store.logger.log(format!(
+                    "Resuming migration from checkpoint offset {}",
+                    offset
+                ));
+                from = offset;
+            }
+        }
+    }
 
-                    let paginated = PaginatedMessages::from_messages(
-                        messages_mapped,
-                        has_next_page,
-                        sequence_mode,
-                    )?;
-                    Ok(paginated)
-                }
-                Err(e) => Err(StoreErrorType::from(e)),
+    let total_count = match to {
+        Some(t) => {
+            let total = data_store
+                .get_message_count()
+                .expect("Failed to get message count");
+            if t > total {
+                total - from
+            } else {
+                t - from
             }
         }
-    }
+        None => {
+            data_store
+                .get_message_count()
+                .expect("Failed to get message count")
+                - from
+        }
+    };
+
+    format!("Total messages to process: {}", total_count);
+
+    let batch_size = config.migration_batch_size.clone() as usize;
+    let concurrency = config.migration_concurrency.max(1);
 
     /*
-      This is a stripped down version of get_messages
-      used to fetch message bunldes for regenerating hash chains
+      Bounds how many bundles are being written at once,
+      a permit is taken before each save task is spawned
+      so a large batch doesn't start one writer per message.
     */
-    async fn get_message_bundles(
-        &self,
-        process_in: &Process,
-        from: &Option<String>,
-        limit: &Option<i32>,
-    ) -> Result<(Vec<(String, Vec<u8>)>, bool), StoreErrorType> {
-        use super::schema::messages::dsl::*;
-        let conn = &mut self.get_read_conn()?;
-        let mut query = messages
-            .filter(process_id.eq(process_in.process.process_id.clone()))
-            .into_boxed();
+    let save_permits = Arc::new(Semaphore::new(concurrency));
 
-        if let Some(from_timestamp_str) = from {
-            let from_timestamp = from_timestamp_str
-                .parse::<i64>()
-                .map_err(StoreErrorType::from)?;
-            query = query.filter(timestamp.gt(from_timestamp));
+    let processed_count = Arc::new(AtomicUsize::new(0));
+    let mut write_failures: Vec<(String, String)> = Vec::new();
+    let mut verification_failures: Vec<MigrationKey> = Vec::new();
+    /*
+      Cleared by the first batch that fails, the
+      checkpoint stays before it from then on.
+    */
+    let mut checkpointing = true;
+
+    // Spawn a task to log progress every minute
+    let processed_count_clone = Arc::clone(&processed_count);
+    let data_store_c = Arc::clone(&data_store);
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            data_store_c.logger.log(format!(
+                "Messages processed update: {}",
+                processed_count_clone.load(Ordering::SeqCst)
+            ));
+            if processed_count_clone.load(Ordering::SeqCst) >= total_count as usize {
+                break;
+            }
         }
+    });
 
-        let limit_val = limit.unwrap_or(100) as i64;
+    for batch_start in (from..from + total_count).step_by(batch_size) {
+        let batch_end = if let Some(t) = to {
+            std::cmp::min(batch_start + batch_size as i64, t)
+        } else {
+            batch_start + batch_size as i64
+        };
 
-        if self.bytestore.clone().is_ready() {
-            let db_messages_result: Result<Vec<DbMessageWithoutData>, DieselError> = query
-                .select((
-                    row_id,
-                    process_id,
-                    message_id,
-                    assignment_id,
-                    epoch,
-                    nonce,
-                    timestamp,
-                    hash_chain,
-                ))
-                .order(timestamp.asc())
-                .limit(limit_val + 1)
-                .load(conn);
+        let data_store = Arc::clone(&data_store);
+        let processed_count = Arc::clone(&processed_count);
 
-            match db_messages_result {
-                Ok(db_messages) => {
-                    let has_next_page = db_messages.len() as i64 > limit_val;
+        let result = data_store.get_all_messages(batch_start, Some(batch_end));
 
-                    let messages_o = if has_next_page {
-                        &db_messages[..(limit_val as usize)]
-                    } else {
-                        &db_messages[..]
-                    };
+        match result {
+            Ok(messages) => {
+                let batch_timer = Instant::now();
+                let batch_messages = messages.len();
+                let batch_bytes: usize = messages.iter().map(|message| message.2.len()).sum();
 
-                    let mut message_bundles: Vec<(String, Vec<u8>)> = vec![];
+                let mut save_handles = Vec::new();
+                for (msg_id, assignment_id, bundle, process_id, _, timestamp) in messages {
+                    let data_store = Arc::clone(&data_store);
+                    let processed_count = Arc::clone(&processed_count);
+                    let permit = save_permits
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("Migration semaphore closed");
 
-                    let message_ids: Vec<(String, Option<String>, String, String)> = messages_o
-                        .iter()
-                        .map(|msg| {
-                            (
-                                msg.message_id.clone(),
-                                msg.assignment_id.clone(),
-                                msg.process_id.clone(),
-                                msg.timestamp.to_string().clone(),
+                    let handle = tokio::spawn(async move {
+                        /*
+                          Only the checksum is kept for the read
+                          back, the bundle itself is moved into
+                          the bytestore.
+                        */
+                        let checksum = match verify {
+                            true => Some(bytestore::bundle_checksum(&bundle)),
+                            false => None,
+                        };
+                        let key = (msg_id, assignment_id, process_id, timestamp);
+                        let saved = data_store
+                            .bytestore
+                            .save_binary(
+                                key.0.clone(),
+                                key.1.clone(),
+                                key.2.clone(),
+                                key.3.clone(),
+                                bundle,
                             )
-                        })
-                        .collect();
-
-                    let binaries = self.bytestore.clone().read_binaries(message_ids).await?;
-
-                    for db_message in messages_o.iter() {
-                        match binaries.get(&(
-                            db_message.message_id.clone(),
-                            db_message.assignment_id.clone(),
-                            db_message.process_id.clone(),
-                            db_message.timestamp.to_string().clone(),
-                        )) {
-                            Some(bytes_result) => message_bundles
-                                .push((db_message.message_id.clone(), bytes_result.clone())),
-                            None => {
-                                let full_message = db_message
-                                    .assignment_id
-                                    .as_ref()
-                                    .map(|assignment_id_d| {
-                                        messages
-                                            .filter(
-                                                message_id
-                                                    .eq(db_message.message_id.clone())
-                                                    .and(assignment_id.eq(assignment_id_d)),
-                                            )
-                                            .order(timestamp.asc())
-                                            .first::<DbMessage>(conn)
-                                    })
-                                    .unwrap_or_else(|| {
-                                        messages
-                                            .filter(message_id.eq(db_message.message_id.clone()))
-                                            .order(timestamp.asc())
-                                            .first::<DbMessage>(conn)
-                                    })?;
-
-                                match full_message.assignment_id.clone() {
-                                    Some(a) => {
-                                        message_bundles.push((a, full_message.bundle.clone()))
-                                    }
-                                    /*
-                                      Anything old enough that it doesnt have
-                                      an assignemnt can be ignored
-                                    */
-                                    None => (),
-                                }
+                            .await;
+                        drop(permit);
+                        match saved {
+                            Ok(_) => {
+                                processed_count.fetch_add(1, Ordering::SeqCst);
+                                Ok((key, checksum))
                             }
+                            Err(e) => Err((key.0, e)),
                         }
-                    }
+                    });
 
-                    return Ok((message_bundles, has_next_page));
+                    save_handles.push(handle);
                 }
-                Err(e) => return Err(StoreErrorType::from(e)),
-            }
-        }
 
-        Err(StoreErrorType::DatabaseError(
-            "Bytestore not ready, cannot regenerate deep hashes".to_string(),
-        ))
-    }
+                let failures_before = write_failures.len() + verification_failures.len();
+                let mut written = Vec::new();
+                for saved in join_all(save_handles).await {
+                    match saved {
+                        Ok(Ok((key, Some(checksum)))) => written.push((key, checksum)),
+                        Ok(Ok((_, None))) => (),
+                        Ok(Err(failure)) => write_failures.push(failure),
+                        Err(e) => write_failures
+                            .push(("unknown".to_string(), format!("Save task failed: {:?}", e))),
+                    }
+                }
 
-    fn get_message(&self, tx_id: &str) -> Result<Message, StoreErrorType> {
-        use super::schema::messages::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+                if verify {
+                    let mismatched = verify_migrated(&data_store, &written).await;
+                    if !mismatched.is_empty() {
+                        data_store.logger.error(format!(
+                            "Batch {}-{}: {} messages failed verification, retrying them",
+                            batch_start,
+                            batch_end,
+                            mismatched.len()
+                        ));
+                        verification_failures.extend(
+                            retry_mismatched(&data_store, (batch_start, batch_end), mismatched)
+                                .await,
+                        );
+                    }
+                }
 
-        /*
-            get the oldest match. in the case of a message that has
-            later assignments, it should be the original message itself.
-        */
-        let db_message_result: Result<Option<DbMessage>, DieselError> = messages
-            .filter(message_id.eq(tx_id).or(assignment_id.eq(tx_id)))
-            .order(timestamp.asc())
-            .first(conn)
-            .optional();
+                checkpointing &=
+                    write_failures.len() + verification_failures.len() == failures_before;
+                if checkpointing && flush_before_checkpoint {
+                    if let Err(e) = data_store.bytestore.flush().await {
+                        write_failures.push(("flush".to_string(), e));
+                        checkpointing = false;
+                    }
+                }
+                if checkpointing {
+                    if let Err(e) = write_migration_checkpoint(&checkpoint_path, batch_end) {
+                        data_store.logger.error(format!(
+                            "Failed to write migration checkpoint {:?}: {:?}",
+                            checkpoint_path, e
+                        ));
+                    }
+                }
 
-        match db_message_result {
-            Ok(Some(db_message)) => {
-                let message_val: serde_json::Value =
-                    serde_json::from_value(db_message.message_data.clone())?;
-                let message: Message = Message::from_val(&message_val, db_message.bundle.clone())?;
-                Ok(message)
+                let elapsed = batch_timer.elapsed();
+                let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+                data_store.logger.log(format!(
+                    "Batch {}-{}: {} messages, {} bytes in {:?}, {:.0} messages/s, {:.0} bytes/s at concurrency {}",
+                    batch_start,
+                    batch_end,
+                    batch_messages,
+                    batch_bytes,
+                    elapsed,
+                    batch_messages as f64 / seconds,
+                    batch_bytes as f64 / seconds,
+                    concurrency
+                ));
+            }
+            Err(e) => {
+                checkpointing = false;
+                data_store
+                    .logger
+                    .error(format!("Error fetching messages: {:?}", e));
             }
-            Ok(None) => Err(StoreErrorType::NotFound("Message not found".to_string())), // Adjust this error type as needed
-            Err(e) => Err(StoreErrorType::from(e)),
         }
     }
 
-    async fn get_latest_message(
-        &self,
-        process_id_in: &str,
-    ) -> Result<Option<Message>, StoreErrorType> {
-        self.logger.log(format!(
-            "retreiving latest message for process - {}",
-            &process_id_in
-        ));
-        use super::schema::messages::dsl::*;
-        /*
-            This must use get_conn because it needs
-            an up to date record from the writer instance
-            it cannot be behind at all as it is used
-            in the scheduling process.
-        */
-        let conn = &mut self.get_conn()?;
+    if let Err(e) = data_store.bytestore.flush().await {
+        write_failures.push(("flush".to_string(), e));
+    }
 
-        self.logger
-            .log(format!("connection established - {}", &process_id_in));
+    let duration = start.elapsed();
+    data_store
+        .logger
+        .log(format!("Time elapsed in data migration is: {:?}", duration));
 
-        // Get the latest DbMessage
-        let latest_db_message_result = messages
-            .filter(process_id.eq(process_id_in))
-            .order(timestamp.desc())
-            .first::<DbMessage>(conn);
+    /*
+      A write failure is RocksDB refusing the write, a
+      verification failure is a write that RocksDB took
+      but that didn't read back the same even after a
+      retry, which means the disk can't be trusted.
+    */
+    for (msg_id, e) in write_failures.iter() {
+        data_store
+            .logger
+            .error(format!("Write failure: {}: {}", msg_id, e));
+    }
+    for key in verification_failures.iter() {
+        data_store
+            .logger
+            .error(format!("Verification failure: {:?}", key));
+    }
+    let summary = format!(
+        "Migration summary: {} messages written, {} write failures, {}",
+        processed_count.load(Ordering::SeqCst),
+        write_failures.len(),
+        match verify {
+            true => format!("{} verification failures", verification_failures.len()),
+            false => "not verified".to_string(),
+        }
+    );
+    data_store.logger.log(summary.clone());
 
-        self.logger.log(format!(
-            "latest message query complete - {}",
-            &process_id_in
-        ));
+    if !write_failures.is_empty() || !verification_failures.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, summary));
+    }
 
-        match latest_db_message_result {
-            Ok(db_message) => {
-                // Deserialize the message_data into Message
-                let message_val: serde_json::Value =
-                    serde_json::from_value(db_message.message_data)
-                        .map_err(|e| StoreErrorType::from(e))?;
+    if let Err(e) = std::fs::remove_file(&checkpoint_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            data_store.logger.error(format!(
+                "Failed to remove migration checkpoint {:?}: {:?}",
+                checkpoint_path, e
+            ));
+        }
+    }
 
-                let message: Message = Message::from_val(&message_val, db_message.bundle.clone())?;
+    Ok(())
+}
 
-                Ok(Some(message))
+/*
+  Populate processes.module_id for rows written before
+  the column was added. Safe to rerun, rows that were
+  already filled in are skipped.
+*/
+pub async fn backfill_process_modules() -> io::Result<()> {
+    use std::time::Instant;
+    let start = Instant::now();
+    dotenv().ok();
+
+    let data_store =
+        Arc::new(StoreClient::new_single_connection().expect("Failed to create StoreClient"));
+    let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+
+    let mut after_row_id = 0;
+    let mut total_updated = 0;
+    loop {
+        match data_store.backfill_module_ids(after_row_id, config.migration_batch_size) {
+            Ok((Some(last_row_id), updated)) => {
+                after_row_id = last_row_id;
+                total_updated += updated;
+                data_store.logger.log(format!(
+                    "Processes backfilled through row {}, updated: {}",
+                    after_row_id, total_updated
+                ));
+            }
+            Ok((None, _)) => break,
+            Err(e) => {
+                data_store
+                    .logger
+                    .error(format!("Error backfilling module ids: {:?}", e));
+                return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", e)));
             }
-            Err(DieselError::NotFound) => Ok(None), // No messages found
-            Err(e) => Err(StoreErrorType::from(e)),
         }
     }
+
+    let duration = start.elapsed();
+    data_store
+        .logger
+        .log(format!("Time elapsed in module id backfill is: {:?}", duration));
+
+    Ok(())
 }
 
-impl RouterDataStore for StoreClient {
-    fn save_process_scheduler(
-        &self,
-        process_scheduler: &ProcessScheduler,
-    ) -> Result<String, StoreErrorType> {
-        use super::schema::process_schedulers::dsl::*;
-        let conn = &mut self.get_conn()?;
+/*
+  Run StoreClient::migrate_key_format from the cli
+  binary. It needs the bytestore opened for writing so
+  the su has to be stopped.
+*/
+pub async fn migrate_key_format() -> io::Result<()> {
+    use std::time::Instant;
+    require_disk_store()?;
+    let start = Instant::now();
+    dotenv().ok();
 
-        let new_process_scheduler = NewProcessScheduler {
-            process_id: &process_scheduler.process_id,
-            scheduler_row_id: &process_scheduler.scheduler_row_id,
-        };
+    let data_store =
+        Arc::new(StoreClient::new_single_connection().expect("Failed to create StoreClient"));
+    data_store
+        .bytestore
+        .try_connect()
+        .await
+        .expect("Failed to connect to bytestore");
 
-        match diesel::insert_into(process_schedulers)
-            .values(&new_process_scheduler)
-            .on_conflict(process_id)
-            .do_nothing()
-            .execute(conn)
-        {
-            Ok(_) => Ok("saved".to_string()),
-            Err(e) => Err(StoreErrorType::from(e)),
-        }
-    }
+    let migrated = data_store
+        .migrate_key_format()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
 
-    fn get_process_scheduler(
-        &self,
-        process_id_in: &str,
-    ) -> Result<ProcessScheduler, StoreErrorType> {
-        use super::schema::process_schedulers::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+    data_store.logger.log(format!(
+        "Migrated {} bytestore keys in {:?}",
+        migrated,
+        start.elapsed()
+    ));
 
-        let db_process_result: Result<Option<DbProcessScheduler>, DieselError> = process_schedulers
-            .filter(process_id.eq(process_id_in))
-            .first(conn)
-            .optional();
+    Ok(())
+}
 
-        match db_process_result {
-            Ok(Some(db_process_scheduler)) => {
-                let process_scheduler: ProcessScheduler = ProcessScheduler {
-                    row_id: Some(db_process_scheduler.row_id),
-                    process_id: db_process_scheduler.process_id,
-                    scheduler_row_id: db_process_scheduler.scheduler_row_id,
-                };
-                Ok(process_scheduler)
+/*
+  Where regenerate_deep_hashes records the processes
+  row id it has finished through, in the same format
+  as the migration checkpoint.
+*/
+const DEEPHASH_CHECKPOINT: &str = "deephash_checkpoint";
+
+/*
+  Recompute the deep hashes of one process and save
+  the new version after them. Returns false when the
+  process was already on version and skip_current is
+  set, so a rerun passes over finished processes.
+*/
+async fn regenerate_process_deep_hashes(
+    data_store: Arc<StoreClient>,
+    gateway: Arc<dyn super::super::core::dal::Gateway>,
+    process: &Process,
+    version: &String,
+    limit: i32,
+    skip_current: bool,
+) -> Result<bool, String> {
+    let process_id = &process.process.process_id;
+    if skip_current {
+        if let Ok(current) = data_store.get_deephash_version(process_id).await {
+            if &current == version {
+                return Ok(false);
             }
-            Ok(None) => Err(StoreErrorType::NotFound(
-                "Process scheduler not found".to_string(),
-            )),
-            Err(e) => Err(StoreErrorType::from(e)),
         }
     }
 
-    fn save_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType> {
-        use super::schema::schedulers::dsl::*;
-        let conn = &mut self.get_conn()?;
+    let bundles = super::super::core::flows::regenerate_deephashes(
+        data_store.clone(),
+        gateway,
+        data_store.logger.clone(),
+        process,
+        limit,
+    )
+    .await?;
+    data_store
+        .save_deephash_version(process_id, version)
+        .await?;
+    data_store.logger.log(format!(
+        "Regenerated deep hashes for {} from {} bundles",
+        process_id, bundles
+    ));
+    Ok(true)
+}
 
-        let new_scheduler = NewScheduler {
-            url: &scheduler.url,
-            process_count: &scheduler.process_count,
-            no_route: scheduler.no_route.as_ref(),
-            wallets_to_route: scheduler.wallets_to_route.as_deref(),
-            wallets_only: scheduler.wallets_only.as_ref(),
-        };
+/*
+  Brings the deep hashes of every process up to
+  CURRENT_DEEPHASH_VERSION outside of the su server,
+  which otherwise does it the first time a process is
+  written to. It needs the bytestore opened for
+  writing so the su has to be stopped.
+
+  Processes are read MIGRATION_BATCH_SIZE at a time by
+  row id and up to concurrency of them are regenerated
+  at once. A process's version is saved after all of
+  its hashes, so a rerun skips the processes that
+  finished. After each page without failures its last
+  row id is saved to SU_DATA_DIR/deephash_checkpoint
+  and a rerun starts after it, the file is removed
+  once every process is done. With process_id only
+  that process is regenerated, even if it is already
+  on the current version.
+
+  There is a single database connection, so parallel
+  processes take turns on their queries, the gateway
+  lookups and bytestore writes are what overlap.
+*/
+pub async fn regenerate_deep_hashes(
+    process_id: Option<String>,
+    concurrency: usize,
+) -> io::Result<()> {
+    use super::gateway::ArweaveGateway;
+    require_disk_store()?;
+    let start = Instant::now();
+    dotenv().ok();
 
-        match diesel::insert_into(schedulers)
-            .values(&new_scheduler)
-            .on_conflict(url)
-            .do_nothing()
-            .execute(conn)
-        {
-            Ok(_) => Ok("saved".to_string()),
-            Err(e) => Err(StoreErrorType::from(e)),
-        }
+    let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+    if !config.use_disk {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Deep hashes are kept in the bytestore, set USE_DISK to regenerate them",
+        ));
     }
+    let version = config.current_deephash_version.clone();
+    let limit = config.deephash_recalc_limit;
 
-    fn update_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType> {
-        use super::schema::schedulers::dsl::*;
-        let conn = &mut self.get_conn()?;
+    let data_store =
+        Arc::new(StoreClient::new_single_connection().expect("Failed to create StoreClient"));
+    data_store
+        .bytestore
+        .try_connect()
+        .await
+        .expect("Failed to connect to bytestore");
+    let gateway: Arc<dyn super::super::core::dal::Gateway> = Arc::new(
+        ArweaveGateway::new()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+    );
 
-        // Ensure scheduler.row_id is Some(value) before calling this function
-        match diesel::update(schedulers.filter(row_id.eq(scheduler.row_id.unwrap())))
-            .set((
-                process_count.eq(scheduler.process_count),
-                url.eq(&scheduler.url),
-                no_route.eq(&scheduler.no_route),
-                wallets_to_route.eq(&scheduler.wallets_to_route),
-                wallets_only.eq(&scheduler.wallets_only),
-            ))
-            .execute(conn)
-        {
-            Ok(_) => Ok("updated".to_string()),
-            Err(e) => Err(StoreErrorType::from(e)),
-        }
+    if let Some(process_id) = process_id {
+        let process = data_store
+            .get_process(&process_id)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("{:?}", e)))?;
+        regenerate_process_deep_hashes(
+            data_store.clone(),
+            gateway,
+            &process,
+            &version,
+            limit,
+            false,
+        )
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        data_store
+            .bytestore
+            .flush()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        return Ok(());
     }
 
-    fn get_scheduler(&self, row_id_in: &i32) -> Result<Scheduler, StoreErrorType> {
-        use super::schema::schedulers::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+    let checkpoint_path = std::path::Path::new(&config.su_data_dir).join(DEEPHASH_CHECKPOINT);
+    let mut after_row_id = read_migration_checkpoint(&checkpoint_path)?.map(|row_id| row_id as i32);
+    if let Some(row_id) = after_row_id {
+        data_store.logger.log(format!(
+            "Resuming deep hash regeneration after process row {}",
+            row_id
+        ));
+    }
 
-        let db_scheduler_result: Result<Option<DbScheduler>, DieselError> = schedulers
-            .filter(row_id.eq(row_id_in))
-            .first(conn)
-            .optional();
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let processed_count = Arc::new(AtomicUsize::new(0));
+    let regenerated_count = Arc::new(AtomicUsize::new(0));
+    let mut failures: Vec<(String, String)> = Vec::new();
 
-        match db_scheduler_result {
-            Ok(Some(db_scheduler)) => {
-                let scheduler: Scheduler = Scheduler {
-                    row_id: Some(db_scheduler.row_id),
-                    url: db_scheduler.url,
-                    process_count: db_scheduler.process_count,
-                    no_route: db_scheduler.no_route,
-                    wallets_to_route: db_scheduler.wallets_to_route,
-                    wallets_only: db_scheduler.wallets_only,
-                };
-                Ok(scheduler)
-            }
-            Ok(None) => Err(StoreErrorType::NotFound("Scheduler not found".to_string())),
-            Err(e) => Err(StoreErrorType::from(e)),
+    // Spawn a task to log progress every 10 seconds
+    let processed_count_clone = Arc::clone(&processed_count);
+    let data_store_c = Arc::clone(&data_store);
+    let progress = tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            data_store_c.logger.log(format!(
+                "Processes processed update: {}",
+                processed_count_clone.load(Ordering::SeqCst)
+            ));
         }
-    }
+    });
 
-    fn get_scheduler_by_url(&self, url_in: &String) -> Result<Scheduler, StoreErrorType> {
-        use super::schema::schedulers::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+    loop {
+        let (processes, next_row_id) = match data_store
+            .get_processes(after_row_id, config.migration_batch_size as i32)
+            .await
+        {
+            Ok(page) => page,
+            Err(e) => {
+                failures.push(("page".to_string(), format!("{:?}", e)));
+                break;
+            }
+        };
 
-        let db_scheduler_result: Result<Option<DbScheduler>, DieselError> =
-            schedulers.filter(url.eq(url_in)).first(conn).optional();
+        let mut handles = Vec::new();
+        for process in processes {
+            let data_store = Arc::clone(&data_store);
+            let gateway = gateway.clone();
+            let version = version.clone();
+            let processed_count = Arc::clone(&processed_count);
+            let regenerated_count = Arc::clone(&regenerated_count);
+            let permit = permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Regeneration semaphore closed");
+
+            handles.push(tokio::spawn(async move {
+                let result = regenerate_process_deep_hashes(
+                    data_store, gateway, &process, &version, limit, true,
+                )
+                .await;
+                drop(permit);
+                processed_count.fetch_add(1, Ordering::SeqCst);
+                match result {
+                    Ok(true) => {
+                        regenerated_count.fetch_add(1, Ordering::SeqCst);
+                        Ok(())
+                    }
+                    Ok(false) => Ok(()),
+                    Err(e) => Err((process.process.process_id, e)),
+                }
+            }));
+        }
 
-        match db_scheduler_result {
-            Ok(Some(db_scheduler)) => {
-                let scheduler: Scheduler = Scheduler {
-                    row_id: Some(db_scheduler.row_id),
-                    url: db_scheduler.url,
-                    process_count: db_scheduler.process_count,
-                    no_route: db_scheduler.no_route,
-                    wallets_to_route: db_scheduler.wallets_to_route,
-                    wallets_only: db_scheduler.wallets_only,
-                };
-                Ok(scheduler)
+        let failures_before = failures.len();
+        for regenerated in join_all(handles).await {
+            match regenerated {
+                Ok(Ok(())) => (),
+                Ok(Err(failure)) => failures.push(failure),
+                Err(e) => failures.push((
+                    "unknown".to_string(),
+                    format!("Regeneration task failed: {:?}", e),
+                )),
             }
-            Ok(None) => Err(StoreErrorType::NotFound("Scheduler not found".to_string())),
-            Err(e) => Err(StoreErrorType::from(e)),
         }
+
+        let row_id = match next_row_id {
+            Some(row_id) => row_id,
+            None => break,
+        };
+        /*
+          The checkpoint stays before the first page with
+          a failure, later pages are still worked through.
+        */
+        if failures.is_empty() {
+            if let Err(e) = data_store.bytestore.flush().await {
+                failures.push(("flush".to_string(), e));
+            } else if let Err(e) = write_migration_checkpoint(&checkpoint_path, row_id as i64) {
+                data_store.logger.error(format!(
+                    "Failed to write deep hash checkpoint {:?}: {:?}",
+                    checkpoint_path, e
+                ));
+            }
+        } else if failures.len() > failures_before {
+            data_store.logger.error(format!(
+                "{} processes failed before process row {}, the checkpoint is not moved past them",
+                failures.len() - failures_before,
+                row_id
+            ));
+        }
+        after_row_id = Some(row_id);
     }
+    progress.abort();
 
-    fn get_all_schedulers(&self) -> Result<Vec<Scheduler>, StoreErrorType> {
-        use super::schema::schedulers::dsl::*;
-        let conn = &mut self.get_read_conn()?;
+    if let Err(e) = data_store.bytestore.flush().await {
+        failures.push(("flush".to_string(), e));
+    }
 
-        match schedulers.order(row_id.asc()).load::<DbScheduler>(conn) {
-            Ok(db_schedulers) => {
-                let schedulers_out: Vec<Scheduler> = db_schedulers
-                    .into_iter()
-                    .map(|db_scheduler| Scheduler {
-                        row_id: Some(db_scheduler.row_id),
-                        url: db_scheduler.url,
-                        process_count: db_scheduler.process_count,
-                        no_route: db_scheduler.no_route,
-                        wallets_to_route: db_scheduler.wallets_to_route,
-                        wallets_only: db_scheduler.wallets_only,
-                    })
-                    .collect();
-                Ok(schedulers_out)
-            }
-            Err(e) => Err(StoreErrorType::from(e)),
+    data_store.logger.log(format!(
+        "Time elapsed in deep hash regeneration is: {:?}",
+        start.elapsed()
+    ));
+    for (process_id, e) in failures.iter() {
+        data_store
+            .logger
+            .error(format!("Regeneration failure: {}: {}", process_id, e));
+    }
+    let summary = format!(
+        "Deep hash regeneration summary: {} processes checked, {} regenerated to version {}, {} failures",
+        processed_count.load(Ordering::SeqCst),
+        regenerated_count.load(Ordering::SeqCst),
+        version,
+        failures.len()
+    );
+    data_store.logger.log(summary.clone());
+
+    if !failures.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, summary));
+    }
+
+    if let Err(e) = std::fs::remove_file(&checkpoint_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            data_store.logger.error(format!(
+                "Failed to remove deep hash checkpoint {:?}: {:?}",
+                checkpoint_path, e
+            ));
         }
     }
-}
 
-#[derive(Queryable, Selectable)]
-#[diesel(table_name = super::schema::processes)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct DbProcess {
-    pub row_id: i32,
-    pub process_id: String,
-    pub process_data: serde_json::Value,
-    pub bundle: Vec<u8>,
-    pub epoch: Option<i32>,
-    pub nonce: Option<i32>,
-    pub timestamp: Option<i64>,
-    pub hash_chain: Option<String>,
+    Ok(())
 }
 
-#[derive(Queryable, Selectable)]
-#[diesel(table_name = super::schema::messages)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct DbMessage {
-    pub row_id: i32,
-    pub process_id: String,
-    pub message_id: String,
-    pub assignment_id: Option<String>,
-    pub message_data: serde_json::Value,
-    pub epoch: i32,
-    pub nonce: i32,
-    pub timestamp: i64,
-    pub bundle: Vec<u8>,
-    pub hash_chain: String,
-}
+/*
+  Report the messages that share an assignment id so
+  they can be cleaned up before the unique index
+  migration runs. Nothing is changed.
+*/
+pub async fn find_duplicate_assignments() -> io::Result<()> {
+    dotenv().ok();
 
-#[derive(Queryable, Selectable)]
-#[diesel(table_name = super::schema::messages)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct DbMessageWithoutData {
-    pub row_id: i32,
-    pub process_id: String,
-    pub message_id: String,
-    pub assignment_id: Option<String>,
-    pub epoch: i32,
-    pub nonce: i32,
-    pub timestamp: i64,
-    pub hash_chain: String,
-}
+    let data_store = StoreClient::new_single_connection().expect("Failed to create StoreClient");
+    let duplicates = data_store
+        .find_duplicate_assignments()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
 
-#[derive(Insertable)]
-#[diesel(table_name = super::schema::messages)]
-pub struct NewMessage<'a> {
-    pub process_id: &'a str,
-    pub message_id: &'a str,
-    pub assignment_id: &'a str,
-    pub message_data: serde_json::Value,
-    pub bundle: &'a [u8],
-    pub epoch: &'a i32,
-    pub nonce: &'a i32,
-    pub timestamp: &'a i64,
-    pub hash_chain: &'a str,
+    for duplicate in duplicates.iter() {
+        data_store.logger.log(format!(
+            "assignment {} has {} rows, row ids {:?}, message ids {:?}",
+            duplicate.assignment_id, duplicate.row_count, duplicate.row_ids, duplicate.message_ids
+        ));
+    }
+    data_store.logger.log(format!(
+        "Found {} duplicated assignment ids",
+        duplicates.len()
+    ));
+
+    Ok(())
 }
 
-#[derive(Insertable)]
-#[diesel(table_name = super::schema::processes)]
-pub struct NewProcess<'a> {
-    pub process_id: &'a str,
-    pub process_data: serde_json::Value,
-    pub bundle: &'a [u8],
-    pub epoch: Option<i32>,          // New nullable field
-    pub nonce: Option<i32>,          // New nullable field
-    pub hash_chain: Option<&'a str>, // New nullable field
-    pub timestamp: Option<i64>,      // New nullable field
+/*
+  Write the router's schedulers and assignments as json
+  to the path given after the function name, or to
+  stdout when there is none.
+*/
+pub async fn export_router_state() -> io::Result<()> {
+    dotenv().ok();
+
+    let data_store = StoreClient::new_single_connection().expect("Failed to create StoreClient");
+    let state = data_store
+        .export_router_state()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    let args: Vec<String> = env::args().collect();
+    match args.get(2) {
+        Some(path) => {
+            let file = std::fs::File::create(path)?;
+            serde_json::to_writer_pretty(file, &state)?;
+            data_store.logger.log(format!(
+                "Exported {} schedulers and {} assignments to {}",
+                state.schedulers.len(),
+                state.assignments.len(),
+                path
+            ));
+        }
+        None => println!("{}", serde_json::to_string_pretty(&state)?),
+    }
+
+    Ok(())
 }
 
-#[derive(Queryable, Selectable)]
-#[diesel(table_name = super::schema::schedulers)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct DbScheduler {
-    pub row_id: i32,
-    pub url: String,
-    pub process_count: i32,
-    pub no_route: Option<bool>,
-    pub wallets_to_route: Option<String>,
-    pub wallets_only: Option<bool>,
+/*
+  Load a file written by export_router_state. The
+  existing state is merged into unless --replace is
+  passed, and --skip-invalid drops assignments to
+  unknown schedulers instead of failing the import.
+*/
+pub async fn import_router_state() -> io::Result<()> {
+    dotenv().ok();
+
+    let args: Vec<String> = env::args().collect();
+    let path = args.get(2).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Router state path not provided",
+        )
+    })?;
+    let merge = !args.iter().any(|a| a == "--replace");
+    let skip_violations = args.iter().any(|a| a == "--skip-invalid");
+
+    let file = std::fs::File::open(path)?;
+    let state: RouterState = serde_json::from_reader(file)?;
+
+    let data_store = StoreClient::new_single_connection().expect("Failed to create StoreClient");
+    let report = data_store
+        .import_router_state(&state, merge, skip_violations)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    data_store.logger.log(format!(
+        "Imported {} schedulers and {} assignments, skipped {}",
+        report.schedulers,
+        report.assignments,
+        report.skipped.len()
+    ));
+
+    Ok(())
 }
 
-#[derive(Insertable)]
-#[diesel(table_name = super::schema::schedulers)]
-pub struct NewScheduler<'a> {
-    pub url: &'a str,
-    pub process_count: &'a i32,
-    pub no_route: Option<&'a bool>,
-    pub wallets_to_route: Option<&'a str>,
-    pub wallets_only: Option<&'a bool>,
-}
+/*
+  Checkpoint the bytestore from the cli binary. This
+  opens SU_DATA_DIR itself so it is for when the su is
+  stopped, a running su should be checkpointed through
+  the /admin/bytestore/checkpoint route instead.
+*/
+pub async fn checkpoint_bytestore() -> io::Result<()> {
+    require_disk_store()?;
+    dotenv().ok();
+
+    let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+    let checkpoint_dir = config.bytestore_checkpoint_dir.clone();
+    let args: Vec<String> = env::args().collect();
+    let path = match args.get(2).cloned().or(checkpoint_dir) {
+        Some(path) => path,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Checkpoint path not provided and BYTESTORE_CHECKPOINT_DIR is not set",
+            ))
+        }
+    };
+
+    let logger = SuLog::init();
+    let bytestore = bytestore::ByteStore::new(config, logger.clone());
+    bytestore
+        .try_connect()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-#[derive(Queryable, Selectable)]
-#[diesel(table_name = super::schema::process_schedulers)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct DbProcessScheduler {
-    pub row_id: i32,
-    pub process_id: String,
-    pub scheduler_row_id: i32,
-}
+    let checkpoint = bytestore
+        .create_checkpoint(&path)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-#[derive(Insertable)]
-#[diesel(table_name = super::schema::process_schedulers)]
-pub struct NewProcessScheduler<'a> {
-    pub process_id: &'a str,
-    pub scheduler_row_id: &'a i32,
+    logger.log(format!(
+        "Bytestore checkpoint written to {}, size: {} bytes, removed: {:?}",
+        checkpoint.path, checkpoint.size_bytes, checkpoint.removed
+    ));
+
+    Ok(())
 }
 
 /*
-  bytestore is a performance enhancement implemented within
-  the data store. This is implemented using RocksDB in BlobDB mode.
-  It is used for fast retrieval of messages.
+  Run by init_deps before the bytestore connects, so
+  sync_bytestore catches the restored bytestore up
+  with the tail of postgres afterwards.
+*/
+pub fn restore_bytestore_on_startup(
+    config: &AoConfig,
+    logger: &Arc<dyn Log>,
+) -> Result<(), String> {
+    match &config.bytestore_restore_from {
+        Some(checkpoint) => bytestore::restore_checkpoint(
+            checkpoint,
+            &config.su_data_dir,
+            config.bytestore_restore_force,
+            logger,
+        ),
+        None => Ok(()),
+    }
+}
 
-  See https://rocksdb.org/blog/2021/05/26/integrated-blob-db.html
+/*
+  Restore a checkpoint from the cli binary while the su
+  is stopped, then sync the tail of postgres into it.
+  Takes the checkpoint path and an optional --force.
 */
-mod bytestore {
-    use super::super::super::config::AoConfig;
+pub async fn restore_bytestore() -> io::Result<()> {
+    require_disk_store()?;
+    dotenv().ok();
+
+    let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
+    let args: Vec<String> = env::args().collect();
+    let checkpoint = match args.get(2) {
+        Some(checkpoint) => checkpoint.clone(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Checkpoint path not provided",
+            ))
+        }
+    };
+    let force = args.iter().skip(3).any(|arg| arg == "--force");
+
+    let logger = SuLog::init();
+    bytestore::restore_checkpoint(&checkpoint, &config.su_data_dir, force, &logger)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    let data_store =
+        Arc::new(StoreClient::new_single_connection().expect("Failed to create StoreClient"));
+    data_store
+        .bytestore
+        .try_connect()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    logger.log("bytestore restore - syncing the tail from postgres".to_string());
+    tokio::task::spawn_blocking(move || data_store.sync_bytestore())
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed to sync the bytestore tail: {:?}", e),
+            )
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "disk-store")]
+    use super::bytestore::{
+        bundle_checksum, check_data_dir, check_lock_file, checkpoint_db, checksum_matches,
+        column_families, decode_value, disk_level, disk_usage_percent, encode_value,
+        get_with_fallback, is_tombstone, multi_get_with_fallback, parse_legacy_message_key,
+        prefix_end, process_deep_hashes, prune_process_keys, restore_checkpoint, DiskLevel,
+        MemoryGuard, DEEPHASHES_CF, MESSAGES_CF,
+    };
+    use super::cache_invalidation::next_backoff;
+    use super::SuLog;
+    use super::{
+        drain_pool, fill_page, has_idle_connection, insert_message_error, is_retryable,
+        like_contains, like_prefix, oldest_per_key, read_migration_checkpoint, retry_write,
+        scheduler_row_id, scheduler_update_result, trim_window, write_migration_checkpoint,
+        BytestoreSync, CircuitBreaker, CountedCache, DbMessage, MessageThroughput, ProcessLocks,
+        RateLimiter,
+    };
+    use crate::domain::core::dal::{ErrorCategory, FlowError, Scheduler, StoreErrorType};
+    use crate::domain::flows::in_compaction_window;
     use dashmap::DashMap;
-    use rocksdb::{Options, DB};
-    use std::sync::Arc;
-    use std::sync::RwLock;
+    #[cfg(feature = "disk-store")]
+    use rocksdb::{IteratorMode, Options, WriteOptions, DB};
+    use std::collections::{HashSet, VecDeque};
+    use std::fs;
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex as StdMutex};
+    use std::time::{Duration, Instant};
 
-    pub struct ByteStore {
-        db: RwLock<Option<DB>>,
-        config: AoConfig,
+    #[tokio::test]
+    async fn test_process_locks_serialize_writes() {
+        let locks = Arc::new(ProcessLocks::new());
+        let nonces = Arc::new(StdMutex::new(Vec::<i32>::new()));
+
+        let mut handles = Vec::new();
+        for _ in 0..50 {
+            let locks = locks.clone();
+            let nonces = nonces.clone();
+            handles.push(tokio::spawn(async move {
+                locks
+                    .with_lock("process", async {
+                        let next = match nonces.lock().unwrap().last() {
+                            Some(n) => n + 1,
+                            None => 0,
+                        };
+                        tokio::task::yield_now().await;
+                        nonces.lock().unwrap().push(next);
+                    })
+                    .await;
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let expected: Vec<i32> = (0..50).collect();
+        assert_eq!(*nonces.lock().unwrap(), expected);
+        assert!(locks.locks.is_empty());
     }
 
-    impl ByteStore {
-        pub fn new(config: AoConfig) -> Self {
-            ByteStore {
-                db: RwLock::new(None),
-                config,
-            }
-        }
+    /*
+      get_process_uncached writes what it reads back into
+      the cache, so later cached reads see the fresh value
+    */
+    #[tokio::test]
+    async fn test_counted_cache_insert_refreshes_stale_entry() {
+        let cache: CountedCache<i32> = CountedCache::new("test", 2);
 
-        pub fn try_connect(&self) -> Result<(), String> {
-            let mut opts = Options::default();
-            opts.create_if_missing(true);
-            opts.set_enable_blob_files(true); // Enable blob files
-            opts.set_blob_file_size(5 * 1024 * 1024 * 1024); // 5GB max for now
-            opts.set_min_blob_size(1024); // low value ensures it is used
+        cache.insert("process".to_string(), 1).await;
+        cache.insert("process".to_string(), 2).await;
 
-            let new_db = DB::open(&opts, &self.config.su_data_dir)
-                .map_err(|e| format!("Failed to open RocksDB: {:?}", e))?;
+        assert_eq!(cache.get(&"process".to_string()).await, Some(2));
+        let stats = cache.stats().await;
+        assert_eq!(stats.len, 1);
+        assert_eq!(stats.evictions, 0);
+    }
 
-            let mut db_write = self.db.write().unwrap();
-            *db_write = Some(new_db);
+    #[tokio::test]
+    async fn test_counted_cache_ttl() {
+        let cache: CountedCache<i32> =
+            CountedCache::new("test", 2).with_ttl(Duration::from_millis(50));
 
-            Ok(())
-        }
+        cache.insert("process".to_string(), 1).await;
+        assert_eq!(cache.get(&"process".to_string()).await, Some(1));
 
-        pub fn try_read_instance_connect(&self) -> Result<(), String> {
-            let mut opts = Options::default();
-            opts.set_enable_blob_files(true); // Enable blob files
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(cache.get(&"process".to_string()).await, None);
 
-            // Open the database in read-only mode
-            let new_db = DB::open_for_read_only(&opts, &self.config.su_data_dir, false)
-                .map_err(|e| format!("Failed to open RocksDB in read-only mode: {:?}", e))?;
+        let stats = cache.stats().await;
+        assert_eq!((stats.hits, stats.misses, stats.len), (1, 1, 0));
+    }
 
-            let mut db_write = self.db.write().unwrap();
-            *db_write = Some(new_db);
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_compression_round_trip() {
+        let bundle = br#"{"tags":[{"name":"Action","value":"Eval"}]}"#.repeat(50);
 
-            Ok(())
+        let compressed = encode_value(bundle.clone(), Some(3));
+        assert!(compressed.len() < bundle.len());
+        assert_eq!(decode_value(compressed).unwrap(), bundle);
+
+        // compression off still gets the checksum header
+        let raw = encode_value(bundle.clone(), None);
+        assert_eq!(&raw[raw.len() - bundle.len()..], &bundle[..]);
+        assert_eq!(decode_value(raw).unwrap(), bundle);
+    }
+
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_checksum_mismatch() {
+        let bundle = vec![7u8; 256];
+
+        let mut truncated = encode_value(bundle.clone(), None);
+        truncated.truncate(truncated.len() - 10);
+        assert!(decode_value(truncated).is_err());
+
+        let mut flipped = encode_value(bundle.clone(), Some(3));
+        let last = flipped.len() - 1;
+        flipped[last] ^= 0xff;
+        assert!(decode_value(flipped).is_err());
+
+        let checksum = Some(bundle_checksum(&bundle));
+        assert!(checksum_matches(&bundle, &checksum));
+        assert!(!checksum_matches(&bundle[..100], &checksum));
+        // rows from before checksums are not verified
+        assert!(checksum_matches(&bundle[..100], &None));
+    }
+
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_reads_legacy_values() {
+        // a legacy bundle starts with its item count
+        let mut legacy = vec![0u8; 64];
+        legacy[0] = 2;
+        assert_eq!(decode_value(legacy.clone()).unwrap(), legacy);
+
+        assert_eq!(decode_value(vec![]).unwrap(), Vec::<u8>::new());
+        assert!(decode_value(b"AOZ\0\x09data".to_vec()).is_err());
+
+        // compressed values written before checksums
+        let mut unchecked = b"AOZ\0\x01".to_vec();
+        unchecked.extend(zstd::bulk::compress(&legacy, 3).unwrap());
+        assert_eq!(decode_value(unchecked).unwrap(), legacy);
+    }
+
+    #[tokio::test]
+    async fn test_counted_cache_stats() {
+        let cache: CountedCache<i32> = CountedCache::new("test", 2);
+
+        assert_eq!(cache.get(&"a".to_string()).await, None);
+        cache.insert("a".to_string(), 1).await;
+        cache.insert("b".to_string(), 2).await;
+        assert_eq!(cache.get(&"a".to_string()).await, Some(1));
+
+        // replacing an existing key is not an eviction
+        cache.insert("a".to_string(), 3).await;
+        // "b" is now the least recently used so it is evicted
+        cache.insert("c".to_string(), 4).await;
+        assert_eq!(cache.get(&"b".to_string()).await, None);
+        assert_eq!(cache.get(&"c".to_string()).await, Some(4));
+
+        let stats = cache.stats().await;
+        assert_eq!(stats.name, "test");
+        assert_eq!(stats.capacity, 2);
+        assert_eq!(stats.len, 2);
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.insertions, 4);
+        assert_eq!(stats.evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn test_counted_cache_resize() {
+        let cache: CountedCache<i32> = CountedCache::new("test", 2);
+        cache.insert("a".to_string(), 1).await;
+        cache.insert("b".to_string(), 2).await;
+
+        cache.resize(NonZeroUsize::new(3).unwrap()).await;
+        cache.insert("c".to_string(), 3).await;
+        assert_eq!(cache.stats().await.len, 3);
+
+        // shrinking keeps the most recently used
+        cache.resize(NonZeroUsize::new(1).unwrap()).await;
+        assert_eq!(cache.get(&"a".to_string()).await, None);
+        assert_eq!(cache.get(&"c".to_string()).await, Some(3));
+        let stats = cache.stats().await;
+        assert_eq!(stats.capacity, 1);
+        assert_eq!(stats.evictions, 2);
+    }
+
+    fn test_scheduler(row_id: Option<i32>, version: Option<i32>) -> Scheduler {
+        Scheduler {
+            row_id,
+            url: "https://su.example".to_string(),
+            process_count: 0,
+            no_route: None,
+            wallets_to_route: None,
+            wallets_only: None,
+            version,
+            consecutive_failures: 0,
         }
+    }
 
-        pub fn is_ready(&self) -> bool {
-            match self.db.read() {
-                Ok(r) => r.is_some(),
-                Err(_) => false,
+    #[test]
+    fn test_update_scheduler_without_row_id() {
+        let scheduler = test_scheduler(None, Some(0));
+        assert!(matches!(
+            scheduler_row_id(&scheduler),
+            Err(StoreErrorType::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_update_scheduler_concurrent_update_conflicts() {
+        /*
+          Two callers read the scheduler at the same version,
+          the first update matches the row and bumps the
+          version, so the second matches nothing.
+        */
+        let first = test_scheduler(Some(1), Some(4));
+        let second = test_scheduler(Some(1), Some(4));
+
+        assert!(scheduler_update_result(1, 1, first.version).is_ok());
+        assert!(matches!(
+            scheduler_update_result(1, 0, second.version),
+            Err(StoreErrorType::Conflict(_))
+        ));
+        assert!(matches!(
+            scheduler_update_result(1, 0, None),
+            Err(StoreErrorType::NotFound { .. })
+        ));
+    }
+
+    struct TestDirs(Vec<&'static str>);
+
+    impl Drop for TestDirs {
+        fn drop(&mut self) {
+            for dir in &self.0 {
+                let _ = fs::remove_dir_all(dir);
             }
         }
+    }
 
-        pub async fn read_binaries(
-            &self,
-            ids: Vec<(String, Option<String>, String, String)>,
-        ) -> Result<DashMap<(String, Option<String>, String, String), Vec<u8>>, String> {
-            let max_memory_usage = self.config.max_read_memory;
-            let binaries = Arc::new(DashMap::new());
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return Err("Failed to acquire read lock".into()),
-            };
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_checkpoint() {
+        let _dirs = TestDirs(vec!["test_checkpoint_data", "test_checkpoint_backups"]);
+        let key = b"message___process___1___message";
+
+        let db = DB::open_default("test_checkpoint_data").unwrap();
+        db.put(key, b"bundle").unwrap();
+
+        assert!(checkpoint_db(
+            &db,
+            "test_checkpoint_data",
+            "test_checkpoint_data/backups",
+            None
+        )
+        .is_err());
+
+        let first = checkpoint_db(
+            &db,
+            "test_checkpoint_data",
+            "test_checkpoint_backups",
+            Some(1),
+        )
+        .unwrap();
+        assert!(first.size_bytes > 0);
+        assert!(first.removed.is_empty());
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = checkpoint_db(
+            &db,
+            "test_checkpoint_data",
+            "test_checkpoint_backups",
+            Some(1),
+        )
+        .unwrap();
+        assert_eq!(second.removed, vec![first.path.clone()]);
+
+        let copy = DB::open_for_read_only(&Options::default(), &second.path, false).unwrap();
+        assert_eq!(copy.get(key).unwrap(), Some(b"bundle".to_vec()));
+    }
 
-            if let Some(ref db) = *db {
-                let mut total_memory_usage: usize = 0;
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_restore_checkpoint() {
+        let _dirs = TestDirs(vec![
+            "test_restore_live",
+            "test_restore_backups",
+            "test_restore_data",
+        ]);
+        let logger = SuLog::init();
+        let key = b"message___process___1___message";
 
-                for id in ids {
-                    let binaries = binaries.clone();
-                    let key = ByteStore::create_key(&id.0, &id.1, &id.2, &id.3);
-                    if let Ok(Some(value)) = db.get(&key) {
-                        /*
-                          This is added here because really large message lists
-                          with large messages are filling up the machines memory
-                          and freezing it.
-                        */
-                        total_memory_usage += value.len();
-                        if total_memory_usage > max_memory_usage {
-                            return Err(format!(
-                                "Memory usage exceeded the limit: {} bytes",
-                                max_memory_usage
-                            ));
-                        }
-                        binaries.insert(id.clone(), value);
-                    }
-                }
-                Ok(Arc::try_unwrap(binaries).map_err(|_| "Failed to unwrap Arc")?)
-            } else {
-                Err("Database is not initialized".into())
-            }
+        let checkpoint = {
+            let db = DB::open_default("test_restore_live").unwrap();
+            db.put(key, b"bundle").unwrap();
+            checkpoint_db(&db, "test_restore_live", "test_restore_backups", None).unwrap()
+        };
+
+        assert!(restore_checkpoint(
+            "test_restore_backups/missing",
+            "test_restore_data",
+            false,
+            &logger
+        )
+        .is_err());
+
+        restore_checkpoint(&checkpoint.path, "test_restore_data", false, &logger).unwrap();
+        {
+            let db =
+                DB::open_for_read_only(&Options::default(), "test_restore_data", false).unwrap();
+            assert_eq!(db.get(key).unwrap(), Some(b"bundle".to_vec()));
         }
 
-        pub fn save_binary(
-            &self,
-            message_id: String,
-            assignment_id: Option<String>,
-            process_id: String,
-            timestamp: String,
-            binary: Vec<u8>,
-        ) -> Result<(), String> {
-            let key = ByteStore::create_key(&message_id, &assignment_id, &process_id, &timestamp);
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return Err("Failed to acquire read lock".into()),
-            };
+        assert!(restore_checkpoint(&checkpoint.path, "test_restore_data", false, &logger).is_err());
+        restore_checkpoint(&checkpoint.path, "test_restore_data", true, &logger).unwrap();
 
-            if let Some(ref db) = *db {
-                db.put(key, binary)
-                    .map_err(|e| format!("Failed to write to RocksDB: {:?}", e))?;
-                Ok(())
-            } else {
-                Err("Database is not initialized".into())
+        for entry in fs::read_dir(".").unwrap() {
+            let name = entry.unwrap().file_name().to_string_lossy().to_string();
+            if name.starts_with("test_restore_data.replaced-") {
+                fs::remove_dir_all(&name).unwrap();
             }
         }
+    }
 
-        pub fn delete_binary(
-            &self,
-            message_id: String,
-            assignment_id: Option<String>,
-            process_id: String,
-            timestamp: String,
-        ) -> Result<(), String> {
-            let key = ByteStore::create_key(&message_id, &assignment_id, &process_id, &timestamp);
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return Err("Failed to acquire read lock".into()),
-            };
-        
-            if let Some(ref db) = *db {
-                db.delete(key)
-                    .map_err(|e| format!("Failed to delete from RocksDB: {:?}", e))?;
-                Ok(())
-            } else {
-                Err("Database is not initialized".into())
-            }
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_check_data_dir() {
+        let _dirs = TestDirs(vec!["test_data_dir"]);
+        let data_dir = std::path::Path::new("test_data_dir/nested");
+
+        assert!(check_data_dir(data_dir, false, true, &[]).is_err());
+        // a read only su never creates it
+        assert!(check_data_dir(data_dir, true, false, &[]).is_err());
+        check_data_dir(data_dir, true, true, &[]).unwrap();
+        assert!(data_dir.is_dir());
+
+        // a LOCK left behind with nobody holding it is fine
+        check_lock_file(data_dir).unwrap();
+        fs::write(data_dir.join("LOCK"), b"").unwrap();
+        check_lock_file(data_dir).unwrap();
+
+        let checkpoint_dir = [("BYTESTORE_CHECKPOINT_DIR", "test_data_dir/nested/backups")];
+        assert!(check_data_dir(data_dir, false, true, &checkpoint_dir).is_err());
+        let sync_dir = [("SU_FILE_SYNC_DB_DIR", "test_data_dir")];
+        assert!(check_data_dir(data_dir, false, false, &sync_dir).is_err());
+        let elsewhere = [("SU_FILE_SYNC_DB_DIR", "test_data_dir/sync")];
+        check_data_dir(data_dir, false, true, &elsewhere).unwrap();
+    }
+
+    #[test]
+    fn test_migration_checkpoint() {
+        let _dirs = TestDirs(vec!["test_migration_checkpoint"]);
+        fs::create_dir_all("test_migration_checkpoint").unwrap();
+        let path = std::path::Path::new("test_migration_checkpoint/migration_checkpoint");
+
+        assert_eq!(read_migration_checkpoint(path).unwrap(), None);
+        write_migration_checkpoint(path, 1000).unwrap();
+        write_migration_checkpoint(path, 2000).unwrap();
+        assert_eq!(read_migration_checkpoint(path).unwrap(), Some(2000));
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::write(path, "{}").unwrap();
+        assert!(read_migration_checkpoint(path).is_err());
+    }
+
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_prune_process_keys() {
+        let _dirs = TestDirs(vec!["test_prune_data"]);
+        let db = DB::open_default("test_prune_data").unwrap();
+        for process_id in ["process1", "process2"] {
+            db.put(format!("message___{}___1___m1", process_id), b"bundle").unwrap();
+            db.put(format!("message___{}___2___m2___a2", process_id), b"bundle").unwrap();
+            db.put(format!("deephash___{}___hash", process_id), process_id).unwrap();
+            db.put(format!("deephashversion___{}", process_id), b"1").unwrap();
         }
-      
 
-        fn create_key(
-            message_id: &str,
-            assignment_id: &Option<String>,
-            process_id: &str,
-            timestamp: &str,
-        ) -> Vec<u8> {
-            match assignment_id {
-                Some(assignment_id) => format!(
-                    "message___{}___{}___{}___{}",
-                    process_id, timestamp, message_id, assignment_id
+        let write_opts = WriteOptions::default();
+        assert_eq!(prune_process_keys(&db, "process1", &write_opts).unwrap(), 4);
+        assert_eq!(prune_process_keys(&db, "process1", &write_opts).unwrap(), 0);
+
+        assert_eq!(db.get(b"message___process1___1___m1").unwrap(), None);
+        assert_eq!(db.get(b"deephashversion___process1").unwrap(), None);
+        assert!(db.get(b"message___process2___1___m1").unwrap().is_some());
+        assert!(db.get(b"deephash___process2___hash").unwrap().is_some());
+        assert!(db.get(b"deephashversion___process2").unwrap().is_some());
+        assert_eq!(db.iterator(IteratorMode::Start).count(), 4);
+    }
+
+    /*
+      A purge only takes the read lock, so saves for
+      other processes still see the bytestore as ready
+      and land in it while the purge is running.
+    */
+    #[cfg(feature = "disk-store")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_bytestore_save_during_prune() {
+        use super::bytestore::ByteStore;
+        use crate::domain::config::AoConfig;
+
+        let _dirs = TestDirs(vec!["test_save_during_prune"]);
+        let config = AoConfig::from_vars(&[
+            ("USE_DISK", "true"),
+            ("SU_DATA_DIR", "test_save_during_prune"),
+        ])
+        .unwrap();
+        let bytestore = Arc::new(ByteStore::new(config, SuLog::init()));
+        bytestore.try_connect().await.unwrap();
+
+        let binaries = (0..50000)
+            .map(|i| {
+                (
+                    format!("m{}", i),
+                    Some(format!("a{}", i)),
+                    "process1".to_string(),
+                    i.to_string(),
+                    b"bundle".to_vec(),
                 )
-                .into_bytes(),
-                None => format!("message___{}___{}___{}", process_id, timestamp, message_id)
-                    .into_bytes(),
-            }
+            })
+            .collect();
+        bytestore.save_batch(binaries, vec![]).await.unwrap();
+
+        let prune = {
+            let bytestore = bytestore.clone();
+            tokio::spawn(async move { bytestore.prune_by_process("process1").await })
+        };
+
+        // the same check save_message_binary makes before writing
+        let mut saved = 0;
+        while !prune.is_finished() {
+            assert!(bytestore.is_ready());
+            bytestore
+                .save_binary(
+                    format!("m{}", saved),
+                    Some(format!("a{}", saved)),
+                    "process2".to_string(),
+                    saved.to_string(),
+                    b"bundle".to_vec(),
+                )
+                .await
+                .unwrap();
+            saved += 1;
         }
+        assert_eq!(prune.await.unwrap().unwrap(), 50000);
+
+        for i in 0..saved {
+            assert!(
+                bytestore
+                    .exists(
+                        &format!("m{}", i),
+                        &Some(format!("a{}", i)),
+                        "process2",
+                        &i.to_string()
+                    )
+                    .await
+            );
+        }
+    }
 
-        pub fn exists(
-            &self,
-            message_id: &str,
-            assignment_id: &Option<String>,
-            process_id: &str,
-            timestamp: &str,
-        ) -> bool {
-            let key = ByteStore::create_key(message_id, assignment_id, process_id, timestamp);
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return false,
-            };
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_process_deep_hashes() {
+        let _dirs = TestDirs(vec!["test_process_deep_hashes"]);
+        let db = DB::open_default("test_process_deep_hashes").unwrap();
+        db.put(b"message___process1___1___m1", b"bundle").unwrap();
+        db.put(b"deephash___process1___b", b"process1").unwrap();
+        db.put(b"deephash___process1___a", b"process1").unwrap();
+        db.put(b"deephashversion___process1", b"1").unwrap();
+        db.put(b"deephash___process2___c", b"process2").unwrap();
+
+        assert_eq!(
+            process_deep_hashes(&db, "process1").unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert!(process_deep_hashes(&db, "process3").unwrap().is_empty());
+    }
+
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_column_families() {
+        let _dirs = TestDirs(vec!["test_column_families_data"]);
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let db = DB::open_cf_descriptors(
+            &opts,
+            "test_column_families_data",
+            column_families(false, 5 * 1024 * 1024 * 1024, 1024),
+        )
+        .unwrap();
+        let messages = db.cf_handle(MESSAGES_CF).unwrap();
+        let deephashes = db.cf_handle(DEEPHASHES_CF).unwrap();
+
+        // a key written before the column families is still read
+        db.put(b"message___process1___1___m1", b"old").unwrap();
+        db.put_cf(messages, b"message___process1___2___m2", b"new")
+            .unwrap();
+        db.put_cf(deephashes, b"deephash___process1___hash", b"process1")
+            .unwrap();
+        db.put_cf(deephashes, b"deephashversion___process1", b"1")
+            .unwrap();
+
+        let read = |cf, key: &[u8]| get_with_fallback(&db, cf, key).unwrap();
+        assert_eq!(
+            read(MESSAGES_CF, b"message___process1___1___m1"),
+            Some(b"old".to_vec())
+        );
+        assert_eq!(
+            read(MESSAGES_CF, b"message___process1___2___m2"),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(read(MESSAGES_CF, b"deephash___process1___hash"), None);
+        assert!(db.get(b"deephash___process1___hash").unwrap().is_none());
+
+        let keys: Vec<Vec<u8>> = vec![
+            b"message___process1___2___m2".to_vec(),
+            b"message___process1___3___m3".to_vec(),
+            b"message___process1___1___m1".to_vec(),
+        ];
+        let values: Vec<Option<Vec<u8>>> = multi_get_with_fallback(&db, MESSAGES_CF, &keys)
+            .into_iter()
+            .map(|value| value.unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec![Some(b"new".to_vec()), None, Some(b"old".to_vec())]
+        );
+
+        let write_opts = WriteOptions::default();
+        assert_eq!(prune_process_keys(&db, "process1", &write_opts).unwrap(), 4);
+        assert_eq!(read(MESSAGES_CF, b"message___process1___1___m1"), None);
+        assert_eq!(read(DEEPHASHES_CF, b"deephashversion___process1"), None);
+    }
+
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_parse_legacy_message_key() {
+        let process_id = "p_RK3sBw8_8Ob4jmk3h1___Xsp7yu4U9sTPNlnNAOKg";
+        let message_id = "m2c9rEfvA4Xd1gO1e3Ct5DlbAX6SBqwOuB9Fv5FBiYE";
+        let assignment_id = "a2c9rEfvA4Xd1gO1e3Ct5DlbAX6SBqwOuB9Fv5FBiYE";
+
+        let legacy_key = format!("message___{}___1715000000000___{}", process_id, message_id);
+        let parsed = parse_legacy_message_key(legacy_key.as_bytes()).unwrap();
+        assert_eq!(parsed.process_id, process_id);
+        assert_eq!(parsed.timestamp, "1715000000000");
+        assert_eq!(parsed.message_id, message_id);
+
+        let current_key = format!(
+            "message___{}___1715000000000___{}___{}",
+            process_id, message_id, assignment_id
+        );
+        assert_eq!(parse_legacy_message_key(current_key.as_bytes()), None);
+        assert_eq!(
+            parse_legacy_message_key(format!("deephash___{}___hash", process_id).as_bytes()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker_transitions() {
+        let breaker = CircuitBreaker::new("test", 2, Duration::from_millis(50), SuLog::init());
+        let fail = || Err::<(), ()>(());
+
+        assert!(matches!(
+            breaker.call(fail),
+            Err(StoreErrorType::DatabaseError { .. })
+        ));
+        assert_eq!(breaker.stats().state, "closed");
+        assert!(breaker.call(fail).is_err());
+        assert_eq!(breaker.stats().state, "open");
+        assert_eq!(breaker.stats().opened, 1);
+
+        // fails fast without touching the pool while open
+        assert!(matches!(
+            breaker.call(|| -> Result<(), ()> { panic!("checkout while open") }),
+            Err(StoreErrorType::Unavailable(_))
+        ));
+        assert_eq!(breaker.stats().rejected, 1);
+
+        // a failed probe opens it again
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(matches!(
+            breaker.call(fail),
+            Err(StoreErrorType::DatabaseError { .. })
+        ));
+        assert_eq!(breaker.stats().state, "open");
+        assert_eq!(breaker.stats().opened, 2);
+
+        // a good probe closes it
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.call(|| Ok::<(), ()>(())).is_ok());
+        let stats = breaker.stats();
+        assert_eq!(stats.state, "closed");
+        assert_eq!(stats.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_rate_limiter_per_process() {
+        let limiter = RateLimiter::new(
+            Some(1.0),
+            Some(2),
+            vec!["exempt".to_string()],
+            SuLog::init(),
+        )
+        .unwrap();
+
+        assert!(limiter.acquire("process1").is_ok());
+        assert!(limiter.acquire("process1").is_ok());
+        let err = limiter.acquire("process1").unwrap_err();
+        assert!(matches!(err, StoreErrorType::RateLimited { .. }));
+        assert_eq!(err.retry_after(), Some(1));
+        let err = FlowError::from(err);
+        assert_eq!(err.http_status(), 429);
+        assert_eq!(err.retry_after(), Some(1));
+
+        // other processes have their own bucket
+        assert!(limiter.acquire("process2").is_ok());
+
+        for _ in 0..10 {
+            assert!(limiter.acquire("exempt").is_ok());
+        }
+
+        assert!(RateLimiter::new(None, Some(2), vec![], SuLog::init()).is_none());
+        assert_eq!(StoreErrorType::database("retry after 1s").retry_after(), None);
+    }
+
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"message___"), Some(b"message__`".to_vec()));
+        assert_eq!(prefix_end(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff\xff"), None);
+        assert_eq!(prefix_end(b""), None);
+    }
+
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_disk_level() {
+        assert_eq!(disk_level(50.0, 85.0, 95.0), DiskLevel::Ok);
+        assert_eq!(disk_level(85.0, 85.0, 95.0), DiskLevel::Soft);
+        assert_eq!(disk_level(94.9, 85.0, 95.0), DiskLevel::Soft);
+        assert_eq!(disk_level(95.0, 85.0, 95.0), DiskLevel::Hard);
+        assert_eq!(disk_level(100.0, 85.0, 95.0), DiskLevel::Hard);
+
+        let usage = disk_usage_percent(&std::env::temp_dir()).unwrap();
+        assert!((0.0..=100.0).contains(&usage));
+        assert!(disk_usage_percent(std::path::Path::new("/no/such/dir")).is_err());
+    }
+
+    #[test]
+    fn test_in_compaction_window() {
+        assert!(in_compaction_window(0, 0, 24));
+        assert!(in_compaction_window(23, 0, 24));
+        assert!(in_compaction_window(2, 2, 5));
+        assert!(!in_compaction_window(5, 2, 5));
+        // a window over midnight
+        assert!(in_compaction_window(23, 22, 3));
+        assert!(in_compaction_window(1, 22, 3));
+        assert!(!in_compaction_window(12, 22, 3));
+    }
+
+    #[test]
+    fn test_like_contains_escapes_wildcards() {
+        assert_eq!(like_contains("abc"), "%abc%");
+        assert_eq!(like_contains("a_b%c\\d"), "%a\\_b\\%c\\\\d%");
+    }
+
+    #[test]
+    fn test_like_prefix_escapes_wildcards() {
+        assert_eq!(like_prefix("abc"), "abc%");
+        assert_eq!(like_prefix("a_b%c\\d"), "a\\_b\\%c\\\\d%");
+    }
+
+    struct UniqueViolation(Option<&'static str>);
+
+    impl diesel::result::DatabaseErrorInformation for UniqueViolation {
+        fn message(&self) -> &str {
+            "duplicate key value violates unique constraint"
+        }
+        fn details(&self) -> Option<&str> {
+            None
+        }
+        fn hint(&self) -> Option<&str> {
+            None
+        }
+        fn table_name(&self) -> Option<&str> {
+            Some("messages")
+        }
+        fn column_name(&self) -> Option<&str> {
+            None
+        }
+        fn constraint_name(&self) -> Option<&str> {
+            self.0
+        }
+        fn statement_position(&self) -> Option<i32> {
+            None
+        }
+    }
 
-            if let Some(ref db) = *db {
-                match db.get(&key) {
-                    Ok(Some(_)) => true,
-                    _ => false,
-                }
-            } else {
-                false
+    fn unique_violation(constraint: Option<&'static str>) -> diesel::result::Error {
+        diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            Box::new(UniqueViolation(constraint)),
+        )
+    }
+
+    #[test]
+    fn test_duplicate_assignment_insert_is_message_exists() {
+        for constraint in [
+            Some("idx_messages_assignment_id_unique"),
+            Some("messages_assignment_id_key"),
+            None,
+        ] {
+            match insert_message_error(unique_violation(constraint), "assignment1") {
+                StoreErrorType::MessageExists(msg) => assert!(msg.contains("assignment1")),
+                other => panic!("expected MessageExists, got {:?}", other),
             }
         }
 
-        pub fn save_deep_hash(
-            &self,
-            process_id: &String,
-            deep_hash: &String,
-        ) -> Result<(), String> {
-            let key = format!("deephash___{}___{}", process_id, deep_hash).into_bytes();
-
-            let value = format!("{}", process_id).into_bytes();
+        match insert_message_error(
+            unique_violation(Some("idx_messages_message_id_data_item_unique")),
+            "assignment1",
+        ) {
+            StoreErrorType::MessageExists(msg) => assert_eq!(msg, "Message already exists"),
+            other => panic!("expected MessageExists, got {:?}", other),
+        }
 
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return Err("Failed to acquire read lock".into()),
-            };
+        assert!(matches!(
+            insert_message_error(unique_violation(Some("messages_pkey")), "assignment1"),
+            StoreErrorType::DatabaseError { .. }
+        ));
+        assert!(matches!(
+            insert_message_error(diesel::result::Error::RollbackTransaction, "assignment1"),
+            StoreErrorType::DatabaseError { .. }
+        ));
+    }
 
-            if let Some(ref db) = *db {
-                db.put(key, value)
-                    .map_err(|e| format!("Failed to write to RocksDB: {:?}", e))?;
-                Ok(())
-            } else {
-                Err("Database is not initialized".into())
-            }
+    fn db_message(row_id: i32, msg_id: &str, assign_id: Option<&str>, ts: i64) -> DbMessage {
+        DbMessage {
+            row_id,
+            process_id: "process1".to_string(),
+            message_id: msg_id.to_string(),
+            assignment_id: assign_id.map(|a| a.to_string()),
+            message_data: serde_json::Value::Null,
+            epoch: 0,
+            nonce: row_id,
+            timestamp: ts,
+            bundle: format!("db-{}", row_id).into_bytes(),
+            hash_chain: String::new(),
+            deleted_at: None,
+            bundle_checksum: None,
+            sender_id: None,
         }
+    }
 
-        pub fn delete_deep_hash(
-            &self,
-            process_id: &String,
-            deep_hash: &String,
-        ) -> Result<(), String> {
-            let key = format!("deephash___{}___{}", process_id, deep_hash).into_bytes();
-        
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return Err("Failed to acquire read lock".into()),
-            };
-        
-            if let Some(ref db) = *db {
-                db.delete(key)
-                    .map_err(|e| format!("Failed to delete from RocksDB: {:?}", e))?;
-                Ok(())
-            } else {
-                Err("Database is not initialized".into())
-            }
-        }
-      
+    #[test]
+    fn test_fill_page_batches_bytestore_misses() {
+        let page_rows = vec![
+            db_message(1, "m1", Some("a1"), 100),
+            db_message(2, "m2", Some("a2"), 200),
+            db_message(3, "m3", None, 300),
+            db_message(4, "m4", Some("a4"), 400),
+        ];
+        let key = |m: &DbMessage| {
+            (
+                m.message_id.clone(),
+                m.assignment_id.clone(),
+                m.process_id.clone(),
+                m.timestamp.to_string(),
+            )
+        };
 
-        pub fn save_deep_hash_version(
-            &self,
-            process_id: &String,
-            version: &String,
-        ) -> Result<(), String> {
-            let key = format!("deephashversion___{}", process_id).into_bytes();
+        // Only every other row made it into the bytestore
+        let binaries = DashMap::new();
+        binaries.insert(key(&page_rows[0]), b"bytes-1".to_vec());
+        binaries.insert(key(&page_rows[2]), b"bytes-3".to_vec());
+
+        // What postgres holds for the misses, oldest first
+        let db_rows = vec![
+            db_message(2, "m2", Some("a2"), 200),
+            db_message(4, "m4", Some("a4"), 400),
+            db_message(5, "m2", Some("a5"), 500),
+        ];
+
+        let loads = StdMutex::new(0);
+        let page = fill_page(
+            &page_rows,
+            &binaries,
+            key,
+            |missing| {
+                *loads.lock().unwrap() += 1;
+                let expected: HashSet<_> = [
+                    ("m2".to_string(), Some("a2".to_string())),
+                    ("m4".to_string(), Some("a4".to_string())),
+                ]
+                .into_iter()
+                .collect();
+                assert_eq!(missing, &expected);
+                Ok(oldest_per_key(db_rows, missing))
+            },
+            |_, bytes| Ok(bytes.clone()),
+            |_, db_row| Ok(db_row.bundle.clone()),
+        )
+        .unwrap();
+
+        assert_eq!(*loads.lock().unwrap(), 1);
+        assert_eq!(
+            page,
+            vec![
+                b"bytes-1".to_vec(),
+                b"db-2".to_vec(),
+                b"bytes-3".to_vec(),
+                b"db-4".to_vec(),
+            ]
+        );
+
+        // A fully populated page never touches postgres
+        let page = fill_page(
+            &page_rows[..1],
+            &binaries,
+            key,
+            |_| panic!("no misses to load"),
+            |_, bytes| Ok(bytes.clone()),
+            |_, db_row| Ok(db_row.bundle.clone()),
+        )
+        .unwrap();
+        assert_eq!(page, vec![b"bytes-1".to_vec()]);
+
+        // A miss postgres doesn't have is NotFound
+        let result = fill_page(
+            &page_rows[1..2],
+            &binaries,
+            key,
+            |_| Ok(Default::default()),
+            |_, bytes| Ok(bytes.clone()),
+            |_, db_row| Ok(db_row.bundle.clone()),
+        );
+        assert!(matches!(result, Err(StoreErrorType::NotFound { .. })));
+    }
 
-            let value = format!("{}", version).into_bytes();
+    #[test]
+    fn test_oldest_per_key_matches_single_lookups() {
+        let rows = vec![
+            db_message(1, "m1", None, 100),
+            db_message(2, "m1", Some("a2"), 200),
+            db_message(3, "m1", Some("a3"), 300),
+            db_message(4, "m1", Some("a3"), 400),
+        ];
+        let wanted: HashSet<_> = [
+            ("m1".to_string(), None),
+            ("m1".to_string(), Some("a3".to_string())),
+        ]
+        .into_iter()
+        .collect();
+
+        let found = oldest_per_key(rows, &wanted);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[&("m1".to_string(), None)].row_id, 1);
+        assert_eq!(found[&("m1".to_string(), Some("a3".to_string()))].row_id, 3);
+    }
 
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return Err("Failed to acquire read lock".into()),
-            };
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_bytestore_tombstone_is_not_a_value() {
+        assert!(is_tombstone(b"tombstone"));
 
-            if let Some(ref db) = *db {
-                db.put(key, value)
-                    .map_err(|e| format!("Failed to write to RocksDB: {:?}", e))?;
-                Ok(())
-            } else {
-                Err("Database is not initialized".into())
-            }
+        let bundle = vec![1u8; 64];
+        assert!(!is_tombstone(&bundle));
+        assert!(!is_tombstone(&encode_value(bundle.clone(), None)));
+        assert!(!is_tombstone(&encode_value(bundle, Some(3))));
+        assert!(!is_tombstone(&[]));
+    }
+
+    #[test]
+    fn test_cache_invalidation_backoff() {
+        let mut backoff = Duration::from_secs(1);
+        let mut waits = vec![];
+        for _ in 0..8 {
+            waits.push(backoff.as_secs());
+            backoff = next_backoff(backoff);
         }
+        assert_eq!(waits, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+    }
 
-        pub fn get_deep_hash_version(&self, process_id: &String) -> Result<String, String> {
-            let key = format!("deephashversion___{}", process_id).into_bytes();
+    /*
+      The message count cache the way StoreClient drives
+      it, the database count is stood in for by a number.
+    */
+    #[tokio::test]
+    async fn test_message_count_cache() {
+        let cache: CountedCache<i64> =
+            CountedCache::new("message_counts", 2).with_ttl(Duration::from_millis(50));
+        let process_id = "process".to_string();
+
+        // a save before the first read leaves nothing to count up
+        assert!(!cache.update(&process_id, |count| *count += 1).await);
+        assert_eq!(cache.get(&process_id).await, None);
+
+        cache.insert(process_id.clone(), 10).await;
+        assert!(cache.update(&process_id, |count| *count += 1).await);
+        assert!(cache.update(&process_id, |count| *count += 1).await);
+        assert_eq!(cache.get(&process_id).await, Some(12));
+
+        // saves don't push the ttl out, a drifted count is read again
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        cache.update(&process_id, |count| *count += 1).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(cache.get(&process_id).await, None);
+
+        cache.insert(process_id.clone(), 7).await;
+        assert_eq!(cache.get(&process_id).await, Some(7));
+    }
 
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return Err("Cannot acquire read lock, deep hash version".to_string()),
-            };
+    #[tokio::test]
+    async fn test_counted_cache_clear() {
+        let cache: CountedCache<u32> = CountedCache::new("test", 4);
+        cache.insert("a".to_string(), 1).await;
+        cache.insert("b".to_string(), 2).await;
+        cache.clear().await;
 
-            if let Some(ref db) = *db {
-                match db.get(&key) {
-                    Ok(Some(v)) => match String::from_utf8(v) {
-                        Ok(vs) => Ok(vs),
-                        Err(_) => Err("Error parsing deep hash version".to_string()),
-                    },
-                    _ => return Err("No deephash version found".to_string()),
-                }
-            } else {
-                return Err("Cannot acquire read lock, deep hash version, match".to_string());
-            }
-        }
+        assert_eq!(cache.get(&"a".to_string()).await, None);
+        assert_eq!(cache.stats().await.len, 0);
+    }
 
-        pub fn deep_hash_exists(&self, process_id: &String, deep_hash: &String) -> bool {
-            let key = format!("deephash___{}___{}", process_id, deep_hash).into_bytes();
+    #[cfg(feature = "disk-store")]
+    #[test]
+    fn test_memory_guard_limits_in_flight_reads() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
 
-            let db = match self.db.read() {
-                Ok(r) => r,
-                Err(_) => return false,
-            };
+        let mut first = MemoryGuard::new(in_flight.clone());
+        first.reserve(60, 100).unwrap();
+        first.reserve(30, 100).unwrap();
 
-            if let Some(ref db) = *db {
-                match db.get(&key) {
-                    Ok(Some(_)) => true,
-                    _ => false,
-                }
-            } else {
-                false
-            }
+        // a second request can't take more than what is left
+        let mut second = MemoryGuard::new(in_flight.clone());
+        assert!(second.reserve(20, 100).is_err());
+        second.reserve(10, 100).unwrap();
+        assert!(second.is_full(100));
+        assert_eq!(in_flight.load(Ordering::SeqCst), 100);
+
+        drop(first);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 10);
+        assert!(!second.is_full(100));
+
+        drop(second);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bytestore_sync_passes_dont_overlap_and_stop() {
+        let sync = Arc::new(BytestoreSync::default());
+
+        let pass = sync.begin_pass().unwrap();
+        assert!(sync.begin_pass().is_none());
+        drop(pass);
+        assert!(sync.begin_pass().is_some());
+
+        // stop wakes a sleeping task and waits for it to end
+        let task_sync = sync.clone();
+        let handle =
+            tokio::spawn(async move { while task_sync.sleep(Duration::from_secs(3600)).await {} });
+        *sync.handle.lock().unwrap() = Some(handle);
+
+        tokio::time::timeout(Duration::from_secs(5), sync.stop())
+            .await
+            .unwrap();
+        assert!(sync.is_stopping());
+        assert!(!sync.sleep(Duration::from_secs(3600)).await);
+    }
+
+    #[test]
+    fn test_store_error_categories() {
+        let err = StoreErrorType::not_found("message", "abc");
+        assert_eq!(err.to_string(), "message abc not found");
+        assert_eq!(err.http_status(), 404);
+
+        let err = FlowError::from(insert_message_error(unique_violation(None), "assignment1"));
+        assert_eq!(err.category(), Some(ErrorCategory::Conflict));
+        assert_eq!(err.http_status(), 409);
+
+        // anything from outside the store is a bad request, whatever its message says
+        let err = FlowError::from("not_found: Failed to parse owner");
+        assert_eq!(err.category(), None);
+        assert_eq!(err.http_status(), 400);
+        assert_eq!(err.to_string(), "not_found: Failed to parse owner");
+
+        // the diesel error is kept as the source
+        let err = StoreErrorType::from(diesel::result::Error::RollbackTransaction);
+        assert_eq!(err.http_status(), 500);
+        assert!(std::error::Error::source(&err).is_some());
+        assert!(std::error::Error::source(&StoreErrorType::database("plain")).is_none());
+    }
+
+    struct NoopManager;
+
+    impl diesel::r2d2::ManageConnection for NoopManager {
+        type Connection = ();
+        type Error = diesel::r2d2::Error;
+
+        fn connect(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn is_valid(&self, _conn: &mut ()) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _conn: &mut ()) -> bool {
+            false
         }
     }
-}
 
-/*
-  This function is the migation program will
-  copy all the message data from the database to rocksdb.
-  It is not meant to be run anywhere within the su
-  server itself but is built into its own binary.
-*/
-pub async fn migrate_to_disk() -> io::Result<()> {
-    use std::time::{Duration, Instant};
-    let start = Instant::now();
-    dotenv().ok();
+    #[tokio::test]
+    async fn test_drain_pool_waits_for_checked_out_connections() {
+        let pool = diesel::r2d2::Pool::builder()
+            .max_size(2)
+            .build(NoopManager)
+            .unwrap();
+        assert!(drain_pool(&pool, Duration::from_secs(1)).await.is_ok());
+
+        let conn = pool.get().unwrap();
+        assert!(matches!(
+            drain_pool(&pool, Duration::from_millis(300)).await,
+            Err(StoreErrorType::Unavailable(_))
+        ));
 
-    let data_store = Arc::new(StoreClient::new().expect("Failed to create StoreClient"));
-    data_store
-        .bytestore
-        .try_connect()
-        .expect("Failed to connect to bytestore");
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            drop(conn);
+        });
+        assert!(drain_pool(&pool, Duration::from_secs(5)).await.is_ok());
+        release.await.unwrap();
+    }
 
-    let args: Vec<String> = env::args().collect();
-    let range: &String = args.get(2).expect("Range argument not provided");
-    let parts: Vec<&str> = range.split('-').collect();
-    let from = parts[0].parse().expect("Invalid starting offset");
-    let to = if parts.len() > 1 {
-        Some(parts[1].parse().expect("Invalid records to pull"))
-    } else {
-        None
-    };
+    #[test]
+    fn test_has_idle_connection() {
+        let pool = diesel::r2d2::Pool::builder()
+            .max_size(1)
+            .build(NoopManager)
+            .unwrap();
+        assert!(has_idle_connection(&pool));
+
+        let conn = pool.get().unwrap();
+        assert!(!has_idle_connection(&pool));
+        drop(conn);
+        assert!(has_idle_connection(&pool));
+    }
 
-    let total_count = match to {
-        Some(t) => {
-            let total = data_store
-                .get_message_count()
-                .expect("Failed to get message count");
-            if t > total {
-                total - from
-            } else {
-                t - from
-            }
+    #[test]
+    fn test_diesel_not_found_is_not_found() {
+        let err = StoreErrorType::from(diesel::result::Error::NotFound);
+        assert!(matches!(err, StoreErrorType::NotFound { .. }));
+        assert_eq!(err.http_status(), 404);
+        assert_eq!(err.to_string(), "record not found");
+
+        // through ? the same way the store's queries use it
+        let lookup = || -> Result<i32, StoreErrorType> { Err(diesel::result::Error::NotFound)? };
+        assert!(matches!(lookup(), Err(StoreErrorType::NotFound { .. })));
+
+        // insert errors that aren't unique violations convert the same way
+        assert!(matches!(
+            insert_message_error(diesel::result::Error::NotFound, "assignment1"),
+            StoreErrorType::NotFound { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_message_throughput_window() {
+        let base = Instant::now();
+        let mut saves: VecDeque<Instant> = [0, 30, 59]
+            .iter()
+            .map(|secs| base + Duration::from_secs(*secs))
+            .collect();
+
+        trim_window(&mut saves, base + Duration::from_secs(60));
+        assert_eq!(saves.len(), 2);
+        trim_window(&mut saves, base + Duration::from_secs(120));
+        assert!(saves.is_empty());
+
+        let throughput = MessageThroughput::default();
+        assert_eq!(throughput.per_second().await, 0.0);
+        for _ in 0..30 {
+            throughput.record().await;
         }
-        None => {
-            data_store
-                .get_message_count()
-                .expect("Failed to get message count")
-                - from
+        assert_eq!(throughput.per_second().await, 0.5);
+    }
+
+    struct PgError(&'static str);
+
+    impl diesel::result::DatabaseErrorInformation for PgError {
+        fn message(&self) -> &str {
+            self.0
         }
-    };
+        fn details(&self) -> Option<&str> {
+            None
+        }
+        fn hint(&self) -> Option<&str> {
+            None
+        }
+        fn table_name(&self) -> Option<&str> {
+            None
+        }
+        fn column_name(&self) -> Option<&str> {
+            None
+        }
+        fn constraint_name(&self) -> Option<&str> {
+            None
+        }
+        fn statement_position(&self) -> Option<i32> {
+            None
+        }
+    }
 
-    format!("Total messages to process: {}", total_count);
+    fn pg_error(kind: diesel::result::DatabaseErrorKind, message: &'static str) -> StoreErrorType {
+        StoreErrorType::from(diesel::result::Error::DatabaseError(
+            kind,
+            Box::new(PgError(message)),
+        ))
+    }
 
-    let config = AoConfig::new(Some("su".to_string())).expect("Failed to read configuration");
-    let batch_size = config.migration_batch_size.clone() as usize;
+    #[test]
+    fn test_retryable_write_errors() {
+        use diesel::result::DatabaseErrorKind;
+
+        assert!(is_retryable(&pg_error(
+            DatabaseErrorKind::SerializationFailure,
+            "could not serialize access due to concurrent update"
+        )));
+        assert!(is_retryable(&pg_error(
+            DatabaseErrorKind::Unknown,
+            "deadlock detected"
+        )));
+        assert!(!is_retryable(&pg_error(
+            DatabaseErrorKind::UniqueViolation,
+            "duplicate key value violates unique constraint"
+        )));
+        assert!(!is_retryable(&StoreErrorType::database(
+            "deadlock detected"
+        )));
+        assert!(!is_retryable(&StoreErrorType::from(
+            diesel::result::Error::NotFound
+        )));
+    }
 
-    let processed_count = Arc::new(AtomicUsize::new(0));
+    #[tokio::test]
+    async fn test_retry_write_retries_only_retryable_errors() {
+        use diesel::result::DatabaseErrorKind;
 
-    // Spawn a task to log progress every minute
-    let processed_count_clone = Arc::clone(&processed_count);
-    let data_store_c = Arc::clone(&data_store);
-    tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(10));
-        loop {
-            interval.tick().await;
-            data_store_c.logger.log(format!(
-                "Messages processed update: {}",
-                processed_count_clone.load(Ordering::SeqCst)
-            ));
-            if processed_count_clone.load(Ordering::SeqCst) >= total_count as usize {
-                break;
+        let logger = SuLog::init();
+        let counter = AtomicUsize::new(0);
+        let attempts = &counter;
+        let deadlock_twice = move || async move {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(pg_error(DatabaseErrorKind::Unknown, "deadlock detected")),
+                _ => Ok("saved"),
             }
-        }
-    });
+        };
 
-    for batch_start in (from..from + total_count).step_by(batch_size) {
-        let batch_end = if let Some(t) = to {
-            std::cmp::min(batch_start + batch_size as i64, t)
-        } else {
-            batch_start + batch_size as i64
+        assert_eq!(
+            retry_write(&logger, 3, "test", deadlock_twice)
+                .await
+                .unwrap(),
+            "saved"
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // out of retries the last error is returned
+        attempts.store(0, Ordering::SeqCst);
+        assert!(retry_write(&logger, 1, "test", deadlock_twice)
+            .await
+            .is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // anything else fails on the first attempt
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<(), StoreErrorType> =
+            retry_write(&logger, 3, "test", move || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(StoreErrorType::Conflict("stale".to_string()))
+            })
+            .await;
+        assert!(matches!(result, Err(StoreErrorType::Conflict(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    /*
+      Saves for one process are queued without waiting
+      on each other, so they go out in a single batch,
+      and the queued nonces stay reserved until then.
+    */
+    #[tokio::test]
+    async fn test_batch_writer_batches_one_process() {
+        use super::batch_writer::{BatchWriter, PendingMessage};
+        use crate::domain::core::store_suite::assignment;
+
+        let batches = Arc::new(StdMutex::new(Vec::new()));
+        let writer = {
+            let batches = batches.clone();
+            BatchWriter::with_writer(5, 1000, move |pending| {
+                batches.lock().unwrap().push(pending.len());
+                let results: Vec<Result<String, StoreErrorType>> =
+                    pending.iter().map(|_| Ok("saved".to_string())).collect();
+                async move { results }
+            })
         };
 
-        let data_store = Arc::clone(&data_store);
-        let processed_count = Arc::clone(&processed_count);
+        let mut queued = Vec::new();
+        for nonce in 1..=5 {
+            let message = assignment("process1", nonce, nonce as i64);
+            let pending = PendingMessage::new(&message, b"bundle", None).unwrap();
+            queued.push(writer.enqueue(pending).await.unwrap());
+        }
+        assert_eq!(writer.reserved_nonce("process1"), Some(5));
 
-        let result = data_store.get_all_messages(batch_start, Some(batch_end));
+        for queued in queued {
+            assert_eq!(queued.result().await.unwrap(), "saved");
+        }
+        assert_eq!(*batches.lock().unwrap(), vec![5]);
+        assert_eq!(writer.reserved_nonce("process1"), None);
 
-        match result {
-            Ok(messages) => {
-                let mut save_handles: Vec<JoinHandle<()>> = Vec::new();
-                for message in messages {
-                    let msg_id = message.0;
-                    let assignment_id = message.1;
-                    let bundle = message.2;
-                    let process_id = message.3;
-                    let timestamp = message.5;
-                    let data_store = Arc::clone(&data_store);
-                    let processed_count = Arc::clone(&processed_count);
+        writer.shutdown().await;
+    }
 
-                    let handle = tokio::spawn(async move {
-                        data_store
-                            .bytestore
-                            .clone()
-                            .save_binary(
-                                msg_id.clone(),
-                                assignment_id.clone(),
-                                process_id.clone(),
-                                timestamp.clone(),
-                                bundle,
-                            )
-                            .unwrap();
-                        processed_count.fetch_add(1, Ordering::SeqCst);
-                    });
+    /*
+      The shared store suite against a real postgres, the
+      one DATABASE_URL points at. It leaves its rows
+      behind so only point it at a scratch database, run
+      with cargo test -- --ignored.
+    */
+    #[tokio::test]
+    #[ignore]
+    async fn test_store_client_suite() {
+        use super::StoreClient;
+        use crate::domain::core::store_suite::{
+            data_store_suite, deep_hash_suite, get_messages_suite, router_data_store_suite,
+        };
 
-                    save_handles.push(handle);
+        let store = StoreClient::new().unwrap();
+        let prefix = format!(
+            "suite-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        );
+        data_store_suite(&store, &prefix).await;
+        get_messages_suite(&store, &prefix).await;
+        deep_hash_suite(&store, &prefix).await;
+        router_data_store_suite(&store, &prefix).await;
+    }
+
+    /*
+      Writers that each read the latest message, build the
+      next nonce and save it, the way the flows do, racing
+      on one process. The loser of a race is rejected and
+      reads the latest again, so the nonces come out
+      gapless and every lock is cleaned up. Also needs the
+      postgres DATABASE_URL points at.
+    */
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn test_save_message_concurrent_nonces() {
+        use super::StoreClient;
+        use crate::domain::core::dal::DataStore;
+        use crate::domain::core::store_suite::{assignment, process};
+
+        const WRITERS: i32 = 30;
+
+        let store = Arc::new(StoreClient::new().unwrap());
+        let process_id = format!(
+            "nonces-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        );
+        store.save_process(&process(&process_id), &[]).await.unwrap();
+
+        let mut handles = Vec::new();
+        for writer in 0..WRITERS {
+            let store = store.clone();
+            let process_id = process_id.clone();
+            handles.push(tokio::spawn(async move {
+                loop {
+                    let nonce = match store.get_latest_message(&process_id).await.unwrap() {
+                        Some(latest) => latest.nonce().unwrap() + 1,
+                        None => 1,
+                    };
+
+                    // ids of its own, so only the nonce can clash
+                    let mut message = assignment(&process_id, nonce, nonce as i64);
+                    message.assignment.id = format!("{}-w{}-n{}", process_id, writer, nonce);
+                    for tag in message.assignment.tags.iter_mut() {
+                        if tag.name == "Message" {
+                            tag.value = format!("{}-m{}-n{}", process_id, writer, nonce);
+                        }
+                    }
+
+                    match store.save_message(&message, &[], None).await {
+                        Ok(_) => return,
+                        Err(StoreErrorType::Conflict(_)) => continue,
+                        Err(e) => panic!("unexpected save error {:?}", e),
+                    }
                 }
-                join_all(save_handles).await;
-            }
-            Err(e) => {
-                data_store
-                    .logger
-                    .error(format!("Error fetching messages: {:?}", e));
-            }
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
         }
+
+        let process = store.get_process(&process_id).await.unwrap();
+        let page = store
+            .get_messages(
+                &process,
+                &crate::domain::core::dal::MessageFilter {
+                    limit: Some(WRITERS + 1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let nonces: Vec<i32> = page
+            .edges
+            .iter()
+            .map(|edge| edge.node.nonce().unwrap())
+            .collect();
+        assert_eq!(nonces, (1..=WRITERS).collect::<Vec<i32>>());
+        assert!(store.process_locks.locks.is_empty());
     }
 
-    let duration = start.elapsed();
-    data_store
-        .logger
-        .log(format!("Time elapsed in data migration is: {:?}", duration));
+    /*
+      The clock can step back between two messages, the
+      later nonce has to be the latest one anyway. Needs
+      a migrated postgres at DATABASE_URL.
+    */
+    #[tokio::test]
+    #[ignore]
+    async fn test_get_latest_message_orders_by_nonce() {
+        use super::StoreClient;
+        use crate::domain::core::dal::DataStore;
+        use crate::domain::core::store_suite::{assignment, process};
+
+        let store = StoreClient::new().unwrap();
+        let run = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let stepped = format!("latest-stepped-{}", run);
+        let ordered = format!("latest-ordered-{}", run);
+
+        for process_id in [&stepped, &ordered] {
+            store.save_process(&process(process_id), &[]).await.unwrap();
+        }
+        for (nonce, timestamp) in [(1, 20), (2, 10)] {
+            let message = assignment(&stepped, nonce, timestamp);
+            store.save_message(&message, &[], None).await.unwrap();
+        }
+        for (nonce, timestamp) in [(1, 10), (2, 20), (3, 30)] {
+            let message = assignment(&ordered, nonce, timestamp);
+            store.save_message(&message, &[], None).await.unwrap();
+        }
 
-    Ok(())
+        let latest = store.get_latest_message(&stepped).await.unwrap().unwrap();
+        assert_eq!(latest.nonce().unwrap(), 2);
+
+        let latest = store
+            .get_latest_messages_for_processes(&[&stepped, &ordered])
+            .await
+            .unwrap();
+        assert_eq!(latest[&stepped].nonce().unwrap(), 2);
+        assert_eq!(latest[&ordered].nonce().unwrap(), 3);
+    }
+
+    #[derive(diesel::QueryableByName)]
+    struct Setting {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        setting: String,
+    }
+
+    // also needs the postgres DATABASE_URL points at
+    #[tokio::test]
+    #[ignore]
+    async fn test_statement_timeout() {
+        use super::StoreClient;
+        use crate::domain::config::AoConfig;
+        use diesel::RunQueryDsl;
+
+        let timeout_ms = AoConfig::new(Some("su".to_string()))
+            .unwrap()
+            .db_statement_timeout_ms;
+        let store = StoreClient::new().unwrap();
+        let query = "SELECT setting FROM pg_settings WHERE name = 'statement_timeout'";
+
+        for mut conn in [store.get_conn().unwrap(), store.get_read_conn().unwrap()] {
+            let setting = diesel::sql_query(query)
+                .get_result::<Setting>(&mut conn)
+                .unwrap();
+            assert_eq!(setting.setting, timeout_ms.to_string());
+        }
+    }
 }
@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+/*
+  Long running admin operations are started in the
+  background and return a task id, which is polled
+  until the task is done or failed. Only the latest
+  MAX_ADMIN_TASKS are kept, the oldest finished ones
+  are dropped first.
+*/
+pub const MAX_ADMIN_TASKS: usize = 100;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminTaskStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AdminTask {
+    pub id: u64,
+    pub action: String,
+    pub status: AdminTaskStatus,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct AdminTasks {
+    next_id: AtomicU64,
+    tasks: DashMap<u64, AdminTask>,
+}
+
+impl AdminTasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self, action: &str, now: u64) -> u64 {
+        self.prune();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        self.tasks.insert(
+            id,
+            AdminTask {
+                id,
+                action: action.to_string(),
+                status: AdminTaskStatus::Running,
+                started_at: now,
+                finished_at: None,
+                result: None,
+                error: None,
+            },
+        );
+        id
+    }
+
+    pub fn finish(&self, id: u64, outcome: Result<serde_json::Value, String>, now: u64) {
+        if let Some(mut task) = self.tasks.get_mut(&id) {
+            task.finished_at = Some(now);
+            match outcome {
+                Ok(result) => {
+                    task.status = AdminTaskStatus::Done;
+                    task.result = Some(result);
+                }
+                Err(e) => {
+                    task.status = AdminTaskStatus::Failed;
+                    task.error = Some(e);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<AdminTask> {
+        self.tasks.get(&id).map(|task| task.clone())
+    }
+
+    /*
+      Make room for one more task. Running tasks are
+      never dropped, so there can be more than
+      MAX_ADMIN_TASKS while they are all running.
+    */
+    fn prune(&self) {
+        while self.tasks.len() >= MAX_ADMIN_TASKS {
+            let oldest = self
+                .tasks
+                .iter()
+                .filter(|task| task.status != AdminTaskStatus::Running)
+                .map(|task| task.id)
+                .min();
+            match oldest {
+                Some(id) => self.tasks.remove(&id),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_admin_tasks() {
+        let tasks = AdminTasks::new();
+        let done = tasks.start("sync_bytestore", 1);
+        let failed = tasks.start("check_integrity", 2);
+        assert_ne!(done, failed);
+        assert_eq!(tasks.get(done).unwrap().status, AdminTaskStatus::Running);
+
+        tasks.finish(done, Ok(json!({ "synced": 3 })), 5);
+        tasks.finish(failed, Err("not found".to_string()), 6);
+
+        let task = tasks.get(done).unwrap();
+        assert_eq!(task.status, AdminTaskStatus::Done);
+        assert_eq!(task.finished_at, Some(5));
+        assert_eq!(task.result, Some(json!({ "synced": 3 })));
+        let task = tasks.get(failed).unwrap();
+        assert_eq!(task.status, AdminTaskStatus::Failed);
+        assert_eq!(task.error.as_deref(), Some("not found"));
+        assert!(tasks.get(failed + 1).is_none());
+    }
+
+    #[test]
+    fn test_admin_tasks_prune() {
+        let tasks = AdminTasks::new();
+        let running = tasks.start("sync_bytestore", 0);
+        let first_done = tasks.start("check_integrity", 0);
+        tasks.finish(first_done, Ok(json!(null)), 0);
+        for _ in 2..MAX_ADMIN_TASKS {
+            let id = tasks.start("check_integrity", 0);
+            tasks.finish(id, Ok(json!(null)), 0);
+        }
+        assert_eq!(tasks.tasks.len(), MAX_ADMIN_TASKS);
+
+        // the oldest finished task goes, the running one stays
+        let latest = tasks.start("check_integrity", 0);
+        assert_eq!(tasks.tasks.len(), MAX_ADMIN_TASKS);
+        assert!(tasks.get(running).is_some());
+        assert!(tasks.get(first_done).is_none());
+        assert!(tasks.get(latest).is_some());
+    }
+}
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use super::tags::Tag;
 
 use super::bytes::{ByteErrorType, DataBundle, DataItem};
-use super::dal::{Gateway, GatewayTx, Log, ScheduleProvider, Signer, TxStatus};
+use super::dal::{FlowError, Gateway, GatewayTx, Log, ScheduleProvider, Signer, TxStatus};
 use super::json::Process;
 
 pub struct Builder<'a> {
@@ -35,6 +35,12 @@ impl From<BuilderErrorType> for String {
     }
 }
 
+impl From<BuilderErrorType> for FlowError {
+    fn from(error: BuilderErrorType) -> Self {
+        FlowError::from(String::from(error))
+    }
+}
+
 impl From<String> for BuilderErrorType {
     fn from(error: String) -> Self {
         BuilderErrorType::BuilderError(error)
@@ -299,6 +305,12 @@ mod tests {
         fn error(&self, message: String) {
             println!("{}", message);
         }
+        fn debug(&self, message: String) {
+            println!("{}", message);
+        }
+        fn audit(&self, message: String) {
+            println!("{}", message);
+        }
     }
 
     struct MockScheduler;
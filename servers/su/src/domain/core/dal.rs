@@ -1,9 +1,14 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub use super::bytes::DataItem;
-pub use super::json::{JsonErrorType, Message, PaginatedMessages, Process};
-pub use super::router::{ProcessScheduler, Scheduler};
+pub use super::json::{JsonErrorType, Message, PaginatedMessages, Process, ReadTiming};
+pub use super::router::{
+    ProcessCountCorrection, ProcessScheduler, RouterImportReport, RouterState,
+    RouterStateAssignment, RouterStateScheduler, Scheduler,
+};
 pub use super::tags::Tag;
 
 /*
@@ -55,6 +60,12 @@ pub trait Signer: Send + Sync {
 pub trait Log: Send + Sync {
     fn log(&self, message: String);
     fn error(&self, message: String);
+    fn debug(&self, message: String);
+    /*
+      Record of an operator action, kept separate from
+      the regular logs so it can be routed elsewhere.
+    */
+    fn audit(&self, message: String);
 }
 
 pub trait ScheduleProvider {
@@ -77,6 +88,12 @@ pub trait Config: Send + Sync {
     fn enable_router_check(&self) -> bool;
     fn router_url(&self) -> String;
     fn assignment(&self) -> String;
+    fn bytestore_miss_rate_warning(&self) -> f64;
+    fn admin_api_key(&self) -> Option<String>;
+    fn bytestore_checkpoint_dir(&self) -> Option<String>;
+    fn recount_process_counts_on_startup(&self) -> bool;
+    fn get_messages_timing(&self) -> bool;
+    fn slow_get_messages_ms(&self) -> Option<u64>;
 }
 
 #[derive(Debug)]
@@ -94,58 +111,722 @@ pub trait Uploader: Send + Sync {
     fn upload(&self, tx: Vec<u8>) -> Result<(), UploaderErrorType>;
 }
 
+pub type ErrorSource = Box<dyn std::error::Error + Send + Sync>;
+
+/*
+  Errors from the data stores. Every variant belongs to
+  one ErrorCategory, which decides the status code the
+  http layer answers with. Errors converted from another
+  library keep that error as their source.
+*/
 #[derive(Debug)]
 pub enum StoreErrorType {
-    DatabaseError(String),
-    NotFound(String),
-    JsonError(String),
-    EnvVarError(String),
-    IntError(String),
+    DatabaseError {
+        message: String,
+        source: Option<ErrorSource>,
+    },
+    NotFound {
+        entity: &'static str,
+        id: String,
+    },
+    JsonError {
+        message: String,
+        source: Option<ErrorSource>,
+    },
+    EnvVarError(std::env::VarError),
+    ConfigError(String),
+    IntError(std::num::ParseIntError),
     MessageExists(String),
+    InvalidInput {
+        field: &'static str,
+        reason: String,
+    },
+    Conflict(String),
+    VerificationError(String),
+    ReadOnly(String),
+    Unavailable(String),
+    RateLimited {
+        message: String,
+        retry_after: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    Database,
+    NotFound,
+    InvalidInput,
+    Conflict,
+    ReadOnly,
+    Unavailable,
+    RateLimited,
+    Serialization,
+    Configuration,
+}
+
+impl ErrorCategory {
+    pub fn http_status(&self) -> u16 {
+        match self {
+            ErrorCategory::NotFound => 404,
+            ErrorCategory::InvalidInput => 400,
+            ErrorCategory::Conflict => 409,
+            ErrorCategory::ReadOnly => 403,
+            ErrorCategory::Unavailable => 503,
+            ErrorCategory::RateLimited => 429,
+            ErrorCategory::Database
+            | ErrorCategory::Serialization
+            | ErrorCategory::Configuration => 500,
+        }
+    }
+}
+
+impl StoreErrorType {
+    pub fn database(message: impl Into<String>) -> Self {
+        StoreErrorType::DatabaseError {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn not_found(entity: &'static str, id: impl ToString) -> Self {
+        StoreErrorType::NotFound {
+            entity,
+            id: id.to_string(),
+        }
+    }
+
+    pub fn invalid_input(field: &'static str, reason: impl Into<String>) -> Self {
+        StoreErrorType::InvalidInput {
+            field,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            StoreErrorType::DatabaseError { .. } => ErrorCategory::Database,
+            StoreErrorType::NotFound { .. } => ErrorCategory::NotFound,
+            StoreErrorType::JsonError { .. } | StoreErrorType::IntError(_) => {
+                ErrorCategory::Serialization
+            }
+            StoreErrorType::EnvVarError(_) | StoreErrorType::ConfigError(_) => {
+                ErrorCategory::Configuration
+            }
+            StoreErrorType::MessageExists(_) | StoreErrorType::Conflict(_) => {
+                ErrorCategory::Conflict
+            }
+            StoreErrorType::InvalidInput { .. } | StoreErrorType::VerificationError(_) => {
+                ErrorCategory::InvalidInput
+            }
+            StoreErrorType::ReadOnly(_) => ErrorCategory::ReadOnly,
+            StoreErrorType::Unavailable(_) => ErrorCategory::Unavailable,
+            StoreErrorType::RateLimited { .. } => ErrorCategory::RateLimited,
+        }
+    }
+
+    pub fn http_status(&self) -> u16 {
+        self.category().http_status()
+    }
+
+    // seconds a rate limited caller should wait before retrying
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            StoreErrorType::RateLimited { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for StoreErrorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreErrorType::DatabaseError { message, .. }
+            | StoreErrorType::JsonError { message, .. } => write!(f, "{}", message),
+            StoreErrorType::NotFound { entity, id } if id.is_empty() => {
+                write!(f, "{} not found", entity)
+            }
+            StoreErrorType::NotFound { entity, id } => write!(f, "{} {} not found", entity, id),
+            StoreErrorType::EnvVarError(e) => write!(f, "data store env var error: {}", e),
+            StoreErrorType::IntError(e) => write!(f, "data store int error: {}", e),
+            StoreErrorType::InvalidInput { field, reason } => {
+                write!(f, "invalid {}: {}", field, reason)
+            }
+            StoreErrorType::MessageExists(message)
+            | StoreErrorType::Conflict(message)
+            | StoreErrorType::ConfigError(message)
+            | StoreErrorType::VerificationError(message)
+            | StoreErrorType::ReadOnly(message)
+            | StoreErrorType::Unavailable(message)
+            | StoreErrorType::RateLimited { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for StoreErrorType {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreErrorType::DatabaseError { source, .. }
+            | StoreErrorType::JsonError { source, .. } => source
+                .as_deref()
+                .map(|e| e as &(dyn std::error::Error + 'static)),
+            StoreErrorType::EnvVarError(e) => Some(e),
+            StoreErrorType::IntError(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<serde_json::Error> for StoreErrorType {
     fn from(error: serde_json::Error) -> Self {
-        StoreErrorType::JsonError(format!("data store json error: {}", error))
+        StoreErrorType::JsonError {
+            message: format!("data store json error: {}", error),
+            source: Some(Box::new(error)),
+        }
     }
 }
 
 impl From<StoreErrorType> for String {
     fn from(error: StoreErrorType) -> Self {
-        format!("{:?}", error)
+        error.to_string()
     }
 }
 
 impl From<String> for StoreErrorType {
     fn from(error: String) -> Self {
-        StoreErrorType::DatabaseError(format!("{:?}", error))
+        StoreErrorType::database(error)
     }
 }
 
 impl From<std::string::FromUtf8Error> for StoreErrorType {
     fn from(err: std::string::FromUtf8Error) -> StoreErrorType {
-        StoreErrorType::JsonError(format!("UTF-8 conversion error: {:?}", err))
+        StoreErrorType::JsonError {
+            message: format!("UTF-8 conversion error: {}", err),
+            source: Some(Box::new(err)),
+        }
+    }
+}
+
+/*
+  The error the flows hand back to the http layer.
+  Store errors keep their category and retry hint so
+  the status is picked from those instead of the
+  message, everything else has no category and is a
+  bad request.
+*/
+#[derive(Debug)]
+pub struct FlowError {
+    message: String,
+    category: Option<ErrorCategory>,
+    retry_after: Option<u64>,
+}
+
+impl FlowError {
+    pub fn category(&self) -> Option<ErrorCategory> {
+        self.category
+    }
+
+    pub fn http_status(&self) -> u16 {
+        self.category
+            .map(|category| category.http_status())
+            .unwrap_or(400)
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        self.retry_after
+    }
+}
+
+impl std::fmt::Display for FlowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for FlowError {}
+
+impl From<StoreErrorType> for FlowError {
+    fn from(error: StoreErrorType) -> Self {
+        FlowError {
+            message: error.to_string(),
+            category: Some(error.category()),
+            retry_after: error.retry_after(),
+        }
+    }
+}
+
+impl From<String> for FlowError {
+    fn from(message: String) -> Self {
+        FlowError {
+            message,
+            category: None,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<&str> for FlowError {
+    fn from(message: &str) -> Self {
+        FlowError::from(message.to_string())
+    }
+}
+
+impl From<UploaderErrorType> for FlowError {
+    fn from(error: UploaderErrorType) -> Self {
+        FlowError::from(String::from(error))
+    }
+}
+
+impl From<FlowError> for String {
+    fn from(error: FlowError) -> Self {
+        error.message
+    }
+}
+
+/*
+  Snapshot of the bytestore read/write counters
+  since startup, used to tell how often reads are
+  falling back to postgres.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ByteStoreCounters {
+    pub reads_attempted: u64,
+    pub reads_hit: u64,
+    pub reads_missed: u64,
+    pub writes: u64,
+    pub write_failures: u64,
+    pub deletes: u64,
+    /*
+      Bytes written to the bytestore as stored and
+      before compression, these are equal unless
+      BYTESTORE_COMPRESSION is on.
+    */
+    pub stored_bytes: u64,
+    pub logical_bytes: u64,
+    /*
+      Bundles that failed their checksum, from either
+      store, or bytestore values that couldn't be decoded.
+    */
+    pub corrupt_reads: u64,
+    /*
+      Times the bytestore went in or out of degraded
+      mode because its disk was nearly full.
+    */
+    pub degraded_transitions: u64,
+}
+
+impl ByteStoreCounters {
+    pub fn miss_rate(&self) -> f64 {
+        if self.reads_attempted == 0 {
+            return 0.0;
+        }
+        self.reads_missed as f64 / self.reads_attempted as f64
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        if self.reads_attempted == 0 {
+            return 0.0;
+        }
+        self.reads_hit as f64 / self.reads_attempted as f64
+    }
+}
+
+/*
+  RocksDB's own view of the bytestore, read from
+  its properties. num_keys is an estimate and RocksDB
+  may not report it.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ByteStoreStatistics {
+    pub sst_count: u64,
+    pub blob_file_count: u64,
+    pub memtable_bytes: u64,
+    pub num_keys: Option<u64>,
+}
+
+/*
+  The result of a background bytestore sync pass,
+  pass is tail for the one at startup and incremental
+  for the periodic ones. errors counts the messages
+  that couldn't be read or written, last_error is the
+  most recent of them.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ByteStoreSyncRun {
+    pub pass: String,
+    pub checked: u64,
+    pub synced: u64,
+    pub errors: u64,
+    pub last_error: Option<String>,
+    pub duration_ms: u64,
+    pub finished_at: u64,
+}
+
+impl ByteStoreSyncRun {
+    pub fn record_error(&mut self, error: String) {
+        self.errors += 1;
+        self.last_error = Some(error);
+    }
+}
+
+/*
+  A checkpoint written by the bytestore, size_bytes
+  counts every file in it even though most of them
+  are hard links to the live data dir.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ByteStoreCheckpoint {
+    pub path: String,
+    pub size_bytes: u64,
+    pub removed: Vec<String>,
+}
+
+/*
+  A manual bytestore compaction, the sizes are the
+  sst and blob files on disk before and after it.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ByteStoreCompaction {
+    pub prefix: Option<String>,
+    pub size_before: u64,
+    pub size_after: u64,
+    pub duration_ms: u64,
+}
+
+/*
+  Hit and miss counts for one of the data store's
+  in memory caches, used to check the cache sizing.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct CacheStats {
+    pub name: String,
+    pub capacity: usize,
+    pub len: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub insertions: u64,
+    pub evictions: u64,
+}
+
+/*
+  State of one of the circuit breakers guarding the
+  database pools. opened counts how many times the
+  breaker has tripped, rejected how many checkouts
+  it turned away while open.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct CircuitBreakerStats {
+    pub name: String,
+    pub state: String,
+    pub consecutive_failures: u32,
+    pub opened: u64,
+    pub rejected: u64,
+}
+
+/*
+  Result of checking every subsystem of the data
+  store at once, backs the /health endpoint. A read
+  only replica has no write pool so db_write_ok is
+  true there, likewise bytestore_ok when the
+  bytestore is turned off.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct HealthStatus {
+    pub db_write_ok: bool,
+    pub db_read_ok: bool,
+    pub bytestore_ok: bool,
+    pub cache_hit_rate: f64,
+    pub write_pool_idle: u32,
+    pub read_pool_idle: u32,
+    /*
+      The bytestore is degraded while its disk is over
+      BYTESTORE_DISK_HARD_LIMIT_PERCENT, messages are
+      still saved to postgres so it isn't a failure.
+    */
+    pub bytestore_degraded: bool,
+    pub disk_usage_percent: Option<f64>,
+    pub error_messages: Vec<String>,
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.db_write_ok && self.db_read_ok && self.bytestore_ok
+    }
+}
+
+/*
+  The window a process's messages span, both
+  timestamps are None if it has no messages.
+*/
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ProcessTimeline {
+    pub min_timestamp: Option<i64>,
+    pub max_timestamp: Option<i64>,
+    pub message_count: i64,
+}
+
+/*
+  Result of checking every message of a process.
+  corrupt_bundles don't match the checksum saved with
+  them in postgres, mismatched_bundles are intact but
+  hold data items other than the ids of their row.
+  The bytestore lists are empty when the bytestore
+  isn't in use.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ProcessIntegrityReport {
+    pub process_id: String,
+    pub checked: u64,
+    pub corrupt_bundles: Vec<String>,
+    pub mismatched_bundles: Vec<String>,
+    pub missing_from_bytestore: Vec<String>,
+    pub bytestore_mismatches: Vec<String>,
+}
+
+/*
+  What a purge removed from each store. A store that
+  couldn't be cleaned is listed in failures, the purge
+  is done once a run comes back with none.
+*/
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PurgeReport {
+    pub process_id: String,
+    pub messages_deleted: u64,
+    pub archived_messages_deleted: u64,
+    pub bytestore_keys_deleted: u64,
+    pub process_deleted: bool,
+    pub process_scheduler_deleted: bool,
+    pub cache_evicted: bool,
+    pub failures: Vec<String>,
+}
+
+impl PurgeReport {
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/*
+  What a su needs to take over a process from another
+  so data items it already scheduled aren't scheduled
+  again: the process bundle, base64url encoded, and
+  its deep hash state.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessHandover {
+    pub process_id: String,
+    pub process_bundle: String,
+    pub deep_hash_version: Option<String>,
+    pub deep_hashes: Vec<String>,
+}
+
+/*
+  The query parameters for DataStore::get_messages,
+  new filters can be added here without changing the
+  trait signature.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<i32>,
+    pub from_nonce: Option<String>,
+    pub to_nonce: Option<String>,
+    pub epoch: Option<i32>,
+    pub include_deleted: bool,
+    /*
+      from and from_nonce are exclusive by default,
+      this makes them inclusive so a page can start
+      at a given nonce or timestamp.
+    */
+    pub inclusive: bool,
+    // fill in PaginatedMessages::timing
+    pub timing: bool,
+}
+
+impl MessageFilter {
+    pub fn from(mut self, from: Option<String>) -> Self {
+        self.from = from;
+        self
+    }
+
+    pub fn to(mut self, to: Option<String>) -> Self {
+        self.to = to;
+        self
+    }
+
+    pub fn limit(mut self, limit: Option<i32>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn from_nonce(mut self, from_nonce: Option<String>) -> Self {
+        self.from_nonce = from_nonce;
+        self
+    }
+
+    pub fn to_nonce(mut self, to_nonce: Option<String>) -> Self {
+        self.to_nonce = to_nonce;
+        self
+    }
+
+    pub fn epoch(mut self, epoch: Option<i32>) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    pub fn include_deleted(mut self, include_deleted: bool) -> Self {
+        self.include_deleted = include_deleted;
+        self
+    }
+
+    pub fn inclusive(mut self, inclusive: bool) -> Self {
+        self.inclusive = inclusive;
+        self
+    }
+
+    pub fn timing(mut self, timing: bool) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /*
+      Whether this page starts at or before the process
+      itself, which sits at nonce 0 and its own timestamp.
+      An exclusive start never includes it in timestamp
+      mode, and only the -1 nonce includes it in nonce
+      mode.
+    */
+    pub fn starts_at_process(&self, process: &Process) -> Result<bool, StoreErrorType> {
+        match (&self.from_nonce, &self.to_nonce) {
+            (None, None) => match &self.from {
+                None => Ok(true),
+                Some(f) => Ok(self.inclusive && f.parse::<i64>()? <= process.timestamp()?),
+            },
+            (_, _) => match &self.from_nonce {
+                None => Ok(true),
+                Some(f) => {
+                    let f = f.parse::<i32>()?;
+                    if self.inclusive {
+                        Ok(f <= process.nonce()?)
+                    } else {
+                        Ok(f == -1)
+                    }
+                }
+            },
+        }
+    }
+
+    /*
+      from and from_nonce as exclusive bounds, for
+      stores that only filter exclusively. Both are
+      integers so an inclusive start n is the same as
+      an exclusive start n - 1.
+    */
+    pub fn exclusive_from(&self) -> Result<Option<String>, StoreErrorType> {
+        match &self.from {
+            Some(f) if self.inclusive => Ok(Some((f.parse::<i64>()? - 1).to_string())),
+            f => Ok(f.clone()),
+        }
+    }
+
+    pub fn exclusive_from_nonce(&self) -> Result<Option<String>, StoreErrorType> {
+        match &self.from_nonce {
+            Some(f) if self.inclusive => Ok(Some((f.parse::<i32>()? - 1).to_string())),
+            f => Ok(f.clone()),
+        }
     }
 }
 
 #[async_trait]
 pub trait DataStore: Send + Sync {
-    fn save_process(&self, process: &Process, bundle_in: &[u8]) -> Result<String, StoreErrorType>;
+    async fn save_process(
+        &self,
+        process: &Process,
+        bundle_in: &[u8],
+    ) -> Result<String, StoreErrorType>;
     async fn get_process(&self, process_id_in: &str) -> Result<Process, StoreErrorType>;
+    /*
+      Skips any cached copy and reads the latest stored
+      process, for the scheduling and write paths. The
+      cache is refreshed with the result.
+    */
+    async fn get_process_uncached(&self, process_id_in: &str) -> Result<Process, StoreErrorType>;
+    /*
+      Whether a process is stored, without reading or
+      parsing it. A cached process counts as found.
+    */
+    async fn check_process_exists(&self, process_id_in: &str) -> Result<bool, StoreErrorType>;
+    async fn get_processes_by_epoch(
+        &self,
+        epoch: i32,
+        limit: i32,
+        after_row_id: Option<i32>,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType>;
+    async fn get_processes_by_module(
+        &self,
+        module_id: &str,
+        cursor: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType>;
+    /*
+      Page through every process in row order, pass the
+      returned row id back in as after_row_id for the next
+      page. Bundles are left out, use get_process_bundle
+      for the raw bytes.
+    */
+    async fn get_processes(
+        &self,
+        after_row_id: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType>;
+    /*
+      Page through the processes assigned to a scheduler,
+      in the order they were assigned. The cursor is the
+      process_schedulers row id, None on the last page.
+    */
+    async fn get_processes_by_scheduler(
+        &self,
+        scheduler_row_id: i32,
+        after_row_id: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType>;
+    async fn get_process_bundle(&self, process_id: &str) -> Result<Vec<u8>, StoreErrorType>;
+    /*
+      The process along with the data item bytes it was
+      saved from. Always read from the store, the cache
+      doesn't hold bundles.
+    */
+    async fn get_process_with_bundle(
+        &self,
+        process_id: &str,
+    ) -> Result<(Process, Vec<u8>), StoreErrorType>;
     async fn save_message(
         &self,
         message: &Message,
         bundle_in: &[u8],
         deep_hash: Option<&String>,
     ) -> Result<String, StoreErrorType>;
+    /*
+      Save a new process together with its first message
+      so a crash can't leave the process without it.
+    */
+    async fn atomic_save_process_and_message(
+        &self,
+        process: &Process,
+        process_bundle: &[u8],
+        message: &Message,
+        message_bundle: &[u8],
+        deep_hash: Option<&String>,
+    ) -> Result<(), StoreErrorType>;
+    /*
+      Called once when the server stops so anything
+      buffered by the store can be flushed.
+    */
+    async fn shutdown(&self);
     async fn get_messages(
         &self,
         process: &Process,
-        from: &Option<String>,
-        to: &Option<String>,
-        limit: &Option<i32>,
-        from_nonce: &Option<String>,
-        to_nonce: &Option<String>,
+        filter: &MessageFilter,
     ) -> Result<PaginatedMessages, StoreErrorType>;
     async fn get_message_bundles(
         &self,
@@ -153,18 +834,202 @@ pub trait DataStore: Send + Sync {
         from: &Option<String>,
         limit: &Option<i32>,
     ) -> Result<(Vec<(String, Vec<u8>)>, bool), StoreErrorType>;
-    fn get_message(&self, message_id_in: &str) -> Result<Message, StoreErrorType>;
+    async fn get_message(&self, message_id_in: &str) -> Result<Message, StoreErrorType>;
+    async fn get_message_bundle(&self, tx_id: &str) -> Result<Vec<u8>, StoreErrorType>;
+    async fn get_message_epoch(&self, message_id: &str) -> Result<i32, StoreErrorType>;
+    async fn get_message_with_fallback(
+        &self,
+        message_id: &str,
+        assignment_id: &Option<String>,
+    ) -> Result<Message, StoreErrorType>;
+    async fn soft_delete_message(&self, message_id: &str) -> Result<(), StoreErrorType>;
+    async fn tombstone_message_in_bytestore(&self, message_id: &str) -> Result<(), StoreErrorType>;
+    async fn archive_old_messages(
+        &self,
+        process_id: &str,
+        older_than_days: u32,
+    ) -> Result<u64, StoreErrorType>;
+    async fn count_messages_since(
+        &self,
+        process_id: &str,
+        since_timestamp: i64,
+    ) -> Result<i64, StoreErrorType>;
+    /*
+      Messages of the process with from < timestamp <= to,
+      without reading any of them.
+    */
+    async fn get_messages_count_in_timestamp_range(
+        &self,
+        process_id: &str,
+        from: i64,
+        to: i64,
+    ) -> Result<i64, StoreErrorType>;
+    async fn get_process_timeline(
+        &self,
+        process_id: &str,
+    ) -> Result<ProcessTimeline, StoreErrorType>;
+    async fn get_cached_message_count(&self, process_id: &str) -> Result<i64, StoreErrorType>;
+    /*
+      A process's rows split into (assignments, messages),
+      a row with an assignment id counts as an assignment.
+    */
+    async fn get_assignment_count(&self, process_id: &str) -> Result<(i64, i64), StoreErrorType>;
     async fn get_latest_message(
         &self,
         process_id_in: &str,
     ) -> Result<Option<Message>, StoreErrorType>;
-    fn check_existing_message(&self, message_id: &String) -> Result<(), StoreErrorType>;
+    /*
+      The latest message of each process in one query,
+      processes with no messages are left out.
+    */
+    async fn get_latest_messages_for_processes(
+        &self,
+        process_ids: &[&str],
+    ) -> Result<HashMap<String, Message>, StoreErrorType>;
+    async fn get_messages_by_sender(
+        &self,
+        sender_id: &str,
+        process_id: &str,
+        limit: i32,
+    ) -> Result<Vec<Message>, StoreErrorType>;
+    /*
+      A process's messages in insert order, after the
+      given row id. Unlike a timestamp, row ids can't
+      collide, so nothing is skipped when a page ends
+      between two messages saved in the same millisecond.
+      The row id of the last message is handed back to
+      pass in for the next page, None if there were none.
+    */
+    async fn get_messages_after_rowid(
+        &self,
+        process_id: &str,
+        after_row_id: i64,
+        limit: i32,
+    ) -> Result<(Vec<Message>, Option<i64>), StoreErrorType>;
+    async fn get_hash_chain_tail(
+        &self,
+        process_id: &str,
+        depth: u32,
+    ) -> Result<Vec<String>, StoreErrorType>;
+    /*
+      Up to 10 of a process's messages whose hash chain
+      starts with hash_chain_prefix, in nonce order. This
+      is for audit and debugging tools working from a
+      truncated hash chain, not for request handling, a
+      short prefix can scan most of the process.
+    */
+    async fn get_messages_by_hash_chain_prefix(
+        &self,
+        process_id: &str,
+        hash_chain_prefix: &str,
+    ) -> Result<Vec<Message>, StoreErrorType>;
+    /*
+      A process's messages in one epoch with a nonce
+      from from_nonce to to_nonce, both included, in
+      nonce order. Nothing caps how many come back, keep
+      the range to what the caller can hold.
+    */
+    async fn get_messages_for_epoch_nonce_range(
+        &self,
+        process_id: &str,
+        epoch: i32,
+        from_nonce: i32,
+        to_nonce: i32,
+    ) -> Result<Vec<Message>, StoreErrorType>;
+    async fn update_message_hash_chain(
+        &self,
+        process_id: &str,
+        nonce: i32,
+        new_hash_chain: &str,
+    ) -> Result<(), StoreErrorType>;
+    async fn update_message_hash_chains_batch(
+        &self,
+        updates: &[(String, i32, String)],
+    ) -> Result<u64, StoreErrorType>;
+    async fn purge_process(
+        &self,
+        process_id: &str,
+        force: bool,
+    ) -> Result<PurgeReport, StoreErrorType>;
+    async fn check_existing_message(&self, message_id: &String) -> Result<(), StoreErrorType>;
+    /*
+      A frozen process takes no new messages, save_message
+      returns ReadOnly for it. Used while it is handed over
+      to another su. Not kept across restarts or shared
+      with other su instances.
+    */
+    fn freeze_process(&self, process_id: &str);
+    fn unfreeze_process(&self, process_id: &str);
+    fn is_process_frozen(&self, process_id: &str) -> bool;
+    fn bytestore_counters(&self) -> Option<ByteStoreCounters>;
+    async fn bytestore_statistics(&self) -> Result<ByteStoreStatistics, StoreErrorType>;
+    fn bytestore_last_sync(&self) -> Option<ByteStoreSyncRun>;
+    async fn create_bytestore_checkpoint(
+        &self,
+        path: &str,
+    ) -> Result<ByteStoreCheckpoint, StoreErrorType>;
+    /*
+      Compact the bytestore keys starting with prefix, or
+      all of them. Refused with a Conflict while a sync
+      or migration is writing to the bytestore.
+    */
+    async fn compact_bytestore(
+        &self,
+        prefix: Option<String>,
+    ) -> Result<ByteStoreCompaction, StoreErrorType>;
+    /*
+      Sync every message after after_row_id into the
+      bytestore. Blocking, Conflict if another sync pass
+      is running.
+    */
+    fn sync_bytestore_after(&self, after_row_id: i32) -> Result<ByteStoreSyncRun, StoreErrorType>;
+    /*
+      Drop a process from the caches of this su, and of
+      the other instances when CACHE_INVALIDATION is on.
+    */
+    async fn invalidate_process_cache(&self, process_id: &str) -> Result<(), StoreErrorType>;
+    /*
+      Change how many processes this su keeps cached,
+      until it restarts and goes back to
+      PROCESS_CACHE_SIZE.
+    */
+    async fn resize_process_cache(&self, new_size: usize) -> Result<(), StoreErrorType>;
+    async fn check_process_integrity(
+        &self,
+        process_id: &str,
+    ) -> Result<ProcessIntegrityReport, StoreErrorType>;
+    async fn applied_migrations(&self) -> Result<Vec<String>, StoreErrorType>;
+    async fn cache_stats(&self) -> Vec<CacheStats>;
+    fn circuit_breakers(&self) -> Vec<CircuitBreakerStats>;
+    /*
+      Messages saved per second over the last minute,
+      None when the store doesn't track it.
+    */
+    async fn message_throughput(&self) -> Option<f64>;
+    async fn health_check(&self) -> HealthStatus;
+    /*
+      A liveness check that doesn't touch the database,
+      health_check is the one that runs queries.
+    */
+    fn ping(&self) -> bool;
     async fn check_existing_deep_hash(
         &self,
         process_id: &String,
         deep_hash: &String,
     ) -> Result<(), StoreErrorType>;
+    /*
+      The deep hashes in deep_hashes that are already
+      saved for the process, in the order they were
+      given, so a batch can be screened in one read
+      before anything is written.
+    */
+    async fn check_existing_deep_hashes(
+        &self,
+        process_id: &String,
+        deep_hashes: &[String],
+    ) -> Result<Vec<String>, StoreErrorType>;
     async fn get_deephash_version(&self, process_id: &String) -> Result<String, StoreErrorType>;
+    async fn get_deephashes(&self, process_id: &str) -> Result<Vec<String>, StoreErrorType>;
     async fn save_deephash_version(
         &self,
         process_id: &String,
@@ -175,62 +1040,1154 @@ pub trait DataStore: Send + Sync {
         process_id: &String,
         deep_hash: &String,
     ) -> Result<(), StoreErrorType>;
+    /*
+      Whether save_deephash actually keeps the hash, it
+      is skipped when the store has nowhere to put it.
+    */
+    fn keeps_deep_hashes(&self) -> bool;
 }
 
 #[async_trait]
 pub trait RouterDataStore: Send + Sync {
-    fn save_process_scheduler(
+    async fn save_process_scheduler(
         &self,
         process_scheduler: &ProcessScheduler,
     ) -> Result<String, StoreErrorType>;
-    fn get_process_scheduler(
+    async fn get_process_scheduler(
         &self,
         process_id_in: &str,
     ) -> Result<ProcessScheduler, StoreErrorType>;
-    fn save_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType>;
-    fn update_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType>;
-    fn get_scheduler(&self, row_id_in: &i32) -> Result<Scheduler, StoreErrorType>;
-    fn get_scheduler_by_url(&self, url_in: &String) -> Result<Scheduler, StoreErrorType>;
-    fn get_all_schedulers(&self) -> Result<Vec<Scheduler>, StoreErrorType>;
+    async fn save_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType>;
+    async fn update_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType>;
+    async fn get_scheduler(&self, row_id_in: &i32) -> Result<Scheduler, StoreErrorType>;
+    async fn get_scheduler_by_url(&self, url_in: &String) -> Result<Scheduler, StoreErrorType>;
+    async fn get_all_schedulers(&self) -> Result<Vec<Scheduler>, StoreErrorType>;
+    async fn get_scheduler_for_wallet(
+        &self,
+        wallet_id: &str,
+    ) -> Result<Option<Scheduler>, StoreErrorType>;
+    async fn increment_process_count(&self, row_id: i32, delta: i32)
+        -> Result<i32, StoreErrorType>;
+    async fn recount_process_counts(&self) -> Result<Vec<ProcessCountCorrection>, StoreErrorType>;
+    /*
+      The scheduler a new process from owner_wallet should
+      be assigned to, Unavailable if none can take it.
+    */
+    async fn select_scheduler_for_spawn(
+        &self,
+        owner_wallet: &str,
+    ) -> Result<Scheduler, StoreErrorType>;
+    async fn export_router_state(&self) -> Result<RouterState, StoreErrorType>;
+    /*
+      With merge the schedulers and assignments in state
+      are upserted by url and process id, otherwise both
+      tables are emptied first. An assignment whose
+      scheduler doesn't exist fails the whole import
+      unless skip_violations is set. Either way it runs
+      in one transaction.
+    */
+    async fn import_router_state(
+        &self,
+        state: &RouterState,
+        merge: bool,
+        skip_violations: bool,
+    ) -> Result<RouterImportReport, StoreErrorType>;
 }
 
 pub struct MockRouterDataStore;
 
 #[async_trait]
 impl RouterDataStore for MockRouterDataStore {
-    fn save_process_scheduler(
+    async fn save_process_scheduler(
         &self,
         _process_scheduler: &ProcessScheduler,
     ) -> Result<String, StoreErrorType> {
         unreachable!("save_process_scheduler is not implemented in MockRouterDataStore");
     }
 
-    fn get_process_scheduler(
+    async fn get_process_scheduler(
         &self,
         _process_id_in: &str,
     ) -> Result<ProcessScheduler, StoreErrorType> {
         unreachable!("get_process_scheduler is not implemented in MockRouterDataStore");
     }
 
-    fn save_scheduler(&self, _scheduler: &Scheduler) -> Result<String, StoreErrorType> {
+    async fn save_scheduler(&self, _scheduler: &Scheduler) -> Result<String, StoreErrorType> {
         unreachable!("save_scheduler is not implemented in MockRouterDataStore");
     }
 
-    fn update_scheduler(&self, _scheduler: &Scheduler) -> Result<String, StoreErrorType> {
+    async fn update_scheduler(&self, _scheduler: &Scheduler) -> Result<String, StoreErrorType> {
         unreachable!("update_scheduler is not implemented in MockRouterDataStore");
     }
 
-    fn get_scheduler(&self, _row_id_in: &i32) -> Result<Scheduler, StoreErrorType> {
+    async fn get_scheduler(&self, _row_id_in: &i32) -> Result<Scheduler, StoreErrorType> {
         unreachable!("get_scheduler is not implemented in MockRouterDataStore");
     }
 
-    fn get_scheduler_by_url(&self, _url_in: &String) -> Result<Scheduler, StoreErrorType> {
+    async fn get_scheduler_by_url(&self, _url_in: &String) -> Result<Scheduler, StoreErrorType> {
         unreachable!("get_scheduler_by_url is not implemented in MockRouterDataStore");
     }
 
-    fn get_all_schedulers(&self) -> Result<Vec<Scheduler>, StoreErrorType> {
+    async fn get_all_schedulers(&self) -> Result<Vec<Scheduler>, StoreErrorType> {
         unreachable!("get_all_schedulers is not implemented in MockRouterDataStore");
     }
+
+    async fn get_scheduler_for_wallet(
+        &self,
+        _wallet_id: &str,
+    ) -> Result<Option<Scheduler>, StoreErrorType> {
+        unreachable!("get_scheduler_for_wallet is not implemented in MockRouterDataStore");
+    }
+
+    async fn increment_process_count(
+        &self,
+        _row_id: i32,
+        _delta: i32,
+    ) -> Result<i32, StoreErrorType> {
+        unreachable!("increment_process_count is not implemented in MockRouterDataStore");
+    }
+
+    async fn recount_process_counts(&self) -> Result<Vec<ProcessCountCorrection>, StoreErrorType> {
+        unreachable!("recount_process_counts is not implemented in MockRouterDataStore");
+    }
+
+    async fn select_scheduler_for_spawn(
+        &self,
+        _owner_wallet: &str,
+    ) -> Result<Scheduler, StoreErrorType> {
+        unreachable!("select_scheduler_for_spawn is not implemented in MockRouterDataStore");
+    }
+
+    async fn export_router_state(&self) -> Result<RouterState, StoreErrorType> {
+        unreachable!("export_router_state is not implemented in MockRouterDataStore");
+    }
+
+    async fn import_router_state(
+        &self,
+        _state: &RouterState,
+        _merge: bool,
+        _skip_violations: bool,
+    ) -> Result<RouterImportReport, StoreErrorType> {
+        unreachable!("import_router_state is not implemented in MockRouterDataStore");
+    }
+}
+
+/*
+  An in memory DataStore and RouterDataStore for unit
+  testing the code that uses one without a postgres.
+  Processes, messages, schedulers and process
+  schedulers are kept in the order they were saved,
+  with the 1 based position standing in for the row
+  id, bundles aren't kept. get_messages pages like the
+  other stores, process first. What the mock doesn't
+  hold returns an error saying it isn't supported.
+*/
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockDataStore {
+    pub messages: tokio::sync::Mutex<Vec<Message>>,
+    pub processes: tokio::sync::Mutex<Vec<Process>>,
+    deep_hashes: tokio::sync::Mutex<Vec<(String, String)>>,
+    deep_hash_versions: tokio::sync::Mutex<HashMap<String, String>>,
+    frozen_processes: std::sync::Mutex<Vec<String>>,
+    schedulers: tokio::sync::Mutex<Vec<Scheduler>>,
+    process_schedulers: tokio::sync::Mutex<Vec<ProcessScheduler>>,
+    failing_saves: std::sync::atomic::AtomicUsize,
+    save_latency: std::sync::Mutex<Option<std::time::Duration>>,
+}
+
+#[cfg(test)]
+impl MockDataStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /*
+      The next n process and message saves fail with a
+      database error, as if postgres went away.
+    */
+    pub fn fail_next_saves(&self, n: usize) {
+        self.failing_saves
+            .store(n, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /*
+      Every process and message save waits this long
+      before it is applied.
+    */
+    pub fn set_save_latency(&self, latency: Option<std::time::Duration>) {
+        *self.save_latency.lock().unwrap() = latency;
+    }
+
+    async fn before_save(&self) -> Result<(), StoreErrorType> {
+        let latency = *self.save_latency.lock().unwrap();
+        if let Some(latency) = latency {
+            tokio::time::sleep(latency).await;
+        }
+        let injected = self
+            .failing_saves
+            .fetch_update(
+                std::sync::atomic::Ordering::SeqCst,
+                std::sync::atomic::Ordering::SeqCst,
+                |n| n.checked_sub(1),
+            )
+            .is_ok();
+        match injected {
+            true => Err(StoreErrorType::database("Injected save failure")),
+            false => Ok(()),
+        }
+    }
+
+    async fn insert_process(&self, process: &Process) -> Result<(), StoreErrorType> {
+        process
+            .validate()
+            .map_err(|errors| StoreErrorType::invalid_input("process", errors.join("; ")))?;
+        let mut processes = self.processes.lock().await;
+        if !processes
+            .iter()
+            .any(|p| p.process.process_id == process.process.process_id)
+        {
+            processes.push(process.clone());
+        }
+        Ok(())
+    }
+
+    async fn insert_message(
+        &self,
+        message: &Message,
+        deep_hash: Option<&String>,
+    ) -> Result<(), StoreErrorType> {
+        let process_id = message.process_id()?;
+        if self.is_process_frozen(&process_id) {
+            return Err(StoreErrorType::ReadOnly(format!(
+                "Process {} is frozen",
+                process_id
+            )));
+        }
+        let assignment_id = message.assignment_id()?;
+        let mut messages = self.messages.lock().await;
+        if messages
+            .iter()
+            .any(|m| m.assignment_id().ok().as_deref() == Some(assignment_id.as_str()))
+        {
+            return Err(StoreErrorType::MessageExists(format!(
+                "Assignment {} already exists",
+                assignment_id
+            )));
+        }
+        messages.push(message.clone());
+
+        if let Some(deep_hash) = deep_hash {
+            self.save_deephash(&process_id, deep_hash).await?;
+        }
+        Ok(())
+    }
+
+    /*
+      Processes matching keep, a page at a time with the
+      1 based position standing in for the row id.
+    */
+    async fn process_page(
+        &self,
+        keep: impl Fn(&Process) -> bool,
+        after_row_id: Option<i32>,
+        limit: i32,
+    ) -> (Vec<Process>, Option<i32>) {
+        let rows: Vec<(i32, Process)> = self
+            .processes
+            .lock()
+            .await
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (i as i32 + 1, p))
+            .filter(|(row_id, p)| *row_id > after_row_id.unwrap_or(0) && keep(p))
+            .take(limit.max(0) as usize)
+            .map(|(row_id, p)| (row_id, p.clone()))
+            .collect();
+
+        let cursor = match rows.len() as i32 == limit {
+            true => rows.last().map(|(row_id, _)| *row_id),
+            false => None,
+        };
+        (rows.into_iter().map(|(_, p)| p).collect(), cursor)
+    }
+
+    async fn process_messages(&self, process_id: &str) -> Vec<Message> {
+        self.messages
+            .lock()
+            .await
+            .iter()
+            .filter(|m| m.process_id().ok().as_deref() == Some(process_id))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+fn unsupported(what: &str) -> StoreErrorType {
+    StoreErrorType::database(format!("{} is not supported by the mock store", what))
+}
+
+#[cfg(test)]
+#[async_trait]
+impl DataStore for MockDataStore {
+    async fn save_process(
+        &self,
+        process: &Process,
+        _bundle_in: &[u8],
+    ) -> Result<String, StoreErrorType> {
+        self.before_save().await?;
+        self.insert_process(process).await?;
+        Ok("saved".to_string())
+    }
+
+    async fn get_process(&self, process_id_in: &str) -> Result<Process, StoreErrorType> {
+        self.processes
+            .lock()
+            .await
+            .iter()
+            .find(|p| p.process.process_id == process_id_in)
+            .cloned()
+            .ok_or_else(|| StoreErrorType::not_found("process", process_id_in))
+    }
+
+    async fn get_process_uncached(&self, process_id_in: &str) -> Result<Process, StoreErrorType> {
+        self.get_process(process_id_in).await
+    }
+
+    async fn check_process_exists(&self, process_id_in: &str) -> Result<bool, StoreErrorType> {
+        Ok(self
+            .processes
+            .lock()
+            .await
+            .iter()
+            .any(|p| p.process.process_id == process_id_in))
+    }
+
+    async fn get_processes_by_epoch(
+        &self,
+        epoch: i32,
+        limit: i32,
+        after_row_id: Option<i32>,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        Ok(self
+            .process_page(|p| p.epoch().ok() == Some(epoch), after_row_id, limit)
+            .await)
+    }
+
+    async fn get_processes_by_module(
+        &self,
+        module_id: &str,
+        cursor: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        Ok(self
+            .process_page(
+                |p| p.module_id().as_deref() == Some(module_id),
+                cursor,
+                limit,
+            )
+            .await)
+    }
+
+    async fn get_processes(
+        &self,
+        after_row_id: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        Ok(self.process_page(|_| true, after_row_id, limit).await)
+    }
+
+    async fn get_processes_by_scheduler(
+        &self,
+        scheduler_row_id: i32,
+        after_row_id: Option<i32>,
+        limit: i32,
+    ) -> Result<(Vec<Process>, Option<i32>), StoreErrorType> {
+        let assigned: Vec<(i32, String)> = self
+            .process_schedulers
+            .lock()
+            .await
+            .iter()
+            .filter(|ps| {
+                ps.scheduler_row_id == scheduler_row_id
+                    && ps.row_id.unwrap_or(0) > after_row_id.unwrap_or(0)
+            })
+            .map(|ps| (ps.row_id.unwrap_or(0), ps.process_id.clone()))
+            .collect();
+
+        let processes = self.processes.lock().await;
+        let rows: Vec<(i32, Process)> = assigned
+            .into_iter()
+            .filter_map(|(row_id, process_id)| {
+                processes
+                    .iter()
+                    .find(|p| p.process.process_id == process_id)
+                    .map(|p| (row_id, p.clone()))
+            })
+            .take(limit.max(0) as usize)
+            .collect();
+
+        let cursor = match rows.len() as i32 == limit {
+            true => rows.last().map(|(row_id, _)| *row_id),
+            false => None,
+        };
+        Ok((rows.into_iter().map(|(_, p)| p).collect(), cursor))
+    }
+
+    async fn get_process_bundle(&self, _process_id: &str) -> Result<Vec<u8>, StoreErrorType> {
+        Err(unsupported("Reading process bundles"))
+    }
+
+    async fn get_process_with_bundle(
+        &self,
+        _process_id: &str,
+    ) -> Result<(Process, Vec<u8>), StoreErrorType> {
+        Err(unsupported("Reading process bundles"))
+    }
+
+    async fn save_message(
+        &self,
+        message: &Message,
+        _bundle_in: &[u8],
+        deep_hash: Option<&String>,
+    ) -> Result<String, StoreErrorType> {
+        self.before_save().await?;
+        self.insert_message(message, deep_hash).await?;
+        Ok("saved".to_string())
+    }
+
+    async fn atomic_save_process_and_message(
+        &self,
+        process: &Process,
+        _process_bundle: &[u8],
+        message: &Message,
+        _message_bundle: &[u8],
+        deep_hash: Option<&String>,
+    ) -> Result<(), StoreErrorType> {
+        self.before_save().await?;
+        self.insert_process(process).await?;
+        self.insert_message(message, deep_hash).await?;
+        Ok(())
+    }
+
+    async fn shutdown(&self) {}
+
+    async fn get_messages(
+        &self,
+        process: &Process,
+        filter: &MessageFilter,
+    ) -> Result<PaginatedMessages, StoreErrorType> {
+        let by_nonce = filter.from_nonce.is_some() || filter.to_nonce.is_some();
+        let (from, to) = match by_nonce {
+            true => (filter.exclusive_from_nonce()?, filter.to_nonce.clone()),
+            false => (filter.exclusive_from()?, filter.to.clone()),
+        };
+        let from = from.map(|f| f.parse::<i64>()).transpose()?;
+        let to = to.map(|t| t.parse::<i64>()).transpose()?;
+        let include_process = process.assignment.is_some() && filter.starts_at_process(process)?;
+        let limit = match include_process {
+            true => filter.limit.unwrap_or(100) - 1,
+            false => filter.limit.unwrap_or(100),
+        }
+        .max(0) as usize;
+
+        let mut matching = vec![];
+        for message in self.process_messages(&process.process.process_id).await {
+            if filter.epoch.is_some() && message.epoch().ok() != filter.epoch {
+                continue;
+            }
+            let key = match by_nonce {
+                true => message.nonce().map(i64::from),
+                false => message.timestamp(),
+            }?;
+            if from.map_or(false, |f| key <= f) || to.map_or(false, |t| key > t) {
+                continue;
+            }
+            matching.push(((message.timestamp()?, message.nonce()?), message));
+        }
+        matching.sort_by_key(|(order, _)| *order);
+
+        let has_next_page = matching.len() > limit;
+        let mut messages = vec![];
+        if include_process {
+            messages.push(Message::from_process(process.clone())?);
+        }
+        messages.extend(matching.into_iter().take(limit).map(|(_, m)| m));
+        let sequence_mode = match by_nonce {
+            true => "nonce",
+            false => "timestamp",
+        };
+        Ok(PaginatedMessages::from_messages(
+            messages,
+            has_next_page,
+            sequence_mode,
+        )?)
+    }
+
+    async fn get_message_bundles(
+        &self,
+        _process: &Process,
+        _from: &Option<String>,
+        _limit: &Option<i32>,
+    ) -> Result<(Vec<(String, Vec<u8>)>, bool), StoreErrorType> {
+        Err(unsupported("Reading message bundles"))
+    }
+
+    async fn get_message(&self, message_id_in: &str) -> Result<Message, StoreErrorType> {
+        self.messages
+            .lock()
+            .await
+            .iter()
+            .find(|m| {
+                m.message_id().ok().as_deref() == Some(message_id_in)
+                    || m.assignment_id().ok().as_deref() == Some(message_id_in)
+            })
+            .cloned()
+            .ok_or_else(|| StoreErrorType::not_found("message", message_id_in))
+    }
+
+    async fn get_message_bundle(&self, _tx_id: &str) -> Result<Vec<u8>, StoreErrorType> {
+        Err(unsupported("Reading message bundles"))
+    }
+
+    async fn get_message_epoch(&self, message_id: &str) -> Result<i32, StoreErrorType> {
+        let message = self.get_message(message_id).await?;
+        Ok(message.epoch()?)
+    }
+
+    async fn get_message_with_fallback(
+        &self,
+        message_id: &str,
+        assignment_id: &Option<String>,
+    ) -> Result<Message, StoreErrorType> {
+        match assignment_id {
+            Some(assignment_id) => self.get_message(assignment_id).await,
+            None => self.get_message(message_id).await,
+        }
+    }
+
+    async fn soft_delete_message(&self, _message_id: &str) -> Result<(), StoreErrorType> {
+        Err(unsupported("Soft delete"))
+    }
+
+    async fn tombstone_message_in_bytestore(
+        &self,
+        _message_id: &str,
+    ) -> Result<(), StoreErrorType> {
+        Err(unsupported("Tombstoning messages"))
+    }
+
+    async fn archive_old_messages(
+        &self,
+        _process_id: &str,
+        _older_than_days: u32,
+    ) -> Result<u64, StoreErrorType> {
+        Err(unsupported("Archiving messages"))
+    }
+
+    async fn count_messages_since(
+        &self,
+        process_id: &str,
+        since_timestamp: i64,
+    ) -> Result<i64, StoreErrorType> {
+        Ok(self
+            .process_messages(process_id)
+            .await
+            .iter()
+            .filter(|m| m.timestamp().map_or(false, |t| t > since_timestamp))
+            .count() as i64)
+    }
+
+    async fn get_messages_count_in_timestamp_range(
+        &self,
+        process_id: &str,
+        from: i64,
+        to: i64,
+    ) -> Result<i64, StoreErrorType> {
+        Ok(self
+            .process_messages(process_id)
+            .await
+            .iter()
+            .filter(|m| m.timestamp().map_or(false, |t| t > from && t <= to))
+            .count() as i64)
+    }
+
+    async fn get_process_timeline(
+        &self,
+        process_id: &str,
+    ) -> Result<ProcessTimeline, StoreErrorType> {
+        let messages = self.process_messages(process_id).await;
+        let timestamps = messages.iter().filter_map(|m| m.timestamp().ok());
+        Ok(ProcessTimeline {
+            min_timestamp: timestamps.clone().min(),
+            max_timestamp: timestamps.max(),
+            message_count: messages.len() as i64,
+        })
+    }
+
+    async fn get_cached_message_count(&self, process_id: &str) -> Result<i64, StoreErrorType> {
+        Ok(self.process_messages(process_id).await.len() as i64)
+    }
+
+    async fn get_assignment_count(&self, process_id: &str) -> Result<(i64, i64), StoreErrorType> {
+        let messages = self.process_messages(process_id).await;
+        let assignments = messages
+            .iter()
+            .filter(|m| !m.assignment.id.is_empty())
+            .count() as i64;
+        Ok((assignments, messages.len() as i64 - assignments))
+    }
+
+    async fn get_latest_message(
+        &self,
+        process_id_in: &str,
+    ) -> Result<Option<Message>, StoreErrorType> {
+        Ok(self
+            .process_messages(process_id_in)
+            .await
+            .into_iter()
+            .max_by_key(|m| {
+                (
+                    m.epoch().unwrap_or(0),
+                    m.nonce().unwrap_or(0),
+                    m.timestamp().unwrap_or(0),
+                )
+            }))
+    }
+
+    async fn get_latest_messages_for_processes(
+        &self,
+        process_ids: &[&str],
+    ) -> Result<HashMap<String, Message>, StoreErrorType> {
+        let mut latest = HashMap::new();
+        for process_id in process_ids {
+            if let Some(message) = self.get_latest_message(process_id).await? {
+                latest.insert(process_id.to_string(), message);
+            }
+        }
+        Ok(latest)
+    }
+
+    async fn get_messages_by_sender(
+        &self,
+        sender_id: &str,
+        process_id: &str,
+        limit: i32,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        let mut messages: Vec<Message> = self
+            .process_messages(process_id)
+            .await
+            .into_iter()
+            .filter(|m| {
+                m.message
+                    .as_ref()
+                    .map_or(false, |inner| inner.owner.address == sender_id)
+            })
+            .collect();
+        messages.sort_by_key(|m| std::cmp::Reverse(m.timestamp().unwrap_or(0)));
+        messages.truncate(limit.max(0) as usize);
+        Ok(messages)
+    }
+
+    async fn get_messages_after_rowid(
+        &self,
+        process_id: &str,
+        after_row_id: i64,
+        limit: i32,
+    ) -> Result<(Vec<Message>, Option<i64>), StoreErrorType> {
+        let rows: Vec<(i64, Message)> = self
+            .messages
+            .lock()
+            .await
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (i as i64 + 1, m))
+            .filter(|(row_id, m)| {
+                *row_id > after_row_id && m.process_id().ok().as_deref() == Some(process_id)
+            })
+            .take(limit.max(0) as usize)
+            .map(|(row_id, m)| (row_id, m.clone()))
+            .collect();
+
+        let cursor = rows.last().map(|(row_id, _)| *row_id);
+        Ok((rows.into_iter().map(|(_, m)| m).collect(), cursor))
+    }
+
+    async fn get_hash_chain_tail(
+        &self,
+        process_id: &str,
+        depth: u32,
+    ) -> Result<Vec<String>, StoreErrorType> {
+        let mut messages = self.process_messages(process_id).await;
+        messages.sort_by_key(|m| std::cmp::Reverse(m.nonce().unwrap_or(0)));
+        messages
+            .iter()
+            .take(depth as usize)
+            .map(|m| m.hash_chain().map_err(StoreErrorType::from))
+            .collect()
+    }
+
+    async fn get_messages_by_hash_chain_prefix(
+        &self,
+        process_id: &str,
+        hash_chain_prefix: &str,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        let mut messages: Vec<Message> = self
+            .process_messages(process_id)
+            .await
+            .into_iter()
+            .filter(|m| {
+                m.hash_chain().map_or(false, |hash_chain| {
+                    hash_chain.starts_with(hash_chain_prefix)
+                })
+            })
+            .collect();
+        messages.sort_by_key(|m| m.nonce().unwrap_or(0));
+        messages.truncate(10);
+        Ok(messages)
+    }
+
+    async fn get_messages_for_epoch_nonce_range(
+        &self,
+        process_id: &str,
+        epoch: i32,
+        from_nonce: i32,
+        to_nonce: i32,
+    ) -> Result<Vec<Message>, StoreErrorType> {
+        let mut messages: Vec<Message> = self
+            .process_messages(process_id)
+            .await
+            .into_iter()
+            .filter(|m| {
+                m.epoch().ok() == Some(epoch)
+                    && m.nonce()
+                        .is_ok_and(|n| (from_nonce..=to_nonce).contains(&n))
+            })
+            .collect();
+        messages.sort_by_key(|m| m.nonce().unwrap_or(0));
+        Ok(messages)
+    }
+
+    async fn update_message_hash_chain(
+        &self,
+        _process_id: &str,
+        _nonce: i32,
+        _new_hash_chain: &str,
+    ) -> Result<(), StoreErrorType> {
+        Err(unsupported("Updating hash chains"))
+    }
+
+    async fn update_message_hash_chains_batch(
+        &self,
+        _updates: &[(String, i32, String)],
+    ) -> Result<u64, StoreErrorType> {
+        Err(unsupported("Updating hash chains"))
+    }
+
+    async fn purge_process(
+        &self,
+        _process_id: &str,
+        _force: bool,
+    ) -> Result<PurgeReport, StoreErrorType> {
+        Err(unsupported("Purging processes"))
+    }
+
+    async fn check_existing_message(&self, message_id: &String) -> Result<(), StoreErrorType> {
+        match self.get_message(message_id).await {
+            Ok(_) => Err(StoreErrorType::MessageExists(
+                "Message already exists".to_string(),
+            )),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn freeze_process(&self, process_id: &str) {
+        let mut frozen = self.frozen_processes.lock().unwrap();
+        if !frozen.iter().any(|p| p == process_id) {
+            frozen.push(process_id.to_string());
+        }
+    }
+
+    fn unfreeze_process(&self, process_id: &str) {
+        self.frozen_processes
+            .lock()
+            .unwrap()
+            .retain(|p| p != process_id);
+    }
+
+    fn is_process_frozen(&self, process_id: &str) -> bool {
+        self.frozen_processes
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|p| p == process_id)
+    }
+
+    fn keeps_deep_hashes(&self) -> bool {
+        true
+    }
+
+    fn bytestore_counters(&self) -> Option<ByteStoreCounters> {
+        None
+    }
+
+    async fn bytestore_statistics(&self) -> Result<ByteStoreStatistics, StoreErrorType> {
+        Err(unsupported("Bytestore statistics"))
+    }
+
+    fn bytestore_last_sync(&self) -> Option<ByteStoreSyncRun> {
+        None
+    }
+
+    async fn create_bytestore_checkpoint(
+        &self,
+        _path: &str,
+    ) -> Result<ByteStoreCheckpoint, StoreErrorType> {
+        Err(unsupported("Bytestore checkpoints"))
+    }
+
+    async fn compact_bytestore(
+        &self,
+        _prefix: Option<String>,
+    ) -> Result<ByteStoreCompaction, StoreErrorType> {
+        Err(unsupported("Bytestore compaction"))
+    }
+
+    fn sync_bytestore_after(&self, _after_row_id: i32) -> Result<ByteStoreSyncRun, StoreErrorType> {
+        Err(unsupported("Bytestore syncs"))
+    }
+
+    async fn invalidate_process_cache(&self, _process_id: &str) -> Result<(), StoreErrorType> {
+        Ok(())
+    }
+
+    async fn resize_process_cache(&self, _new_size: usize) -> Result<(), StoreErrorType> {
+        Err(unsupported("Process cache resizing"))
+    }
+
+    async fn check_process_integrity(
+        &self,
+        _process_id: &str,
+    ) -> Result<ProcessIntegrityReport, StoreErrorType> {
+        Err(unsupported("Integrity checks"))
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<String>, StoreErrorType> {
+        Err(unsupported("Listing migrations"))
+    }
+
+    async fn cache_stats(&self) -> Vec<CacheStats> {
+        vec![]
+    }
+
+    fn circuit_breakers(&self) -> Vec<CircuitBreakerStats> {
+        vec![]
+    }
+
+    async fn message_throughput(&self) -> Option<f64> {
+        None
+    }
+
+    async fn health_check(&self) -> HealthStatus {
+        HealthStatus {
+            db_write_ok: true,
+            db_read_ok: true,
+            bytestore_ok: true,
+            cache_hit_rate: 0.0,
+            write_pool_idle: 0,
+            read_pool_idle: 0,
+            bytestore_degraded: false,
+            disk_usage_percent: None,
+            error_messages: vec![],
+        }
+    }
+
+    fn ping(&self) -> bool {
+        true
+    }
+
+    async fn check_existing_deep_hash(
+        &self,
+        process_id: &String,
+        deep_hash: &String,
+    ) -> Result<(), StoreErrorType> {
+        let existing = self
+            .check_existing_deep_hashes(process_id, std::slice::from_ref(deep_hash))
+            .await?;
+        match existing.is_empty() {
+            true => Ok(()),
+            false => Err(StoreErrorType::MessageExists(
+                "Deep hash already exists".to_string(),
+            )),
+        }
+    }
+
+    async fn check_existing_deep_hashes(
+        &self,
+        process_id: &String,
+        deep_hashes: &[String],
+    ) -> Result<Vec<String>, StoreErrorType> {
+        let saved = self.deep_hashes.lock().await;
+        Ok(deep_hashes
+            .iter()
+            .filter(|dh| saved.iter().any(|(p, d)| p == process_id && d == *dh))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_deephash_version(&self, process_id: &String) -> Result<String, StoreErrorType> {
+        self.deep_hash_versions
+            .lock()
+            .await
+            .get(process_id)
+            .cloned()
+            .ok_or_else(|| StoreErrorType::not_found("deep hash version", process_id))
+    }
+
+    async fn get_deephashes(&self, process_id: &str) -> Result<Vec<String>, StoreErrorType> {
+        Ok(self
+            .deep_hashes
+            .lock()
+            .await
+            .iter()
+            .filter(|(p, _)| p == process_id)
+            .map(|(_, d)| d.clone())
+            .collect())
+    }
+
+    async fn save_deephash_version(
+        &self,
+        process_id: &String,
+        version: &String,
+    ) -> Result<(), StoreErrorType> {
+        self.deep_hash_versions
+            .lock()
+            .await
+            .insert(process_id.clone(), version.clone());
+        Ok(())
+    }
+
+    async fn save_deephash(
+        &self,
+        process_id: &String,
+        deep_hash: &String,
+    ) -> Result<(), StoreErrorType> {
+        self.deep_hashes
+            .lock()
+            .await
+            .push((process_id.clone(), deep_hash.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl RouterDataStore for MockDataStore {
+    async fn save_process_scheduler(
+        &self,
+        process_scheduler: &ProcessScheduler,
+    ) -> Result<String, StoreErrorType> {
+        let mut process_schedulers = self.process_schedulers.lock().await;
+        if !process_schedulers
+            .iter()
+            .any(|ps| ps.process_id == process_scheduler.process_id)
+        {
+            let row_id = process_schedulers.len() as i32 + 1;
+            process_schedulers.push(ProcessScheduler {
+                row_id: Some(row_id),
+                process_id: process_scheduler.process_id.clone(),
+                scheduler_row_id: process_scheduler.scheduler_row_id,
+            });
+        }
+        Ok("saved".to_string())
+    }
+
+    async fn get_process_scheduler(
+        &self,
+        process_id_in: &str,
+    ) -> Result<ProcessScheduler, StoreErrorType> {
+        self.process_schedulers
+            .lock()
+            .await
+            .iter()
+            .find(|ps| ps.process_id == process_id_in)
+            .cloned()
+            .ok_or_else(|| StoreErrorType::not_found("process scheduler", process_id_in))
+    }
+
+    async fn save_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType> {
+        scheduler.wallets_to_route_list()?;
+        let mut schedulers = self.schedulers.lock().await;
+        if !schedulers.iter().any(|s| s.url == scheduler.url) {
+            let row_id = schedulers.len() as i32 + 1;
+            schedulers.push(Scheduler {
+                row_id: Some(row_id),
+                version: Some(0),
+                consecutive_failures: 0,
+                ..scheduler.clone()
+            });
+        }
+        Ok("saved".to_string())
+    }
+
+    async fn update_scheduler(&self, scheduler: &Scheduler) -> Result<String, StoreErrorType> {
+        let row_id = scheduler.row_id.ok_or_else(|| {
+            StoreErrorType::invalid_input("row_id", "Cannot update a scheduler without a row_id")
+        })?;
+        scheduler.wallets_to_route_list()?;
+        let mut schedulers = self.schedulers.lock().await;
+        let existing = match schedulers.iter_mut().find(|s| s.row_id == Some(row_id)) {
+            Some(existing) => existing,
+            None => return Err(StoreErrorType::not_found("scheduler", row_id)),
+        };
+        if let Some(version) = scheduler.version {
+            if existing.version != Some(version) {
+                return Err(StoreErrorType::Conflict(format!(
+                    "Scheduler was modified since version {} was read",
+                    version
+                )));
+            }
+        }
+        existing.url = scheduler.url.clone();
+        existing.no_route = scheduler.no_route;
+        existing.wallets_to_route = scheduler.wallets_to_route.clone();
+        existing.wallets_only = scheduler.wallets_only;
+        existing.version = existing.version.map(|v| v + 1);
+        Ok("updated".to_string())
+    }
+
+    async fn get_scheduler(&self, row_id_in: &i32) -> Result<Scheduler, StoreErrorType> {
+        self.schedulers
+            .lock()
+            .await
+            .iter()
+            .find(|s| s.row_id == Some(*row_id_in))
+            .cloned()
+            .ok_or_else(|| StoreErrorType::not_found("scheduler", row_id_in))
+    }
+
+    async fn get_scheduler_by_url(&self, url_in: &String) -> Result<Scheduler, StoreErrorType> {
+        self.schedulers
+            .lock()
+            .await
+            .iter()
+            .find(|s| &s.url == url_in)
+            .cloned()
+            .ok_or_else(|| StoreErrorType::not_found("scheduler", url_in))
+    }
+
+    async fn get_all_schedulers(&self) -> Result<Vec<Scheduler>, StoreErrorType> {
+        Ok(self.schedulers.lock().await.clone())
+    }
+
+    async fn get_scheduler_for_wallet(
+        &self,
+        wallet_id: &str,
+    ) -> Result<Option<Scheduler>, StoreErrorType> {
+        let mut routed = vec![];
+        for scheduler in self.schedulers.lock().await.iter() {
+            if scheduler.wallets_only == Some(true)
+                && scheduler
+                    .wallets_to_route_list()?
+                    .iter()
+                    .any(|w| w == wallet_id)
+            {
+                routed.push(scheduler.clone());
+            }
+        }
+        Ok(routed.into_iter().min_by_key(|s| s.process_count))
+    }
+
+    async fn increment_process_count(
+        &self,
+        row_id: i32,
+        delta: i32,
+    ) -> Result<i32, StoreErrorType> {
+        let mut schedulers = self.schedulers.lock().await;
+        match schedulers.iter_mut().find(|s| s.row_id == Some(row_id)) {
+            Some(scheduler) => {
+                scheduler.process_count += delta;
+                Ok(scheduler.process_count)
+            }
+            None => Err(StoreErrorType::not_found("scheduler", row_id)),
+        }
+    }
+
+    async fn recount_process_counts(&self) -> Result<Vec<ProcessCountCorrection>, StoreErrorType> {
+        let mut schedulers = self.schedulers.lock().await;
+        let process_schedulers = self.process_schedulers.lock().await;
+        let mut corrections = vec![];
+        for scheduler in schedulers.iter_mut() {
+            let actual = process_schedulers
+                .iter()
+                .filter(|ps| Some(ps.scheduler_row_id) == scheduler.row_id)
+                .count() as i32;
+            if actual != scheduler.process_count {
+                corrections.push(ProcessCountCorrection {
+                    scheduler_row_id: scheduler.row_id.unwrap_or(0),
+                    url: scheduler.url.clone(),
+                    previous_count: scheduler.process_count,
+                    corrected_count: actual,
+                });
+                scheduler.process_count = actual;
+            }
+        }
+        Ok(corrections)
+    }
+
+    /*
+      With the SCHEDULER_MAX_FAILURES default and no
+      SCHEDULER_CAPACITY.
+    */
+    async fn select_scheduler_for_spawn(
+        &self,
+        owner_wallet: &str,
+    ) -> Result<Scheduler, StoreErrorType> {
+        let schedulers = self.get_all_schedulers().await?;
+        super::router::select_scheduler(schedulers, owner_wallet, 3, None)
+    }
+
+    async fn export_router_state(&self) -> Result<RouterState, StoreErrorType> {
+        let schedulers = self.schedulers.lock().await;
+        let assignments = self
+            .process_schedulers
+            .lock()
+            .await
+            .iter()
+            .filter_map(|ps| {
+                schedulers
+                    .iter()
+                    .find(|s| s.row_id == Some(ps.scheduler_row_id))
+                    .map(|s| RouterStateAssignment {
+                        process_id: ps.process_id.clone(),
+                        scheduler_url: s.url.clone(),
+                    })
+            })
+            .collect();
+
+        Ok(RouterState {
+            schedulers: schedulers
+                .iter()
+                .map(|s| RouterStateScheduler {
+                    url: s.url.clone(),
+                    no_route: s.no_route,
+                    wallets_to_route: s.wallets_to_route.clone(),
+                    wallets_only: s.wallets_only,
+                })
+                .collect(),
+            assignments,
+        })
+    }
+
+    async fn import_router_state(
+        &self,
+        _state: &RouterState,
+        _merge: bool,
+        _skip_violations: bool,
+    ) -> Result<RouterImportReport, StoreErrorType> {
+        Err(unsupported("Importing router state"))
+    }
 }
 
 pub trait CoreMetrics: Send + Sync {
@@ -242,6 +2199,8 @@ pub trait CoreMetrics: Send + Sync {
     fn write_assignment_observe(&self, duration: u128);
     fn acquire_write_lock_observe(&self, duration: u128);
     fn failed_message_save(&self);
+    fn rate_limited_message(&self);
+    fn process_throughput_observe(&self, counts: Vec<(String, i64)>);
 }
 
 #[async_trait]
@@ -253,4 +2212,61 @@ pub enum ExtRouterErrorType {
     NotFound(String),
     NetworkError(String),
     ConfigError(String)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::store_suite::{
+        assignment, data_store_suite, deep_hash_suite, get_messages_suite, process,
+        router_data_store_suite,
+    };
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_mock_data_store_messages() {
+        let store = MockDataStore::new();
+        data_store_suite(&store, "mock").await;
+        get_messages_suite(&store, "mock").await;
+        deep_hash_suite(&store, "mock").await;
+    }
+
+    #[tokio::test]
+    async fn test_mock_router_data_store() {
+        let store = MockDataStore::new();
+        router_data_store_suite(&store, "mock").await;
+
+        store.increment_process_count(1, 5).await.unwrap();
+        let corrections = store.recount_process_counts().await.unwrap();
+        assert_eq!(corrections.len(), 1);
+        assert_eq!(corrections[0].scheduler_row_id, 1);
+        assert_eq!(corrections[0].previous_count, 5);
+        assert_eq!(corrections[0].corrected_count, 0);
+
+        let state = store.export_router_state().await.unwrap();
+        assert_eq!(state.schedulers.len(), 2);
+        assert_eq!(state.assignments.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_data_store_fault_injection() {
+        let store = MockDataStore::new();
+        store.fail_next_saves(2);
+        assert!(store.save_process(&process("p1"), &[]).await.is_err());
+        assert!(store
+            .save_message(&assignment("p1", 1, 100), &[], None)
+            .await
+            .is_err());
+        store.save_process(&process("p1"), &[]).await.unwrap();
+        assert_eq!(store.processes.lock().await.len(), 1);
+        assert!(store.messages.lock().await.is_empty());
+
+        store.set_save_latency(Some(Duration::from_millis(20)));
+        let start = Instant::now();
+        store
+            .save_message(&assignment("p1", 1, 100), &[], None)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -1,3 +1,4 @@
+use std::future::Future;
 use std::sync::Arc;
 use std::time::Instant;
 use std::time::{SystemTime, SystemTimeError, UNIX_EPOCH};
@@ -8,13 +9,15 @@ use serde_json::json;
 use simd_json::to_string as simd_to_string;
 use tokio::sync::Mutex;
 
+use super::admin::AdminTasks;
 use super::builder::Builder;
 use super::bytes::{DataBundle, DataItem};
 use super::json::{Message, Process};
 use super::scheduler;
+use super::verify::verify_process_bundle;
 
 use super::dal::{
-    Config, CoreMetrics, DataStore, ExtRouter, ExtRouterErrorType, Gateway, Log, RouterDataStore, Signer, Uploader, Wallet
+    Config, CoreMetrics, DataStore, ExtRouter, ExtRouterErrorType, FlowError, Gateway, Log, MessageFilter, ProcessHandover, RouterDataStore, Signer, StoreErrorType, Uploader, Wallet
 };
 
 pub struct Deps {
@@ -42,6 +45,12 @@ pub struct Deps {
       given process
     */
     pub deephash_locks: Arc<DashMap<String, Arc<Mutex<String>>>>,
+
+    /*
+      The admin operations running in the background,
+      polled by their task id.
+    */
+    pub admin_tasks: Arc<AdminTasks>,
 }
 
 /*
@@ -63,7 +72,32 @@ async fn upload(deps: &Arc<Deps>, build_result: Vec<u8>) -> Result<String, Strin
     Ok(result)
 }
 
-fn id_res(deps: &Arc<Deps>, id: String, start_top_level: Instant) -> Result<String, String> {
+/*
+  Writes turned away by the store's per process
+  rate limit are counted so we can see them in
+  the metrics.
+*/
+async fn save_message(
+    deps: &Arc<Deps>,
+    message: &Message,
+    binary: &[u8],
+    deep_hash: Option<&String>,
+) -> Result<String, FlowError> {
+    match deps
+        .data_store
+        .save_message(message, binary, deep_hash)
+        .await
+    {
+        Ok(r) => Ok(r),
+        Err(e @ StoreErrorType::RateLimited { .. }) => {
+            deps.metrics.rate_limited_message();
+            Err(e.into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn id_res(deps: &Arc<Deps>, id: String, start_top_level: Instant) -> Result<String, FlowError> {
     match system_time_u64() {
         Ok(timestamp) => {
             let response_json = json!({
@@ -77,10 +111,73 @@ fn id_res(deps: &Arc<Deps>, id: String, start_top_level: Instant) -> Result<Stri
 
             Ok(response_json.to_string())
         }
-        Err(e) => Err(format!("{:?}", e)),
+        Err(e) => Err(format!("{:?}", e).into()),
     }
 }
 
+/*
+  Recompute and save the deep hashes of every message
+  on a process, reading the bundles a page of limit at
+  a time. Returns how many bundles were read, saving
+  the new deep hash version is left to the caller.
+*/
+pub async fn regenerate_deephashes(
+    data_store: Arc<dyn DataStore>,
+    gateway: Arc<dyn Gateway>,
+    logger: Arc<dyn Log>,
+    process: &Process,
+    limit: i32,
+) -> Result<usize, String> {
+    let process_id = &process.process.process_id;
+    let limit = Some(limit);
+    let mut from = None;
+    let mut total = 0;
+
+    loop {
+        let bundles = data_store
+            .get_message_bundles(process, &from, &limit)
+            .await?;
+
+        if bundles.0.len() < 1 {
+            break;
+        }
+
+        total = total + bundles.0.len();
+
+        logger.log(format!(
+            "Total bundles retrieved in deephash calc for process {}: {}",
+            total, process_id
+        ));
+
+        let final_bundle = bundles.0[bundles.0.len() - 1].clone();
+        let final_message = Message::from_bytes(final_bundle.1)?;
+        from = Some(final_message.timestamp()?.to_string());
+
+        for (_, bundle) in &bundles.0 {
+            let msg = Message::from_bytes(bundle.clone())?;
+            /*
+              msg_deephash produces an error or None for a message
+              that shouldn't get deep hashed. So we swallow the error
+              or None value here to proceed with the full recompute
+              of all the deep hashes
+            */
+            match msg_deephash(gateway.clone(), &msg, bundle).await {
+                Ok(Some(dh)) => {
+                    data_store.save_deephash(process_id, &dh).await?;
+                }
+                Ok(None) => (),
+                Err(_) => (),
+            };
+        }
+
+        if bundles.1 == false {
+            break;
+        }
+    }
+
+    Ok(total)
+}
+
 /*
   Recompute and save all deep hashes on a process
   this is done on demand for a process when writing
@@ -124,10 +221,6 @@ async fn maybe_recalc_deephashes(deps: Arc<Deps>, process_id: &String) -> Result
     deps.logger
         .log(format!("Checking deephash version for {}", process_id));
 
-    let mut from = None;
-    let limit = Some(deps.config.deephash_recalc_limit());
-    let mut total = 0;
-
     match deps.data_store.get_deephash_version(process_id).await {
         Ok(d) => {
             if d == deps.config.current_deephash_version() {
@@ -152,48 +245,14 @@ async fn maybe_recalc_deephashes(deps: Arc<Deps>, process_id: &String) -> Result
         Err(_) => return Ok(()),
     };
 
-    loop {
-        let bundles = deps
-            .data_store
-            .get_message_bundles(&process, &from, &limit)
-            .await?;
-
-        if bundles.0.len() < 1 {
-            break;
-        }
-
-        total = total + bundles.0.len();
-
-        deps.logger.log(format!(
-            "Total bundles retrieved in deephash calc for process {}: {}",
-            total, process_id
-        ));
-
-        let final_bundle = bundles.0[bundles.0.len() - 1].clone();
-        let final_message = Message::from_bytes(final_bundle.1)?;
-        from = Some(final_message.timestamp()?.to_string());
-
-        for (_, bundle) in &bundles.0 {
-            let msg = Message::from_bytes(bundle.clone())?;
-            /*
-              msg_deephash produces an error or None for a message
-              that shouldn't get deep hashed. So we swallow the error
-              or None value here to proceed with the full recompute
-              of all the deep hashes
-            */
-            match msg_deephash(deps.gateway.clone(), &msg, bundle).await {
-                Ok(Some(dh)) => {
-                    deps.data_store.save_deephash(&process_id, &dh).await?;
-                }
-                Ok(None) => (),
-                Err(_) => (),
-            };
-        }
-
-        if bundles.1 == false {
-            break;
-        }
-    }
+    regenerate_deephashes(
+        deps.data_store.clone(),
+        deps.gateway.clone(),
+        deps.logger.clone(),
+        &process,
+        deps.config.deephash_recalc_limit(),
+    )
+    .await?;
 
     deps.data_store
         .save_deephash_version(process_id, &deps.config.current_deephash_version())
@@ -227,7 +286,7 @@ pub async fn write_item(
     assign: Option<String>,
     base_layer: Option<String>,
     exclude: Option<String>,
-) -> Result<String, String> {
+) -> Result<String, FlowError> {
     deps.logger.log(format!("write item called"));
     let start_top_level = Instant::now();
     let builder = init_builder(&deps)?;
@@ -244,9 +303,9 @@ pub async fn write_item(
             Some(type_tag) => match type_tag.value.as_str() {
                 "Process" => (data_item.id(), Some(data_item)),
                 "Message" => (data_item.target(), Some(data_item)),
-                _ => return Err("Unsupported Type tag value".to_string()),
+                _ => return Err("Unsupported Type tag value".into()),
             },
-            None => return Err("Type tag not present".to_string()),
+            None => return Err("Type tag not present".into()),
         }
     };
 
@@ -285,7 +344,7 @@ pub async fn write_item(
       set cache that gets set before the lock is released
     */
     if let Some(ref item) = data_item {
-        deps.data_store.check_existing_message(&item.id())?;
+        deps.data_store.check_existing_message(&item.id()).await?;
     };
 
     deps.logger
@@ -323,7 +382,7 @@ pub async fn write_item(
        POST of an assignment
     */
     if process_id.is_some() ^ assign.is_some() {
-        return Err("If sending assign or process-id, you must send both.".to_string());
+        return Err("If sending assign or process-id, you must send both.".into());
     } else if let (Some(process_id), Some(assign)) = (process_id.clone(), assign.clone()) {
         let assignment = builder
             .gen_assignment(
@@ -334,14 +393,14 @@ pub async fn write_item(
             )
             .await?;
 
-        let process = deps.data_store.get_process(&process_id).await?;
+        let process = deps.data_store.get_process_uncached(&process_id).await?;
 
         let gateway_tx = match builder
             .verify_assignment(&assign, &process, &base_layer)
             .await?
         {
             Some(g) => g,
-            None => return Err("Invalid gateway tx for assignming".to_string()),
+            None => return Err("Invalid gateway tx for assignming".into()),
         };
 
         /*
@@ -371,13 +430,17 @@ pub async fn write_item(
             }
         };
 
+        /*
+          Only the assignment is bundled and saved, the
+          assigned item stays wherever it already is and
+          is read from there by id, so an item assigned
+          many times isn't stored again for each one.
+        */
         let aid = assignment.id();
         let return_aid = assignment.id();
         let build_result = builder.bundle_items(vec![assignment]).await?;
         let message = Message::from_bundle(&build_result.bundle)?;
-        deps.data_store
-            .save_message(&message, &build_result.binary, deep_hash.as_ref())
-            .await?;
+        save_message(&deps, &message, &build_result.binary, deep_hash.as_ref()).await?;
         deps.logger.log(format!("saved message"));
 
         /*
@@ -404,7 +467,7 @@ pub async fn write_item(
 
     let data_item = match data_item {
         Some(d) => d,
-        None => return Err("Unable to parse data item".to_string()),
+        None => return Err("Unable to parse data item".into()),
     };
 
     let tags = data_item.tags().clone();
@@ -415,12 +478,12 @@ pub async fn write_item(
         .iter()
         .any(|tag| tag.name == "Data-Protocol" || tag.name == "data-protocol");
     if !proto_tag_exists {
-        return Err("Data-Protocol tag not present".to_string());
+        return Err("Data-Protocol tag not present".into());
     }
 
     let type_tag = match type_tag {
         Some(t) => t,
-        None => return Err("Invalid Type Tag".to_string()),
+        None => return Err("Invalid Type Tag".into()),
     };
 
     if type_tag.value == "Process" {
@@ -429,7 +492,7 @@ pub async fn write_item(
 
         if !mod_tag_exists || !sched_tag_exists {
             return Err(
-                "Required Module and Scheduler tags for Process type not present".to_string(),
+                "Required Module and Scheduler tags for Process type not present".into(),
             );
         }
 
@@ -453,7 +516,7 @@ pub async fn write_item(
                           here as well.
                         */
                         if a != deps.config.assignment() {
-                            return Err("Process does not belong on this SU".to_string())
+                            return Err("Process does not belong on this SU".into())
                         }
                     },
                     Err(e) => {
@@ -468,7 +531,7 @@ pub async fn write_item(
                               check the router for a process id we cant determine
                               if it is safe so throw an error.
                             */
-                            _ => return Err("Unable to check router".to_string())
+                            _ => return Err("Unable to check router".into())
                         }
                     }
                   }
@@ -481,7 +544,7 @@ pub async fn write_item(
                     "Data" => (),
                     tx_id => {
                         if !deps.gateway.check_head(tx_id.to_string()).await? {
-                            return Err("Invalid tx id for On-Boot tag".to_string());
+                            return Err("Invalid tx id for On-Boot tag".into());
                         }
                     }
                 },
@@ -498,7 +561,8 @@ pub async fn write_item(
 
             let process = Process::from_bundle(&build_result.bundle)?;
             deps.data_store
-                .save_process(&process, &build_result.binary)?;
+                .save_process(&process, &build_result.binary)
+                .await?;
 
             deps.scheduler
                 .commit(&mut *schedule_info, &next_schedule_info, did, aid);
@@ -514,7 +578,8 @@ pub async fn write_item(
                 &build_result.bundle_data_item,
             )?;
             deps.data_store
-                .save_process(&process, &build_result.binary)?;
+                .save_process(&process, &build_result.binary)
+                .await?;
             deps.logger.log(format!("saved process"));
 
             /*
@@ -551,7 +616,7 @@ pub async fn write_item(
                 let mut mutable_item = data_item.clone();
                 let deep_hash = match mutable_item.deep_hash() {
                     Ok(d) => d,
-                    Err(_) => return Err("Unable to calculate deep hash".to_string()),
+                    Err(_) => return Err("Unable to calculate deep hash".into()),
                 };
 
                 /*
@@ -572,9 +637,7 @@ pub async fn write_item(
         let build_result = builder.bundle_items(vec![assignment, data_item]).await?;
         let message = Message::from_bundle(&build_result.bundle)?;
 
-        deps.data_store
-            .save_message(&message, &build_result.binary, deep_hash.as_ref())
-            .await?;
+        save_message(&deps, &message, &build_result.binary, deep_hash.as_ref()).await?;
 
         deps.logger.log(format!("saved message"));
 
@@ -590,7 +653,7 @@ pub async fn write_item(
         upload(&deps, build_result.binary.to_vec()).await?;
         return id_res(&deps, message.message_id()?, start_top_level);
     } else {
-        return Err("Type tag not present".to_string());
+        return Err("Type tag not present".into());
     }
 }
 
@@ -602,10 +665,13 @@ pub async fn read_message_data(
     limit: Option<i32>,
     from_nonce: Option<String>,
     to_nonce: Option<String>,
-) -> Result<String, String> {
+    include_deleted: bool,
+    inclusive: bool,
+    timing: bool,
+) -> Result<String, FlowError> {
     let start_top_level = Instant::now();
     let start_get_message = Instant::now();
-    if let Ok(message) = deps.data_store.get_message(&tx_id) {
+    if let Ok(message) = deps.data_store.get_message(&tx_id).await {
         if message.message.is_some()
             || ((message.message_id()? != message.process_id()?)
                 && (message.assignment_id()? == tx_id))
@@ -613,21 +679,54 @@ pub async fn read_message_data(
             let elapsed_get_message = start_get_message.elapsed();
             deps.metrics
                 .get_message_observe(elapsed_get_message.as_millis());
-            return serde_json::to_string(&message).map_err(|e| format!("{:?}", e));
+            return serde_json::to_string(&message).map_err(|e| format!("{:?}", e).into());
         }
     }
 
     if let Ok(process) = deps.data_store.get_process(&tx_id).await {
+        /*
+          The breakdown is also gathered whenever slow
+          reads are logged, since which ones are slow
+          isn't known until they finish. It only goes
+          out in the response if it was asked for.
+        */
+        let timing = timing && deps.config.get_messages_timing();
+        let slow_ms = deps.config.slow_get_messages_ms();
+        let filter = MessageFilter::default()
+            .from(from)
+            .to(to)
+            .limit(limit)
+            .from_nonce(from_nonce)
+            .to_nonce(to_nonce)
+            .include_deleted(include_deleted)
+            .inclusive(inclusive)
+            .timing(timing || slow_ms.is_some());
+
         let start = Instant::now();
-        let messages = deps
+        let mut messages = deps
             .data_store
-            .get_messages(&process, &from, &to, &limit, &from_nonce, &to_nonce)
+            .get_messages(&process, &filter)
             .await?;
         let duration = start.elapsed();
         deps.logger
             .log(format!("Time elapsed in get_messages() is: {:?}", duration));
         deps.metrics.get_messages_observe(duration.as_millis());
 
+        if let Some(slow_ms) = slow_ms {
+            if duration.as_millis() >= slow_ms as u128 {
+                deps.logger.error(format!(
+                    "Slow get_messages() for process {} took {:?}, {} messages, {:?}",
+                    tx_id,
+                    duration,
+                    messages.edges.len(),
+                    messages.timing
+                ));
+            }
+        }
+        if !timing {
+            messages.timing = None;
+        }
+
         let result = simd_to_string(&messages).map_err(|e| format!("{:?}", e))?;
 
         let elapsed_top_level = start_top_level.elapsed();
@@ -637,25 +736,95 @@ pub async fn read_message_data(
         return Ok(result);
     }
 
-    Err("Message or Process not found".to_string())
+    Err("Message or Process not found".into())
+}
+
+/*
+  The raw stored bundle for a message, None
+  if the message doesn't exist.
+*/
+pub async fn read_message_bundle(
+    deps: Arc<Deps>,
+    tx_id: String,
+) -> Result<Option<Vec<u8>>, FlowError> {
+    match deps.data_store.get_message_bundle(&tx_id).await {
+        Ok(bundle) => Ok(Some(bundle)),
+        Err(StoreErrorType::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
 }
 
-pub async fn read_latest_message(deps: Arc<Deps>, process_id: String) -> Result<String, String> {
+pub async fn read_latest_message(deps: Arc<Deps>, process_id: String) -> Result<String, FlowError> {
+    if !deps.data_store.check_process_exists(&process_id).await? {
+        return Err(StoreErrorType::not_found("process", &process_id).into());
+    }
     if let Ok(Some(message)) = deps.data_store.get_latest_message(&process_id).await {
-        return serde_json::to_string(&message).map_err(|e| format!("{:?}", e));
+        return serde_json::to_string(&message).map_err(|e| format!("{:?}", e).into());
     } else {
-        Err("Latest message not available".to_string())
+        Err("Latest message not available".into())
+    }
+}
+
+/*
+  Page through the processes spawned from a module,
+  the next_cursor in the response is passed back as
+  cursor to get the next page.
+*/
+pub async fn read_processes_by_module(
+    deps: Arc<Deps>,
+    module_id: String,
+    cursor: Option<i32>,
+    limit: Option<i32>,
+) -> Result<String, FlowError> {
+    let limit = limit.unwrap_or(100);
+    if limit < 1 || limit > 1000 {
+        return Err("limit must be between 1 and 1000".into());
     }
+
+    let (processes, next_cursor) = deps
+        .data_store
+        .get_processes_by_module(&module_id, cursor, limit)
+        .await?;
+
+    let inner: Vec<_> = processes.into_iter().map(|p| p.process).collect();
+    let response_json = json!({ "processes": inner, "next_cursor": next_cursor });
+    Ok(response_json.to_string())
 }
 
-pub async fn read_process(deps: Arc<Deps>, process_id: String) -> Result<String, String> {
+/*
+  For long polling a process's messages, the cursor
+  in the response is passed back as after. It stays
+  the same when there was nothing new.
+*/
+pub async fn read_messages_after_rowid(
+    deps: Arc<Deps>,
+    process_id: String,
+    after: Option<i64>,
+    limit: Option<i32>,
+) -> Result<String, FlowError> {
+    let limit = limit.unwrap_or(100);
+    if limit < 1 || limit > 1000 {
+        return Err("limit must be between 1 and 1000".into());
+    }
+    let after = after.unwrap_or(0);
+
+    let (messages, cursor) = deps
+        .data_store
+        .get_messages_after_rowid(&process_id, after, limit)
+        .await?;
+
+    let response_json = json!({ "messages": messages, "cursor": cursor.unwrap_or(after) });
+    Ok(response_json.to_string())
+}
+
+pub async fn read_process(deps: Arc<Deps>, process_id: String) -> Result<String, FlowError> {
     let start = Instant::now();
     let process = deps.data_store.get_process(&process_id).await?;
     let elapsed = start.elapsed();
     deps.metrics.get_process_observe(elapsed.as_millis());
     let result = match serde_json::to_string(&process.process) {
         Ok(r) => r,
-        Err(e) => return Err(format!("{:?}", e)),
+        Err(e) => return Err(format!("{:?}", e).into()),
     };
     Ok(result)
 }
@@ -675,7 +844,7 @@ fn system_time_u64() -> Result<u64, SystemTimeError> {
     Ok(millis)
 }
 
-pub async fn timestamp(deps: Arc<Deps>) -> Result<String, String> {
+pub async fn timestamp(deps: Arc<Deps>) -> Result<String, FlowError> {
     match system_time() {
         Ok(timestamp) => {
             let network_info = deps.gateway.network_info().await;
@@ -687,27 +856,646 @@ pub async fn timestamp(deps: Arc<Deps>) -> Result<String, String> {
                         json!({ "timestamp": timestamp, "block_height": height_string });
                     Ok(response_json.to_string())
                 }
-                Err(e) => Err(format!("{:?}", e)),
+                Err(e) => Err(format!("{:?}", e).into()),
             }
         }
-        Err(e) => Err(format!("{:?}", e)),
+        Err(e) => Err(format!("{:?}", e).into()),
     }
 }
 
-pub async fn health(deps: Arc<Deps>) -> Result<String, String> {
+pub async fn health(deps: Arc<Deps>) -> Result<String, FlowError> {
     match system_time() {
         Ok(timestamp) => {
             let wallet_address = match deps.wallet.wallet_address() {
                 Ok(w) => w,
-                Err(e) => return Err(e),
+                Err(e) => return Err(e.into()),
             };
-            let response_json = json!({ "timestamp": timestamp, "address": wallet_address });
+            let mut response_json = json!({ "timestamp": timestamp, "address": wallet_address });
+            if let Some(counters) = deps.data_store.bytestore_counters() {
+                response_json["bytestore"] = json!({
+                    "reads_attempted": counters.reads_attempted,
+                    "reads_hit": counters.reads_hit,
+                    "reads_missed": counters.reads_missed,
+                    "writes": counters.writes,
+                    "write_failures": counters.write_failures,
+                    "deletes": counters.deletes,
+                    "stored_bytes": counters.stored_bytes,
+                    "logical_bytes": counters.logical_bytes,
+                    "corrupt_reads": counters.corrupt_reads,
+                    "hit_rate": counters.hit_rate(),
+                });
+            }
+            let caches = deps.data_store.cache_stats().await;
+            if !caches.is_empty() {
+                response_json["caches"] = json!(caches);
+            }
+            let breakers = deps.data_store.circuit_breakers();
+            if !breakers.is_empty() {
+                response_json["db_breakers"] = json!(breakers);
+            }
+            if let Some(rate) = deps.data_store.message_throughput().await {
+                response_json["messages_per_second"] = json!(rate);
+            }
             Ok(response_json.to_string())
         }
-        Err(e) => Err(format!("{:?}", e)),
+        Err(e) => Err(format!("{:?}", e).into()),
+    }
+}
+
+/*
+  The bytestore counters since startup alongside
+  RocksDB's own statistics and the result of the
+  last background sync, for the admin endpoint.
+*/
+pub async fn bytestore_admin_stats(deps: Arc<Deps>, operator: String) -> Result<String, FlowError> {
+    let counters = deps.data_store.bytestore_counters();
+    let statistics = deps.data_store.bytestore_statistics().await;
+    let outcome = match &statistics {
+        Ok(_) => json!("read"),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    audit_admin(&deps, "bytestore_stats", &operator, json!({ "outcome": outcome }));
+
+    let statistics = statistics?;
+    let last_sync = deps.data_store.bytestore_last_sync();
+    let response_json = json!({
+        "counters": counters,
+        "statistics": statistics,
+        "last_sync": last_sync
+    });
+    Ok(response_json.to_string())
+}
+
+/*
+  Write a bytestore checkpoint to path, or to the
+  configured checkpoint dir if no path is given, as
+  a background admin task.
+*/
+pub fn start_bytestore_checkpoint(
+    deps: Arc<Deps>,
+    path: Option<String>,
+    operator: String,
+) -> Result<String, FlowError> {
+    let path = match path.or(deps.config.bytestore_checkpoint_dir()) {
+        Some(path) => path,
+        None => {
+            let error = "No checkpoint path given and BYTESTORE_CHECKPOINT_DIR is not set";
+            audit_admin(
+                &deps,
+                "bytestore_checkpoint",
+                &operator,
+                json!({ "outcome": { "error": error } }),
+            );
+            return Err(error.into());
+        }
+    };
+
+    let data_store = deps.data_store.clone();
+    let logger = deps.logger.clone();
+    let fields = json!({ "path": path });
+    start_admin_task(
+        deps,
+        "bytestore_checkpoint",
+        operator,
+        fields,
+        async move {
+            let checkpoint = data_store.create_bytestore_checkpoint(&path).await?;
+            logger.log(format!(
+                "bytestore checkpoint written to {}, size: {} bytes, removed: {:?}",
+                checkpoint.path, checkpoint.size_bytes, checkpoint.removed
+            ));
+            Ok(json!(checkpoint))
+        },
+    )
+}
+
+/*
+  Compact the bytestore keys starting with prefix, or
+  all of them, as a background admin task.
+*/
+pub fn start_bytestore_compaction(
+    deps: Arc<Deps>,
+    prefix: Option<String>,
+    operator: String,
+) -> Result<String, FlowError> {
+    let fields = json!({ "prefix": prefix });
+    start_admin_task(
+        deps.clone(),
+        "bytestore_compaction",
+        operator,
+        fields,
+        async move { Ok(compact_bytestore(deps, prefix).await?) },
+    )
+}
+
+/*
+  Compact the bytestore keys starting with prefix, or
+  all of them, logging how much space it gave back.
+*/
+pub async fn compact_bytestore(
+    deps: Arc<Deps>,
+    prefix: Option<String>,
+) -> Result<serde_json::Value, FlowError> {
+    deps.logger.log(format!(
+        "bytestore compaction of {} starting",
+        prefix.as_deref().unwrap_or("every key")
+    ));
+    let compaction = deps.data_store.compact_bytestore(prefix).await?;
+    deps.logger.log(format!(
+        "bytestore compaction done in {}ms, size before: {} bytes, after: {} bytes, reclaimed: {} bytes",
+        compaction.duration_ms,
+        compaction.size_before,
+        compaction.size_after,
+        compaction.size_before.saturating_sub(compaction.size_after)
+    ));
+
+    Ok(json!(compaction))
+}
+
+/*
+  Whether hour, 0 to 23 in UTC, falls in the window
+  from start up to end. A window with start after
+  end runs over midnight.
+*/
+pub fn in_compaction_window(hour: u32, start: u32, end: u32) -> bool {
+    if start <= end {
+        hour >= start && hour < end
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+/*
+  Run by the ROCKSDB_COMPACTION_INTERVAL_HOURS schedule,
+  waits for the quiet window then compacts the message
+  keys. A compaction refused because a sync or migration
+  is writing is left for the next run.
+*/
+pub async fn scheduled_bytestore_compaction(deps: Arc<Deps>, window: (u32, u32)) {
+    loop {
+        let hour = match system_time_u64() {
+            Ok(millis) => (millis / 3_600_000 % 24) as u32,
+            Err(_) => return,
+        };
+        if in_compaction_window(hour, window.0, window.1) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(600)).await;
+    }
+
+    // the prefix of every message key in the bytestore
+    if let Err(e) = compact_bytestore(deps.clone(), Some("message___".to_string())).await {
+        deps.logger
+            .error(format!("Scheduled bytestore compaction skipped: {}", e));
     }
 }
 
+/*
+  Purge a process from every store, holding its
+  scheduler lock so nothing is assigned while it is
+  being removed. Every attempt is written to the
+  audit log, including the ones that fail.
+*/
+pub async fn purge_process(
+    deps: Arc<Deps>,
+    process_id: String,
+    force: bool,
+    operator: String,
+) -> Result<String, FlowError> {
+    let locked_schedule_info = deps.scheduler.acquire_lock(process_id.clone()).await?;
+    let schedule_info = locked_schedule_info.lock().await;
+
+    let result = deps.data_store.purge_process(&process_id, force).await;
+    deps.scheduler.forget(&process_id);
+    drop(schedule_info);
+
+    let time = system_time_u64().map_err(|e| format!("{:?}", e))?;
+    let outcome = match &result {
+        Ok(report) => json!({ "report": report }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    deps.logger.audit(
+        json!({
+            "action": "purge_process",
+            "operator": operator,
+            "time": time,
+            "process_id": process_id,
+            "force": force,
+            "outcome": outcome,
+        })
+        .to_string(),
+    );
+
+    let report = result?;
+    Ok(json!(report).to_string())
+}
+
+/*
+  Stop or resume taking messages for a process on this
+  su. The scheduler lock is held while the flag is set
+  so a message already being scheduled is saved first.
+  The flag is only held in this su's memory, a restart
+  or another su instance still takes messages.
+*/
+pub async fn freeze_process(
+    deps: Arc<Deps>,
+    process_id: String,
+    frozen: bool,
+    operator: String,
+) -> Result<String, FlowError> {
+    let locked_schedule_info = deps.scheduler.acquire_lock(process_id.clone()).await?;
+    let schedule_info = locked_schedule_info.lock().await;
+    match frozen {
+        true => deps.data_store.freeze_process(&process_id),
+        false => deps.data_store.unfreeze_process(&process_id),
+    }
+    drop(schedule_info);
+
+    let action = match frozen {
+        true => "freeze_process",
+        false => "unfreeze_process",
+    };
+    audit_admin(
+        &deps,
+        action,
+        &operator,
+        json!({ "process_id": process_id }),
+    );
+
+    Ok(json!({ "process_id": process_id, "frozen": frozen }).to_string())
+}
+
+/*
+  The process and its deep hash state for another su
+  to import. The process has to be frozen first so no
+  message is scheduled here after the export.
+*/
+pub async fn export_process_handover(
+    deps: Arc<Deps>,
+    process_id: String,
+    operator: String,
+) -> Result<String, FlowError> {
+    let result = process_handover(&deps, &process_id).await;
+    let outcome = match &result {
+        Ok(_) => json!("exported"),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    audit_admin(
+        &deps,
+        "export_process_handover",
+        &operator,
+        json!({ "process_id": process_id, "outcome": outcome }),
+    );
+
+    serde_json::to_string(&result?).map_err(|e| e.to_string().into())
+}
+
+async fn process_handover(deps: &Deps, process_id: &str) -> Result<ProcessHandover, FlowError> {
+    if !deps.data_store.is_process_frozen(process_id) {
+        return Err(StoreErrorType::Conflict(format!(
+            "Process {} must be frozen before it is handed over",
+            process_id
+        ))
+        .into());
+    }
+
+    let process_id = process_id.to_string();
+    let process_bundle = deps.data_store.get_process_bundle(&process_id).await?;
+    let deep_hash_version = deps.data_store.get_deephash_version(&process_id).await.ok();
+    let deep_hashes = deps.data_store.get_deephashes(&process_id).await?;
+
+    Ok(ProcessHandover {
+        process_id,
+        process_bundle: base64_url::encode(&process_bundle),
+        deep_hash_version,
+        deep_hashes,
+    })
+}
+
+/*
+  Save a process exported by export_process_handover on
+  another su along with its deep hashes, so data items
+  the other su already scheduled are rejected here.
+*/
+pub async fn import_process_handover(
+    deps: Arc<Deps>,
+    input: Vec<u8>,
+    operator: String,
+) -> Result<String, FlowError> {
+    let handover: ProcessHandover = serde_json::from_slice(&input)
+        .map_err(|e| StoreErrorType::invalid_input("body", e.to_string()))?;
+    let result = save_process_handover(&deps, &handover).await;
+    let outcome = match &result {
+        Ok(_) => json!("imported"),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    audit_admin(
+        &deps,
+        "import_process_handover",
+        &operator,
+        json!({
+            "process_id": handover.process_id,
+            "deep_hashes": handover.deep_hashes.len(),
+            "outcome": outcome,
+        }),
+    );
+    result?;
+
+    Ok(json!({
+        "process_id": handover.process_id,
+        "deep_hashes": handover.deep_hashes.len(),
+    })
+    .to_string())
+}
+
+async fn save_process_handover(deps: &Deps, handover: &ProcessHandover) -> Result<(), FlowError> {
+    /*
+      Without the deep hashes this su would schedule data
+      items the other su already did, so refuse up front
+      rather than import the process alone.
+    */
+    if !deps.data_store.keeps_deep_hashes() {
+        return Err(StoreErrorType::Unavailable(
+            "Deep hashes can't be saved here, the bytestore isn't ready".to_string(),
+        )
+        .into());
+    }
+
+    let bundle = base64_url::decode(&handover.process_bundle)
+        .map_err(|e| StoreErrorType::invalid_input("process_bundle", e.to_string()))?;
+    let process = verify_process_bundle(&bundle, &handover.process_id)?;
+
+    deps.data_store.save_process(&process, &bundle).await?;
+    if let Some(version) = &handover.deep_hash_version {
+        deps.data_store
+            .save_deephash_version(&handover.process_id, version)
+            .await?;
+    }
+    for deep_hash in &handover.deep_hashes {
+        deps.data_store
+            .save_deephash(&handover.process_id, deep_hash)
+            .await?;
+    }
+    Ok(())
+}
+
+/*
+  Write an admin action to the audit log, fields are
+  added next to the action, operator and time.
+*/
+fn audit_admin(deps: &Deps, action: &str, operator: &str, fields: serde_json::Value) {
+    let mut entry = json!({
+        "action": action,
+        "operator": operator,
+        "time": system_time_u64().unwrap_or(0),
+    });
+    if let (Some(entry), Some(fields)) = (entry.as_object_mut(), fields.as_object()) {
+        entry.extend(fields.clone());
+    }
+    deps.logger.audit(entry.to_string());
+}
+
+/*
+  Run task in the background and return its admin
+  task id to poll. It is audited when it starts and
+  again with its outcome when it ends.
+*/
+fn start_admin_task<F>(
+    deps: Arc<Deps>,
+    action: &'static str,
+    operator: String,
+    fields: serde_json::Value,
+    task: F,
+) -> Result<String, FlowError>
+where
+    F: Future<Output = Result<serde_json::Value, String>> + Send + 'static,
+{
+    let started_at = system_time_u64().map_err(|e| format!("{:?}", e))?;
+    let task_id = deps.admin_tasks.start(action, started_at);
+    let mut started = fields.clone();
+    started["task_id"] = json!(task_id);
+    started["status"] = json!("started");
+    audit_admin(&deps, action, &operator, started);
+
+    tokio::spawn(async move {
+        let outcome = task.await;
+        let mut finished = fields;
+        finished["task_id"] = json!(task_id);
+        finished["outcome"] = match &outcome {
+            Ok(result) => json!({ "result": result }),
+            Err(e) => json!({ "error": e }),
+        };
+        deps.admin_tasks
+            .finish(task_id, outcome, system_time_u64().unwrap_or(0));
+        audit_admin(&deps, action, &operator, finished);
+    });
+
+    Ok(json!({ "task_id": task_id }).to_string())
+}
+
+pub fn admin_task(deps: Arc<Deps>, task_id: u64, operator: String) -> Result<String, FlowError> {
+    audit_admin(&deps, "admin_task", &operator, json!({ "task_id": task_id }));
+    match deps.admin_tasks.get(task_id) {
+        Some(task) => Ok(json!(task).to_string()),
+        None => Err(StoreErrorType::not_found("admin task", task_id).into()),
+    }
+}
+
+/*
+  Everything the store knows about itself, the
+  health check, caches, breakers and bytestore.
+*/
+pub async fn admin_stats(deps: Arc<Deps>, operator: String) -> Result<String, FlowError> {
+    audit_admin(&deps, "admin_stats", &operator, json!({}));
+
+    let response_json = json!({
+        "health": deps.data_store.health_check().await,
+        "caches": deps.data_store.cache_stats().await,
+        "db_breakers": deps.data_store.circuit_breakers(),
+        "messages_per_second": deps.data_store.message_throughput().await,
+        "bytestore": {
+            "counters": deps.data_store.bytestore_counters(),
+            "statistics": deps.data_store.bytestore_statistics().await.ok(),
+            "last_sync": deps.data_store.bytestore_last_sync(),
+        },
+    });
+    Ok(response_json.to_string())
+}
+
+pub async fn list_migrations(deps: Arc<Deps>, operator: String) -> Result<String, FlowError> {
+    audit_admin(&deps, "list_migrations", &operator, json!({}));
+
+    let migrations = deps.data_store.applied_migrations().await?;
+    Ok(json!({ "migrations": migrations }).to_string())
+}
+
+/*
+  Recount every scheduler's process_count on demand,
+  only a router keeps scheduler rows.
+*/
+pub async fn recount_process_counts(deps: Arc<Deps>, operator: String) -> Result<String, FlowError> {
+    if deps.config.mode() != "router" {
+        return Err(StoreErrorType::invalid_input(
+            "mode",
+            "process counts are only kept in router mode",
+        )
+        .into());
+    }
+
+    let result = super::router::recount_process_counts(deps.clone()).await;
+    let outcome = match &result {
+        Ok(corrections) => json!({ "corrections": corrections.len() }),
+        Err(e) => json!({ "error": e }),
+    };
+    audit_admin(&deps, "recount_process_counts", &operator, outcome);
+
+    Ok(json!({ "corrections": result? }).to_string())
+}
+
+pub async fn invalidate_process_cache(
+    deps: Arc<Deps>,
+    process_id: String,
+    operator: String,
+) -> Result<String, FlowError> {
+    let result = deps.data_store.invalidate_process_cache(&process_id).await;
+    let outcome = match &result {
+        Ok(_) => json!("invalidated"),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    audit_admin(
+        &deps,
+        "invalidate_process_cache",
+        &operator,
+        json!({ "process_id": process_id, "outcome": outcome }),
+    );
+
+    result?;
+    Ok(json!({ "process_id": process_id, "invalidated": true }).to_string())
+}
+
+pub async fn resize_process_cache(
+    deps: Arc<Deps>,
+    size: usize,
+    operator: String,
+) -> Result<String, FlowError> {
+    let result = deps.data_store.resize_process_cache(size).await;
+    let outcome = match &result {
+        Ok(_) => json!("resized"),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    audit_admin(
+        &deps,
+        "resize_process_cache",
+        &operator,
+        json!({ "size": size, "outcome": outcome }),
+    );
+
+    result?;
+    Ok(json!({ "size": size }).to_string())
+}
+
+/*
+  Sync every message after row id after into the
+  bytestore, as a background admin task.
+*/
+pub fn start_bytestore_sync(
+    deps: Arc<Deps>,
+    after: i32,
+    operator: String,
+) -> Result<String, FlowError> {
+    let data_store = deps.data_store.clone();
+    start_admin_task(
+        deps,
+        "sync_bytestore",
+        operator,
+        json!({ "after": after }),
+        async move {
+            let run = tokio::task::spawn_blocking(move || data_store.sync_bytestore_after(after))
+                .await
+                .map_err(|e| format!("{:?}", e))??;
+            Ok(json!(run))
+        },
+    )
+}
+
+/*
+  Check a process's bundles against their checksums
+  and the bytestore, as a background admin task.
+*/
+pub fn start_integrity_check(
+    deps: Arc<Deps>,
+    process_id: String,
+    operator: String,
+) -> Result<String, FlowError> {
+    let data_store = deps.data_store.clone();
+    let fields = json!({ "process_id": process_id });
+    start_admin_task(
+        deps,
+        "check_process_integrity",
+        operator,
+        fields,
+        async move {
+            let report = data_store.check_process_integrity(&process_id).await?;
+            Ok(json!(report))
+        },
+    )
+}
+
+/*
+  Log the bytestore counters and warn when too many
+  reads are missing the bytestore and falling back
+  to postgres. Polled in the background when
+  USE_DISK is on.
+*/
+pub fn bytestore_stats(deps: Arc<Deps>) {
+    let counters = match deps.data_store.bytestore_counters() {
+        Some(c) => c,
+        None => return,
+    };
+
+    deps.logger.log(format!(
+        "bytestore stats - reads: {}, hits: {}, misses: {}, hit rate: {:.4}, writes: {}, write failures: {}, deletes: {}",
+        counters.reads_attempted,
+        counters.reads_hit,
+        counters.reads_missed,
+        counters.hit_rate(),
+        counters.writes,
+        counters.write_failures,
+        counters.deletes
+    ));
+
+    let miss_rate_warning = deps.config.bytestore_miss_rate_warning();
+    if counters.miss_rate() > miss_rate_warning {
+        deps.logger.error(format!(
+            "bytestore miss rate {:.4} is above {}, reads are falling back to postgres",
+            counters.miss_rate(),
+            miss_rate_warning
+        ));
+    }
+}
+
+/*
+  Count the messages saved in the last minute for
+  every process scheduled in that window and hand
+  them to the metrics. Polled in the background
+  when metrics are enabled.
+*/
+pub async fn process_throughput(deps: Arc<Deps>) -> Result<(), String> {
+    let since = system_time_u64().map_err(|e| format!("{:?}", e))? as i64 - 60_000;
+
+    let mut counts = vec![];
+    for process_id in deps.scheduler.active_processes(since) {
+        let count = deps
+            .data_store
+            .count_messages_since(&process_id, since)
+            .await?;
+        counts.push((process_id, count));
+    }
+
+    deps.metrics.process_throughput_observe(counts);
+    Ok(())
+}
+
 pub async fn msg_deephash(
     gateway: Arc<dyn Gateway>,
     message: &Message,
@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use super::bytes::{ByteErrorType, DataBundle, DataItem};
+use super::dal::FlowError;
 use super::tags::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -15,6 +16,12 @@ impl From<JsonErrorType> for String {
     }
 }
 
+impl From<JsonErrorType> for FlowError {
+    fn from(error: JsonErrorType) -> Self {
+        FlowError::from(String::from(error))
+    }
+}
+
 impl From<base64_url::base64::DecodeError> for JsonErrorType {
     fn from(error: base64_url::base64::DecodeError) -> Self {
         JsonErrorType::JsonError(format!("Json error: {:?}", error))
@@ -105,11 +112,46 @@ pub struct Message {
 pub struct PaginatedMessages {
     pub page_info: PageInfo,
     pub edges: Vec<Edge>,
+    // only set when the request asked for a timing breakdown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<ReadTiming>,
+}
+
+/*
+  Where the time of a get_messages went, in
+  milliseconds. query_ms includes waiting for a
+  connection. The fallback is the single postgres
+  query for rows the bytestore didn't have, and
+  deserialize is building the messages once the bytes
+  are in hand. Without a bytestore there is no
+  read_binaries or fallback.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReadTiming {
+    pub query_ms: f64,
+    pub read_binaries_ms: f64,
+    pub fallback_queries: usize,
+    pub fallback_ms: f64,
+    pub deserialize_ms: f64,
+    pub total_ms: f64,
+    pub bytestore_hits: usize,
+    pub bytestore_misses: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PageInfo {
     pub has_next_page: bool,
+    /*
+      The cursor of the last edge, named after the
+      parameter it goes back in as. Only the one for
+      the page's sequence mode is set. They are meant
+      for the default exclusive from, with inclusive
+      they would repeat the last edge.
+    */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_from_nonce: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -395,6 +437,57 @@ impl Process {
         }
     }
 
+    /*
+      Older processes were spawned without a Module
+      tag so this is None for them, not an error.
+    */
+    pub fn module_id(&self) -> Option<String> {
+        self.process
+            .tags
+            .iter()
+            .find(|tag| tag.name == "Module")
+            .map(|tag| tag.value.clone())
+    }
+
+    /*
+      Check a process has what save_process reads out of
+      it, so a malformed one is rejected up front rather
+      than saved with empty columns. The assignment tags
+      are only required when there is an assignment, the
+      su attaches one when process assignment is enabled.
+    */
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+
+        if self.process.process_id.trim().is_empty() {
+            errors.push("process_id is empty".to_string());
+        }
+
+        if let Err(e) = serde_json::to_value(self).and_then(serde_json::from_value::<Process>) {
+            errors.push(format!("process data does not round trip: {}", e));
+        }
+
+        if self.assignment.is_some() {
+            if let Err(e) = self.epoch() {
+                errors.push(format!("epoch: {:?}", e));
+            }
+            if let Err(e) = self.nonce() {
+                errors.push(format!("nonce: {:?}", e));
+            }
+            if let Err(e) = self.hash_chain() {
+                errors.push(format!("hash_chain: {:?}", e));
+            }
+            if let Err(e) = self.timestamp() {
+                errors.push(format!("timestamp: {:?}", e));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
     pub fn from_val(value: &serde_json::Value) -> Result<Self, JsonErrorType> {
         match value.get("assignment") {
             Some(_) => {
@@ -837,8 +930,6 @@ impl PaginatedMessages {
         has_next_page: bool,
         sequence_mode: &'static str,
     ) -> Result<Self, JsonErrorType> {
-        let page_info = PageInfo { has_next_page };
-
         let edges = messages
             .into_iter()
             .try_fold(Vec::new(), |mut acc, message| {
@@ -866,7 +957,31 @@ impl PaginatedMessages {
                 Ok(acc)
             })?;
 
-        Ok(PaginatedMessages { page_info, edges })
+        /*
+          The process counts as the last edge when it is
+          the only one, its cursor is the start of the
+          process so the next page begins after it. Pages
+          are ascending only, a descending sort would
+          need this to come from the first edge and go
+          back in as a to instead.
+        */
+        let last_cursor = edges.last().map(|e| e.cursor.clone());
+        let (next_from, next_from_nonce) = match sequence_mode {
+            "nonce" => (None, last_cursor),
+            _ => (last_cursor, None),
+        };
+
+        let page_info = PageInfo {
+            has_next_page,
+            next_from,
+            next_from_nonce,
+        };
+
+        Ok(PaginatedMessages {
+            page_info,
+            edges,
+            timing: None,
+        })
     }
 }
 
@@ -931,4 +1046,112 @@ mod tests {
             "boxXWZqkBaZmOKJ3Vh7PZzC07Q9OXmxF4QT_ikodfNY".to_string()
         );
     }
+
+    #[test]
+    fn test_next_page_cursor() {
+        let message_bytes =
+            base64_url::decode(&ITEM_STR.to_string()).expect("failed to encode data item");
+        let process_bytes =
+            base64_url::decode(&PROCESS_ITEM_STR.to_string()).expect("failed to encode data item");
+        let assignment_bytes = base64_url::decode(&ASSIGNMENT_ITEM_STR.to_string())
+            .expect("failed to encode data item");
+        let assignment = DataItem::from_bytes(assignment_bytes).expect("failed to build data item");
+
+        let mut data_bundle = DataBundle::new();
+        data_bundle.add_item(assignment.clone());
+        data_bundle
+            .add_item(DataItem::from_bytes(message_bytes).expect("failed to build data item"));
+        let message = Message::from_bundle(&data_bundle).expect("failed to create message");
+
+        let mut data_bundle = DataBundle::new();
+        data_bundle.add_item(assignment);
+        data_bundle
+            .add_item(DataItem::from_bytes(process_bytes).expect("failed to build data item"));
+        let process = Process::from_bundle(&data_bundle).expect("failed to create process");
+        let process_message = Message::from_process(process).expect("failed to create message");
+
+        let page =
+            PaginatedMessages::from_messages(vec![message.clone()], true, "timestamp").unwrap();
+        assert_eq!(
+            page.page_info.next_from,
+            Some(message.timestamp().unwrap().to_string())
+        );
+        assert_eq!(page.page_info.next_from_nonce, None);
+
+        let page = PaginatedMessages::from_messages(vec![message.clone()], true, "nonce").unwrap();
+        assert_eq!(page.page_info.next_from, None);
+        assert_eq!(
+            page.page_info.next_from_nonce,
+            Some(message.nonce().unwrap().to_string())
+        );
+
+        // a first page holding only the process still advances past it
+        let page =
+            PaginatedMessages::from_messages(vec![process_message.clone()], true, "nonce").unwrap();
+        assert_eq!(
+            page.page_info.next_from_nonce,
+            Some(process_message.nonce().unwrap().to_string())
+        );
+
+        let page = PaginatedMessages::from_messages(vec![], false, "timestamp").unwrap();
+        assert_eq!(page.page_info.next_from, None);
+        let serialized = serde_json::to_value(&page).unwrap();
+        assert_eq!(
+            serialized["page_info"],
+            serde_json::json!({ "has_next_page": false })
+        );
+        // timing is left out unless it was asked for
+        assert!(serialized.get("timing").is_none());
+    }
+
+    #[test]
+    fn test_process_module_id() {
+        let item_bytes =
+            base64_url::decode(&PROCESS_ITEM_STR.to_string()).expect("failed to encode data item");
+        let assignment_item_bytes = base64_url::decode(&ASSIGNMENT_ITEM_STR.to_string())
+            .expect("failed to encode data item");
+        let mut data_bundle = DataBundle::new();
+        data_bundle.add_item(
+            DataItem::from_bytes(assignment_item_bytes).expect("failed to build data item"),
+        );
+        data_bundle.add_item(DataItem::from_bytes(item_bytes).expect("failed to build data item"));
+        let mut process = Process::from_bundle(&data_bundle).expect("failed to create process");
+
+        // this process predates the Module tag
+        assert_eq!(process.module_id(), None);
+
+        process
+            .process
+            .tags
+            .push(Tag::new("Module", "module-tx-id"));
+        assert_eq!(process.module_id(), Some("module-tx-id".to_string()));
+    }
+
+    #[test]
+    fn test_process_validate() {
+        let item_bytes =
+            base64_url::decode(&PROCESS_ITEM_STR.to_string()).expect("failed to encode data item");
+        let assignment_item_bytes = base64_url::decode(&ASSIGNMENT_ITEM_STR.to_string())
+            .expect("failed to encode data item");
+        let mut data_bundle = DataBundle::new();
+        data_bundle.add_item(
+            DataItem::from_bytes(assignment_item_bytes).expect("failed to build data item"),
+        );
+        data_bundle.add_item(DataItem::from_bytes(item_bytes).expect("failed to build data item"));
+        let mut process = Process::from_bundle(&data_bundle).expect("failed to create process");
+        assert_eq!(process.validate(), Ok(()));
+
+        process.process.process_id = "".to_string();
+        if let Some(assignment) = process.assignment.as_mut() {
+            assignment.tags.retain(|tag| tag.name != "Nonce");
+        }
+        let errors = process.validate().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("process_id"));
+        assert!(errors[1].starts_with("nonce"));
+
+        process.process.process_id = "boxXWZqkBaZmOKJ3Vh7PZzC07Q9OXmxF4QT_ikodfNY".to_string();
+        process.assignment = None;
+        assert_eq!(process.validate(), Ok(()));
+    }
 }
@@ -21,3 +21,13 @@ pub mod flows;
 
 // router logic
 pub mod router;
+
+// background admin tasks
+pub mod admin;
+
+// checking bundles against the ids they are saved under
+pub mod verify;
+
+// contract tests shared by the DataStore impls
+#[cfg(test)]
+pub mod store_suite;
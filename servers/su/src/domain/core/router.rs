@@ -1,10 +1,10 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{fmt::Debug, sync::Arc};
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
 use tokio::{fs::File, io::AsyncReadExt};
 
 use super::builder::Builder;
-use crate::domain::core::dal::StoreErrorType;
+use crate::domain::core::dal::{FlowError, StoreErrorType};
 use crate::domain::flows::Deps;
 
 /*
@@ -16,7 +16,7 @@ use crate::domain::flows::Deps;
     a file. It is a basic load balancer implementation
 */
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Scheduler {
     pub row_id: Option<i32>,
     pub url: String,
@@ -24,14 +24,160 @@ pub struct Scheduler {
     pub no_route: Option<bool>,
     pub wallets_to_route: Option<String>,
     pub wallets_only: Option<bool>,
+    /*
+      The version the scheduler was read at, when set
+      update_scheduler only applies if the row is still
+      at this version.
+    */
+    pub version: Option<i32>,
+    /*
+      Failed attempts in a row to reach the scheduler,
+      over SCHEDULER_MAX_FAILURES it gets no new spawns.
+    */
+    pub consecutive_failures: i32,
+}
+
+impl Scheduler {
+    /*
+        wallets_to_route is stored as a comma separated
+        list of arweave addresses, parse it and make sure
+        every entry is a 43 character base64url address.
+        An empty value means no wallets are routed here.
+    */
+    pub fn wallets_to_route_list(&self) -> Result<Vec<String>, StoreErrorType> {
+        let raw = match &self.wallets_to_route {
+            Some(w) if !w.trim().is_empty() => w,
+            _ => return Ok(vec![]),
+        };
+
+        raw.split(',')
+            .map(|s| s.trim())
+            .map(|wallet| {
+                if is_valid_address(wallet) {
+                    Ok(wallet.to_string())
+                } else {
+                    Err(StoreErrorType::invalid_input(
+                        "wallets_to_route",
+                        format!(
+                            "Invalid wallet address '{}' for scheduler {}",
+                            wallet, self.url
+                        ),
+                    ))
+                }
+            })
+            .collect()
+    }
 }
 
+fn is_valid_address(address: &str) -> bool {
+    address.len() == 43
+        && address
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[derive(Clone)]
 pub struct ProcessScheduler {
     pub row_id: Option<i32>,
     pub process_id: String,
     pub scheduler_row_id: i32,
 }
 
+#[derive(Debug, Serialize)]
+pub struct ProcessCountCorrection {
+    pub scheduler_row_id: i32,
+    pub url: String,
+    pub previous_count: i32,
+    pub corrected_count: i32,
+}
+
+/*
+  The schedulers and process assignments of a router,
+  for standing up a new router or restoring one.
+  Assignments point at their scheduler by url since
+  row ids differ between databases. process_count
+  isn't carried over, it is recounted on import.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouterState {
+    pub schedulers: Vec<RouterStateScheduler>,
+    pub assignments: Vec<RouterStateAssignment>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouterStateScheduler {
+    pub url: String,
+    pub no_route: Option<bool>,
+    pub wallets_to_route: Option<String>,
+    pub wallets_only: Option<bool>,
+}
+
+impl From<&RouterStateScheduler> for Scheduler {
+    fn from(scheduler: &RouterStateScheduler) -> Self {
+        Scheduler {
+            row_id: None,
+            url: scheduler.url.clone(),
+            process_count: 0,
+            no_route: scheduler.no_route,
+            wallets_to_route: scheduler.wallets_to_route.clone(),
+            wallets_only: scheduler.wallets_only,
+            version: None,
+            consecutive_failures: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouterStateAssignment {
+    pub process_id: String,
+    pub scheduler_url: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RouterImportReport {
+    pub schedulers: usize,
+    pub assignments: usize,
+    /*
+      Assignments left out because their scheduler
+      doesn't exist, only when skipping violations.
+    */
+    pub skipped: Vec<RouterStateAssignment>,
+}
+
+/*
+  Pair each assignment with the row id of its scheduler
+  by url. The ones whose scheduler isn't in scheduler_ids
+  are returned separately as violations. A process
+  listed more than once keeps its last scheduler, a
+  single upsert can't touch the same row twice.
+*/
+pub fn resolve_assignments<'a>(
+    assignments: &'a [RouterStateAssignment],
+    scheduler_ids: &HashMap<String, i32>,
+) -> (Vec<(&'a str, i32)>, Vec<RouterStateAssignment>) {
+    let mut resolved: Vec<(&str, i32)> = Vec::with_capacity(assignments.len());
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    let mut violations = vec![];
+    for assignment in assignments {
+        let id = match scheduler_ids.get(&assignment.scheduler_url) {
+            Some(id) => *id,
+            None => {
+                violations.push(assignment.clone());
+                continue;
+            }
+        };
+        let process_id = assignment.process_id.as_str();
+        match positions.get(process_id) {
+            Some(&i) => resolved[i].1 = id,
+            None => {
+                positions.insert(process_id, resolved.len());
+                resolved.push((process_id, id));
+            }
+        }
+    }
+    (resolved, violations)
+}
+
 #[derive(Deserialize, Debug)]
 struct SchedulerEntry {
     url: String,
@@ -40,6 +186,54 @@ struct SchedulerEntry {
     wallets_only: Option<bool>,
 }
 
+/*
+    Pick the scheduler for a new process. Schedulers
+    flagged no_route are never picked. If any scheduler
+    routes the owner's wallet only those are candidates,
+    otherwise every one that isn't wallets_only is. Of
+    the candidates, the ones that have failed more than
+    max_failures times in a row or are at capacity are
+    dropped and the least loaded of the rest wins.
+*/
+pub fn select_scheduler(
+    schedulers: Vec<Scheduler>,
+    owner_wallet: &str,
+    max_failures: i32,
+    capacity: Option<i32>,
+) -> Result<Scheduler, StoreErrorType> {
+    let mut routed = vec![];
+    let mut general = vec![];
+    for scheduler in schedulers {
+        if scheduler.no_route.unwrap_or(false) {
+            continue;
+        }
+        if scheduler
+            .wallets_to_route_list()?
+            .iter()
+            .any(|w| w == owner_wallet)
+        {
+            routed.push(scheduler);
+        } else if !scheduler.wallets_only.unwrap_or(false) {
+            general.push(scheduler);
+        }
+    }
+
+    let candidates = if routed.is_empty() { general } else { routed };
+    let candidate_count = candidates.len();
+
+    candidates
+        .into_iter()
+        .filter(|s| s.consecutive_failures <= max_failures)
+        .filter(|s| capacity.map_or(true, |c| s.process_count < c))
+        .min_by_key(|s| s.process_count)
+        .ok_or_else(|| {
+            StoreErrorType::Unavailable(format!(
+                "No scheduler can take a new process, {} candidates are failing or full",
+                candidate_count
+            ))
+        })
+}
+
 pub fn hash(data: &[u8]) -> Vec<u8> {
     let mut hasher = Sha256::new();
     hasher.update(data);
@@ -69,8 +263,10 @@ pub async fn init_schedulers(deps: Arc<Deps>) -> Result<String, String> {
         if the scheduler doesnt exist yet create it
     */
     for entry in urls {
-        if let Err(StoreErrorType::NotFound(_)) =
-            deps.router_data_store.get_scheduler_by_url(&entry.url)
+        if let Err(StoreErrorType::NotFound { .. }) = deps
+            .router_data_store
+            .get_scheduler_by_url(&entry.url)
+            .await
         {
             let scheduler = Scheduler {
                 row_id: None,
@@ -79,31 +275,75 @@ pub async fn init_schedulers(deps: Arc<Deps>) -> Result<String, String> {
                 no_route: entry.no_route,
                 wallets_to_route: entry.wallets_to_route.clone(),
                 wallets_only: entry.wallets_only,
+                version: None,
+                consecutive_failures: 0,
             };
-            deps.router_data_store.save_scheduler(&scheduler)?;
+            deps.router_data_store.save_scheduler(&scheduler).await?;
             deps.logger
                 .log(format!("saved new scheduler: {}", entry.url));
         }
 
         /*
           If we no longer what to route any process to this su
-          we can set no_route to true.
+          we can set no_route to true. On a Conflict another
+          router instance updated the row in between, so
+          re-read it and try again.
         */
-        let mut sched = deps.router_data_store.get_scheduler_by_url(&entry.url)?;
-        sched.no_route = entry.no_route;
-        sched.wallets_to_route = entry.wallets_to_route.clone();
-        sched.wallets_only = entry.wallets_only;
-        deps.router_data_store.update_scheduler(&sched)?;
+        let mut attempts = 0;
+        loop {
+            let mut sched = deps
+                .router_data_store
+                .get_scheduler_by_url(&entry.url)
+                .await?;
+            sched.no_route = entry.no_route;
+            sched.wallets_to_route = entry.wallets_to_route.clone();
+            sched.wallets_only = entry.wallets_only;
+            match deps.router_data_store.update_scheduler(&sched).await {
+                Err(StoreErrorType::Conflict(_)) if attempts < 3 => attempts += 1,
+                Err(e) => return Err(e.into()),
+                Ok(_) => break,
+            }
+        }
     }
 
     Ok("schedulers initialized".to_string())
 }
 
+/*
+    Maintenance routine that recomputes each scheduler's
+    process_count from process_schedulers, logging every
+    correction it makes and a summary. Runs at startup in
+    router mode after init_schedulers when
+    RECOUNT_PROCESS_COUNTS_ON_STARTUP is on, and from
+    the admin route.
+*/
+pub async fn recount_process_counts(
+    deps: Arc<Deps>,
+) -> Result<Vec<ProcessCountCorrection>, String> {
+    let corrections = deps.router_data_store.recount_process_counts().await?;
+
+    for correction in corrections.iter() {
+        deps.logger.log(format!(
+            "corrected process_count for scheduler {} ({}): {} -> {}",
+            correction.url,
+            correction.scheduler_row_id,
+            correction.previous_count,
+            correction.corrected_count
+        ));
+    }
+    deps.logger.log(format!(
+        "process counts recounted, {} corrections made",
+        corrections.len()
+    ));
+
+    Ok(corrections)
+}
+
 // if this returns Ok(Some(String)) then the server should return a redirect to the String
 pub async fn redirect_process_id(
     deps: Arc<Deps>,
     process_id: Option<String>,
-) -> Result<Option<String>, String> {
+) -> Result<Option<String>, FlowError> {
     if deps.config.mode() != "router" {
         return Ok(None);
     }
@@ -111,10 +351,11 @@ pub async fn redirect_process_id(
     let pid = process_id.ok_or("No process-id query parameter provided")?;
 
     // every other process_id, redirect
-    let process_scheduler = deps.router_data_store.get_process_scheduler(&pid)?;
+    let process_scheduler = deps.router_data_store.get_process_scheduler(&pid).await?;
     let scheduler = deps
         .router_data_store
-        .get_scheduler(&process_scheduler.scheduler_row_id)?;
+        .get_scheduler(&process_scheduler.scheduler_row_id)
+        .await?;
     Ok(Some(scheduler.url))
 }
 
@@ -123,12 +364,12 @@ pub async fn redirect_tx_id(
     deps: Arc<Deps>,
     tx_id: String,
     process_id: Option<String>,
-) -> Result<Option<String>, String> {
+) -> Result<Option<String>, FlowError> {
     if deps.config.mode() != "router" {
         return Ok(None);
     }
 
-    let process_to_query = match deps.router_data_store.get_process_scheduler(&tx_id) {
+    let process_to_query = match deps.router_data_store.get_process_scheduler(&tx_id).await {
         Ok(_) => tx_id,
         /*
             we didn't find a process scheduler based on the tx_id
@@ -139,10 +380,12 @@ pub async fn redirect_tx_id(
 
     let process_scheduler = deps
         .router_data_store
-        .get_process_scheduler(&process_to_query)?;
+        .get_process_scheduler(&process_to_query)
+        .await?;
     let scheduler = deps
         .router_data_store
-        .get_scheduler(&process_scheduler.scheduler_row_id)?;
+        .get_scheduler(&process_scheduler.scheduler_row_id)
+        .await?;
     Ok(Some(scheduler.url))
 }
 
@@ -152,23 +395,28 @@ pub async fn redirect_data_item(
     input: Vec<u8>,
     process_id: Option<String>,
     assign: Option<String>,
-) -> Result<Option<String>, String> {
+) -> Result<Option<String>, FlowError> {
     if deps.config.mode() != "router" {
         return Ok(None);
     }
 
     // XOR, if we have one of these, we must have both.
     if process_id.is_some() ^ assign.is_some() {
-        return Err("If sending assign or process-id, you must send both.".to_string());
+        return Err("If sending assign or process-id, you must send both.".into());
     } else if let (Some(process_id), Some(_assign)) = (process_id, assign) {
-        match deps.router_data_store.get_process_scheduler(&process_id) {
+        match deps
+            .router_data_store
+            .get_process_scheduler(&process_id)
+            .await
+        {
             Ok(process_scheduler) => {
                 let scheduler = deps
                     .router_data_store
-                    .get_scheduler(&process_scheduler.scheduler_row_id)?;
+                    .get_scheduler(&process_scheduler.scheduler_row_id)
+                    .await?;
                 return Ok(Some(scheduler.url));
             }
-            Err(_) => return Err("Unable to locate scheduler for process-id".to_string()),
+            Err(_) => return Err("Unable to locate scheduler for process-id".into()),
         }
     }
 
@@ -184,7 +432,7 @@ pub async fn redirect_data_item(
 
     let owner_bytes = match base64_url::decode(&owner) {
         Ok(h) => h,
-        Err(_) => return Err("Failed to parse owner".to_string()),
+        Err(_) => return Err("Failed to parse owner".into()),
     };
     let address_hash = hash(&owner_bytes);
     let owner_address = base64_url::encode(&address_hash);
@@ -195,101 +443,225 @@ pub async fn redirect_data_item(
                 new process so we need to generate a
                 process_schedulers record and return the url
             */
-            let mut schedulers = deps
+            let scheduler = deps
                 .router_data_store
-                .get_all_schedulers()?
-                .into_iter()
-                .filter(|scheduler| scheduler.no_route.unwrap_or(false) == false)
-                .collect::<Vec<_>>();
+                .select_scheduler_for_spawn(&owner_address)
+                .await?;
 
-            /*
-                This logic is added for routing wallet addresses to
-                specific schedulers. It will find the first scheduler
-                with a wallet matching the owner and route the new spawn
-                there.
-            */
-            for scheduler in schedulers.iter_mut() {
-                match &scheduler.wallets_to_route {
-                    Some(w) => {
-                        let wallets: Vec<String> =
-                            w.split(',').map(|s| s.trim().to_string()).collect();
-
-                        for wallet in wallets {
-                            if owner_address == wallet {
-                                scheduler.process_count += 1;
-                                deps.router_data_store.update_scheduler(scheduler)?;
-
-                                let scheduler_row_id =
-                                    if let Some(m_scheduler_row_id) = scheduler.row_id {
-                                        m_scheduler_row_id
-                                    } else {
-                                        /*
-                                            this should be unreachable but return an error
-                                            just in case so the router doesn't crash
-                                        */
-                                        return Err("Missing id on scheduler".to_string());
-                                    };
-
-                                let process_scheduler = ProcessScheduler {
-                                    row_id: None,
-                                    scheduler_row_id,
-                                    process_id: id,
-                                };
-                                deps.router_data_store
-                                    .save_process_scheduler(&process_scheduler)?;
-
-                                return Ok(Some(scheduler.url.clone()));
-                            }
-                        }
-                    }
-                    None => {}
-                }
-            }
+            let scheduler_row_id = if let Some(scheduler_row_id) = scheduler.row_id {
+                scheduler_row_id
+            } else {
+                /*
+                    this should be unreachable but return an error
+                    just in case so the router doesn't crash
+                */
+                return Err("Missing id on scheduler".into());
+            };
 
-            schedulers.retain(|scheduler| scheduler.wallets_only.unwrap_or(false) == false);
+            deps.router_data_store
+                .increment_process_count(scheduler_row_id, 1)
+                .await?;
 
-            if let Some(min_scheduler) = schedulers.iter_mut().min_by_key(|s| s.process_count) {
-                min_scheduler.process_count += 1;
-                deps.router_data_store.update_scheduler(min_scheduler)?;
+            let process_scheduler = ProcessScheduler {
+                row_id: None,
+                scheduler_row_id,
+                process_id: id,
+            };
+            deps.router_data_store
+                .save_process_scheduler(&process_scheduler)
+                .await?;
 
-                let scheduler_row_id = if let Some(min_scheduler_row_id) = min_scheduler.row_id {
-                    min_scheduler_row_id
-                } else {
-                    /*
-                        this should be unreachable but return an error
-                        just in case so the router doesn't crash
-                    */
-                    return Err("Missing id on scheduler".to_string());
-                };
-
-                let process_scheduler = ProcessScheduler {
-                    row_id: None,
-                    scheduler_row_id,
-                    process_id: id,
-                };
-                deps.router_data_store
-                    .save_process_scheduler(&process_scheduler)?;
-
-                Ok(Some(min_scheduler.url.clone()))
-            } else {
-                Err("Could not find a scheduler to assign".to_string())
-            }
+            Ok(Some(scheduler.url))
         }
         "Message" => {
             /*
                 otherwise, fetch the correct scheduler based
                 on the messages's target
             */
-            match deps.router_data_store.get_process_scheduler(&target) {
+            match deps.router_data_store.get_process_scheduler(&target).await {
                 Ok(process_scheduler) => {
                     let scheduler = deps
                         .router_data_store
-                        .get_scheduler(&process_scheduler.scheduler_row_id)?;
+                        .get_scheduler(&process_scheduler.scheduler_row_id)
+                        .await?;
                     Ok(Some(scheduler.url))
                 }
-                Err(_) => Err("Unable to locate scheduler for message target".to_string()),
+                Err(_) => Err("Unable to locate scheduler for message target".into()),
+            }
+        }
+        _ => Err("Cannot redirect data item, invalid Type Tag".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS_A: &str = "vh-NTHVvlKZqRxc8LyyTNok65yQ55a_PJ1zWLb9G2JI";
+    const ADDRESS_B: &str = "fcoN_xJeisVsPXA-trzVAuIiqO3ydLQxM-L4XbrQKzY";
+
+    fn scheduler_with_wallets(wallets: Option<&str>) -> Scheduler {
+        Scheduler {
+            row_id: Some(1),
+            url: "https://su.example".to_string(),
+            process_count: 0,
+            no_route: None,
+            wallets_to_route: wallets.map(|w| w.to_string()),
+            wallets_only: None,
+            version: None,
+            consecutive_failures: 0,
+        }
+    }
+
+    #[test]
+    fn test_wallets_to_route_list() {
+        let scheduler =
+            scheduler_with_wallets(Some(&format!("{}, {}", ADDRESS_A, ADDRESS_B)));
+        assert_eq!(
+            scheduler.wallets_to_route_list().unwrap(),
+            vec![ADDRESS_A.to_string(), ADDRESS_B.to_string()]
+        );
+
+        assert!(scheduler_with_wallets(None)
+            .wallets_to_route_list()
+            .unwrap()
+            .is_empty());
+        assert!(scheduler_with_wallets(Some(""))
+            .wallets_to_route_list()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_wallets_to_route_list_invalid() {
+        let malformed = [
+            format!("[\"{}\"]", ADDRESS_A),
+            format!("{},", ADDRESS_A),
+            "not-a-wallet".to_string(),
+            format!("{}+", &ADDRESS_A[..42]),
+        ];
+
+        for wallets in malformed.iter() {
+            match scheduler_with_wallets(Some(wallets)).wallets_to_route_list() {
+                Err(StoreErrorType::InvalidInput { field, reason }) => {
+                    assert_eq!(field, "wallets_to_route");
+                    assert!(reason.contains("https://su.example"));
+                }
+                other => panic!("expected InvalidInput for {}, got {:?}", wallets, other),
             }
         }
-        _ => Err("Cannot redirect data item, invalid Type Tag".to_string()),
+    }
+
+    #[test]
+    fn test_resolve_assignments() {
+        let assignment = |process_id: &str, scheduler_url: &str| RouterStateAssignment {
+            process_id: process_id.to_string(),
+            scheduler_url: scheduler_url.to_string(),
+        };
+        let assignments = vec![
+            assignment("p1", "https://su1.example"),
+            assignment("p2", "https://missing.example"),
+            assignment("p3", "https://su2.example"),
+            assignment("p1", "https://su2.example"),
+        ];
+        let scheduler_ids = HashMap::from([
+            ("https://su1.example".to_string(), 1),
+            ("https://su2.example".to_string(), 2),
+        ]);
+
+        let (resolved, violations) = resolve_assignments(&assignments, &scheduler_ids);
+
+        assert_eq!(resolved, vec![("p1", 2), ("p3", 2)]);
+        assert_eq!(
+            violations,
+            vec![assignment("p2", "https://missing.example")]
+        );
+    }
+
+    fn candidate(row_id: i32, process_count: i32) -> Scheduler {
+        Scheduler {
+            row_id: Some(row_id),
+            url: format!("https://su{}.example", row_id),
+            process_count,
+            no_route: None,
+            wallets_to_route: None,
+            wallets_only: None,
+            version: Some(0),
+            consecutive_failures: 0,
+        }
+    }
+
+    fn selected_row_id(schedulers: Vec<Scheduler>, capacity: Option<i32>) -> Option<i32> {
+        select_scheduler(schedulers, ADDRESS_A, 3, capacity)
+            .ok()
+            .and_then(|s| s.row_id)
+    }
+
+    #[test]
+    fn test_select_scheduler_least_loaded() {
+        let schedulers = vec![candidate(1, 5), candidate(2, 2), candidate(3, 2)];
+        assert_eq!(selected_row_id(schedulers, None), Some(2));
+    }
+
+    #[test]
+    fn test_select_scheduler_skips_no_route() {
+        let mut idle = candidate(1, 0);
+        idle.no_route = Some(true);
+        assert_eq!(selected_row_id(vec![idle, candidate(2, 5)], None), Some(2));
+    }
+
+    #[test]
+    fn test_select_scheduler_routes_wallets() {
+        let mut dedicated = candidate(1, 10);
+        dedicated.wallets_to_route = Some(ADDRESS_A.to_string());
+        dedicated.wallets_only = Some(true);
+        let mut other_wallet = candidate(2, 0);
+        other_wallet.wallets_to_route = Some(ADDRESS_B.to_string());
+        other_wallet.wallets_only = Some(true);
+
+        // the owner's wallet is routed so the idle general scheduler is passed over
+        let schedulers = vec![dedicated, other_wallet, candidate(3, 0)];
+        assert_eq!(selected_row_id(schedulers, None), Some(1));
+
+        // wallets_only schedulers never take other wallets
+        let mut wallets_only = candidate(4, 0);
+        wallets_only.wallets_only = Some(true);
+        assert_eq!(
+            selected_row_id(vec![wallets_only, candidate(5, 9)], None),
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn test_select_scheduler_skips_failing() {
+        let mut failing = candidate(1, 0);
+        failing.consecutive_failures = 4;
+        let mut at_threshold = candidate(2, 3);
+        at_threshold.consecutive_failures = 3;
+        let schedulers = vec![failing, at_threshold, candidate(3, 5)];
+        assert_eq!(selected_row_id(schedulers, None), Some(2));
+    }
+
+    #[test]
+    fn test_select_scheduler_skips_full() {
+        let schedulers = vec![candidate(1, 10), candidate(2, 12)];
+        assert_eq!(selected_row_id(schedulers, Some(11)), Some(1));
+        let schedulers = vec![candidate(1, 10), candidate(2, 12)];
+        assert_eq!(selected_row_id(schedulers, Some(10)), None);
+    }
+
+    #[test]
+    fn test_select_scheduler_unavailable() {
+        let mut failing = candidate(1, 0);
+        failing.consecutive_failures = 10;
+        let mut dedicated = candidate(2, 0);
+        dedicated.wallets_to_route = Some(ADDRESS_A.to_string());
+        dedicated.consecutive_failures = 10;
+
+        for schedulers in [vec![], vec![failing], vec![dedicated, candidate(3, 0)]] {
+            let err = select_scheduler(schedulers, ADDRESS_A, 3, None).unwrap_err();
+            assert!(matches!(err, StoreErrorType::Unavailable(_)));
+            assert_eq!(err.http_status(), 503);
+        }
     }
 }
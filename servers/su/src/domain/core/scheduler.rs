@@ -56,6 +56,27 @@ impl ProcessScheduler {
         }
     }
 
+    /*
+        processes that were scheduled at or after
+        since_timestamp according to the in memory cache
+    */
+    pub fn active_processes(&self, since_timestamp: i64) -> Vec<String> {
+        self.cache
+            .iter()
+            .filter(|entry| entry.value().schedule_info.timestamp >= since_timestamp)
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /*
+        drop the cached schedule for a process so the
+        next increment reads it from the data store,
+        used after a process has been purged
+    */
+    pub fn forget(&self, id: &str) {
+        self.cache.remove(id);
+    }
+
     /*
         acquire the lock while also obtaining
         the info needed epoch, nonce etc.. to
@@ -151,7 +172,7 @@ impl ProcessScheduler {
 
             let latest_message = match self.deps.data_store.get_latest_message(&id).await {
                 Ok(m) => m,
-                Err(e) => return Err(format!("{:?}", e)),
+                Err(e) => return Err(e.into()),
             };
 
             self.deps.logger.log(format!(
@@ -177,7 +198,7 @@ impl ProcessScheduler {
                   There is no message yet so the Nonce will start
                   at the process
                 */
-                None => match self.deps.data_store.get_process(&id).await {
+                None => match self.deps.data_store.get_process_uncached(&id).await {
                     Ok(process) => {
                         self.deps
                             .logger
@@ -216,14 +237,14 @@ impl ProcessScheduler {
                       will start at 0
                     */
                     Err(e) => match e {
-                        StoreErrorType::NotFound(_) => {
+                        StoreErrorType::NotFound { .. } => {
                             let hash_chain = gen_hash_chain(&id, None)?;
                             self.deps
                                 .logger
                                 .log(format!("hash chain generated new process - {}", &id));
                             (0 as i32, 0 as i32, hash_chain)
                         }
-                        _ => return Err(format!("{:?}", e)),
+                        _ => return Err(e.into()),
                     },
                 },
             }
@@ -0,0 +1,397 @@
+use super::dal::{
+    DataStore, Message, MessageFilter, Process, ProcessScheduler, RouterDataStore, Scheduler,
+    StoreErrorType, Tag,
+};
+use super::json::{AssignmentInner, Owner, ProcessInner};
+
+/*
+  What every DataStore and RouterDataStore has to do,
+  run against the MockDataStore in the dal tests and
+  against a StoreClient on a scratch postgres by the
+  ignored test in the store tests. Every id is built
+  from prefix so a run doesn't collide with what an
+  earlier one left in the database, keep it short
+  since it is also padded out into a wallet address.
+*/
+
+pub fn owner() -> Owner {
+    Owner {
+        address: "owner-address".to_string(),
+        key: "owner-key".to_string(),
+    }
+}
+
+pub fn assignment(process_id: &str, nonce: i32, timestamp: i64) -> Message {
+    Message {
+        message: None,
+        assignment: AssignmentInner {
+            id: format!("{}-assignment-{}", process_id, nonce),
+            owner: owner(),
+            tags: vec![
+                Tag::new("Process", process_id),
+                Tag::new("Message", &format!("{}-message-{}", process_id, nonce)),
+                Tag::new("Epoch", "0"),
+                Tag::new("Nonce", &nonce.to_string()),
+                Tag::new("Timestamp", &timestamp.to_string()),
+                Tag::new("Hash-Chain", &format!("hash-{}", nonce)),
+            ],
+            signature: "signature".to_string(),
+            anchor: None,
+            target: None,
+        },
+    }
+}
+
+pub fn process(process_id: &str) -> Process {
+    Process {
+        process: ProcessInner {
+            process_id: process_id.to_string(),
+            block: "1".to_string(),
+            owner: owner(),
+            tags: vec![],
+            timestamp: 0,
+            data: None,
+            anchor: None,
+            signature: None,
+            target: None,
+        },
+        assignment: None,
+    }
+}
+
+/*
+  A process with its own assignment at nonce 0, which
+  get_messages returns as the first message.
+*/
+pub fn assigned_process(process_id: &str, timestamp: i64) -> Process {
+    Process {
+        assignment: Some(assignment(process_id, 0, timestamp).assignment),
+        ..process(process_id)
+    }
+}
+
+fn scheduler(url: &str, wallets_to_route: Option<String>) -> Scheduler {
+    Scheduler {
+        row_id: None,
+        url: url.to_string(),
+        process_count: 0,
+        no_route: Some(false),
+        wallets_only: Some(wallets_to_route.is_some()),
+        wallets_to_route,
+        version: None,
+        consecutive_failures: 0,
+    }
+}
+
+pub async fn data_store_suite(store: &dyn DataStore, prefix: &str) {
+    let p1 = format!("{}-p1", prefix);
+    let p2 = format!("{}-p2", prefix);
+    let p3 = format!("{}-p3", prefix);
+    let p4 = format!("{}-p4", prefix);
+    let p5 = format!("{}-p5", prefix);
+
+    store.save_process(&process(&p1), &[]).await.unwrap();
+    for (nonce, timestamp) in [(1, 100), (2, 200), (3, 300)] {
+        store
+            .save_message(&assignment(&p1, nonce, timestamp), &[], None)
+            .await
+            .unwrap();
+    }
+    store
+        .save_message(&assignment(&p2, 1, 150), &[], None)
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        store
+            .save_message(&assignment(&p1, 3, 300), &[], None)
+            .await,
+        Err(StoreErrorType::MessageExists(_))
+    ));
+
+    let process_1 = store.get_process(&p1).await.unwrap();
+    let page = store
+        .get_messages(
+            &process_1,
+            &MessageFilter {
+                limit: Some(2),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(page.page_info.has_next_page);
+    assert_eq!(page.edges.len(), 2);
+
+    let page = store
+        .get_messages(
+            &process_1,
+            &MessageFilter {
+                from_nonce: Some("1".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(!page.page_info.has_next_page);
+    assert_eq!(page.page_info.next_from_nonce, Some("3".to_string()));
+
+    let latest = store.get_latest_message(&p1).await.unwrap().unwrap();
+    assert_eq!(latest.nonce().unwrap(), 3);
+
+    // a tie on timestamp goes to the higher nonce, not the last saved
+    for nonce in [5, 4] {
+        store
+            .save_message(&assignment(&p4, nonce, 500), &[], None)
+            .await
+            .unwrap();
+    }
+    let latest = store.get_latest_message(&p4).await.unwrap().unwrap();
+    assert_eq!(latest.nonce().unwrap(), 5);
+    assert_eq!(store.count_messages_since(&p1, 100).await.unwrap(), 2);
+    assert_eq!(
+        store
+            .get_messages_count_in_timestamp_range(&p1, 100, 200)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        store.get_hash_chain_tail(&p1, 2).await.unwrap(),
+        vec!["hash-3".to_string(), "hash-2".to_string()]
+    );
+    let by_prefix = store
+        .get_messages_by_hash_chain_prefix(&p1, "hash-")
+        .await
+        .unwrap();
+    let nonces: Vec<i32> = by_prefix.iter().map(|m| m.nonce().unwrap()).collect();
+    assert_eq!(nonces, vec![1, 2, 3]);
+    // the _ is matched literally, not as a LIKE wildcard
+    assert!(store
+        .get_messages_by_hash_chain_prefix(&p1, "hash_")
+        .await
+        .unwrap()
+        .is_empty());
+    let in_range = store
+        .get_messages_for_epoch_nonce_range(&p1, 0, 2, 3)
+        .await
+        .unwrap();
+    let nonces: Vec<i32> = in_range.iter().map(|m| m.nonce().unwrap()).collect();
+    assert_eq!(nonces, vec![2, 3]);
+    assert!(store
+        .get_messages_for_epoch_nonce_range(&p1, 1, 1, 3)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(store.get_latest_message(&p3).await.unwrap().is_none());
+
+    // a message id looks up its oldest assignment, a later one is found by its own id
+    let message_id = format!("{}-message-1", p5);
+    store
+        .save_message(&assignment(&p5, 1, 100), &[], None)
+        .await
+        .unwrap();
+    let mut reassigned = assignment(&p5, 2, 200);
+    for tag in reassigned.assignment.tags.iter_mut() {
+        if tag.name == "Message" {
+            tag.value = message_id.clone();
+        }
+    }
+    store.save_message(&reassigned, &[], None).await.unwrap();
+    let found = store.get_message(&message_id).await.unwrap();
+    assert_eq!(found.nonce().unwrap(), 1);
+    let found = store
+        .get_message(&format!("{}-assignment-2", p5))
+        .await
+        .unwrap();
+    assert_eq!(found.nonce().unwrap(), 2);
+    assert_eq!(found.message_id().unwrap(), message_id);
+    assert!(matches!(
+        store.get_message(&format!("{}-missing", p5)).await,
+        Err(StoreErrorType::NotFound { .. })
+    ));
+
+    assert!(store.check_process_exists(&p1).await.unwrap());
+    assert!(!store.check_process_exists(&p2).await.unwrap());
+}
+
+/*
+  A process with an assignment comes back as the first
+  message of the first page and takes one of its slots,
+  later pages don't include it.
+*/
+pub async fn get_messages_suite(store: &dyn DataStore, prefix: &str) {
+    let p = format!("{}-spawned", prefix);
+    let spawned = assigned_process(&p, 50);
+    store.save_process(&spawned, &[]).await.unwrap();
+    for (nonce, timestamp) in [(1, 100), (2, 100), (3, 200)] {
+        store
+            .save_message(&assignment(&p, nonce, timestamp), &[], None)
+            .await
+            .unwrap();
+    }
+
+    let page = store
+        .get_messages(&spawned, &MessageFilter::default().limit(Some(2)))
+        .await
+        .unwrap();
+    assert!(page.page_info.has_next_page);
+    assert_eq!(page.edges.len(), 2);
+    assert_eq!(page.edges[0].node.nonce().unwrap(), 0);
+    assert_eq!(page.edges[1].node.nonce().unwrap(), 1);
+
+    // same timestamp, so nonce decides the order
+    let page = store
+        .get_messages(
+            &spawned,
+            &MessageFilter::default().from_nonce(Some("1".to_string())),
+        )
+        .await
+        .unwrap();
+    let nonces: Vec<i32> = page
+        .edges
+        .iter()
+        .map(|edge| edge.node.nonce().unwrap())
+        .collect();
+    assert_eq!(nonces, vec![2, 3]);
+    assert!(!page.page_info.has_next_page);
+
+    let page = store
+        .get_messages(
+            &spawned,
+            &MessageFilter::default()
+                .from_nonce(Some("0".to_string()))
+                .inclusive(true),
+        )
+        .await
+        .unwrap();
+    assert_eq!(page.edges.len(), 4);
+}
+
+pub async fn deep_hash_suite(store: &dyn DataStore, prefix: &str) {
+    let p = format!("{}-deephash", prefix);
+    let deep_hash = format!("{}-hash", prefix);
+    store
+        .save_message(&assignment(&p, 1, 100), &[], Some(&deep_hash))
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        store.check_existing_deep_hash(&p, &deep_hash).await,
+        Err(StoreErrorType::MessageExists(_))
+    ));
+    let other_hash = format!("{}-other", deep_hash);
+    store
+        .save_message(&assignment(&p, 2, 200), &[], Some(&other_hash))
+        .await
+        .unwrap();
+    // only the saved ones come back, in the order they were asked for
+    assert_eq!(
+        store
+            .check_existing_deep_hashes(
+                &p,
+                &[
+                    other_hash.clone(),
+                    "missing".to_string(),
+                    deep_hash.clone(),
+                    "also-missing".to_string(),
+                ],
+            )
+            .await
+            .unwrap(),
+        vec![other_hash.clone(), deep_hash.clone()]
+    );
+    assert!(store
+        .check_existing_deep_hashes(&p, &["missing".to_string()])
+        .await
+        .unwrap()
+        .is_empty());
+    let mut saved = store.get_deephashes(&p).await.unwrap();
+    saved.sort();
+    assert_eq!(saved, vec![deep_hash, other_hash]);
+}
+
+pub async fn router_data_store_suite<S: DataStore + RouterDataStore>(store: &S, prefix: &str) {
+    let wallet = format!("{:_<43}", prefix);
+    let general_url = format!("https://{}-general", prefix);
+    let routed_url = format!("https://{}-routed", prefix);
+    store
+        .save_scheduler(&scheduler(&general_url, None))
+        .await
+        .unwrap();
+    store
+        .save_scheduler(&scheduler(&routed_url, Some(wallet.clone())))
+        .await
+        .unwrap();
+    // saving the same url again leaves the first one
+    store
+        .save_scheduler(&scheduler(&routed_url, None))
+        .await
+        .unwrap();
+
+    let routed = store.get_scheduler_by_url(&routed_url).await.unwrap();
+    let row_id = routed.row_id.unwrap();
+    assert_eq!(routed.wallets_to_route.as_deref(), Some(wallet.as_str()));
+    assert_eq!(store.get_scheduler(&row_id).await.unwrap().url, routed_url);
+    assert!(matches!(
+        store
+            .get_scheduler_by_url(&format!("{}-missing", routed_url))
+            .await,
+        Err(StoreErrorType::NotFound { .. })
+    ));
+
+    let for_wallet = store.get_scheduler_for_wallet(&wallet).await.unwrap();
+    assert_eq!(for_wallet.unwrap().row_id, Some(row_id));
+    let spawn = store.select_scheduler_for_spawn(&wallet).await.unwrap();
+    assert_eq!(spawn.row_id, Some(row_id));
+
+    // an update from a stale read conflicts
+    let stale = routed.clone();
+    store
+        .update_scheduler(&Scheduler {
+            no_route: Some(false),
+            ..routed
+        })
+        .await
+        .unwrap();
+    assert!(matches!(
+        store.update_scheduler(&stale).await,
+        Err(StoreErrorType::Conflict(_))
+    ));
+    assert!(matches!(
+        store
+            .update_scheduler(&Scheduler {
+                row_id: None,
+                ..stale
+            })
+            .await,
+        Err(StoreErrorType::InvalidInput { .. })
+    ));
+
+    assert_eq!(store.increment_process_count(row_id, 2).await.unwrap(), 2);
+    assert_eq!(store.increment_process_count(row_id, -1).await.unwrap(), 1);
+
+    let p = format!("{}-routed-process", prefix);
+    store.save_process(&process(&p), &[]).await.unwrap();
+    store
+        .save_process_scheduler(&ProcessScheduler {
+            row_id: None,
+            process_id: p.clone(),
+            scheduler_row_id: row_id,
+        })
+        .await
+        .unwrap();
+    let process_scheduler = store.get_process_scheduler(&p).await.unwrap();
+    assert_eq!(process_scheduler.scheduler_row_id, row_id);
+    assert!(matches!(
+        store.get_process_scheduler(&format!("{}-missing", p)).await,
+        Err(StoreErrorType::NotFound { .. })
+    ));
+
+    let (processes, _) = store
+        .get_processes_by_scheduler(row_id, None, 10)
+        .await
+        .unwrap();
+    assert_eq!(processes.len(), 1);
+    assert_eq!(processes[0].process.process_id, p);
+}
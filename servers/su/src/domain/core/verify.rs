@@ -0,0 +1,103 @@
+use super::dal::{Message, Process, StoreErrorType};
+
+/*
+  Check that a bundle is the data items it is being
+  saved or was saved as. The ids are worked out again
+  from the signatures in the bundle, so bytes stored
+  under the wrong ids are caught here instead of when
+  a cu fails to verify them. Signatures themselves
+  are not checked, only the ids they hash to.
+*/
+pub fn verify_message_bundle(
+    bundle: &[u8],
+    message_id: &str,
+    assignment_id: Option<&str>,
+) -> Result<(), StoreErrorType> {
+    let parsed = Message::from_bytes(bundle.to_vec()).map_err(|e| {
+        StoreErrorType::VerificationError(format!(
+            "Bundle for message {} does not parse: {:?}",
+            message_id, e
+        ))
+    })?;
+    check_message_ids(&parsed, message_id, assignment_id)
+}
+
+// the same as verify_message_bundle, with the ids taken from the message
+pub fn verify_message(message: &Message, bundle: &[u8]) -> Result<(), StoreErrorType> {
+    verify_message_bundle(
+        bundle,
+        &message.message_id()?,
+        Some(&message.assignment_id()?),
+    )
+}
+
+/*
+  Returns the process parsed out of the bundle, so a
+  caller that only has the bytes doesn't parse twice.
+*/
+pub fn verify_process_bundle(bundle: &[u8], process_id: &str) -> Result<Process, StoreErrorType> {
+    let parsed = Process::from_bytes(bundle.to_vec()).map_err(|e| {
+        StoreErrorType::VerificationError(format!(
+            "Bundle for process {} does not parse: {:?}",
+            process_id, e
+        ))
+    })?;
+    check_id("process id", process_id, &parsed.process.process_id)?;
+    Ok(parsed)
+}
+
+fn check_message_ids(
+    parsed: &Message,
+    message_id: &str,
+    assignment_id: Option<&str>,
+) -> Result<(), StoreErrorType> {
+    check_id("message id", message_id, &parsed.message_id()?)?;
+    match assignment_id {
+        Some(assignment_id) => check_id("assignment id", assignment_id, &parsed.assignment_id()?),
+        None => Ok(()),
+    }
+}
+
+fn check_id(name: &str, claimed: &str, actual: &str) -> Result<(), StoreErrorType> {
+    match claimed == actual {
+        true => Ok(()),
+        false => Err(StoreErrorType::VerificationError(format!(
+            "The bundle has {} {} but was given {}",
+            name, actual, claimed
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::core::store_suite::assignment;
+
+    #[test]
+    fn test_check_message_ids() {
+        let parsed = assignment("process", 1, 100);
+
+        check_message_ids(&parsed, "process-message-1", Some("process-assignment-1")).unwrap();
+        check_message_ids(&parsed, "process-message-1", None).unwrap();
+        assert!(matches!(
+            check_message_ids(&parsed, "process-message-2", None),
+            Err(StoreErrorType::VerificationError(_))
+        ));
+        assert!(matches!(
+            check_message_ids(&parsed, "process-message-1", Some("process-assignment-2")),
+            Err(StoreErrorType::VerificationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_bundle_that_does_not_parse() {
+        assert!(matches!(
+            verify_message_bundle(&[1], "message", None),
+            Err(StoreErrorType::VerificationError(_))
+        ));
+        assert!(matches!(
+            verify_process_bundle(&[], "process"),
+            Err(StoreErrorType::VerificationError(_))
+        ));
+    }
+}
@@ -1,7 +1,8 @@
+use std::str::FromStr;
 use std::sync::{Arc, Once};
 
 use env_logger::Env;
-use log::{error, info};
+use log::{debug, error, info, LevelFilter};
 
 use crate::domain::Log;
 
@@ -11,8 +12,18 @@ static INIT: Once = Once::new();
 
 impl SuLog {
     pub fn init() -> Arc<dyn Log> {
+        SuLog::init_with_level("info")
+    }
+
+    /*
+      Only the first call sets the level, so the server
+      calls this with LOG_LEVEL before anything else
+      initializes the logger. RUST_LOG still overrides it.
+    */
+    pub fn init_with_level(level: &str) -> Arc<dyn Log> {
         INIT.call_once(|| {
-            env_logger::init_from_env(Env::default().default_filter_or("info"));
+            let level = LevelFilter::from_str(level).unwrap_or(LevelFilter::Info);
+            env_logger::init_from_env(Env::default().default_filter_or(level.to_string()));
         });
         Arc::new(SuLog {})
     }
@@ -26,4 +37,12 @@ impl Log for SuLog {
     fn error(&self, message: String) {
         error!("{}", message);
     }
+
+    fn debug(&self, message: String) {
+        debug!("{}", message);
+    }
+
+    fn audit(&self, message: String) {
+        info!(target: "audit", "{}", message);
+    }
 }
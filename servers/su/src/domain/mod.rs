@@ -2,6 +2,7 @@ use core::dal::RouterDataStore;
 use std::sync::Arc;
 
 use tokio::task::spawn_blocking;
+use tokio::time::{interval, Duration};
 
 use dashmap::DashMap;
 
@@ -11,32 +12,82 @@ mod core;
 mod logger;
 
 use clients::{
-    gateway::ArweaveGateway, local_store, signer::ArweaveSigner, store, uploader::UploaderClient,
+    gateway::ArweaveGateway, signer::ArweaveSigner, store, uploader::UploaderClient,
     wallet::FileWallet, su_router::SuRouter
 };
+#[cfg(feature = "disk-store")]
+use clients::local_store;
 use config::AoConfig;
 use core::dal::{Config, DataStore, Gateway, Log, MockRouterDataStore, ExtRouter};
 use logger::SuLog;
 
 pub use clients::metrics::PromMetrics;
+pub use core::dal::{ErrorCategory, FlowError};
 pub use core::flows;
 pub use core::router;
 pub use flows::Deps;
+#[cfg(feature = "disk-store")]
 pub use local_store::migration::migrate_to_local;
+#[cfg(feature = "disk-store")]
 pub use local_store::sync_local::sync_local_drives;
-pub use store::migrate_to_disk;
+pub use clients::sqlite::migrate_to_sqlite;
+pub use store::{
+    backfill_process_modules, checkpoint_bytestore, export_router_state,
+    find_duplicate_assignments, import_router_state, migrate_key_format, migrate_to_disk,
+    regenerate_deep_hashes, restore_bytestore,
+};
 
-pub async fn init_deps(mode: Option<String>) -> (Arc<Deps>, Arc<PromMetrics>) {
-    let logger: Arc<dyn Log> = SuLog::init();
+#[cfg(feature = "disk-store")]
+fn local_data_store(config: &AoConfig) -> Arc<dyn DataStore> {
+    Arc::new(
+        local_store::store::LocalStoreClient::new(&config.su_file_db_dir, &config.su_index_db_dir)
+            .expect("Failed to create LocalStoreClient"),
+    )
+}
+
+#[cfg(not(feature = "disk-store"))]
+fn local_data_store(_config: &AoConfig) -> Arc<dyn DataStore> {
+    panic!("USE_LOCAL_STORE needs the disk-store feature, this su was built without it");
+}
+
+fn warm_bytestore(data_store: &store::StoreClient, warmup: (i64, i64), logger: &Arc<dyn Log>) {
+    let (messages_per_process, process_count) = warmup;
+    if messages_per_process <= 0 {
+        return;
+    }
+    if let Err(e) = data_store.warm_bytestore(messages_per_process, process_count) {
+        logger.error(format!("Bytestore warmup failed: {:?}", e));
+    }
+}
 
+pub async fn init_deps(mode: Option<String>) -> (Arc<Deps>, Arc<PromMetrics>) {
     let config = Arc::new(AoConfig::new(mode.clone()).expect("Failed to read configuration"));
 
-    let data_store = if !config.use_local_store {
+    let logger: Arc<dyn Log> = SuLog::init_with_level(&config.log_level);
+
+    if let Some(config_file) = &config.config_file {
+        logger.log(format!("Read configuration from {}", config_file));
+    }
+    for warning in &config.config_warnings {
+        logger.error(warning.clone());
+    }
+    logger.log(format!("Configuration: {}", config.redacted()));
+
+    let data_store = if !config.use_local_store && config.read_only {
+        let ds = Arc::new(
+            store::StoreClient::new_read_only().expect("Failed to create read only StoreClient"),
+        );
+        ds.validate_pool_connections()
+            .expect("Failed to connect to the database");
+        Some(ds)
+    } else if !config.use_local_store {
         let ds = Arc::new(store::StoreClient::new().expect("Failed to create StoreClient"));
         match ds.run_migrations() {
             Ok(m) => logger.log(m),
             Err(e) => logger.log(format!("{:?}", e)),
         }
+        ds.validate_pool_connections()
+            .expect("Failed to connect to the database");
         Some(ds)
     } else {
         None
@@ -49,32 +100,56 @@ pub async fn init_deps(mode: Option<String>) -> (Arc<Deps>, Arc<PromMetrics>) {
     };
 
     let main_data_store: Arc<dyn DataStore> = if config.use_local_store {
-        Arc::new(
-            local_store::store::LocalStoreClient::new(
-                &config.su_file_db_dir,
-                &config.su_index_db_dir,
-            )
-            .expect("Failed to create LocalStoreClient"),
-        ) as Arc<dyn DataStore>
+        local_data_store(&config)
     } else {
         data_store.clone().unwrap().clone()
     };
 
-    if config.use_disk && config.mode != "router" {
+    /*
+      The warmup only runs once the bytestore has
+      connected, inside the same background task.
+    */
+    let warmup = (
+        config.bytestore_warmup_messages,
+        config.bytestore_warmup_processes,
+    );
+
+    if config.use_disk && config.mode != "router" && config.read_only {
         let logger_clone = logger.clone();
         let d_clone = data_store.clone().unwrap().clone();
-        /*
-          sync_bytestore is a blocking routine so we must
-          call spawn_blocking or the server wont start until
-          its complete and we want to do it in the background
-        */
         spawn_blocking(move || {
-            if let Err(e) = d_clone.sync_bytestore() {
-                logger_clone.log(format!("Failed to migrate tail messages: {:?}", e));
-            } else {
-                logger_clone.log("Successfully migrated tail messages".to_string());
-            }
+            d_clone.connect_bytestore_read_only();
+            warm_bytestore(&d_clone, warmup, &logger_clone);
         });
+    } else if config.use_disk && config.mode != "router" {
+        store::restore_bytestore_on_startup(&config, &logger)
+            .expect("Failed to restore the bytestore");
+
+        let logger_clone = logger.clone();
+        let sync_interval = match config.bytestore_sync_interval_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        /*
+          The sync runs in the background so the server
+          starts without waiting for it, and is stopped
+          by data_store.shutdown() when the server stops.
+        */
+        data_store
+            .clone()
+            .unwrap()
+            .spawn_bytestore_sync(sync_interval, move |d| {
+                warm_bytestore(d, warmup, &logger_clone)
+            });
+
+        if config.bytestore_disk_check_interval_secs > 0 {
+            data_store
+                .clone()
+                .unwrap()
+                .spawn_bytestore_disk_watchdog(Duration::from_secs(
+                    config.bytestore_disk_check_interval_secs,
+                ));
+        }
     }
 
     let scheduler_deps = Arc::new(core::scheduler::SchedulerDeps {
@@ -107,21 +182,75 @@ pub async fn init_deps(mode: Option<String>) -> (Arc<Deps>, Arc<PromMetrics>) {
 
     let ext_router: Arc<dyn ExtRouter>  = Arc::new(SuRouter{});
 
-    (
-        Arc::new(Deps {
-            data_store: main_data_store,
-            router_data_store,
-            logger,
-            config,
-            scheduler,
-            gateway,
-            signer,
-            wallet,
-            uploader,
-            metrics,
-            deephash_locks,
-            ext_router
-        }),
-        metrics_clone,
-    )
+    let enable_metrics = config.enable_metrics;
+    let enable_bytestore_stats = config.use_disk && config.mode != "router";
+    let bytestore_stats_interval = config.bytestore_stats_interval_minutes;
+    let compaction_interval = match config.rocksdb_compaction_interval_hours {
+        0 => None,
+        hours if enable_bytestore_stats && !config.read_only => Some(hours),
+        _ => None,
+    };
+    let compaction_window = (
+        config.rocksdb_compaction_window_start_hour,
+        config.rocksdb_compaction_window_end_hour,
+    );
+
+    let deps = Arc::new(Deps {
+        data_store: main_data_store,
+        router_data_store,
+        logger,
+        config,
+        scheduler,
+        gateway,
+        signer,
+        wallet,
+        uploader,
+        metrics,
+        deephash_locks,
+        ext_router,
+        admin_tasks: Arc::new(core::admin::AdminTasks::new()),
+    });
+
+    if enable_bytestore_stats {
+        let deps_clone = deps.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(bytestore_stats_interval * 60));
+            // the first tick completes immediately, skip it
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                flows::bytestore_stats(deps_clone.clone());
+            }
+        });
+    }
+
+    if let Some(hours) = compaction_interval {
+        let deps_clone = deps.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(hours * 3600));
+            // the first tick completes immediately, skip it
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                flows::scheduled_bytestore_compaction(deps_clone.clone(), compaction_window).await;
+            }
+        });
+    }
+
+    if enable_metrics {
+        let deps_clone = deps.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(60));
+            loop {
+                ticker.tick().await;
+                if let Err(e) = flows::process_throughput(deps_clone.clone()).await {
+                    deps_clone
+                        .logger
+                        .error(format!("Failed to poll process throughput: {}", e));
+                }
+            }
+        });
+    }
+
+    (deps, metrics_clone)
 }
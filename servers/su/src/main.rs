@@ -5,14 +5,20 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use actix_cors::Cors;
 use actix_web::{
-    http::header::LOCATION, middleware::Logger, web, App, HttpRequest, HttpResponse, HttpServer,
-    Responder,
+    http::{
+        header::{AUTHORIZATION, LOCATION, RETRY_AFTER},
+        StatusCode,
+    },
+    middleware::Logger,
+    web, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 
+use ring::constant_time::verify_slices_are_equal;
 use serde::Deserialize;
 use serde_json::json;
 
-use su::domain::{flows, init_deps, router, Deps, PromMetrics};
+use su::domain::config::CONFIG_FILE;
+use su::domain::{flows, init_deps, router, Deps, FlowError, PromMetrics};
 
 #[derive(Deserialize)]
 struct FromTo {
@@ -25,6 +31,11 @@ struct FromTo {
     from_nonce: Option<String>,
     #[serde(rename = "to-nonce")]
     to_nonce: Option<String>,
+    // only honored along with the admin token
+    #[serde(rename = "include-deleted")]
+    include_deleted: Option<bool>,
+    // makes from and from-nonce inclusive, they are exclusive by default
+    inclusive: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -43,6 +54,53 @@ struct ProcessIdRequired {
     process_id: String,
 }
 
+#[derive(Deserialize)]
+struct AfterLimit {
+    after: Option<i64>,
+    limit: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct ModuleId {
+    module_id: String,
+}
+
+#[derive(Deserialize)]
+struct CursorLimit {
+    cursor: Option<i32>,
+    limit: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct CompactPrefix {
+    prefix: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CheckpointPath {
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Force {
+    force: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct SyncAfter {
+    after: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct CacheSize {
+    size: usize,
+}
+
+#[derive(Deserialize)]
+struct TaskId {
+    task_id: u64,
+}
+
 #[derive(Deserialize)]
 struct OptionalAssign {
     #[serde(rename = "process-id")]
@@ -54,13 +112,101 @@ struct OptionalAssign {
     exclude: Option<String>,
 }
 
-fn err_response(err: String) -> HttpResponse {
-    let error_json = json!({ "error": err });
-    HttpResponse::BadRequest()
+/*
+  Store errors keep their category, which picks the
+  status code. Anything else is a 400. Writes over a
+  process's rate limit also get a Retry-After.
+*/
+fn err_response(err: FlowError) -> HttpResponse {
+    let status = StatusCode::from_u16(err.http_status()).unwrap_or(StatusCode::BAD_REQUEST);
+
+    let mut response = HttpResponse::build(status);
+    if let Some(retry_after) = err.retry_after() {
+        response.insert_header((RETRY_AFTER, retry_after.to_string()));
+    }
+
+    let error_json = json!({ "error": err.to_string() });
+    response
         .content_type("application/json")
         .body(error_json.to_string())
 }
 
+/*
+  The admin routes need the ADMIN_API_KEY as a bearer
+  token and are turned off when it isn't configured.
+  Returns the response to send back if the request
+  isn't allowed through.
+*/
+fn admin_unauthorized(data: &web::Data<AppState>, req: &HttpRequest) -> Option<HttpResponse> {
+    let admin_api_key = match data.deps.config.admin_api_key() {
+        Some(key) => key,
+        None => {
+            return Some(
+                HttpResponse::Forbidden()
+                    .content_type("application/json")
+                    .body(json!({ "error": "Admin routes are disabled" }).to_string()),
+            )
+        }
+    };
+
+    match has_admin_token(&admin_api_key, req) {
+        true => None,
+        false => Some(
+            HttpResponse::Unauthorized()
+                .content_type("application/json")
+                .body(json!({ "error": "Invalid admin credentials" }).to_string()),
+        ),
+    }
+}
+
+fn has_admin_token(admin_api_key: &str, req: &HttpRequest) -> bool {
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(token) => verify_slices_are_equal(token.as_bytes(), admin_api_key.as_bytes()).is_ok(),
+        None => false,
+    }
+}
+
+/*
+  Soft deleted messages are for operators, so the
+  public read route ignores include-deleted unless
+  the request also has the admin token.
+*/
+fn include_deleted_allowed(
+    query_params: &FromTo,
+    admin_api_key: Option<String>,
+    req: &HttpRequest,
+) -> bool {
+    match (query_params.include_deleted, admin_api_key) {
+        (Some(true), Some(key)) => has_admin_token(&key, req),
+        _ => false,
+    }
+}
+
+/*
+  Who made an admin request for the audit log, the
+  X-Operator header or else the remote address.
+*/
+fn admin_operator(req: &HttpRequest) -> String {
+    match req
+        .headers()
+        .get("X-Operator")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(operator) => operator.to_string(),
+        None => req
+            .connection_info()
+            .realip_remote_addr()
+            .unwrap_or("unknown")
+            .to_string(),
+    }
+}
+
 async fn base(
     data: web::Data<AppState>,
     query_params: web::Query<ProcessId>,
@@ -76,14 +222,14 @@ async fn base(
                 .finish();
         }
         Ok(None) => (),
-        Err(err) => return err_response(err.to_string()),
+        Err(err) => return err_response(err),
     }
 
     match flows::health(data.deps.clone()).await {
         Ok(processed_str) => HttpResponse::Ok()
             .content_type("application/json")
             .body(processed_str),
-        Err(err) => err_response(err.to_string()),
+        Err(err) => err_response(err),
     }
 }
 
@@ -102,14 +248,14 @@ async fn timestamp_route(
                 .finish();
         }
         Ok(None) => (),
-        Err(err) => return err_response(err.to_string()),
+        Err(err) => return err_response(err),
     }
 
     match flows::timestamp(data.deps.clone()).await {
         Ok(processed_str) => HttpResponse::Ok()
             .content_type("application/json")
             .body(processed_str),
-        Err(err) => err_response(err.to_string()),
+        Err(err) => err_response(err),
     }
 }
 
@@ -143,7 +289,7 @@ async fn main_post_route(
                 .finish();
         }
         Ok(None) => (),
-        Err(err) => return err_response(err.to_string()),
+        Err(err) => return err_response(err),
     }
 
     match flows::write_item(
@@ -159,7 +305,7 @@ async fn main_post_route(
         Ok(processed_str) => HttpResponse::Ok()
             .content_type("application/json")
             .body(processed_str),
-        Err(err) => err_response(err.to_string()),
+        Err(err) => err_response(err),
     }
 }
 
@@ -176,6 +322,13 @@ async fn main_get_route(
     let process_id = query_params.process_id.clone();
     let from_nonce = query_params.from_nonce.clone();
     let to_nonce = query_params.to_nonce.clone();
+    let include_deleted =
+        include_deleted_allowed(&query_params, data.deps.config.admin_api_key(), &req);
+    let inclusive = query_params.inclusive.unwrap_or(false);
+    let timing = req
+        .headers()
+        .get("X-SU-Timing")
+        .is_some_and(|value| value == "1");
 
     match router::redirect_tx_id(data.deps.clone(), tx_id.clone(), process_id.clone()).await {
         Ok(Some(redirect_url)) => {
@@ -185,7 +338,7 @@ async fn main_get_route(
                 .finish();
         }
         Ok(None) => (),
-        Err(err) => return err_response(err.to_string()),
+        Err(err) => return err_response(err),
     }
 
     let result = flows::read_message_data(
@@ -196,6 +349,9 @@ async fn main_get_route(
         limit,
         from_nonce,
         to_nonce,
+        include_deleted,
+        inclusive,
+        timing,
     )
     .await;
 
@@ -203,7 +359,7 @@ async fn main_get_route(
         Ok(processed_str) => HttpResponse::Ok()
             .content_type("application/json")
             .body(processed_str),
-        Err(err) => err_response(err.to_string()),
+        Err(err) => err_response(err),
     }
 }
 
@@ -222,7 +378,7 @@ async fn read_latest_route(
                 .finish();
         }
         Ok(None) => (),
-        Err(err) => return err_response(err.to_string()),
+        Err(err) => return err_response(err),
     }
 
     let result = flows::read_latest_message(data.deps.clone(), process_id).await;
@@ -231,7 +387,75 @@ async fn read_latest_route(
         Ok(processed_str) => HttpResponse::Ok()
             .content_type("application/json")
             .body(processed_str),
-        Err(err) => err_response(err.to_string()),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn read_events_route(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<ProcessIdRequired>,
+    query_params: web::Query<AfterLimit>,
+) -> impl Responder {
+    let process_id = path.process_id.clone();
+
+    match router::redirect_process_id(data.deps.clone(), Some(process_id.clone())).await {
+        Ok(Some(redirect_url)) => {
+            let target_url = format!("{}{}", redirect_url, req.uri());
+            return HttpResponse::TemporaryRedirect()
+                .insert_header((LOCATION, target_url))
+                .finish();
+        }
+        Ok(None) => (),
+        Err(err) => return err_response(err),
+    }
+
+    match flows::read_messages_after_rowid(
+        data.deps.clone(),
+        process_id,
+        query_params.after,
+        query_params.limit,
+    )
+    .await
+    {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn read_bundle_route(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<TxId>,
+) -> impl Responder {
+    let tx_id = path.tx_id.clone();
+
+    match router::redirect_tx_id(data.deps.clone(), tx_id.clone(), None).await {
+        Ok(Some(redirect_url)) => {
+            let target_url = format!("{}{}", redirect_url, req.uri());
+            return HttpResponse::TemporaryRedirect()
+                .insert_header((LOCATION, target_url))
+                .finish();
+        }
+        Ok(None) => (),
+        Err(err) => return err_response(err),
+    }
+
+    /*
+      The bundle is handed to actix as is, it
+      sets the content length from it without
+      copying it again.
+    */
+    match flows::read_message_bundle(data.deps.clone(), tx_id).await {
+        Ok(Some(bundle)) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(web::Bytes::from(bundle)),
+        Ok(None) => HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json!({ "error": "Message not found" }).to_string()),
+        Err(err) => err_response(err),
     }
 }
 
@@ -250,19 +474,385 @@ async fn read_process_route(
                 .finish();
         }
         Ok(None) => (),
-        Err(err) => return err_response(err.to_string()),
+        Err(err) => return err_response(err),
     }
 
     match flows::read_process(data.deps.clone(), process_id).await {
         Ok(processed_str) => HttpResponse::Ok()
             .content_type("application/json")
             .body(processed_str),
-        Err(err) => err_response(err.to_string()),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn read_processes_by_module_route(
+    data: web::Data<AppState>,
+    path: web::Path<ModuleId>,
+    query_params: web::Query<CursorLimit>,
+) -> impl Responder {
+    let module_id = path.module_id.clone();
+
+    match flows::read_processes_by_module(
+        data.deps.clone(),
+        module_id,
+        query_params.cursor,
+        query_params.limit,
+    )
+    .await
+    {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn bytestore_stats_route(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::bytestore_admin_stats(data.deps.clone(), admin_operator(&req)).await {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn bytestore_checkpoint_route(
+    data: web::Data<AppState>,
+    query_params: web::Query<CheckpointPath>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::start_bytestore_checkpoint(
+        data.deps.clone(),
+        query_params.path.clone(),
+        admin_operator(&req),
+    ) {
+        Ok(processed_str) => HttpResponse::Accepted()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn bytestore_compact_route(
+    data: web::Data<AppState>,
+    query_params: web::Query<CompactPrefix>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::start_bytestore_compaction(
+        data.deps.clone(),
+        query_params.prefix.clone(),
+        admin_operator(&req),
+    ) {
+        Ok(processed_str) => HttpResponse::Accepted()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn purge_process_route(
+    data: web::Data<AppState>,
+    path: web::Path<ProcessIdRequired>,
+    query_params: web::Query<Force>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::purge_process(
+        data.deps.clone(),
+        path.process_id.clone(),
+        query_params.force.unwrap_or(false),
+        admin_operator(&req),
+    )
+    .await
+    {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn freeze_process_route(
+    data: web::Data<AppState>,
+    path: web::Path<ProcessIdRequired>,
+    req: HttpRequest,
+) -> impl Responder {
+    set_process_frozen(data, path, req, true).await
+}
+
+async fn unfreeze_process_route(
+    data: web::Data<AppState>,
+    path: web::Path<ProcessIdRequired>,
+    req: HttpRequest,
+) -> impl Responder {
+    set_process_frozen(data, path, req, false).await
+}
+
+/*
+  The frozen flag lives in this su's memory, it is lost
+  on restart and other su instances don't see it.
+*/
+async fn set_process_frozen(
+    data: web::Data<AppState>,
+    path: web::Path<ProcessIdRequired>,
+    req: HttpRequest,
+    frozen: bool,
+) -> HttpResponse {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::freeze_process(
+        data.deps.clone(),
+        path.process_id.clone(),
+        frozen,
+        admin_operator(&req),
+    )
+    .await
+    {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
     }
 }
 
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok()
+async fn export_handover_route(
+    data: web::Data<AppState>,
+    path: web::Path<ProcessIdRequired>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::export_process_handover(
+        data.deps.clone(),
+        path.process_id.clone(),
+        admin_operator(&req),
+    )
+    .await
+    {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn import_handover_route(
+    data: web::Data<AppState>,
+    req_body: web::Bytes,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::import_process_handover(data.deps.clone(), req_body.to_vec(), admin_operator(&req))
+        .await
+    {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn admin_stats_route(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::admin_stats(data.deps.clone(), admin_operator(&req)).await {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn list_migrations_route(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::list_migrations(data.deps.clone(), admin_operator(&req)).await {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn recount_process_counts_route(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::recount_process_counts(data.deps.clone(), admin_operator(&req)).await {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn invalidate_process_cache_route(
+    data: web::Data<AppState>,
+    path: web::Path<ProcessIdRequired>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::invalidate_process_cache(
+        data.deps.clone(),
+        path.process_id.clone(),
+        admin_operator(&req),
+    )
+    .await
+    {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn resize_process_cache_route(
+    data: web::Data<AppState>,
+    body: web::Json<CacheSize>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::resize_process_cache(data.deps.clone(), body.size, admin_operator(&req)).await {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+/*
+  The sync and integrity check can take minutes, so
+  they answer with a task id to poll at
+  /admin/tasks/{task_id} instead of their result,
+  the same as a checkpoint or compaction.
+*/
+async fn bytestore_sync_route(
+    data: web::Data<AppState>,
+    query_params: web::Query<SyncAfter>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::start_bytestore_sync(
+        data.deps.clone(),
+        query_params.after.unwrap_or(0),
+        admin_operator(&req),
+    ) {
+        Ok(processed_str) => HttpResponse::Accepted()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn process_integrity_route(
+    data: web::Data<AppState>,
+    path: web::Path<ProcessIdRequired>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::start_integrity_check(
+        data.deps.clone(),
+        path.process_id.clone(),
+        admin_operator(&req),
+    ) {
+        Ok(processed_str) => HttpResponse::Accepted()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+async fn admin_task_route(
+    data: web::Data<AppState>,
+    path: web::Path<TaskId>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(response) = admin_unauthorized(&data, &req) {
+        return response;
+    }
+
+    match flows::admin_task(data.deps.clone(), path.task_id, admin_operator(&req)) {
+        Ok(processed_str) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(processed_str),
+        Err(err) => err_response(err),
+    }
+}
+
+/*
+  Used by load balancers, so report unhealthy when
+  either database pool can't run a query, which
+  includes while a circuit breaker is open, or the
+  bytestore is down.
+*/
+async fn health_check(data: web::Data<AppState>) -> impl Responder {
+    let health = data.deps.data_store.health_check().await;
+    let body = json!(health).to_string();
+    if health.is_healthy() {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(body)
+    } else {
+        HttpResponse::ServiceUnavailable()
+            .content_type("application/json")
+            .body(body)
+    }
+}
+
+/*
+  For a liveness probe, checks the pools have an idle
+  connection without checking one out, so it can be
+  called often and never waits on a busy pool. /health
+  runs queries and is the readiness probe.
+*/
+async fn liveness_check(data: web::Data<AppState>) -> impl Responder {
+    if data.deps.data_store.ping() {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::ServiceUnavailable().finish()
+    }
 }
 
 async fn metrics_route(data: web::Data<AppState>) -> impl Responder {
@@ -285,7 +875,20 @@ struct AppState {
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // --config <path> can go anywhere, the same as setting CONFIG_FILE
+    if let Some(i) = args.iter().position(|arg| arg == "--config") {
+        match args.get(i + 1) {
+            Some(path) => env::set_var(CONFIG_FILE, path),
+            None => {
+                let err = Error::new(ErrorKind::InvalidInput, "--config needs a file path");
+                return Err(err);
+            }
+        }
+        args.drain(i..i + 2);
+    }
+
     let mode = match args.get(1) {
         Some(m) => Some(m.clone()),
         None => None,
@@ -324,6 +927,11 @@ async fn main() -> io::Result<()> {
             Err(e) => run_deps.logger.log(format!("{}", e)),
             Ok(m) => run_deps.logger.log(format!("{}", m)),
         };
+        if run_deps.config.recount_process_counts_on_startup() {
+            if let Err(e) = router::recount_process_counts(run_deps.clone()).await {
+                run_deps.logger.log(format!("{}", e));
+            }
+        }
     }
 
     HttpServer::new(move || {
@@ -341,12 +949,120 @@ async fn main() -> io::Result<()> {
             .route("/", web::post().to(main_post_route))
             .route("/timestamp", web::get().to(timestamp_route))
             .route("/health", web::get().to(health_check))
+            .route("/live", web::get().to(liveness_check))
             .route("/metrics", web::get().to(metrics_route))
+            .route(
+                "/admin/bytestore/stats",
+                web::get().to(bytestore_stats_route),
+            )
+            .route(
+                "/admin/bytestore/checkpoint",
+                web::post().to(bytestore_checkpoint_route),
+            )
+            .route(
+                "/admin/bytestore/compact",
+                web::post().to(bytestore_compact_route),
+            )
+            .route(
+                "/admin/processes/{process_id}/purge",
+                web::post().to(purge_process_route),
+            )
+            .route(
+                "/admin/processes/{process_id}/freeze",
+                web::post().to(freeze_process_route),
+            )
+            .route(
+                "/admin/processes/{process_id}/unfreeze",
+                web::post().to(unfreeze_process_route),
+            )
+            .route(
+                "/admin/processes/{process_id}/handover",
+                web::get().to(export_handover_route),
+            )
+            .route(
+                "/admin/processes/handover",
+                web::post().to(import_handover_route),
+            )
+            .route(
+                "/admin/processes/{process_id}/cache/invalidate",
+                web::post().to(invalidate_process_cache_route),
+            )
+            .route(
+                "/admin/cache/resize",
+                web::put().to(resize_process_cache_route),
+            )
+            .route(
+                "/admin/processes/{process_id}/integrity",
+                web::post().to(process_integrity_route),
+            )
+            .route(
+                "/admin/bytestore/sync",
+                web::post().to(bytestore_sync_route),
+            )
+            .route("/admin/stats", web::get().to(admin_stats_route))
+            .route("/admin/migrations", web::get().to(list_migrations_route))
+            .route(
+                "/admin/schedulers/recount",
+                web::post().to(recount_process_counts_route),
+            )
+            .route("/admin/tasks/{task_id}", web::get().to(admin_task_route))
             .route("/{tx_id}", web::get().to(main_get_route))
+            .route(
+                "/processes/module/{module_id}",
+                web::get().to(read_processes_by_module_route),
+            )
             .route("/processes/{process_id}", web::get().to(read_process_route))
             .route("/{process_id}/latest", web::get().to(read_latest_route))
+            .route("/{process_id}/events", web::get().to(read_events_route))
+            .route("/{tx_id}/bundle", web::get().to(read_bundle_route))
     })
     .bind(("0.0.0.0", port))?
     .run()
-    .await
+    .await?;
+
+    /*
+      The server has stopped accepting requests, let the
+      data store flush anything it is still holding.
+    */
+    run_deps.data_store.shutdown().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn query(query_str: &str) -> FromTo {
+        web::Query::<FromTo>::from_query(query_str)
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn test_include_deleted_needs_admin_token() {
+        let key = Some("admin-key".to_string());
+        let admin = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer admin-key"))
+            .to_http_request();
+        let wrong = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer other-key"))
+            .to_http_request();
+        let public = TestRequest::default().to_http_request();
+
+        let requested = query("include-deleted=true");
+        assert!(include_deleted_allowed(&requested, key.clone(), &admin));
+        assert!(!include_deleted_allowed(&requested, key.clone(), &wrong));
+        assert!(!include_deleted_allowed(&requested, key.clone(), &public));
+        // admin routes disabled, nothing can include them
+        assert!(!include_deleted_allowed(&requested, None, &admin));
+
+        assert!(!include_deleted_allowed(
+            &query("include-deleted=false"),
+            key.clone(),
+            &admin
+        ));
+        assert!(!include_deleted_allowed(&query(""), key, &admin));
+    }
 }